@@ -136,6 +136,7 @@ pub struct Controller {
     background_mode: qt_property!(i32; WRITE set_background_mode),
     background_margin: qt_property!(f64; WRITE set_background_margin),
     background_margin_feather: qt_property!(f64; WRITE set_background_margin_feather),
+    output_color_space: qt_property!(i32; WRITE set_output_color_space), // -1 keeps the input color description
 
     lens_loaded: qt_property!(bool; NOTIFY lens_changed),
     set_lens_param: qt_method!(fn(&self, param: QString, value: f64)),
@@ -384,6 +385,7 @@ impl Controller {
                     this.rolling_shutter_estimated(offs.1);
                 }
             } else {
+                this.stabilizer.push_undo_state();
                 let mut gyro = this.stabilizer.gyro.write();
                 gyro.prevent_recompute = true;
                 for x in offsets {
@@ -967,14 +969,16 @@ impl Controller {
                                     size,
                                     data: BufferSource::OpenGL {
                                         texture: ptr1 as u32,
-                                        context: ptr2 as *mut std::ffi::c_void
+                                        context: ptr2 as *mut std::ffi::c_void,
+                                        premultiplied: false
                                     }, ..Default::default()
                                 },
                                 output: BufferDescription {
                                     size,
                                     data: BufferSource::OpenGL {
                                         texture: ptr1 as u32,
-                                        context: ptr2 as *mut std::ffi::c_void
+                                        context: ptr2 as *mut std::ffi::c_void,
+                                        premultiplied: false
                                     }, ..Default::default()
                                 },
                             },
@@ -1004,7 +1008,8 @@ impl Controller {
                                     data: BufferSource::DirectX11 {
                                         texture: ptr1 as *mut std::ffi::c_void,
                                         device:  ptr2 as *mut std::ffi::c_void,
-                                        device_context: ptr3 as *mut std::ffi::c_void
+                                        device_context: ptr3 as *mut std::ffi::c_void,
+                                        premultiplied: false
                                     }, ..Default::default()
                                 },
                                 output: BufferDescription {
@@ -1013,7 +1018,8 @@ impl Controller {
                                     data: BufferSource::DirectX11 {
                                         texture: ptr1 as *mut std::ffi::c_void,
                                         device:  ptr2 as *mut std::ffi::c_void,
-                                        device_context: ptr3 as *mut std::ffi::c_void
+                                        device_context: ptr3 as *mut std::ffi::c_void,
+                                        premultiplied: false
                                     }, ..Default::default()
                                 },
                             },
@@ -1349,6 +1355,13 @@ impl Controller {
     wrap_simple_method!(set_background_mode,           v: i32; recompute);
     wrap_simple_method!(set_background_margin,         v: f64; recompute);
     wrap_simple_method!(set_background_margin_feather, v: f64; recompute);
+    fn set_output_color_space(&self, v: i32) {
+        if v < 0 {
+            self.stabilizer.params.write().output_color_space = None;
+        } else {
+            self.stabilizer.set_output_color_space(core::stabilization_params::ColorSpace::from(v));
+        }
+    }
     wrap_simple_method!(set_video_speed,               v: f64, s: bool, z: bool; recompute; zooming_data_changed);
 
     wrap_simple_method!(set_offset, timestamp_us: i64, offset_ms: f64; recompute; update_offset_model);
@@ -0,0 +1,46 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Visual horizon for footage where the accelerometer can't be used as the gravity reference.
+// The longest near-horizontal straight edge in the frame is assumed to be the horizon, eg. the sky-ground boundary.
+// Frames are 8-bit grayscale, `width * height` bytes each
+
+const MAX_ROLL: f64 = 30.0; // in degrees, steeper lines are not considered a horizon
+const AGREEMENT: f64 = 2.0; // in degrees, lines parallel to the dominant one within this are averaged with it
+
+// Roll of the horizon in degrees, positive when it's rotated clockwise in the image (right side lower)
+pub fn detect_horizon(frame: &[u8], width: usize, height: usize) -> Option<f64> {
+    if width < 8 || height < 8 || frame.len() < width * height { return None; }
+
+    let mut candidates = super::straight_lines::detect_lines(frame, width, height).into_iter()
+        .filter_map(|line| fit_line(&line))
+        .filter(|(angle, _)| angle.abs() <= MAX_ROLL)
+        .collect::<Vec<_>>();
+    candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let &(dominant, _) = candidates.first()?;
+
+    // The Hough direction is quantized to 1°, the least squares fit of every line is more precise. Average it with the
+    // other long lines parallel to the dominant one, eg. both edges of a horizon blurred by haze
+    let (sum, weight) = candidates.iter()
+        .filter(|(angle, _)| (angle - dominant).abs() <= AGREEMENT)
+        .fold((0.0, 0.0), |(sum, weight), (angle, length)| (sum + angle * length, weight + length));
+    Some(sum / weight)
+}
+
+// Direction of the total least squares line through the points in degrees (-90..90), and the length of the points along it
+fn fit_line(points: &[(f64, f64)]) -> Option<(f64, f64)> {
+    if points.len() < 2 { return None; }
+    let n = points.len() as f64;
+    let (mx, my) = points.iter().fold((0.0, 0.0), |(x, y), p| (x + p.0, y + p.1));
+    let (mx, my) = (mx / n, my / n);
+    let (sxx, syy, sxy) = points.iter().fold((0.0, 0.0, 0.0), |(xx, yy, xy), p| {
+        let (dx, dy) = (p.0 - mx, p.1 - my);
+        (xx + dx * dx, yy + dy * dy, xy + dx * dy)
+    });
+    let angle = 0.5 * (2.0 * sxy).atan2(sxx - syy);
+    let (c, s) = (angle.cos(), angle.sin());
+    let (min, max) = points.iter()
+        .map(|p| (p.0 - mx) * c + (p.1 - my) * s)
+        .fold((f64::MAX, f64::MIN), |(min, max), t| (min.min(t), max.max(t)));
+    Some((angle.to_degrees(), max - min))
+}
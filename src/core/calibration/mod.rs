@@ -23,6 +23,8 @@ use rayon::iter::{ ParallelIterator, IntoParallelIterator };
 use crate::stabilization::distortion_models::DistortionModel;
 
 pub mod drawing;
+pub mod straight_lines;
+pub mod horizon_detection;
 
 #[derive(Clone, Default, Debug)]
 pub struct Detected {
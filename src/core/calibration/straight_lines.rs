@@ -0,0 +1,266 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Rough lens estimate for videos without a profile. Long edges in the scene are assumed to be straight lines,
+// so the radial distortion is the one which makes the detected edges the straightest after undistortion.
+// Edges are found with a Sobel filter and a Hough transform. Frames are 8-bit grayscale, `width * height` bytes each
+
+use rayon::iter::{ ParallelIterator, IntoParallelIterator };
+use crate::lens_profile::{ LensProfile, Dimensions };
+
+const MIN_FRAMES: usize = 5;
+const MIN_LINES: usize = 20;
+const THETA_BINS: usize = 180;
+const EDGE_THRESHOLD: f32 = 48.0; // Sobel gradient magnitude
+const MAX_LINES_PER_FRAME: usize = 30;
+const MAX_GAP: f64 = 4.0; // in pixels along the line
+const MAX_POINTS_PER_LINE: usize = 64;
+
+#[derive(thiserror::Error, Debug)]
+pub enum EstimationError {
+    #[error("Not enough frames ({0}), at least {1} are required")]
+    NotEnoughFrames(usize, usize),
+
+    #[error("Frame size doesn't match {0}x{1}")]
+    InvalidFrameSize(u32, u32),
+
+    #[error("Not enough straight lines found ({0}), at least {1} are required")]
+    NotEnoughLines(usize, usize),
+}
+
+struct EdgePoint {
+    x: f64,
+    y: f64,
+    theta: usize, // Gradient direction, THETA_BINS over 0..π
+}
+
+fn detect_edges(frame: &[u8], width: usize, height: usize) -> Vec<EdgePoint> {
+    let px = |x: usize, y: usize| frame[y * width + x] as f32;
+    let mut gradients = vec![(0.0f32, 0.0f32, 0.0f32); width * height]; // gx, gy, magnitude
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let gx = (px(x + 1, y - 1) + 2.0 * px(x + 1, y) + px(x + 1, y + 1)) - (px(x - 1, y - 1) + 2.0 * px(x - 1, y) + px(x - 1, y + 1));
+            let gy = (px(x - 1, y + 1) + 2.0 * px(x, y + 1) + px(x + 1, y + 1)) - (px(x - 1, y - 1) + 2.0 * px(x, y - 1) + px(x + 1, y - 1));
+            gradients[y * width + x] = (gx, gy, (gx * gx + gy * gy).sqrt());
+        }
+    }
+
+    let mut ret = Vec::new();
+    for y in 2..height - 2 {
+        for x in 2..width - 2 {
+            let (gx, gy, mag) = gradients[y * width + x];
+            if mag < EDGE_THRESHOLD { continue; }
+            // Keep only the local maximum along the gradient, so every edge is one pixel wide
+            let (dx, dy) = ((gx / mag).round() as isize, (gy / mag).round() as isize);
+            let at = |dx: isize, dy: isize| gradients[(y as isize + dy) as usize * width + (x as isize + dx) as usize].2;
+            if mag < at(dx, dy) || mag <= at(-dx, -dy) { continue; }
+
+            let theta = (gy as f64).atan2(gx as f64).rem_euclid(std::f64::consts::PI);
+            ret.push(EdgePoint { x: x as f64, y: y as f64, theta: ((theta / std::f64::consts::PI * THETA_BINS as f64) as usize).min(THETA_BINS - 1) });
+        }
+    }
+    ret
+}
+
+fn theta_distance(a: usize, b: usize) -> usize {
+    let d = if a > b { a - b } else { b - a };
+    d.min(THETA_BINS - d)
+}
+
+// Longest straight edges in the frame, as points along each edge
+pub(super) fn detect_lines(frame: &[u8], width: usize, height: usize) -> Vec<Vec<(f64, f64)>> {
+    let edges = detect_edges(frame, width, height);
+    let diag = ((width * width + height * height) as f64).sqrt();
+    let rho_bins = (diag * 2.0).ceil() as usize + 1;
+    let min_length = width.min(height) as f64 * 0.15;
+    let band = (diag * 0.004).max(2.0); // Distorted lines are curved, so allow some distance from the Hough line
+
+    let angles = (0..THETA_BINS).map(|t| {
+        let a = (t as f64 + 0.5) / THETA_BINS as f64 * std::f64::consts::PI;
+        (a.cos(), a.sin())
+    }).collect::<Vec<_>>();
+
+    // Every point only votes for the directions close to its gradient
+    let mut acc = vec![0u32; THETA_BINS * rho_bins];
+    for p in &edges {
+        for dt in -2isize..=2 {
+            let t = (p.theta as isize + dt).rem_euclid(THETA_BINS as isize) as usize;
+            let rho = p.x * angles[t].0 + p.y * angles[t].1;
+            acc[t * rho_bins + (rho + diag).round() as usize] += 1;
+        }
+    }
+
+    let mut peaks = Vec::new();
+    for t in 0..THETA_BINS {
+        for r in 1..rho_bins - 1 {
+            let v = acc[t * rho_bins + r];
+            if (v as f64) < min_length { continue; }
+            let is_max = (-1isize..=1).all(|dt| (-1isize..=1).all(|dr| {
+                let t2 = (t as isize + dt).rem_euclid(THETA_BINS as isize) as usize;
+                acc[t2 * rho_bins + (r as isize + dr) as usize] <= v
+            }));
+            if is_max { peaks.push((v, t, r as f64 - diag)); }
+        }
+    }
+    peaks.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let mut lines = Vec::new();
+    let mut used: Vec<(usize, f64)> = Vec::new();
+    for (_, t, rho) in peaks {
+        if lines.len() >= MAX_LINES_PER_FRAME { break; }
+        if used.iter().any(|&(t2, rho2)| theta_distance(t, t2) <= 3 && (rho - rho2).abs() <= band * 2.0) { continue; }
+        used.push((t, rho));
+
+        let (c, s) = angles[t];
+        let mut points = edges.iter()
+            .filter(|p| theta_distance(p.theta, t) <= 3 && (p.x * c + p.y * s - rho).abs() <= band)
+            .map(|p| (-p.x * s + p.y * c, p.x, p.y)) // position along the line, x, y
+            .collect::<Vec<_>>();
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        // Longest run without gaps
+        let mut best = (0, 0);
+        let mut start = 0;
+        for i in 1..=points.len() {
+            if i == points.len() || points[i].0 - points[i - 1].0 > MAX_GAP {
+                if i - start > best.1 - best.0 { best = (start, i); }
+                start = i;
+            }
+        }
+        if best.1 - best.0 < 2 { continue; }
+        let run = &points[best.0..best.1];
+        if run[run.len() - 1].0 - run[0].0 < min_length { continue; }
+
+        let step = (run.len() as f64 / MAX_POINTS_PER_LINE as f64).max(1.0);
+        lines.push((0..run.len().min(MAX_POINTS_PER_LINE)).map(|i| {
+            let p = run[((i as f64 * step) as usize).min(run.len() - 1)];
+            (p.1, p.2)
+        }).collect());
+    }
+    lines
+}
+
+// Sum of the relative thickness of every line after undistortion with the OpenCV radial model, 0 for perfectly straight lines
+fn curvature(lines: &[Vec<(f64, f64)>], center: (f64, f64), f: f64, k1: f64, k2: f64) -> f64 {
+    lines.iter().map(|line| {
+        let mut pts = Vec::with_capacity(line.len());
+        for &(x, y) in line {
+            let (xd, yd) = ((x - center.0) / f, (y - center.1) / f);
+            let (mut xu, mut yu) = (xd, yd);
+            for _ in 0..8 {
+                let r2 = xu * xu + yu * yu;
+                let scale = 1.0 + k1 * r2 + k2 * r2 * r2;
+                if scale < 0.1 { return 1.0; }
+                xu = xd / scale;
+                yu = yd / scale;
+            }
+            pts.push((xu, yu));
+        }
+        let n = pts.len() as f64;
+        let (mx, my) = pts.iter().fold((0.0, 0.0), |a, p| (a.0 + p.0 / n, a.1 + p.1 / n));
+        let (sxx, syy, sxy) = pts.iter().fold((0.0, 0.0, 0.0), |a, p| (a.0 + (p.0 - mx).powi(2), a.1 + (p.1 - my).powi(2), a.2 + (p.0 - mx) * (p.1 - my)));
+        // Eigenvalues of the covariance matrix
+        let tr = sxx + syy;
+        let det = sxx * syy - sxy * sxy;
+        let disc = (tr * tr / 4.0 - det).max(0.0).sqrt();
+        let (l_max, l_min) = (tr / 2.0 + disc, (tr / 2.0 - disc).max(0.0));
+        if l_max <= 0.0 { 0.0 } else { l_min / l_max }
+    }).sum()
+}
+
+// Estimate `k1` and `k2` of the `opencv_standard` model from `num_frames` frames evenly picked from `frames`.
+// The focal length is unknown, so it's assumed to be half of the width (90° horizontal FOV) and the principal point is the image center.
+// This is only a rough correction for when no profile exists
+pub fn estimate_lens_from_video_lines(frames: &[Vec<u8>], width: u32, height: u32, num_frames: u32) -> Result<LensProfile, EstimationError> {
+    let num_frames = (num_frames as usize).min(frames.len());
+    if num_frames < MIN_FRAMES { return Err(EstimationError::NotEnoughFrames(num_frames, MIN_FRAMES)); }
+    let (w, h) = (width as usize, height as usize);
+    if w < 16 || h < 16 { return Err(EstimationError::InvalidFrameSize(width, height)); }
+
+    let selected = (0..num_frames).map(|i| &frames[i * frames.len() / num_frames]).collect::<Vec<_>>();
+    if selected.iter().any(|f| f.len() < w * h) { return Err(EstimationError::InvalidFrameSize(width, height)); }
+
+    let lines = selected.into_par_iter().flat_map(|frame| detect_lines(frame, w, h)).collect::<Vec<_>>();
+    log::debug!("Found {} straight lines in {} frames", lines.len(), num_frames);
+    if lines.len() < MIN_LINES { return Err(EstimationError::NotEnoughLines(lines.len(), MIN_LINES)); }
+
+    let center = (w as f64 / 2.0, h as f64 / 2.0);
+    let f = w as f64 / 2.0;
+    let cost = |k1: f64, k2: f64| curvature(&lines, center, f, k1, k2);
+
+    // Coarse grid, then refine with a pattern search
+    let mut best = (0.0, 0.0, cost(0.0, 0.0));
+    for i in -10..=10 {
+        for j in -4..=4 {
+            let (k1, k2) = (i as f64 * 0.05, j as f64 * 0.05);
+            let c = cost(k1, k2);
+            if c < best.2 { best = (k1, k2, c); }
+        }
+    }
+    let mut step = 0.025;
+    while step > 1e-4 {
+        let mut improved = false;
+        for (d1, d2) in [(step, 0.0), (-step, 0.0), (0.0, step), (0.0, -step)] {
+            let c = cost(best.0 + d1, best.1 + d2);
+            if c < best.2 { best = (best.0 + d1, best.1 + d2, c); improved = true; }
+        }
+        if !improved { step /= 2.0; }
+    }
+    log::info!("Estimated lens distortion from {} lines: k1: {:.4}, k2: {:.4}, residual: {:.6}", lines.len(), best.0, best.1, best.2);
+
+    let mut profile = LensProfile::default();
+    profile.init();
+    profile.name = "Estimated from straight lines".into();
+    profile.note = "Rough estimate, assumes 90° horizontal FOV".into();
+    profile.distortion_model = Some("opencv_standard".into());
+    profile.calib_dimension = Dimensions { w, h };
+    profile.orig_dimension = Dimensions { w, h };
+    profile.input_horizontal_stretch = 1.0;
+    profile.input_vertical_stretch = 1.0;
+    profile.num_images = num_frames;
+    profile.fisheye_params.RMS_error = best.2 / lines.len() as f64;
+    profile.fisheye_params.camera_matrix = vec![
+        [f,   0.0, center.0],
+        [0.0, f,   center.1],
+        [0.0, 0.0, 1.0]
+    ];
+    profile.fisheye_params.distortion_coeffs = vec![best.0, best.1, 0.0, 0.0, 0.0];
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checkerboard with 16 px squares, so every frame has straight vertical and horizontal edges
+    fn checkerboard(width: usize, height: usize, offset: usize) -> Vec<u8> {
+        (0..width * height).map(|i| {
+            let (x, y) = (i % width + offset, i / width);
+            if (x / 16 + y / 16) % 2 == 0 { 40 } else { 200 }
+        }).collect()
+    }
+
+    #[test]
+    fn estimated_profile_loads_in_manager() {
+        let (width, height) = (128, 96);
+        let frames = (0..8).map(|i| checkerboard(width, height, i * 2)).collect::<Vec<_>>();
+
+        let stab = crate::StabilizationManager::default();
+        stab.params.write().video_size = (width, height);
+        stab.estimate_unknown_lens_profile(&frames, width as u32, height as u32, 5).unwrap();
+
+        let lens = stab.lens.read();
+        assert!(!lens.calibrator_version.is_empty());
+        assert_eq!(lens.distortion_model.as_deref(), Some("opencv_standard"));
+        assert_eq!((lens.calib_dimension.w, lens.calib_dimension.h), (width, height));
+        // Lines are already straight
+        let k = lens.get_distortion_coeffs();
+        assert!(k[0].abs() < 0.05 && k[1].abs() < 0.05, "k1: {}, k2: {}", k[0], k[1]);
+    }
+
+    #[test]
+    fn not_enough_frames() {
+        let frames = vec![checkerboard(128, 96, 0); 2];
+        assert!(matches!(estimate_lens_from_video_lines(&frames, 128, 96, 2), Err(EstimationError::NotEnoughFrames(2, MIN_FRAMES))));
+    }
+}
@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+// Accounting of the host memory the gpu module keeps between frames: pinned OpenCL output buffers and wgpu staging buffers.
+// Every owner holds a `HostAllocation`. When the budget is exceeded, the least recently used allocations are marked as evicted
+// and their wrappers are dropped and created again on the next frame which needs them (see `Stabilization::init_backends`).
+// Eviction never blocks a frame which is already rendering, so the usage can be above the budget until the owners notice.
+// With a budget smaller than the working set, eg. preview and export rendering alternately, the wrappers keep evicting
+// each other and every switch pays the buffer and kernel setup again (compiled programs stay cached).
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering::SeqCst };
+
+static BUDGET: AtomicUsize = AtomicUsize::new(0); // in bytes, 0 = unlimited
+static CLOCK: AtomicU64 = AtomicU64::new(0);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Entry {
+    size: usize,
+    last_use: Arc<AtomicU64>,
+    evicted: Arc<AtomicBool>,
+}
+
+lazy_static::lazy_static! {
+    static ref ALLOCATIONS: Mutex<HashMap<u64, Entry>> = Mutex::new(HashMap::new());
+}
+
+pub struct HostAllocation {
+    id: u64,
+    size: usize,
+    last_use: Arc<AtomicU64>,
+    evicted: Arc<AtomicBool>,
+}
+
+impl HostAllocation {
+    pub fn new(size: usize) -> Self {
+        let id = NEXT_ID.fetch_add(1, SeqCst);
+        let last_use = Arc::new(AtomicU64::new(CLOCK.fetch_add(1, SeqCst)));
+        let evicted = Arc::new(AtomicBool::new(false));
+        ALLOCATIONS.lock().insert(id, Entry { size, last_use: last_use.clone(), evicted: evicted.clone() });
+        enforce_budget(Some(id));
+        Self { id, size, last_use, evicted }
+    }
+    // Mark as used by the current frame, called once per render
+    pub fn touch(&self) {
+        self.last_use.store(CLOCK.fetch_add(1, SeqCst), SeqCst);
+    }
+    // The owner should release the memory before its next use
+    pub fn is_evicted(&self) -> bool {
+        self.evicted.load(SeqCst)
+    }
+    pub fn size(&self) -> usize { self.size }
+}
+impl Drop for HostAllocation {
+    fn drop(&mut self) {
+        ALLOCATIONS.lock().remove(&self.id);
+    }
+}
+
+// Evict the least recently used allocations until the rest fits. `keep` is never evicted, so a single allocation
+// above the budget still works
+fn enforce_budget(keep: Option<u64>) {
+    let budget = BUDGET.load(SeqCst);
+    if budget == 0 { return; }
+    let allocations = ALLOCATIONS.lock();
+    let mut usage: usize = allocations.values().filter(|x| !x.evicted.load(SeqCst)).map(|x| x.size).sum();
+    if usage <= budget { return; }
+
+    let mut candidates: Vec<&Entry> = allocations.iter().filter(|(id, x)| Some(**id) != keep && !x.evicted.load(SeqCst)).map(|(_, x)| x).collect();
+    candidates.sort_by_key(|x| x.last_use.load(SeqCst));
+    for entry in candidates {
+        if usage <= budget { break; }
+        entry.evicted.store(true, SeqCst);
+        usage -= entry.size;
+        log::debug!("Host memory budget {budget} exceeded, evicting {} bytes", entry.size);
+    }
+}
+
+// Cap on the host memory retained by the gpu module, in bytes. 0 disables the limit (default)
+pub fn set_host_memory_budget(bytes: usize) {
+    BUDGET.store(bytes, SeqCst);
+    enforce_budget(None);
+}
+pub fn host_memory_budget() -> usize {
+    BUDGET.load(SeqCst)
+}
+// Bytes currently retained, including evicted allocations not released by their owners yet
+pub fn host_memory_usage() -> usize {
+    ALLOCATIONS.lock().values().map(|x| x.size).sum()
+}
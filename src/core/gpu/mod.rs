@@ -1,8 +1,12 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
 
-#[cfg(feature = "use-opencl")]
+#[cfg(feature = "opencl-core")]
 pub mod opencl;
+#[cfg(feature = "opencl-core")]
+pub mod multi_gpu;
+// On macOS and iOS wgpu runs on Metal, including the `BufferSource::Metal` and `MetalBuffer` interop in `wgpu_interop_metal`,
+// so there is no separate Metal backend with a third copy of the kernel. Builds without `opencl-core` never load the deprecated OpenCL framework
 pub mod wgpu;
 
 pub mod wgpu_interop;
@@ -12,7 +16,10 @@ pub mod wgpu_interop;
 #[cfg(any(target_os = "windows", target_os = "linux"))]  pub mod wgpu_interop_cuda;
 
 pub mod drawing;
+pub mod host_memory;
+pub use host_memory::{ set_host_memory_budget, host_memory_budget, host_memory_usage };
 use std::hash::Hasher;
+use crate::stabilization::Interpolation;
 
 #[derive(Default)]
 pub struct BufferDescription<'a> {
@@ -20,34 +27,50 @@ pub struct BufferDescription<'a> {
     pub rect: Option<(usize, usize, usize, usize)>, // x, y, width, height
     pub rotation: Option<f32>, // pixels rotation in degrees
     pub data: BufferSource<'a>,
-    pub texture_copy: bool
+    pub texture_copy: bool,
+    pub alpha: Option<(&'a [u8], usize)>, // Separate alpha plane and its stride in bytes, one scalar of the pixel type per pixel. Input only, CPU buffers only
+    #[cfg(feature = "opencl-core")]
+    pub cl_image_format: Option<ocl::core::ImageFormat>, // Explicit channel layout of an imported OpenGL/DirectX texture, validated against it. None: inferred from the texture
 }
 pub struct Buffers<'a> {
     pub input: BufferDescription<'a>,
     pub output: BufferDescription<'a>
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum GpuError {
+    #[cfg(feature = "opencl-core")]
+    #[error("OpenCL error {0:?}")]
+    OpenCL(#[from] ocl::Error),
+
+    #[error("Kernel source error: {0}")]
+    KernelSource(String),
+}
+
 #[derive(Debug, Default)]
 pub enum BufferSource<'a> {
     #[default]
     None,
     Cpu { buffer: &'a mut [u8] },
-    #[cfg(feature = "use-opencl")]
+    #[cfg(feature = "opencl-core")]
     OpenCL {
         texture: ocl::ffi::cl_mem,
         queue: ocl::ffi::cl_command_queue
     },
-    #[cfg(target_os = "windows")]
+    #[cfg(all(target_os = "windows", feature = "directx"))]
     DirectX11 {
         texture: *mut std::ffi::c_void, // ID3D11Texture2D*
         device: *mut std::ffi::c_void, // ID3D11Device*
         device_context: *mut std::ffi::c_void, // ID3D11DeviceContext*
+        premultiplied: bool, // color already multiplied by alpha, eg. a compositor's render target
     },
+    #[cfg(feature = "opengl")]
     OpenGL {
         texture: u32, // GLuint
         context: *mut std::ffi::c_void, // OpenGL context pointer
+        premultiplied: bool, // color already multiplied by alpha, eg. a compositor's render target
     },
-    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    #[cfg(all(not(any(target_os = "macos", target_os = "ios")), feature = "vulkan"))]
     Vulkan {
         texture: u64,
         device: u64,
@@ -69,7 +92,24 @@ pub enum BufferSource<'a> {
         buffer: *mut std::ffi::c_void // Cudeviceptr
     },
 }
+impl<'a> BufferSource<'a> {
+    // Imported textures which store premultiplied color. Resampled as-is and written back premultiplied
+    pub fn is_premultiplied(&self) -> bool {
+        match self {
+            #[cfg(feature = "opengl")]
+            BufferSource::OpenGL { premultiplied, .. } => *premultiplied,
+            #[cfg(all(target_os = "windows", feature = "directx"))]
+            BufferSource::DirectX11 { premultiplied, .. } => *premultiplied,
+            _ => false
+        }
+    }
+}
 impl<'a> BufferDescription<'a> {
+    // `rect` if it covers only a part of the buffer, eg. one cell of an atlas. Backends render and copy back only this region
+    pub fn sub_rect(&self) -> Option<(usize, usize, usize, usize)> {
+        self.rect.filter(|&(x, y, w, h)| w > 0 && h > 0 && x + w <= self.size.0 && y + h <= self.size.1 && (w, h) != (self.size.0, self.size.1))
+    }
+
     pub fn get_checksum(&self) -> u32 {
         let mut hasher = crc32fast::Hasher::new();
         hasher.write_usize(self.size.0);
@@ -81,31 +121,38 @@ impl<'a> BufferDescription<'a> {
             hasher.write_usize(r.2);
             hasher.write_usize(r.3);
         }
+        #[cfg(feature = "opencl-core")]
+        if let Some(format) = &self.cl_image_format {
+            let raw = format.to_raw();
+            hasher.write_u32(raw.image_channel_order);
+            hasher.write_u32(raw.image_channel_data_type);
+        }
         match &self.data {
             BufferSource::None => { }
             BufferSource::Cpu { .. } => { }
-            #[cfg(feature = "use-opencl")]
+            #[cfg(feature = "opencl-core")]
             BufferSource::OpenCL { texture, queue } => {
                 if !self.texture_copy {
                     hasher.write_u64(*texture as u64);
                 }
                 hasher.write_u64(*queue as u64);
             }
-            BufferSource::OpenGL { texture, context } => {
+            #[cfg(feature = "opengl")]
+            BufferSource::OpenGL { texture, context, .. } => {
                 if !self.texture_copy {
                     hasher.write_u32(*texture);
                 }
                 hasher.write_u64(*context as u64);
             }
-            #[cfg(target_os = "windows")]
-            BufferSource::DirectX11 { texture, device, device_context } => {
+            #[cfg(all(target_os = "windows", feature = "directx"))]
+            BufferSource::DirectX11 { texture, device, device_context, .. } => {
                 if !self.texture_copy {
                     hasher.write_u64(*texture as u64);
                 }
                 hasher.write_u64(*device as u64);
                 hasher.write_u64(*device_context as u64);
             },
-            #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+            #[cfg(all(not(any(target_os = "macos", target_os = "ios")), feature = "vulkan"))]
             BufferSource::Vulkan { texture, instance, device, physical_device } => {
                 if !self.texture_copy {
                     hasher.write_u64(*texture);
@@ -148,8 +195,25 @@ impl<'a> Buffers<'a> {
     }
 }
 
+// Forget the selected GPU devices after hardware changes. Call `initialize_contexts` afterwards to select the device again
+pub fn reset_context() {
+    #[cfg(feature = "opencl-core")]
+    opencl::OclWrapper::reset_context();
+    wgpu::WgpuWrapper::reset_context();
+}
+
+static EXPLICIT_DEVICE_SELECTION: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+// When enabled, no device is auto-selected by the preference list and the host has to call `set_device` (eg. for managed deployments)
+pub fn set_explicit_device_selection(enabled: bool) {
+    EXPLICIT_DEVICE_SELECTION.store(enabled, std::sync::atomic::Ordering::SeqCst);
+}
+pub fn explicit_device_selection() -> bool {
+    EXPLICIT_DEVICE_SELECTION.load(std::sync::atomic::Ordering::SeqCst)
+}
+
 pub fn initialize_contexts() -> Option<(String, String)> {
-    #[cfg(feature = "use-opencl")]
+    #[cfg(feature = "opencl-core")]
     if std::env::var("NO_OPENCL").unwrap_or_default().is_empty() {
         let cl = std::panic::catch_unwind(|| {
             opencl::OclWrapper::initialize_context(None)
@@ -169,6 +233,11 @@ pub fn initialize_contexts() -> Option<(String, String)> {
         }
     }
 
+    if explicit_device_selection() {
+        log::warn!("Explicit device selection is enabled, not selecting any device automatically");
+        return None;
+    }
+
     if std::env::var("NO_WGPU").unwrap_or_default().is_empty() {
         let wgpu = std::panic::catch_unwind(|| {
             wgpu::WgpuWrapper::initialize_context()
@@ -190,3 +259,103 @@ pub fn initialize_contexts() -> Option<(String, String)> {
 
     None
 }
+
+// Backend selected by `try_initialize_with_fallback`
+#[derive(Debug, Clone, PartialEq)]
+pub enum InitializedBackend {
+    OpenCL { name: String, list_name: String },
+    Wgpu { name: String, list_name: String },
+    Cpu, // No GPU backend could be initialized, frames are rendered on the CPU
+}
+
+// Like `initialize_contexts`, but the OpenCL device also has to build a test program, so a broken driver or compiler is caught before the first frame.
+// Any OpenCL error or panic is logged and the global OpenCL context is cleared, so a later retry selects the device again instead of reusing a broken one.
+// Then wgpu is tried, and `InitializedBackend::Cpu` is returned if that fails too
+pub fn try_initialize_with_fallback(buffers: &Buffers) -> InitializedBackend {
+    #[cfg(feature = "opencl-core")]
+    if std::env::var("NO_OPENCL").unwrap_or_default().is_empty() {
+        let cl = std::panic::catch_unwind(|| -> ocl::Result<(String, String)> {
+            let names = opencl::OclWrapper::initialize_context(Some(buffers))?;
+            opencl::OclWrapper::verify_context("__kernel void verify_context(__global float *p) { p[get_global_id(0)] = 1.0f; }")?;
+            Ok(names)
+        });
+        match cl {
+            Ok(Ok((name, list_name))) => { return InitializedBackend::OpenCL { name, list_name }; },
+            Ok(Err(e)) => { log::error!("OpenCL error init, falling back: {:?}", e); },
+            Err(e) => {
+                if let Some(s) = e.downcast_ref::<&str>() {
+                    log::error!("Failed to initialize OpenCL, falling back: {}", s);
+                } else if let Some(s) = e.downcast_ref::<String>() {
+                    log::error!("Failed to initialize OpenCL, falling back: {}", s);
+                } else {
+                    log::error!("Failed to initialize OpenCL, falling back: {:?}", e);
+                }
+            }
+        }
+        opencl::OclWrapper::reset_context();
+    }
+
+    if explicit_device_selection() {
+        log::warn!("Explicit device selection is enabled, not selecting any device automatically");
+        return InitializedBackend::Cpu;
+    }
+
+    if std::env::var("NO_WGPU").unwrap_or_default().is_empty() {
+        match std::panic::catch_unwind(wgpu::WgpuWrapper::initialize_context) {
+            Ok(Some((name, list_name))) => { return InitializedBackend::Wgpu { name, list_name }; },
+            Ok(None) => { log::error!("wgpu init error, falling back to CPU"); },
+            Err(_) => { log::error!("Failed to initialize wgpu, falling back to CPU"); }
+        }
+    }
+
+    InitializedBackend::Cpu
+}
+
+// Single quality/battery knob over the individual render settings, see `PowerProfileSettings` for what each level changes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PowerProfile {
+    #[default]
+    Performance,
+    Balanced,
+    PowerSaver,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PowerProfileSettings {
+    pub max_interpolation: Interpolation, // More expensive interpolation selected by the user is lowered to this one
+    pub delta_matrices: bool, // fp16 delta encoded matrices upload (OpenCL only)
+    pub sharpening: bool, // Optional adaptive sharpening pass
+}
+
+impl PowerProfile {
+    // Performance: everything as configured
+    // Balanced:    at most bicubic, fp16 matrices
+    // PowerSaver:  bilinear, fp16 matrices, no sharpening
+    pub fn default_settings(&self) -> PowerProfileSettings {
+        match self {
+            Self::Performance => PowerProfileSettings { max_interpolation: Interpolation::Lanczos4, delta_matrices: false, sharpening: true },
+            Self::Balanced    => PowerProfileSettings { max_interpolation: Interpolation::Bicubic,  delta_matrices: true,  sharpening: true },
+            Self::PowerSaver  => PowerProfileSettings { max_interpolation: Interpolation::Bilinear, delta_matrices: true,  sharpening: false },
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref POWER_PROFILE: parking_lot::RwLock<(PowerProfile, std::collections::HashMap<PowerProfile, PowerProfileSettings>)> = Default::default();
+}
+
+// Applies to frames computed after the change
+pub fn set_power_profile(profile: PowerProfile) {
+    POWER_PROFILE.write().0 = profile;
+}
+pub fn get_power_profile() -> PowerProfile {
+    POWER_PROFILE.read().0
+}
+// Replaces the default mapping of `profile`
+pub fn override_power_profile(profile: PowerProfile, settings: PowerProfileSettings) {
+    POWER_PROFILE.write().1.insert(profile, settings);
+}
+pub fn power_profile_settings() -> PowerProfileSettings {
+    let lock = POWER_PROFILE.read();
+    lock.1.get(&lock.0).copied().unwrap_or_else(|| lock.0.default_settings())
+}
@@ -0,0 +1,132 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use super::*;
+use super::opencl::{ CtxWrapper, OclWrapper };
+use crate::stabilization::{ KernelParams, FrameTransform };
+use crate::stabilization::distortion_models::DistortionModel;
+
+// Renders a single frame on several OpenCL devices, each device renders a horizontal band of the output.
+// Every device has its own context and gets the whole source, because any output row can sample any source row. CPU buffers only.
+// The assignment is deterministic: bands depend only on the device list, the weights and the output height, never on the timing,
+// and each band is read back into fixed rows. Renders with the same devices, drivers and inputs are bit-identical.
+// Different devices can differ in the last bits of the float math, so a band moved to another device can change slightly
+pub struct MultiGpuRenderer {
+    gpus: Vec<BandGpu>,
+    output_size: (usize, usize, usize), // width, height, stride
+}
+struct BandGpu {
+    device_index: usize,
+    wrapper: OclWrapper,
+    band: (usize, usize), // y, height
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BandAssignment {
+    pub device_index: usize, // Index in `OclWrapper::list_devices`
+    pub y: usize,
+    pub height: usize,
+}
+
+impl MultiGpuRenderer {
+    // `device_indices` are indices in `OclWrapper::list_devices`, the same as for `OclWrapper::set_device`.
+    // The global context isn't changed. Bands are equal until `set_band_weights` is called
+    pub fn new(device_indices: &[usize], params: &KernelParams, ocl_names: (&str, &str, &str, &str), distortion_model: DistortionModel, digital_lens: Option<DistortionModel>, blended_lens: Option<DistortionModel>, buffers: &Buffers, drawing_len: usize) -> ocl::Result<Self> {
+        if device_indices.is_empty() { return Err("No devices for multi-GPU rendering".into()); }
+        if !matches!(buffers.input.data, BufferSource::Cpu { .. }) || !matches!(buffers.output.data, BufferSource::Cpu { .. }) {
+            return Err("Multi-GPU rendering supports only CPU buffers".into());
+        }
+
+        let mut gpus = Vec::with_capacity(device_indices.len());
+        for &device_index in device_indices {
+            let mut ctx = CtxWrapper::for_device(device_index, Some(buffers))?;
+            let wrapper = OclWrapper::new_in_context(&mut ctx, params, ocl_names, distortion_model.clone(), digital_lens.clone(), blended_lens.clone(), buffers, drawing_len, false, false)?;
+            gpus.push(BandGpu { device_index, wrapper, band: (0, 0) });
+        }
+        let mut ret = Self { gpus, output_size: buffers.output.size };
+        ret.set_band_weights(&vec![1.0; device_indices.len()]);
+        Ok(ret)
+    }
+
+    // Splits the output rows proportionally to `weights`, one per device, eg. the relative speed of the devices.
+    // A device with zero weight doesn't render anything. For reproducible renders use fixed weights instead of measured speeds
+    pub fn set_band_weights(&mut self, weights: &[f64]) {
+        let (count, height) = (self.gpus.len(), self.output_size.1);
+        let weight = |i: usize| weights.get(i).copied().unwrap_or(0.0).max(0.0);
+        let total: f64 = (0..count).map(weight).sum();
+
+        let mut y = 0;
+        let mut sum = 0.0;
+        for (i, gpu) in self.gpus.iter_mut().enumerate() {
+            sum += weight(i);
+            let end = if i + 1 == count {
+                height
+            } else if total > 0.0 {
+                (sum / total * height as f64).round() as usize
+            } else {
+                height * (i + 1) / count
+            };
+            let end = end.clamp(y, height);
+            gpu.band = (y, end - y);
+            gpu.wrapper.set_output_band(Some(gpu.band));
+            y = end;
+        }
+    }
+
+    pub fn bands(&self) -> Vec<BandAssignment> {
+        self.gpus.iter().map(|x| BandAssignment { device_index: x.device_index, y: x.band.0, height: x.band.1 }).collect()
+    }
+
+    // `buffers` must have the sizes given to `new`. The devices render in parallel and each band is read back directly into its rows of the output
+    pub fn render(&self, buffers: &mut Buffers, itm: &FrameTransform, drawing_buffer: &[u8]) -> ocl::Result<()> {
+        if buffers.output.size != self.output_size { return Err("Output size doesn't match the multi-GPU renderer".into()); }
+        let input_size = buffers.input.size;
+        let stride = self.output_size.2;
+        let (input, output) = match (&buffers.input.data, &mut buffers.output.data) {
+            (BufferSource::Cpu { buffer: input }, BufferSource::Cpu { buffer: output }) => (&**input, &mut **output),
+            _ => { return Err("Multi-GPU rendering supports only CPU buffers".into()); }
+        };
+        if output.len() < stride * self.output_size.1 { return Err(format!("Output buffer is too small: {} < {}", output.len(), stride * self.output_size.1).into()); }
+
+        // Bands are contiguous and in the device order, so the output splits into disjoint slices
+        let mut band_buffers = Vec::with_capacity(self.gpus.len());
+        let mut rest = output;
+        for gpu in &self.gpus {
+            let (band, tail) = std::mem::take(&mut rest).split_at_mut(gpu.band.1 * stride);
+            rest = tail;
+            band_buffers.push(Buffers {
+                input: BufferDescription { size: input_size, data: BufferSource::None, ..Default::default() },
+                output: BufferDescription { size: self.output_size, data: BufferSource::Cpu { buffer: band }, ..Default::default() },
+            });
+        }
+
+        // The upload is blocking, so the previous devices are already rendering while the next one receives the source
+        let handles = self.gpus.iter().zip(band_buffers.iter_mut())
+            .filter(|(gpu, _)| gpu.band.1 > 0)
+            .map(|(gpu, band_buffers)| {
+                gpu.wrapper.upload_source(input)?;
+                // Handles are waited for below, or dropped (which also waits) when one of the devices fails
+                unsafe { gpu.wrapper.submit_frame_async(band_buffers, itm, drawing_buffer) }
+            })
+            .collect::<ocl::Result<Vec<_>>>()?;
+        for handle in &handles {
+            handle.wait()?;
+        }
+        Ok(())
+    }
+
+    // Renders the frame with this renderer and with `reference`, a single device wrapper created with the same parameters, and compares the outputs.
+    // Returns the maximum difference of a byte, devices can differ slightly in the floating point math so it's not always 0
+    pub fn validate_against(&self, reference: &OclWrapper, buffers: &mut Buffers, itm: &FrameTransform, drawing_buffer: &[u8]) -> ocl::Result<u8> {
+        self.render(buffers, itm, drawing_buffer)?;
+        let multi = match &buffers.output.data {
+            BufferSource::Cpu { buffer } => buffer.to_vec(),
+            _ => { return Err("Multi-GPU rendering supports only CPU buffers".into()); }
+        };
+        reference.undistort_image(buffers, itm, drawing_buffer)?;
+        match &buffers.output.data {
+            BufferSource::Cpu { buffer } => Ok(multi.iter().zip(buffer.iter()).map(|(a, b)| a.abs_diff(*b)).max().unwrap_or(0)),
+            _ => Err("Multi-GPU rendering supports only CPU buffers".into())
+        }
+    }
+}
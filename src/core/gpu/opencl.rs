@@ -2,9 +2,12 @@
 // Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
 
 use ocl::*;
-use ocl::core::{ ImageDescriptor, MemObjectType, GlTextureTarget };
-use parking_lot::RwLock;
+use ocl::core::{ ImageDescriptor, MemObjectType };
+#[cfg(all(feature = "opengl", feature = "opencl-interop"))]
+use ocl::core::GlTextureTarget;
+use parking_lot::{ RwLock, Mutex };
 use std::ops::DerefMut;
+use std::hash::{ Hash, Hasher };
 use super::*;
 use crate::stabilization::distortion_models::DistortionModel;
 use crate::stabilization::KernelParams;
@@ -22,6 +25,127 @@ pub struct OclWrapper {
     buf_params: Buffer<u8>,
     buf_drawing: Buffer<u8>,
     buf_matrices: Buffer<f32>,
+    buf_quality: Buffer<u8>, // Header and per-tile levels, see `set_quality_map`
+    buf_dense_map: Buffer<f32>, // Header and nodes, see `set_dense_map`
+    drawing_len: usize, // Size of one drawing buffer, `buf_drawing` can hold several, see `upload_drawing_buffer_batch`
+    drawing_index: usize, // Region of `buf_drawing` read by the kernel
+
+    program: Program,
+    output_size: (usize, usize),
+    histogram: Option<HistogramPass>,
+    secondary_output: Option<SecondaryOutput>,
+    delta_matrices: Option<DeltaMatrices>,
+    matrices_batch: Option<MatricesBatch>,
+
+    output_offset: Option<(usize, usize, usize)>, // x, y, stride
+    output_band: Option<(usize, usize)>, // y, height. Only these rows are rendered and read back, see `set_output_band`
+
+    reuse_source: bool,
+
+    distortion_model: DistortionModel,
+    has_other_lenses: bool, // digital or blended lens
+
+    unified_memory: bool,
+    zero_copy: bool, // CPU input/output are wrapped with CL_MEM_USE_HOST_PTR instead of copied
+    mem_base_align: usize, // in bytes
+
+    local_tiling: bool, // Kernel is compiled with TILE_W/TILE_H and needs TILE_WORK_GROUP-aligned work sizes
+    dirty_tiles: Option<(usize, Vec<bool>)>, // tile size, row-major flags over the output
+    has_output: std::sync::atomic::AtomicBool, // `dst` holds a complete previous frame
+
+    context_generation: usize, // Value of CONTEXT_GENERATION when created, see `reset_context`
+    use_image_path: bool, // False if the device couldn't import the OpenGL/DirectX texture as an image, only CPU buffers can be used then
+    program_invalid: std::sync::atomic::AtomicBool, // Set on a program/kernel error, see `categorize_ocl_error`
+    interop_acquired: std::sync::atomic::AtomicBool, // An OpenGL/DirectX texture was acquired by the current `enqueue_frame`, it can't be retried
+    host_memory: Option<host_memory::HostAllocation>, // Pinned `dst` of CPU output, counted in the host memory budget
+    profiling: bool, // Queue created with CL_QUEUE_PROFILING_ENABLE, see `last_kernel_duration_ns`
+    kernel_timing: Mutex<(Option<EventList>, Option<u64>)>, // Kernel events of the last frame, their duration in ns once read
+}
+
+// Optional features of the compiled kernel, for the UI to disable the controls which would have no effect.
+// The first group is fixed at compile time, a change requires a new wrapper. The rest depends on the pixel format and lenses, or on the enabled passes
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KernelCapabilities {
+    pub interpolation: i32, // Taps of the compiled interpolation: 2 bilinear, 4 bicubic, 8 lanczos4
+    pub drawing: bool, // Overlay drawing, compiled out when disabled at creation
+    pub premultiplied_alpha: bool, // Straight alpha interpolated premultiplied, float formats with alpha only
+    pub digital_lens: bool,
+    pub blended_lens: bool,
+    pub half_float: bool, // fp16 pixel format through cl_khr_fp16
+    pub local_tiling: bool,
+
+    pub equirectangular: bool, // The digital lens isn't applied to equirectangular output
+    pub color_correction: bool, // Formats with color channels
+    pub hdr_transfer_functions: bool, // PQ and HLG, formats with color channels
+    pub histogram: bool, // `set_histogram` succeeded
+    pub secondary_output: bool, // `set_secondary_output` succeeded
+    pub delta_matrices: bool, // `set_delta_matrices` succeeded
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    Recoverable, // Retrying may help, eg. temporary lack of memory
+    ContextLost, // The whole context has to be created again
+    ProgramError, // The program has to be compiled again
+    Fatal,
+}
+
+pub fn categorize_ocl_error(e: &ocl::Error) -> ErrorCategory {
+    use ocl::core::Status;
+    match e.api_status() {
+        Some(Status::CL_DEVICE_NOT_AVAILABLE) | Some(Status::CL_MEM_OBJECT_ALLOCATION_FAILURE) |
+        Some(Status::CL_OUT_OF_RESOURCES) | Some(Status::CL_OUT_OF_HOST_MEMORY) => ErrorCategory::Recoverable,
+        Some(Status::CL_INVALID_CONTEXT) | Some(Status::CL_DEVICE_NOT_FOUND) |
+        Some(Status::CL_INVALID_COMMAND_QUEUE) | Some(Status::CL_INVALID_DEVICE) => ErrorCategory::ContextLost,
+        Some(Status::CL_BUILD_PROGRAM_FAILURE) | Some(Status::CL_INVALID_KERNEL) |
+        Some(Status::CL_INVALID_PROGRAM) | Some(Status::CL_INVALID_PROGRAM_EXECUTABLE) => ErrorCategory::ProgramError,
+        _ => ErrorCategory::Fatal
+    }
+}
+
+// Compiled programs by `program_key`, least recently used ones are dropped. A program holds a reference to its context,
+// so the keys stay unique while cached. Programs of a replaced global context are dropped, see `set_global_context`
+pub type KernelProgramCache = lru::LruCache<u64, CompiledProgram>;
+const PROGRAM_CACHE_SIZE: usize = 16;
+
+// Program with the defines it was compiled with, which the wrapper has to match
+#[derive(Clone)]
+pub struct CompiledProgram {
+    pub program: Program,
+    pub tile_size: Option<(usize, usize)>, // TILE_W, TILE_H. The kernel then needs TILE_WORK_GROUP x TILE_WORK_GROUP work groups
+    capabilities: KernelCapabilities, // Of the inputs the source was generated from, see `source_capabilities`
+    context: usize, // Pointer of the context it's built in
+}
+
+enum KernelSource<'a> {
+    Source(String, KernelCapabilities),
+    Compiled(&'a CompiledProgram),
+}
+
+struct MatricesBatch {
+    _buf: Buffer<f32>,
+    frames: Vec<(Buffer<f32>, usize, u32)>, // sub-buffer, matrix count, checksum of the matrices
+    selected: Option<usize>,
+}
+
+struct DeltaMatrices {
+    kernel: Kernel,
+    buf_base: Buffer<f32>,
+    buf_deltas: Buffer<u16>,
+}
+
+struct HistogramPass {
+    kernel: Kernel,
+    buf: Buffer<u32>,
+    bins: usize,
+    channels: usize,
+    result: Mutex<Vec<u32>>,
+}
+
+struct SecondaryOutput {
+    kernel: Kernel,
+    buf: Buffer<u8>,
+    size: (usize, usize, usize), // width, height, stride
 }
 
 pub struct CtxWrapper {
@@ -31,22 +155,74 @@ pub struct CtxWrapper {
 
     pub surface_checksum: u32
 }
+impl CtxWrapper {
+    // New context for the device at `index` in the `set_device` order, independent from the global one
+    pub fn for_device(index: usize, buffers: Option<&Buffers>) -> ocl::Result<Self> {
+        let (p, d, _) = enumerate_devices().into_iter().nth(index).ok_or(ocl::Error::from(ocl::BufferCmdError::MapUnavailable))?;
+        ::log::info!("OpenCL Platform: {}, Device: {} {}", p.name()?, d.vendor()?, d.name()?);
+
+        let context = Context::builder()
+            .properties(OclWrapper::get_properties(buffers))
+            .platform(p)
+            .devices(d)
+            .build()?;
+
+        Ok(CtxWrapper { device: d, context, platform: p, surface_checksum: buffers.map(|x| x.get_checksum()).unwrap_or_default() })
+    }
+}
+
+// Usable devices with their "{platform} {device}: {version}" names. Its order defines the indices of `list_devices`, `set_device` and `set_device_by_name`
+fn enumerate_devices() -> Vec<(Platform, Device, String)> {
+    let mut ret = Vec::new();
+    for p in Platform::list() {
+        if let Ok(devs) = Device::list(p, Some(ocl::flags::DeviceType::new().gpu().accelerator())) {
+            for d in devs {
+                let Some(name) = (|| Some(format!("{} {}: {}", p.name().ok()?, d.name().ok()?, d.version().ok()?)))() else { continue; };
+                if EXCLUSIONS.iter().any(|x| name.contains(x)) { continue; }
+                ret.push((p, d, name));
+            }
+        }
+    }
+    ret
+}
 
 lazy_static::lazy_static! {
     static ref CONTEXT: RwLock<Option<CtxWrapper>> = RwLock::new(None);
+    static ref PROGRAM_CACHE: Mutex<KernelProgramCache> = Mutex::new(lru::LruCache::new(std::num::NonZeroUsize::new(PROGRAM_CACHE_SIZE).unwrap()));
 }
+static CONTEXT_GENERATION: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+static CONTEXT_INITIALIZATIONS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0); // Global contexts set so far, see `new_from_source`
+
+// Replace the global context, eg. after a device change or a `surface_checksum` mismatch. Programs cached for the previous context
+// could never be used again and would keep it alive, so they're dropped. Contexts of `CtxWrapper::for_device` keep their programs
+fn set_global_context(ctx: CtxWrapper) {
+    let mut lock = CONTEXT.write();
+    if let Some(old) = lock.as_ref() {
+        let old_ptr = old.context.as_core().as_ptr() as usize;
+        if old_ptr != ctx.context.as_core().as_ptr() as usize {
+            let mut cache = PROGRAM_CACHE.lock();
+            let stale: Vec<u64> = cache.iter().filter(|(_, x)| x.context == old_ptr).map(|(k, _)| *k).collect();
+            for key in stale { cache.pop(&key); }
+        }
+    }
+    *lock = Some(ctx);
+    CONTEXT_INITIALIZATIONS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+}
+static IMAGE_FALLBACK_WARNED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
 
 const EXCLUSIONS: &[&'static str] = &["Microsoft Basic Render Driver"];
+const TILE_WORK_GROUP: usize = 16; // Work group is TILE_WORK_GROUP x TILE_WORK_GROUP when tiling the source
 
 impl OclWrapper {
     fn get_properties(buffers: Option<&Buffers>) -> ocl::builders::ContextProperties {
         let mut props = ocl::builders::ContextProperties::new();
         if let Some(buffers) = buffers {
             match &buffers.input.data {
+                #[cfg(all(feature = "opengl", feature = "opencl-interop"))]
                 BufferSource::OpenGL { context: _context, .. } => {
                     props = ocl_interop::get_properties_list();
                 }
-                #[cfg(target_os = "windows")]
+                #[cfg(all(target_os = "windows", feature = "directx"))]
                 BufferSource::DirectX11 { device, .. } => {
                     props.set_property_value(ocl::enums::ContextPropertyValue::D3d11DeviceKhr(*device));
                 },
@@ -58,13 +234,7 @@ impl OclWrapper {
 
     pub fn list_devices() -> Vec<String> {
         let devices = std::panic::catch_unwind(|| -> Vec<String> {
-            let mut ret = Vec::new();
-            for p in Platform::list() {
-                if let Ok(devs) = Device::list(p, Some(ocl::flags::DeviceType::new().gpu().accelerator())) {
-                    ret.extend(devs.into_iter().filter_map(|x| Some(format!("{} {}: {}", p.name().ok()?, x.name().ok()?, x.version().ok()?))));
-                }
-            }
-            ret.drain(..).filter(|x| !EXCLUSIONS.iter().any(|e| x.contains(e))).collect()
+            enumerate_devices().into_iter().map(|(_, _, name)| name).collect()
         });
         match devices {
             Ok(devices) => { return devices; },
@@ -89,29 +259,49 @@ impl OclWrapper {
         }
     }
 
+    // Drop the global context, so the next `new` or `initialize_context` enumerates and selects the device again (eg. after connecting an eGPU).
+    // Existing wrappers keep their own references to the old context and refuse to render, see `is_context_valid`
+    pub fn reset_context() {
+        let mut lock = CONTEXT.write();
+        *lock = None;
+        PROGRAM_CACHE.lock().clear();
+        CONTEXT_GENERATION.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
+    // Name and handle of the device this wrapper renders on. It can differ from the selected one, eg. with `BufferSource::OpenCL` the host's queue decides
+    pub fn device_id(&self) -> (usize, String) {
+        let device = self.queue.device();
+        (device.as_core().as_raw() as usize, device.name().unwrap_or_default())
+    }
+
+    // False for wrappers of `new_in_context`, their context isn't affected by `reset_context`
+    fn uses_global_context(&self) -> bool {
+        CONTEXT.read().as_ref().is_some_and(|ctx| ctx.context.as_core().as_ptr() == self.queue.context().as_core().as_ptr())
+    }
+
+    // False if `reset_context` was called after this wrapper was created or its program failed, it has to be created again
+    pub fn is_context_valid(&self) -> bool {
+        self.context_generation == CONTEXT_GENERATION.load(std::sync::atomic::Ordering::SeqCst) && !self.program_invalid.load(std::sync::atomic::Ordering::SeqCst)
+    }
+    // The pinned output buffer went over `gpu::set_host_memory_budget`. Still renders, but should be dropped before the next frame
+    pub fn is_host_memory_evicted(&self) -> bool {
+        self.host_memory.as_ref().map(|x| x.is_evicted()).unwrap_or_default()
+    }
+
     pub fn set_device(index: usize, buffers: &Buffers) -> ocl::Result<()> {
-        let mut i = 0;
-        for p in Platform::list() {
-            if let Ok(devs) = Device::list(p, Some(ocl::flags::DeviceType::new().gpu().accelerator())) {
-                for d in devs {
-                    if EXCLUSIONS.iter().any(|x| d.name().unwrap_or_default().contains(x)) { continue; }
-                    if i == index {
-                        ::log::info!("OpenCL Platform: {}, Device: {} {}", p.name()?, d.vendor()?, d.name()?);
-
-                        let context = Context::builder()
-                            .properties(Self::get_properties(Some(buffers)))
-                            .platform(p)
-                            .devices(d)
-                            .build()?;
-
-                        *CONTEXT.write() = Some(CtxWrapper { device: d, context, platform: p, surface_checksum: buffers.get_checksum() });
-                        return Ok(());
-                    }
-                    i += 1;
-                }
-            }
-        }
-        Err(ocl::BufferCmdError::MapUnavailable.into())
+        set_global_context(CtxWrapper::for_device(index, Some(buffers))?);
+        Ok(())
+    }
+    // Select the first device whose `list_devices` name contains `query` (case-insensitive), eg. "RTX 4090" saved in a project,
+    // which keeps working when the enumeration order changes. Returns the index of the selected device
+    pub fn set_device_by_name(query: &str, buffers: &Buffers) -> ocl::Result<usize> {
+        let query = query.to_lowercase();
+        let devices = enumerate_devices();
+        let Some(index) = devices.iter().position(|(_, _, name)| name.to_lowercase().contains(&query)) else {
+            let names = devices.into_iter().map(|(_, _, name)| name).collect::<Vec<_>>().join(", ");
+            return Err(format!("No OpenCL device matching \"{query}\", available devices: [{names}]").into());
+        };
+        Self::set_device(index, buffers)?;
+        Ok(index)
     }
 
     pub fn initialize_context(buffers: Option<&Buffers>) -> ocl::Result<(String, String)> {
@@ -122,6 +312,11 @@ impl OclWrapper {
             }
         });
 
+        if super::explicit_device_selection() {
+            let devices = Self::list_devices().iter().enumerate().map(|(i, x)| format!("{i}: {x}")).collect::<Vec<_>>().join(", ");
+            return Err(format!("No OpenCL device selected, call `set_device` with one of the available devices: [{devices}]").into());
+        }
+
         let mut platform = None;
         let mut device = None;
         let preference = [ "nvidia", "quadro", "radeon", "geforce", "firepro", "accelerated parallel processing", "graphics" ];
@@ -169,14 +364,83 @@ impl OclWrapper {
         let name = format!("{} {}", device.vendor()?, device.name()?);
         let list_name = format!("[OpenCL] {} {}", platform.name()?, device.name()?);
 
-        *CONTEXT.write() = Some(CtxWrapper { device, context, platform, surface_checksum: buffers.map(|x| x.get_checksum()).unwrap_or_default() });
+        set_global_context(CtxWrapper { device, context, platform, surface_checksum: buffers.map(|x| x.get_checksum()).unwrap_or_default() });
 
         Ok((name, list_name))
     }
 
-    pub fn new(params: &KernelParams, ocl_names: (&str, &str, &str, &str), distortion_model: DistortionModel, digital_lens: Option<DistortionModel>, buffers: &Buffers, drawing_len: usize) -> ocl::Result<Self> {
-        if params.height < 4 || params.output_height < 4 || params.stride < 1 { return Err(ocl::BufferCmdError::AlreadyMapped.into()); }
+    // Key of a program in `KernelProgramCache`: hash of the kernel source and the context and device it's built for
+    pub fn program_key(source: &str, ctx: &CtxWrapper) -> u64 {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        source.hash(&mut hasher);
+        (ctx.context.as_core().as_ptr() as usize).hash(&mut hasher);
+        (ctx.device.as_core().as_raw() as usize).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // `use_priority_hint` requests a high priority queue (`cl_khr_priority_hints`), eg. for lower latency of the live preview.
+    // It's only a hint, drivers are free to ignore it and the effect differs between vendors. A standard queue is used without the extension.
+    // `profiling` creates a profiling queue and records the GPU time of the kernel, see `last_kernel_duration_ns`.
+    // It adds a small overhead per command on some drivers
+    pub fn new(params: &KernelParams, ocl_names: (&str, &str, &str, &str), distortion_model: DistortionModel, digital_lens: Option<DistortionModel>, blended_lens: Option<DistortionModel>, buffers: &Buffers, drawing_len: usize, use_priority_hint: bool, profiling: bool) -> ocl::Result<Self> {
+        Self::check_params(params, buffers)?;
+
+        let has_other_lenses = digital_lens.is_some() || blended_lens.is_some();
+        let kernel = Self::kernel_source(params, ocl_names, &distortion_model, digital_lens.as_ref(), blended_lens.as_ref());
+        let capabilities = Self::source_capabilities(params, ocl_names, digital_lens.is_some(), blended_lens.is_some());
+        Self::new_from_source(params, kernel, capabilities, distortion_model, has_other_lenses, buffers, drawing_len, use_priority_hint, profiling)
+    }
+    fn new_from_source(params: &KernelParams, kernel: String, capabilities: KernelCapabilities, distortion_model: DistortionModel, has_other_lenses: bool, buffers: &Buffers, drawing_len: usize, use_priority_hint: bool, profiling: bool) -> ocl::Result<Self> {
+        // Also counts a context replaced because of a `surface_checksum` mismatch
+        let initializations = CONTEXT_INITIALIZATIONS.load(std::sync::atomic::Ordering::SeqCst);
+        let ret = Self::create(None, params, buffers, drawing_len, KernelSource::Source(kernel, capabilities), distortion_model, has_other_lenses, use_priority_hint, profiling);
+        if ret.is_err() && initializations != CONTEXT_INITIALIZATIONS.load(std::sync::atomic::Ordering::SeqCst) && CONTEXT.read().is_some() {
+            // Don't keep the context (re)initialized by this call for a wrapper which failed (eg. compiler crash), a retry would reuse it
+            Self::reset_context();
+        }
+        ret
+    }
+
+    // Build `source` in the global context to check that the device's compiler works. The context is dropped on failure
+    pub fn verify_context(source: &str) -> ocl::Result<()> {
+        let ret = match CONTEXT.read().as_ref() {
+            Some(ctx) => Program::builder().src(source).devices(ctx.device).build(&ctx.context).map(|_| ()),
+            None => Err("OpenCL context is not initialized".into())
+        };
+        if ret.is_err() {
+            Self::reset_context();
+        }
+        ret
+    }
 
+    // Same as `new`, but in `ctx` instead of the global context, eg. one context per device in `MultiGpuRenderer`
+    pub fn new_in_context(ctx: &mut CtxWrapper, params: &KernelParams, ocl_names: (&str, &str, &str, &str), distortion_model: DistortionModel, digital_lens: Option<DistortionModel>, blended_lens: Option<DistortionModel>, buffers: &Buffers, drawing_len: usize, use_priority_hint: bool, profiling: bool) -> ocl::Result<Self> {
+        Self::check_params(params, buffers)?;
+
+        let has_other_lenses = digital_lens.is_some() || blended_lens.is_some();
+        let kernel = Self::kernel_source(params, ocl_names, &distortion_model, digital_lens.as_ref(), blended_lens.as_ref());
+        let capabilities = Self::source_capabilities(params, ocl_names, digital_lens.is_some(), blended_lens.is_some());
+        Self::create(Some(ctx), params, buffers, drawing_len, KernelSource::Source(kernel, capabilities), distortion_model, has_other_lenses, use_priority_hint, profiling)
+    }
+
+    // Features compiled into the source generated by `kernel_source` from the same arguments
+    fn source_capabilities(params: &KernelParams, ocl_names: (&str, &str, &str, &str), digital_lens: bool, blended_lens: bool) -> KernelCapabilities {
+        let has_color = ocl_names.2 == "float4";
+        KernelCapabilities {
+            interpolation: params.interpolation,
+            drawing: (params.flags & 8) == 8,
+            premultiplied_alpha: (params.flags & 64) == 64 && has_color,
+            digital_lens,
+            blended_lens,
+            half_float: ocl_names.1 == "convert_half4",
+            equirectangular: !digital_lens,
+            color_correction: has_color,
+            hdr_transfer_functions: has_color,
+            ..Default::default()
+        }
+    }
+
+    fn kernel_source(params: &KernelParams, ocl_names: (&str, &str, &str, &str), distortion_model: &DistortionModel, digital_lens: Option<&DistortionModel>, blended_lens: Option<&DistortionModel>) -> String {
         let mut kernel = include_str!("opencl_undistort.cl").to_string();
         // let mut kernel = std::fs::read_to_string("D:/programowanie/projekty/Rust/gyroflow/src/core/gpu/opencl_undistort.cl").unwrap();
 
@@ -184,6 +448,9 @@ impl OclWrapper {
         let default_digital_lens = "float2 digital_undistort_point(float2 uv, __global KernelParams *p) { return uv; }
                                         float2 digital_distort_point(float2 uv, __global KernelParams *p) { return uv; }";
         lens_model_functions.push_str(digital_lens.as_ref().map(|x| x.opencl_functions()).unwrap_or(default_digital_lens));
+        let default_blended_lens = "float2 undistort_point_b(float2 pos, __global KernelParams *p) { return undistort_point(pos, p); }
+                                        float2 distort_point_b(float x, float y, float z, __global KernelParams *p) { return distort_point(x, y, z, p); }";
+        lens_model_functions.push_str(&blended_lens.as_ref().map(|x| x.opencl_functions_blended()).unwrap_or(default_blended_lens.to_string()));
 
         let mut extensions = String::new();
         if ocl_names.1 == "convert_half4" {
@@ -193,21 +460,137 @@ impl OclWrapper {
                 float4 convert_half4_to_float4(half4 v) { return vload_half4(0, (half*)&v); }
             "#);
         }
+        let (mut convert, mut convertf) = (ocl_names.1.to_string(), ocl_names.3.to_string());
+        if let Some(scalar) = ocl_names.0.strip_suffix('3') {
+            // Packed RGB, eg. RGB8 or RGB16. A 3-component vector takes the space of 4, so pixels are loaded and stored with vload3/vstore3,
+            // and converted from/to float4 with an unused w
+            let t = ocl_names.0;
+            extensions.push_str(&format!(r#"
+                #define LOAD_PIXEL(ptr) vload3(0, (__global const {scalar} *)(ptr))
+                #define STORE_PIXEL(v, ptr) vstore3((v), 0, (__global {scalar} *)(ptr))
+                float4 convert_{t}_to_float4({t} v) {{ return (float4)(convert_float3(v), 0.0f); }}
+                {t} convert_float4_to_{t}(float4 v) {{ return convert_{t}_sat(v.xyz); }}
+            "#));
+            convert = format!("convert_float4_to_{t}");
+            convertf = format!("convert_{t}_to_float4");
+        }
 
         kernel = kernel.replace("LENS_MODEL_FUNCTIONS;", &lens_model_functions)
                        .replace("EXTENSIONS;", &extensions)
-                       .replace("DATA_CONVERTF", ocl_names.3)
+                       .replace("DATA_CONVERTF", &convertf)
                        .replace("DATA_TYPEF", ocl_names.2)
-                       .replace("DATA_CONVERT", ocl_names.1)
+                       .replace("DATA_CONVERT", &convert)
                        .replace("DATA_TYPE", ocl_names.0)
                        .replace("PIXEL_BYTES", &format!("{}", params.bytes_per_pixel))
                        .replace("INTERPOLATION", &format!("{}", params.interpolation));
 
+        let (premultiply, unpremultiply) = if (params.flags & 64) == 64 && ocl_names.2 == "float4" {
+            ("px.xyz *= px.w / params->max_pixel_value", "if (px.w > 0.0f) { px.xyz /= px.w / params->max_pixel_value; }")
+        } else {
+            ("", "")
+        };
+        kernel = kernel.replace("UNPREMULTIPLY_ALPHA;", &format!("{unpremultiply};"))
+                       .replace("PREMULTIPLY_ALPHA;", &format!("{premultiply};"));
+
         if (params.flags & 8) == 0 { // Drawing not enabled
             kernel = kernel.replace("params->flags & 8", "false"); // It makes it much faster for some reason
         }
 
-        {
+        kernel
+    }
+
+    // Compiles only the `KernelParams` struct and the model's functions, and runs both `undistort_point` and `distort_point` (with z = 1) on normalized `points`.
+    // For comparing a new model's OpenCL code with its Rust implementation. Returns (undistorted, distorted)
+    pub fn test_distortion_model(device_index: usize, distortion_model: &DistortionModel, params: &KernelParams, points: &[(f32, f32)]) -> Result<(Vec<(f32, f32)>, Vec<(f32, f32)>), GpuError> {
+        if points.is_empty() { return Ok((Vec::new(), Vec::new())); }
+        let ctx = CtxWrapper::for_device(device_index, None)?;
+
+        let source = include_str!("opencl_undistort.cl");
+        let struct_end = source.find("} KernelParams;").ok_or_else(|| GpuError::KernelSource("KernelParams not found in the kernel source".into()))?;
+        let functions = distortion_model.opencl_functions();
+        // Digital lenses define `digital_*` functions only, which take the point as float2
+        let (undistort_call, distort_call) = if functions.contains("digital_undistort_point(") {
+            ("digital_undistort_point(points[i], params)", "digital_distort_point(points[i], params)")
+        } else {
+            ("undistort_point(points[i], params)", "distort_point(points[i].x, points[i].y, 1.0f, params)")
+        };
+
+        let kernel_src = format!("{}}} KernelParams;\n{}\n
+            __kernel void test_distortion(__global const float2 *points, __global float2 *undistorted, __global float2 *distorted, __global KernelParams *params) {{
+                size_t i = get_global_id(0);
+                undistorted[i] = {undistort_call};
+                distorted[i] = {distort_call};
+            }}",
+            source[..struct_end].replace("EXTENSIONS;", ""),
+            functions
+        );
+
+        let program = Program::builder().src(&kernel_src).devices(ctx.device).build(&ctx.context)?;
+        let queue = Queue::new(&ctx.context, ctx.device, None)?;
+
+        let flat: Vec<f32> = points.iter().flat_map(|&(x, y)| [x, y]).collect();
+        let buf_points = Buffer::<f32>::builder().queue(queue.clone()).flags(MemFlags::new().read_only()).len(flat.len()).copy_host_slice(&flat).build()?;
+        let buf_undistorted = Buffer::<f32>::builder().queue(queue.clone()).flags(MemFlags::new().write_only()).len(flat.len()).build()?;
+        let buf_distorted   = Buffer::<f32>::builder().queue(queue.clone()).flags(MemFlags::new().write_only()).len(flat.len()).build()?;
+        let buf_params = Buffer::<u8>::builder().queue(queue.clone()).flags(MemFlags::new().read_only()).len(std::mem::size_of::<KernelParams>()).copy_host_slice(bytemuck::bytes_of(params)).build()?;
+
+        let kernel = Kernel::builder()
+            .program(&program)
+            .name("test_distortion")
+            .queue(queue.clone())
+            .global_work_size(points.len())
+            .arg(&buf_points)
+            .arg(&buf_undistorted)
+            .arg(&buf_distorted)
+            .arg(&buf_params)
+            .build()?;
+        unsafe { kernel.enq()?; }
+
+        let read = |buf: &Buffer<f32>| -> ocl::Result<Vec<(f32, f32)>> {
+            let mut out = vec![0.0f32; flat.len()];
+            buf.read(&mut out).enq()?;
+            Ok(out.chunks_exact(2).map(|x| (x[0], x[1])).collect())
+        };
+        Ok((read(&buf_undistorted)?, read(&buf_distorted)?))
+    }
+
+    // Skips the kernel compilation, `program` has to come from `get_program` of a wrapper with the same lens models and pixel format, created in the current context.
+    // The lens model isn't known here, so the passthrough shortcut for identity lenses is disabled
+    pub fn create_from_existing_program(program: &CompiledProgram, params: &KernelParams, buffers: &Buffers, drawing_len: usize) -> ocl::Result<Self> {
+        Self::check_params(params, buffers)?;
+        Self::create(None, params, buffers, drawing_len, KernelSource::Compiled(program), DistortionModel::default(), true, false, false)
+    }
+
+    pub fn get_program(&self) -> &CompiledProgram { &self.program }
+
+    // Recreates the wrapper from a `Stabilization::serialize_config` dump, using CPU buffers of the dumped sizes.
+    // Render with `config.buffers()` and `config.frame_transform()`
+    pub fn from_config(config: &crate::stabilization::WrapperConfig) -> ocl::Result<Self> {
+        let params = config.kernel_params();
+        let (distortion_model, digital_lens, blended_lens) = config.distortion_models();
+        let (mut input, mut output) = config.synthetic_buffers();
+        let buffers = config.buffers(&mut input, &mut output);
+        let names = &config.ocl_names;
+        Self::new(&params, (&names[0], &names[1], &names[2], &names[3]), distortion_model, digital_lens, blended_lens, &buffers, config.drawing_len, false, false)
+    }
+
+    fn check_params(params: &KernelParams, buffers: &Buffers) -> ocl::Result<()> {
+        if params.height < 4 || params.output_height < 4 || params.stride < 1 { return Err(ocl::BufferCmdError::AlreadyMapped.into()); }
+
+        // Too small stride would make the rows overlap in the image descriptor
+        let bpp = params.bytes_per_pixel.max(0) as usize;
+        if buffers.input.size.2 < buffers.input.size.0 * bpp {
+            return Err(format!("Input stride is too small: {} < {} (width: {}, bytes per pixel: {})", buffers.input.size.2, buffers.input.size.0 * bpp, buffers.input.size.0, bpp).into());
+        }
+        if buffers.output.size.2 < buffers.output.size.0 * bpp {
+            return Err(format!("Output stride is too small: {} < {} (width: {}, bytes per pixel: {})", buffers.output.size.2, buffers.output.size.0 * bpp, buffers.output.size.0, bpp).into());
+        }
+        Ok(())
+    }
+
+    // `ctx_override` is used instead of the global context if set
+    fn create(ctx_override: Option<&mut CtxWrapper>, params: &KernelParams, buffers: &Buffers, drawing_len: usize, source: KernelSource, distortion_model: DistortionModel, has_other_lenses: bool, use_priority_hint: bool, profiling: bool) -> ocl::Result<Self> {
+        if ctx_override.is_none() {
             let ctx = CONTEXT.read();
             let context_initialized = ctx.is_some();
             if !context_initialized || ctx.as_ref().unwrap().surface_checksum != buffers.get_checksum() {
@@ -216,17 +599,26 @@ impl OclWrapper {
             }
         }
         let mut lock = CONTEXT.write();
-        if let Some(ref mut ctx) = *lock {
-            let mut ocl_queue = Queue::new(&ctx.context, ctx.device, None)?;
+        let ctx = match ctx_override { Some(ctx) => Some(ctx), None => lock.as_mut() };
+        if let Some(ctx) = ctx {
+            let mut ocl_queue = Queue::new(&ctx.context, ctx.device, profiling.then(|| ocl::flags::CommandQueueProperties::new().profiling()))?;
+            if use_priority_hint {
+                match Self::create_priority_queue(&ctx.context, &ctx.device, profiling) {
+                    Ok(Some(queue_core)) => { *ocl_queue.deref_mut() = queue_core; ::log::debug!("OpenCL high priority queue"); },
+                    Ok(None) => { ::log::debug!("cl_khr_priority_hints not supported, using a standard queue"); },
+                    Err(e) => { ::log::warn!("Failed to create a high priority queue: {e:?}"); }
+                }
+            }
 
             let in_desc  = ImageDescriptor::new(MemObjectType::Image2d, buffers.input.size.0,  buffers.input.size.1,  1, 1, buffers.input.size.2,  0, None);
             let out_desc = ImageDescriptor::new(MemObjectType::Image2d, buffers.output.size.0, buffers.output.size.1, 1, 1, buffers.output.size.2, 0, None);
 
+            let mut use_image_path = true;
             let mut resolve_texture = |buf: &BufferDescription, is_in: bool, ocl_queue: &mut Queue, desc: ImageDescriptor, _other_img: Option<&(ocl::Image<u8>, u64)>| -> ocl::Result<(Buffer<u8>, Option<(ocl::Image<u8>, u64)>)> {
                 match &buf.data {
                     BufferSource::Cpu { buffer } => {
                         let flags = if is_in { MemFlags::new().read_only().host_write_only() }
-                                           else     { MemFlags::new().write_only().host_read_only().alloc_host_ptr() };
+                                           else     { MemFlags::new().read_write().host_read_only().alloc_host_ptr() }; // read_write for the histogram pass
                         Ok((Buffer::builder().queue(ocl_queue.clone()).len(buffer.len()).flags(flags).build()?, None))
                     },
                     BufferSource::OpenCL { queue, .. } => {
@@ -244,30 +636,32 @@ impl OclWrapper {
                                            else     { MemFlags::new().read_write().host_no_access() };
                         Ok((Buffer::builder().queue(ocl_queue.clone()).len(buf.size.1 * buf.size.2).flags(flags).build()?, None))
                     },
+                    #[cfg(all(feature = "opengl", feature = "opencl-interop"))]
                     BufferSource::OpenGL { texture, .. } => {
                         let flags = if is_in { MemFlags::new().read_only() }
                                            else     { MemFlags::new().write_only() };
 
-                        let img = Image::from_gl_texture(ocl_queue.clone(), flags, desc, GlTextureTarget::GlTexture2d, 0, *texture)?;
+                        let img = Self::image_or_fallback(Image::from_gl_texture(ocl_queue.clone(), flags, desc, GlTextureTarget::GlTexture2d, 0, *texture), &mut use_image_path);
 
                         let flags = if is_in { MemFlags::new().read_only().host_no_access() }
                                            else     { MemFlags::new().read_write().host_no_access() };
 
-                        Ok((Buffer::builder().queue(ocl_queue.clone()).len(buf.size.1 * buf.size.2).flags(flags).build()?, Some((img, *texture as u64))))
+                        Ok((Buffer::builder().queue(ocl_queue.clone()).len(buf.size.1 * buf.size.2).flags(flags).build()?, img.map(|img| (img, *texture as u64))))
                     },
-                    #[cfg(target_os = "windows")]
+                    #[cfg(all(target_os = "windows", feature = "directx"))]
                     BufferSource::DirectX11 { texture, .. } => {
+                        let pixel_count = |img: Option<&ocl::Image<u8>>| img.map(|x| x.pixel_count()).unwrap_or(buf.size.0 * buf.size.1);
                         if is_in {
-                            let img = Image::from_d3d11_texture2d(ocl_queue.clone(), MemFlags::new().read_only(), desc, *texture, 0)?;
-                            Ok((Buffer::builder().queue(ocl_queue.clone()).len(img.pixel_count() * params.bytes_per_pixel as usize).flags(MemFlags::new().read_only().host_no_access()).build()?, Some((img, *texture as u64))))
+                            let img = Self::image_or_fallback(Image::from_d3d11_texture2d(ocl_queue.clone(), MemFlags::new().read_only(), desc, *texture, 0), &mut use_image_path);
+                            Ok((Buffer::builder().queue(ocl_queue.clone()).len(pixel_count(img.as_ref()) * params.bytes_per_pixel as usize).flags(MemFlags::new().read_only().host_no_access()).build()?, img.map(|img| (img, *texture as u64))))
                         } else {
                             let img = match &buffers.input.data {
                                 BufferSource::DirectX11 { texture: in_texture, .. } if *texture == *in_texture => {
-                                    Some((_other_img.unwrap().0.clone(), *texture as u64))
+                                    _other_img.map(|x| x.0.clone())
                                 },
-                                _ => Some((Image::from_d3d11_texture2d(ocl_queue.clone(), MemFlags::new().write_only(), desc, *texture, 0)?, *texture as u64))
+                                _ => Self::image_or_fallback(Image::from_d3d11_texture2d(ocl_queue.clone(), MemFlags::new().write_only(), desc, *texture, 0), &mut use_image_path)
                             };
-                            Ok((Buffer::builder().queue(ocl_queue.clone()).len(img.as_ref().unwrap().0.pixel_count() * params.bytes_per_pixel as usize).flags(MemFlags::new().read_write().host_no_access()).build()?, img))
+                            Ok((Buffer::builder().queue(ocl_queue.clone()).len(pixel_count(img.as_ref()) * params.bytes_per_pixel as usize).flags(MemFlags::new().read_write().host_no_access()).build()?, img.map(|img| (img, *texture as u64))))
                         }
                     },
                     _ => panic!("Unsupported buffer {:?}", buf.data)
@@ -275,32 +669,74 @@ impl OclWrapper {
             };
             let (source_buffer, image_src) = resolve_texture(&buffers.input, true, &mut ocl_queue, in_desc, None)?;
             let (dest_buffer, image_dst) = resolve_texture(&buffers.output, false, &mut ocl_queue, out_desc, image_src.as_ref())?;
+            Self::validate_image_format(&buffers.input,  image_src.as_ref(), params)?;
+            Self::validate_image_format(&buffers.output, image_dst.as_ref(), params)?;
 
-            let program = Program::builder()
-                .src(&kernel)
-                .devices(ctx.device)
-                .build(&ctx.context)?;
+            let mut program = match &source {
+                KernelSource::Compiled(program) => (*program).clone(),
+                KernelSource::Source(kernel, capabilities) => {
+                    // Tile the source in local memory when enabled and the device has enough of it, otherwise the kernel reads global memory directly
+                    let tile_size = Self::select_tile_size(&ctx.device, (params.bytes_per_pixel.max(1) as usize).next_power_of_two());
+                    Self::compile_cached(kernel, *capabilities, tile_size, ctx)?
+                }
+            };
 
             let max_matrix_count = 12 * if (params.flags & 16) == 16 { params.width } else { params.height };
             let flags = MemFlags::new().read_only().host_write_only();
 
             let buf_params   = Buffer::builder().queue(ocl_queue.clone()).flags(flags).len(std::mem::size_of::<KernelParams>()).build()?;
             let buf_drawing  = Buffer::builder().queue(ocl_queue.clone()).flags(flags).len(drawing_len.max(4)).build()?;
-            let buf_matrices = Buffer::builder().queue(ocl_queue.clone()).flags(flags).len(max_matrix_count).build()?;
-
-            let mut builder = Kernel::builder();
-            unsafe {
-                builder.program(&program).name("undistort_image").queue(ocl_queue.clone())
-                    .global_work_size((buffers.output.size.0, buffers.output.size.1))
-                    .disable_arg_type_check()
-                    .arg(&source_buffer)
-                    .arg(&dest_buffer)
-                    .arg(&buf_params)
-                    .arg(&buf_matrices)
-                    .arg(&buf_drawing);
+            let buf_quality  = Buffer::builder().queue(ocl_queue.clone()).flags(flags).len(16).copy_host_slice(&[0u8; 16]).build()?; // Uniform quality
+            let buf_dense_map = Buffer::builder().queue(ocl_queue.clone()).flags(flags).len(4).copy_host_slice(&[0.0f32; 4]).build()?; // No map
+            // Written by the host, or by `decode_matrices` when delta encoding is enabled
+            let buf_matrices = Buffer::builder().queue(ocl_queue.clone()).flags(MemFlags::new().read_write().host_write_only()).len(max_matrix_count).build()?;
+
+            let build_kernel = |program: &CompiledProgram| -> ocl::Result<Kernel> {
+                let mut builder = Kernel::builder();
+                if program.tile_size.is_some() {
+                    // Local work size has to divide the global size, extra work items are outside of the output rect
+                    let round_up = |x: usize| (x + TILE_WORK_GROUP - 1) / TILE_WORK_GROUP * TILE_WORK_GROUP;
+                    builder.global_work_size((round_up(buffers.output.size.0), round_up(buffers.output.size.1)))
+                           .local_work_size((TILE_WORK_GROUP, TILE_WORK_GROUP));
+                } else {
+                    builder.global_work_size((buffers.output.size.0, buffers.output.size.1));
+                }
+                unsafe {
+                    builder.program(&program.program).name("undistort_image").queue(ocl_queue.clone())
+                        .disable_arg_type_check()
+                        .arg(&source_buffer)
+                        .arg(&dest_buffer)
+                        .arg(&buf_params)
+                        .arg(&buf_matrices)
+                        .arg(&buf_drawing)
+                        .arg(&buf_quality)
+                        .arg(&buf_dense_map);
+                }
+                builder.build()
+            };
+
+            let mut kernel = build_kernel(&program)?;
+            if program.tile_size.is_some() {
+                // The compiled kernel can allow smaller work groups than the device, eg. because of its registers and local memory,
+                // and the fixed local size would fail every enqueue with CL_INVALID_WORK_GROUP_SIZE
+                let kernel_work_group = match kernel.wg_info(ctx.device, ocl::enums::KernelWorkGroupInfo::WorkGroupSize) { Ok(ocl::enums::KernelWorkGroupInfoResult::WorkGroupSize(x)) => x, _ => 0 };
+                if kernel_work_group < TILE_WORK_GROUP * TILE_WORK_GROUP {
+                    ::log::debug!("OpenCL kernel work group limit is {kernel_work_group}, compiling without source tiling");
+                    let KernelSource::Source(source, capabilities) = &source else {
+                        return Err(format!("The existing program is compiled with source tiling, which this device allows only for work groups up to {kernel_work_group}").into());
+                    };
+                    program = Self::compile_cached(source, *capabilities, None, ctx)?;
+                    kernel = build_kernel(&program)?;
+                }
             }
+            let tile_size = program.tile_size;
+            ::log::debug!("OpenCL source tile: {:?}", tile_size);
 
-            let kernel = builder.build()?;
+            let unified_memory = matches!(ctx.device.info(ocl::core::DeviceInfo::HostUnifiedMemory), Ok(ocl::core::DeviceInfoResult::HostUnifiedMemory(true)));
+            let mem_base_align = match ctx.device.info(ocl::core::DeviceInfo::MemBaseAddrAlign) { Ok(ocl::core::DeviceInfoResult::MemBaseAddrAlign(x)) => (x as usize / 8).max(1), _ => 4096 };
+            let zero_copy = unified_memory && std::env::var("NO_OPENCL_ZERO_COPY").unwrap_or_default().is_empty() &&
+                            matches!(buffers.input.data, BufferSource::Cpu { .. }) && matches!(buffers.output.data, BufferSource::Cpu { .. });
+            ::log::debug!("OpenCL unified memory: {}, zero-copy: {}", unified_memory, zero_copy);
 
             Ok(Self {
                 kernel,
@@ -311,16 +747,606 @@ impl OclWrapper {
                 image_dst,
                 buf_params,
                 buf_drawing,
+                buf_quality,
+                buf_dense_map,
                 buf_matrices,
+                drawing_len,
+                drawing_index: 0,
+                program,
+                output_size: (buffers.output.size.0, buffers.output.size.1),
+                histogram: None,
+                secondary_output: None,
+                delta_matrices: None,
+                matrices_batch: None,
+                output_offset: None,
+                output_band: None,
+                reuse_source: false,
+                distortion_model,
+                has_other_lenses,
+                unified_memory,
+                zero_copy,
+                mem_base_align,
+                local_tiling: tile_size.is_some(),
+                dirty_tiles: None,
+                has_output: Default::default(),
+                context_generation: CONTEXT_GENERATION.load(std::sync::atomic::Ordering::SeqCst),
+                use_image_path,
+                program_invalid: std::sync::atomic::AtomicBool::new(false),
+                interop_acquired: std::sync::atomic::AtomicBool::new(false),
+                profiling,
+                kernel_timing: Mutex::new((None, None)),
+                host_memory: matches!(buffers.output.data, BufferSource::Cpu { .. }).then(|| host_memory::HostAllocation::new(dest_buffer.len())),
             })
         } else {
             Err(ocl::BufferCmdError::AlreadyMapped.into())
         }
     }
 
+    // Program from `PROGRAM_CACHE`, or compiled and added to it
+    fn compile_cached(kernel: &str, capabilities: KernelCapabilities, tile_size: Option<(usize, usize)>, ctx: &CtxWrapper) -> ocl::Result<CompiledProgram> {
+        let kernel = match tile_size {
+            Some((tw, th)) => format!("#define TILE_W {tw}\n#define TILE_H {th}\n{kernel}"),
+            None => kernel.to_string()
+        };
+        let key = Self::program_key(&kernel, ctx);
+        if let Some(program) = PROGRAM_CACHE.lock().get(&key).cloned() {
+            return Ok(program);
+        }
+        // No fast-math options (eg. `-cl-fast-relaxed-math`), they let the compiler reorder the math differently
+        // per driver version, and the output wouldn't be reproducible on the render farm machines
+        let program = CompiledProgram {
+            program: Self::build_program(&kernel, ctx)?,
+            tile_size,
+            capabilities,
+            context: ctx.context.as_core().as_ptr() as usize,
+        };
+        PROGRAM_CACHE.lock().put(key, program.clone());
+        Ok(program)
+    }
+
+    // Compile `source`, or with the `ocl-cache` feature load the binary which a previous launch stored on disk for the same source and driver
+    fn build_program(source: &str, ctx: &CtxWrapper) -> ocl::Result<Program> {
+        let start = std::time::Instant::now();
+        #[cfg(feature = "ocl-cache")]
+        let cache_path = disk_cache::path(source, &ctx.device);
+        #[cfg(feature = "ocl-cache")]
+        if let Some(binary) = cache_path.as_ref().and_then(|path| std::fs::read(path).ok()).filter(|x| !x.is_empty()) {
+            match Program::builder().binaries(&[&binary]).devices(ctx.device).build(&ctx.context) {
+                Ok(program) => {
+                    ::log::debug!("OpenCL program loaded from cache in {:.2}ms", start.elapsed().as_secs_f64() * 1000.0);
+                    return Ok(program);
+                },
+                // Corrupted, or built by a driver which reports the same version. It's overwritten after the compilation below
+                Err(e) => { ::log::debug!("Cached OpenCL program can't be loaded, compiling from source: {e:?}"); }
+            }
+        }
+
+        let program = Program::builder()
+            .src(source)
+            .devices(ctx.device)
+            .build(&ctx.context)
+            .map_err(|e| Self::program_build_error(source, e))?;
+        ::log::debug!("OpenCL program compiled in {:.2}ms", start.elapsed().as_secs_f64() * 1000.0);
+
+        #[cfg(feature = "ocl-cache")]
+        if let Some(path) = cache_path {
+            disk_cache::store(&path, &program);
+        }
+        Ok(program)
+    }
+
+    // The error of a failed build already contains the device's build log. Log it together with the generated source,
+    // numbered so the lines reported by the compiler can be found after the LENS_MODEL_FUNCTIONS and DATA_TYPE substitutions
+    fn program_build_error(source: &str, err: ocl::Error) -> ocl::Error {
+        ::log::error!("OpenCL program build failed: {err}");
+        let numbered = source.lines().enumerate().map(|(i, line)| format!("{:5}: {line}", i + 1)).collect::<Vec<_>>().join("\n");
+        ::log::error!("OpenCL kernel source:\n{numbered}");
+        format!("OpenCL program build failed: {err}").into()
+    }
+
+    // None if the device doesn't have `cl_khr_priority_hints`
+    fn create_priority_queue(context: &Context, device: &Device, profiling: bool) -> ocl::Result<Option<core::CommandQueue>> {
+        const CL_QUEUE_PRIORITY_KHR: u64 = 0x1096;
+        const CL_QUEUE_PRIORITY_HIGH_KHR: u64 = 1 << 0;
+        const CL_QUEUE_PROPERTIES: u64 = 0x1093;
+        const CL_QUEUE_PROFILING_ENABLE: u64 = 1 << 1;
+        let has_ext = matches!(device.info(ocl::core::DeviceInfo::Extensions), Ok(ocl::core::DeviceInfoResult::Extensions(ext)) if ext.split_whitespace().any(|x| x == "cl_khr_priority_hints"));
+        if !has_ext { return Ok(None); }
+
+        let mut props = vec![CL_QUEUE_PRIORITY_KHR, CL_QUEUE_PRIORITY_HIGH_KHR];
+        if profiling { props.extend([CL_QUEUE_PROPERTIES, CL_QUEUE_PROFILING_ENABLE]); }
+        props.push(0);
+        let mut err = 0;
+        let ptr = unsafe { core::ffi::clCreateCommandQueueWithProperties(context.as_core().as_ptr(), device.as_core().as_raw(), props.as_ptr(), &mut err) };
+        if err != 0 || ptr.is_null() {
+            return Err(format!("clCreateCommandQueueWithProperties failed: {err}").into());
+        }
+        Ok(Some(unsafe { core::CommandQueue::from_raw_create_ptr(ptr) }))
+    }
+
+    // Some devices (eg. pocl, embedded GPU drivers) can't create images from textures, eg. with CL_INVALID_IMAGE_FORMAT.
+    // The wrapper is still created with plain buffers, which can be used with CPU input and output
+    fn image_or_fallback(img: ocl::Result<Image<u8>>, use_image_path: &mut bool) -> Option<Image<u8>> {
+        match img {
+            Ok(img) => Some(img),
+            Err(e) => {
+                if !IMAGE_FALLBACK_WARNED.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                    ::log::warn!("OpenCL device can't import the texture as an image, falling back to buffers: {:?}", e);
+                }
+                *use_image_path = false;
+                None
+            }
+        }
+    }
+    pub fn uses_image_path(&self) -> bool { self.use_image_path }
+
+    pub fn kernel_capabilities(&self) -> KernelCapabilities {
+        KernelCapabilities {
+            local_tiling: self.local_tiling,
+            histogram: self.histogram.is_some(),
+            secondary_output: self.secondary_output.is_some(),
+            delta_matrices: self.delta_matrices.is_some(),
+            ..self.program.capabilities
+        }
+    }
+
+    // Texture data is copied as is and read by the kernel as the pixel format of `params`,
+    // so an explicit `cl_image_format` only has to agree with the texture and the pixel format in size
+    fn validate_image_format(buf: &BufferDescription, image: Option<&(ocl::Image<u8>, u64)>, params: &KernelParams) -> ocl::Result<()> {
+        if let (Some(format), Some((img, _))) = (&buf.cl_image_format, image) {
+            let element_size = match img.info(ocl::enums::ImageInfo::ElementSize)? {
+                ocl::enums::ImageInfoResult::ElementSize(x) => x,
+                _ => 0
+            };
+            if format.pixel_bytes() != element_size || element_size != params.bytes_per_pixel as usize {
+                return Err(format!("Image format {:?} ({} bytes per pixel) doesn't match the texture ({} bytes) or the pixel format ({} bytes)", format, format.pixel_bytes(), element_size, params.bytes_per_pixel).into());
+            }
+        }
+        Ok(())
+    }
+
+    // Format of the imported input or output texture as reported by the driver, None if it's not a texture
+    pub fn get_image_format(&self, input: bool) -> Option<ocl::core::ImageFormat> {
+        let (img, _) = if input { self.image_src.as_ref()? } else { self.image_dst.as_ref()? };
+        match img.info(ocl::enums::ImageInfo::Format) {
+            Ok(ocl::enums::ImageInfoResult::Format(Ok(format))) => Some(format),
+            _ => None
+        }
+    }
+
+    // Opt-in with the OPENCL_TILING environment variable until it's measured to be faster on the supported devices
+    fn select_tile_size(device: &Device, pixel_bytes: usize) -> Option<(usize, usize)> {
+        if std::env::var("OPENCL_TILING").unwrap_or_default().is_empty() { return None; }
+        let local_mem = match device.info(ocl::core::DeviceInfo::LocalMemSize) { Ok(ocl::core::DeviceInfoResult::LocalMemSize(x)) => x as usize, _ => 0 };
+        let max_work_group = match device.info(ocl::core::DeviceInfo::MaxWorkGroupSize) { Ok(ocl::core::DeviceInfoResult::MaxWorkGroupSize(x)) => x, _ => 0 };
+        if max_work_group < TILE_WORK_GROUP * TILE_WORK_GROUP { return None; }
+
+        // Use at most half of the local memory, 32x32 still covers a 16x16 work group with Lanczos taps at 1:1 scale
+        [64, 48, 32].into_iter().find(|s| s * s * pixel_bytes <= local_mem / 2).map(|s| (s, s))
+    }
+
+    // Whether the device shares memory with the host (integrated GPUs)
+    pub fn has_unified_memory(&self) -> bool { self.unified_memory }
+    // Whether CPU buffers are used by the kernel directly instead of being copied to and from the device
+    pub fn is_zero_copy(&self) -> bool { self.zero_copy }
+
+    // Only re-render the output tiles flagged in `dirty` (row-major, `tile_size` x `tile_size` pixels) and keep the rest from the previous frame in `dst`.
+    // The first frame after creation is always rendered fully. `None` renders every tile
+    pub fn set_dirty_tiles(&mut self, tiles: Option<(usize, Vec<bool>)>) {
+        self.dirty_tiles = tiles.filter(|(size, _)| *size > 0).map(|(size, dirty)| {
+            // Has to be a multiple of the work group size with local memory tiling
+            (if self.local_tiling { (size + TILE_WORK_GROUP - 1) / TILE_WORK_GROUP * TILE_WORK_GROUP } else { size }, dirty)
+        });
+    }
+
+    // Wrap a host buffer without copying. Drivers only avoid the copy for aligned memory, so use the regular path otherwise
+    fn wrap_host_buffer(&self, buffer: &[u8], flags: MemFlags) -> ocl::Result<Option<Buffer<u8>>> {
+        if !self.zero_copy || buffer.is_empty() || (buffer.as_ptr() as usize) % self.mem_base_align != 0 {
+            return Ok(None);
+        }
+        Ok(Some(Buffer::builder().queue(self.queue.clone()).flags(flags).len(buffer.len()).use_host_slice(buffer).build()?))
+    }
+
+    // Optional reduction pass over the output buffer, computing luma (and R, G, B if `rgb` is set) histograms for scopes
+    pub fn set_histogram(&mut self, bins: usize, rgb: bool) -> ocl::Result<()> {
+        if bins == 0 {
+            self.histogram = None;
+            return Ok(());
+        }
+        let channels = if rgb { 4 } else { 1 };
+        // The bins of a work group are counted in local memory
+        let device = self.queue.device();
+        let local_mem = match device.info(ocl::core::DeviceInfo::LocalMemSize) { Ok(ocl::core::DeviceInfoResult::LocalMemSize(x)) => x as usize, _ => 0 };
+        if bins * channels * std::mem::size_of::<u32>() > local_mem {
+            return Err(format!("Too many histogram bins ({bins} x {channels}) for the local memory of the device ({local_mem} bytes)").into());
+        }
+        let buf = Buffer::<u32>::builder().queue(self.queue.clone()).flags(MemFlags::new().read_write()).len(bins * channels).build()?;
+
+        let mut builder = Kernel::builder();
+        unsafe {
+            builder.program(&self.program.program).name("compute_histogram").queue(self.queue.clone())
+                .disable_arg_type_check()
+                .arg(&self.dst)
+                .arg(&self.buf_params)
+                .arg(&buf)
+                .arg(bins as i32)
+                .arg(rgb as i32)
+                .arg(self.output_size.0 as i32)
+                .arg(self.output_size.1 as i32)
+                .arg_local::<u32>(bins * channels);
+        }
+        let mut kernel = builder.build()?;
+
+        // Largest square work group the kernel allows, the work size is rounded up to it
+        let max_group = match kernel.wg_info(device, ocl::enums::KernelWorkGroupInfo::WorkGroupSize) { Ok(ocl::enums::KernelWorkGroupInfoResult::WorkGroupSize(x)) => x, _ => 1 };
+        let side = [16, 8, 4, 2].into_iter().find(|x| x * x <= max_group).unwrap_or(1);
+        let round_up = |x: usize| (x + side - 1) / side * side;
+        kernel.set_default_global_work_size(SpatialDims::Two(round_up(self.output_size.0), round_up(self.output_size.1)))
+              .set_default_local_work_size(SpatialDims::Two(side, side));
+
+        self.histogram = Some(HistogramPass { kernel, buf, bins, channels, result: Mutex::new(vec![0; bins * channels]) });
+        Ok(())
+    }
+
+    // Second output at another resolution (eg. a preview next to the full resolution render), area-averaged from the rendered output
+    // in the same submission, so the warp is computed only once. Not supported together with `set_output_offset`
+    pub fn set_secondary_output(&mut self, size: Option<(usize, usize, usize)>) -> ocl::Result<()> {
+        let Some((width, height, stride)) = size.filter(|s| s.0 > 0 && s.1 > 0) else {
+            self.secondary_output = None;
+            return Ok(());
+        };
+        let buf = Buffer::<u8>::builder().queue(self.queue.clone()).flags(MemFlags::new().write_only().host_read_only()).len(stride * height).build()?;
+
+        let mut builder = Kernel::builder();
+        unsafe {
+            builder.program(&self.program.program).name("downscale_output").queue(self.queue.clone())
+                .global_work_size((width, height))
+                .disable_arg_type_check()
+                .arg(&self.dst)
+                .arg(&self.buf_params)
+                .arg(&buf)
+                .arg(self.output_size.0 as i32)
+                .arg(self.output_size.1 as i32)
+                .arg(width as i32)
+                .arg(height as i32)
+                .arg(stride as i32);
+        }
+        let kernel = builder.build()?;
+
+        self.secondary_output = Some(SecondaryOutput { kernel, buf, size: (width, height, stride) });
+        Ok(())
+    }
+    pub fn read_secondary_output(&self, buffer: &mut [u8]) -> ocl::Result<()> {
+        let Some(ref s) = self.secondary_output else { return Err("Secondary output is not enabled".into()); };
+        if buffer.len() < s.size.1 * s.size.2 { return Err(ocl::BufferCmdError::AlreadyMapped.into()); }
+        s.buf.read(&mut buffer[..s.size.1 * s.size.2]).enq()?;
+        Ok(())
+    }
+
+    // Grow `buf_matrices` (eg. when the clip height increases) without reconstructing the whole wrapper.
+    // `new_max_matrix_count` is in floats, same as in `new`
+    pub fn ensure_matrices_capacity(&mut self, new_max_matrix_count: usize) -> ocl::Result<()> {
+        if new_max_matrix_count <= self.buf_matrices.len() { return Ok(()); }
+
+        self.buf_matrices = Buffer::builder().queue(self.queue.clone()).flags(MemFlags::new().read_write().host_write_only()).len(new_max_matrix_count).build()?;
+        self.kernel.set_arg(3, &self.buf_matrices)?;
+
+        if self.delta_matrices.is_some() {
+            self.set_delta_matrices(true)?; // Sized by `buf_matrices`
+        }
+        Ok(())
+    }
+
+    // Uploads the matrices of the next frames (eg. a GOP of the export) into a single device buffer, with one write.
+    // Select a frame with `select_matrices_batch_index` before rendering it and its matrices are used without an upload.
+    // A transform with a different matrix count than the selected one is uploaded as usual
+    pub fn upload_matrices_batch(&mut self, transforms: &[crate::stabilization::FrameTransform]) -> ocl::Result<()> {
+        self.matrices_batch = None;
+        self.kernel.set_arg(3, &self.buf_matrices)?;
+        if transforms.is_empty() { return Ok(()); }
+
+        // Sub-buffer origins have to be aligned to the base address alignment of the device
+        let align = (self.mem_base_align / std::mem::size_of::<f32>()).max(1);
+        let region = |count: usize| ((count * 12).max(1) + align - 1) / align * align;
+        let total: usize = transforms.iter().map(|x| region(x.matrices.len())).sum();
+
+        let mut data = vec![0.0f32; total];
+        let mut regions = Vec::with_capacity(transforms.len());
+        let mut offset = 0;
+        for t in transforms {
+            let floats: &[f32] = bytemuck::cast_slice(&t.matrices);
+            data[offset..offset + floats.len()].copy_from_slice(floats);
+            regions.push((offset, floats.len().max(1), t.matrices.len(), crc32fast::hash(bytemuck::cast_slice(floats))));
+            offset += region(t.matrices.len());
+        }
+        let buf = Buffer::<f32>::builder().queue(self.queue.clone()).flags(MemFlags::new().read_only().host_write_only()).len(total).copy_host_slice(&data).build()?;
+        let frames = regions.into_iter()
+            .map(|(origin, len, count, checksum)| Ok((buf.create_sub_buffer(None, origin, len)?, count, checksum)))
+            .collect::<ocl::Result<Vec<_>>>()?;
+        self.matrices_batch = Some(MatricesBatch { _buf: buf, frames, selected: None });
+        Ok(())
+    }
+
+    // Index into the last `upload_matrices_batch` for the next frame, None uploads the matrices of each frame again
+    pub fn select_matrices_batch_index(&mut self, i: Option<usize>) {
+        if let Some(ref mut b) = self.matrices_batch {
+            b.selected = i.filter(|i| *i < b.frames.len());
+        }
+    }
+
+    // Skip uploading/copying the input to `self.src` and reuse its current contents.
+    // Useful when the input is known to be unchanged, eg. re-rendering a paused frame with a new transform
+    pub fn set_reuse_source(&mut self, v: bool) {
+        self.reuse_source = v;
+    }
+
+    // Render into a sub-region of a larger output buffer, eg. for picture-in-picture.
+    // Uses the global work offset, so the kernel addresses the buffer at (x + x_offset, y + y_offset) with `output_stride` of the larger buffer
+    pub fn set_output_offset(&mut self, x_offset: usize, y_offset: usize, output_stride: usize) {
+        self.output_offset = if x_offset > 0 || y_offset > 0 || output_stride > 0 { Some((x_offset, y_offset, output_stride)) } else { None };
+    }
+
+    // Uploads all frames of an animated overlay with a single mapped write, each one into its own region of the drawing buffer.
+    // Select the region for the next frames with `select_drawing_buffer_index`, and render with an empty drawing buffer to keep it.
+    // All regions become available together with the unmap, so every returned event is the unmap event
+    pub fn upload_drawing_buffer_batch(&mut self, buffers: &[&[u8]]) -> ocl::Result<Vec<Event>> {
+        let len = self.drawing_len;
+        if buffers.is_empty() || len == 0 { return Ok(Vec::new()); }
+        if let Some(x) = buffers.iter().find(|x| x.len() != len) {
+            return Err(format!("Buffer size mismatch drawing_buffer! {} vs {}", len, x.len()).into());
+        }
+        let total = len * buffers.len();
+        if self.buf_drawing.len() < total {
+            self.buf_drawing = Buffer::builder().queue(self.queue.clone()).flags(MemFlags::new().read_only().host_write_only()).len(total).build()?;
+            self.kernel.set_arg(4, &self.buf_drawing)?;
+        }
+
+        let mut map = unsafe { self.buf_drawing.map().write_invalidate().len(total).enq()? };
+        for (i, buffer) in buffers.iter().enumerate() {
+            map[i * len..(i + 1) * len].copy_from_slice(buffer);
+        }
+        let mut event = Event::empty();
+        map.unmap().enew(&mut event).enq()?;
+        Ok(vec![event; buffers.len()])
+    }
+
+    // Region of the drawing buffer used by the next frames, an index into the last `upload_drawing_buffer_batch`
+    pub fn select_drawing_buffer_index(&mut self, i: usize) -> ocl::Result<()> {
+        if (i + 1) * self.drawing_len > self.buf_drawing.len() {
+            return Err(format!("Drawing buffer index out of range: {} of {}", i, self.buf_drawing.len() / self.drawing_len.max(1)).into());
+        }
+        self.drawing_index = i;
+        Ok(())
+    }
+
+    // Interpolation quality per output tile: (tile size in pixels, tiles per row, one level per tile, row-major). 255 is the compiled
+    // interpolation, 0 is bilinear, levels in between blend the two. `None` is uniform quality
+    pub fn set_quality_map(&mut self, map: Option<(usize, usize, Vec<u8>)>) -> ocl::Result<()> {
+        let mut data = vec![0u8; 16];
+        if let Some((tile_size, tiles_x, levels)) = map.filter(|(size, tiles_x, levels)| *size > 0 && *tiles_x > 0 && levels.len() >= *tiles_x) {
+            let tiles_y = levels.len() / tiles_x;
+            data[0..4].copy_from_slice(&(tile_size as i32).to_ne_bytes());
+            data[4..8].copy_from_slice(&(tiles_x as i32).to_ne_bytes());
+            data[8..12].copy_from_slice(&(tiles_y as i32).to_ne_bytes());
+            data.extend_from_slice(&levels[..tiles_x * tiles_y]);
+        }
+        self.buf_quality = Buffer::builder().queue(self.queue.clone()).flags(MemFlags::new().read_only().host_write_only()).len(data.len()).copy_host_slice(&data).build()?;
+        self.kernel.set_arg(5, &self.buf_quality)?;
+        Ok(())
+    }
+
+    // Uploads a dense remap (map width, map height, interleaved input positions of the nodes) used instead of the lens model
+    // when `KernelParamsFlags::DENSE_MAP` is set. See `FrameTransform::dense_map_from_model` for the layout
+    pub fn set_dense_map(&mut self, map: Option<(usize, usize, &[f32])>) -> ocl::Result<()> {
+        let mut data = vec![0.0f32; 4];
+        if let Some((w, h, nodes)) = map {
+            if w < 2 || h < 2 || nodes.len() < w * h * 2 {
+                return Err(format!("Invalid dense map: {}x{} with {} values", w, h, nodes.len()).into());
+            }
+            data[0] = w as f32;
+            data[1] = h as f32;
+            data.extend_from_slice(&nodes[..w * h * 2]);
+        }
+        self.buf_dense_map = Buffer::builder().queue(self.queue.clone()).flags(MemFlags::new().read_only().host_write_only()).len(data.len()).copy_host_slice(&data).build()?;
+        self.kernel.set_arg(6, &self.buf_dense_map)?;
+        Ok(())
+    }
+
+    // Render only the output rows `y..y + height`. The CPU output buffer then holds just these rows (`height * stride` bytes).
+    // Used to split a frame between devices, see `MultiGpuRenderer`. Not supported together with `set_output_offset` and dirty tiles
+    pub fn set_output_band(&mut self, band: Option<(usize, usize)>) {
+        self.output_band = band.filter(|(y, h)| *h > 0 && y + h <= self.output_size.1);
+    }
+
+    // Uploads a CPU input for the next frame rendered with `BufferSource::None` as the input.
+    // Lets several wrappers read the same source buffer without a mutable borrow of it
+    pub fn upload_source(&self, input: &[u8]) -> ocl::Result<()> {
+        if self.src.len() != input.len() { return Err(format!("Buffer size mismatch input! {} vs {}", self.src.len(), input.len()).into()); }
+        self.src.write(input).enq()
+    }
+
+    // Upload a base matrix and per-row f16 deltas instead of full matrices, and reconstruct them on the device.
+    // Saves bandwidth for tall, high-fps frames. Frames which can't be encoded within tolerance fall back to the full upload
+    pub fn set_delta_matrices(&mut self, enabled: bool) -> ocl::Result<()> {
+        if !enabled {
+            self.delta_matrices = None;
+            return Ok(());
+        }
+        let buf_base   = Buffer::<f32>::builder().queue(self.queue.clone()).flags(MemFlags::new().read_only().host_write_only()).len(12).build()?;
+        let buf_deltas = Buffer::<u16>::builder().queue(self.queue.clone()).flags(MemFlags::new().read_only().host_write_only()).len(self.buf_matrices.len()).build()?;
+
+        let mut builder = Kernel::builder();
+        unsafe {
+            builder.program(&self.program.program).name("decode_matrices").queue(self.queue.clone())
+                .global_work_size(self.buf_matrices.len())
+                .disable_arg_type_check() // u16 buffer as `half *`
+                .arg(&buf_base)
+                .arg(&buf_deltas)
+                .arg(&self.buf_matrices);
+        }
+        let kernel = builder.build()?;
+
+        self.delta_matrices = Some(DeltaMatrices { kernel, buf_base, buf_deltas });
+        Ok(())
+    }
+
+    // Histogram of the last rendered frame, laid out as `channels` consecutive blocks of `bins` values (luma, then R, G, B)
+    pub fn get_histogram(&self) -> Option<(usize, Vec<u32>)> {
+        self.histogram.as_ref().map(|h| (h.bins, h.result.lock().clone()))
+    }
+
+    // True if the lens is uncorrected and the transform maps every output pixel to the same input pixel, so the warp is a plain copy
+    fn is_passthrough(&self, buffers: &Buffers, itm: &crate::stabilization::FrameTransform) -> bool {
+        let p = &itm.kernel_params;
+        let k = p.k.map(|x| x as f64);
+        if self.has_other_lenses || !self.distortion_model.is_identity(&k) { return false; }
+        if self.output_offset.is_some() || buffers.input.size != buffers.output.size { return false; }
+        if (p.flags & !(16 | 64 | 128)) != 0 || p.width != p.output_width || p.height != p.output_height || p.source_rect != p.output_rect ||
+           p.input_rotation != 0.0 || p.output_orientation != 0 || p.projection_type != 0 || p.input_crop[2] > 0 ||
+           p.translation2d != [0.0; 2] || p.translation3d != [0.0; 4] ||
+           (p.input_horizontal_stretch > 0.001 && p.input_horizontal_stretch != 1.0) ||
+           (p.input_vertical_stretch   > 0.001 && p.input_vertical_stretch   != 1.0) {
+            return false;
+        }
+        if itm.matrices.iter().any(|m| m != &itm.matrices[0]) { return false; }
+        let m = match itm.matrices.first() { Some(m) => m, None => return false };
+
+        // Corners have to map to themselves
+        let (w, h) = (p.width as f32, p.height as f32);
+        [(0.0, 0.0), (w, 0.0), (0.0, h), (w, h)].iter().all(|&(x, y)| {
+            let z = x * m[6] + y * m[7] + m[8];
+            if z <= 0.0 { return false; }
+            let u = (x * m[0] + y * m[1] + m[2]) / z * p.f[0] + p.c[0];
+            let v = (x * m[3] + y * m[4] + m[5]) / z * p.f[1] + p.c[1];
+            (u - x).abs() < 0.01 && (v - y).abs() < 0.01
+        })
+    }
+
     pub fn undistort_image(&self, buffers: &mut Buffers, itm: &crate::stabilization::FrameTransform, drawing_buffer: &[u8]) -> ocl::Result<()> {
+        match self.enqueue_frame(buffers, itm, drawing_buffer, true) {
+            Ok(()) => Ok(()),
+            Err(e) => match categorize_ocl_error(&e) {
+                ErrorCategory::Recoverable if !self.interop_acquired.load(std::sync::atomic::Ordering::SeqCst) => {
+                    ::log::warn!("OpenCL error, retrying: {e:?}");
+                    let _ = self.queue.finish();
+                    self.enqueue_frame(buffers, itm, drawing_buffer, true)
+                },
+                ErrorCategory::Recoverable => Err(e), // A retry would acquire the texture again without the release
+                ErrorCategory::ContextLost => {
+                    if self.uses_global_context() {
+                        ::log::error!("OpenCL context lost, it will be created again: {e:?}");
+                        Self::reset_context();
+                    } else {
+                        ::log::error!("OpenCL context lost: {e:?}");
+                    }
+                    Err(e)
+                },
+                ErrorCategory::ProgramError => {
+                    ::log::error!("OpenCL program error, it will be compiled again: {e:?}");
+                    PROGRAM_CACHE.lock().clear();
+                    self.program_invalid.store(true, std::sync::atomic::Ordering::SeqCst);
+                    Err(e)
+                },
+                ErrorCategory::Fatal => Err(e)
+            }
+        }
+    }
+
+    // Host to device and device to host copy speed in GB/s, timed over a few blocking transfers of a 64 MB buffer.
+    // Together with `benchmark_kernel` this tells if rendering is bound by the bus (eg. PCIe x1 risers) or by the kernel itself
+    pub fn measure_transfer_bandwidth(&self) -> ocl::Result<(f64, f64)> {
+        const SIZE: usize = 64 * 1024 * 1024;
+        const ITERATIONS: usize = 4;
+
+        let host = vec![0u8; SIZE];
+        let mut readback = vec![0u8; SIZE];
+        let buf = Buffer::<u8>::builder().queue(self.queue.clone()).len(SIZE).flags(MemFlags::new().read_write()).build()?;
+
+        // Warm up, the first transfer includes the allocation on the device
+        buf.write(&host).enq()?;
+        buf.read(&mut readback).enq()?;
+
+        let start = std::time::Instant::now();
+        for _ in 0..ITERATIONS { buf.write(&host).enq()?; }
+        self.queue.finish()?;
+        let upload = start.elapsed().as_secs_f64();
+
+        let start = std::time::Instant::now();
+        for _ in 0..ITERATIONS { buf.read(&mut readback).enq()?; }
+        self.queue.finish()?;
+        let download = start.elapsed().as_secs_f64();
+
+        let gb = (SIZE * ITERATIONS) as f64 / 1e9;
+        Ok((gb / upload.max(1e-9), gb / download.max(1e-9)))
+    }
+
+    pub fn is_profiling(&self) -> bool { self.profiling }
+
+    // Device time between the start of the first and the end of the last kernel run of the previous frame, in nanoseconds.
+    // Waits for that kernel if it's still running (`submit_frame_async`). None if profiling is disabled or the frame was a passthrough copy.
+    // Only the undistortion kernel is measured, not the uploads, the readback or the histogram and secondary output passes
+    pub fn last_kernel_duration_ns(&self) -> Option<u64> {
+        if !self.profiling { return None; }
+        let mut timing = self.kernel_timing.lock();
+        if let Some(events) = timing.0.take() {
+            let mut range: Option<(u64, u64)> = None;
+            for event in events.iter() {
+                if let Err(e) = event.wait_for() { ::log::error!("OpenCL error waiting for the kernel: {e:?}"); return None; }
+                let start = match event.profiling_info(ocl::enums::ProfilingInfo::Start) { Ok(ocl::enums::ProfilingInfoResult::Start(x)) => x, _ => return None };
+                let end   = match event.profiling_info(ocl::enums::ProfilingInfo::End)   { Ok(ocl::enums::ProfilingInfoResult::End(x))   => x, _ => return None };
+                range = Some(range.map_or((start, end), |(s, e)| (s.min(start), e.max(end))));
+            }
+            timing.1 = range.map(|(start, end)| end.saturating_sub(start));
+        }
+        timing.1
+    }
+
+    // Average time of a full frame (upload, kernel and readback) in milliseconds, over `iterations` runs after one warm-up run
+    pub fn benchmark_kernel(&self, buffers: &mut Buffers, itm: &crate::stabilization::FrameTransform, drawing_buffer: &[u8], iterations: usize) -> ocl::Result<f64> {
+        let iterations = iterations.max(1);
+        self.undistort_image(buffers, itm, drawing_buffer)?;
+
+        let start = std::time::Instant::now();
+        for _ in 0..iterations {
+            self.undistort_image(buffers, itm, drawing_buffer)?;
+        }
+        Ok(start.elapsed().as_secs_f64() * 1000.0 / iterations as f64)
+    }
+
+    // Same as `undistort_image`, but doesn't wait for the CPU input upload, the kernel and the CPU output readback.
+    // `buffers` stay borrowed until the returned handle is dropped, which waits for the frame if `wait` wasn't called.
+    // Zero-copy is not used here, and the histogram (if enabled) is still read synchronously
+    //
+    // Safety: the device keeps reading and writing the CPU buffers after this returns, so the returned handle has to be
+    // waited for or dropped before the buffers are used or freed. It must not be leaked (eg. with `std::mem::forget`)
+    pub unsafe fn submit_frame_async<'a>(&self, buffers: &'a mut Buffers, itm: &crate::stabilization::FrameTransform, drawing_buffer: &[u8]) -> ocl::Result<FrameHandle<'a>> {
+        self.enqueue_frame(buffers, itm, drawing_buffer, false)?;
+        // In-order queue, so the marker completes after the upload, kernel and readback
+        let event = self.queue.enqueue_marker::<&EventList>(None)?;
+        self.queue.flush()?;
+        Ok(FrameHandle { event: Some(event), _buffers: std::marker::PhantomData })
+    }
+
+    fn enqueue_frame(&self, buffers: &mut Buffers, itm: &crate::stabilization::FrameTransform, drawing_buffer: &[u8], blocking: bool) -> ocl::Result<()> {
+        if !self.is_context_valid() { return Err("OpenCL context was reset".into()); }
+        self.interop_acquired.store(false, std::sync::atomic::Ordering::SeqCst);
+        if let Some(mem) = &self.host_memory { mem.touch(); }
+        if !self.use_image_path && ![&buffers.input.data, &buffers.output.data].iter().all(|x| matches!(x, BufferSource::None | BufferSource::Cpu { .. } | BufferSource::OpenCL { .. })) {
+            return Err("OpenCL device doesn't support images, texture buffers can't be used".into());
+        }
+
         let matrices = unsafe { std::slice::from_raw_parts(itm.matrices.as_ptr() as *const f32, itm.matrices.len() * 12 ) };
 
+        let passthrough = self.is_passthrough(buffers, itm);
+        if passthrough && self.histogram.is_none() && self.secondary_output.is_none() {
+            if let (BufferSource::Cpu { buffer: input }, BufferSource::Cpu { buffer: output }) = (&buffers.input.data, &mut buffers.output.data) {
+                if input.len() == output.len() {
+                    output.copy_from_slice(input);
+                    return Ok(());
+                }
+            }
+        }
+
         let mut _temp1 = None;
         let mut _temp2 = None;
 
@@ -336,14 +1362,45 @@ impl OclWrapper {
         }
 
         if !drawing_buffer.is_empty() {
-            if self.buf_drawing.len() != drawing_buffer.len() { log::error!("Buffer size mismatch drawing_buffer! {} vs {}", self.buf_drawing.len(), drawing_buffer.len()); return Ok(()); }
-            self.buf_drawing.write(drawing_buffer).enq()?;
+            if self.drawing_len != drawing_buffer.len() { log::error!("Buffer size mismatch drawing_buffer! {} vs {}", self.drawing_len, drawing_buffer.len()); return Ok(()); }
+            self.buf_drawing.write(drawing_buffer).offset(self.drawing_index * self.drawing_len).enq()?;
         }
+
+        // With zero-copy, the kernel arguments point to the wrapped CPU buffers only for this call
+        let host_src = match &buffers.input.data {
+            BufferSource::Cpu { buffer } if blocking && buffer.len() == self.src.len() => self.wrap_host_buffer(buffer, MemFlags::new().read_only())?,
+            _ => None
+        };
+        let host_dst = match &buffers.output.data {
+            BufferSource::Cpu { buffer } if blocking && buffer.len() == self.dst.len() && self.dirty_tiles.is_none() => self.wrap_host_buffer(buffer, MemFlags::new().read_write())?, // Dirty tiles need the previous frame in `dst`
+            _ => None
+        };
+        let src = host_src.as_ref().unwrap_or(&self.src);
+        let dst = host_dst.as_ref().unwrap_or(&self.dst);
+        if self.zero_copy {
+            self.kernel.set_arg(0, src)?;
+            self.kernel.set_arg(1, dst)?;
+            if let Some(ref h) = self.histogram {
+                h.kernel.set_arg(0, dst)?;
+            }
+            if let Some(ref s) = self.secondary_output {
+                s.kernel.set_arg(0, dst)?;
+            }
+        }
+
         match buffers.input.data {
             BufferSource::None => { },
+            BufferSource::Cpu { .. } if self.reuse_source => { },
+            #[cfg(all(feature = "opengl", feature = "opencl-interop"))]
+            BufferSource::OpenGL { .. } if self.reuse_source => { },
+            #[cfg(all(target_os = "windows", feature = "directx"))]
+            BufferSource::DirectX11 { .. } if self.reuse_source => { },
             BufferSource::Cpu { ref buffer } => {
                 if self.src.len() != buffer.len() { log::error!("Buffer size mismatch input! {} vs {}", self.src.len(), buffer.len());  return Ok(()); }
-                self.src.write(buffer as &[u8]).enq()?;
+                if host_src.is_none() {
+                    // Non-blocking is fine, `submit_frame_async` keeps `buffers` borrowed until the frame is done
+                    unsafe { self.src.write(buffer as &[u8]).block(blocking).enq()?; }
+                }
             },
             BufferSource::OpenCL { texture, .. } => {
                 unsafe {
@@ -351,6 +1408,7 @@ impl OclWrapper {
                     self.kernel.set_arg_unchecked(0, core::ArgVal::from_raw(siz, &texture as *const _ as *const std::ffi::c_void, true))?;
                 }
             },
+            #[cfg(all(feature = "opengl", feature = "opencl-interop"))]
             BufferSource::OpenGL { texture, .. } => {
                 if let Some(ref tex) = self.image_src {
                     let mut img = &tex.0;
@@ -360,14 +1418,16 @@ impl OclWrapper {
                         img = _temp1.as_ref().unwrap();
                     }
                     img.cmd().gl_acquire().enq()?;
+                    self.interop_acquired.store(true, std::sync::atomic::Ordering::SeqCst);
                     let _ = img.cmd().copy_to_buffer(&self.src, 0).enq();
                     img.cmd().gl_release().enq()?;
                 }
             },
-            #[cfg(target_os = "windows")]
+            #[cfg(all(target_os = "windows", feature = "directx"))]
             BufferSource::DirectX11 { .. } => {
                 if let Some(ref tex) = self.image_src {
                     tex.0.cmd().d3d11_acquire().enq()?;
+                    self.interop_acquired.store(true, std::sync::atomic::Ordering::SeqCst);
                     let _ = tex.0.cmd().copy_to_buffer(&self.src, 0).enq();
                     tex.0.cmd().d3d11_release().enq()?;
                 }
@@ -379,21 +1439,131 @@ impl OclWrapper {
                 unsafe {
                     let siz = std::mem::size_of::<ocl::ffi::cl_mem>() as usize;
                     self.kernel.set_arg_unchecked(1, core::ArgVal::from_raw(siz, &texture as *const _ as *const std::ffi::c_void, true))?;
+                    if let Some(ref h) = self.histogram {
+                        h.kernel.set_arg_unchecked(0, core::ArgVal::from_raw(siz, &texture as *const _ as *const std::ffi::c_void, true))?;
+                    }
+                    if let Some(ref s) = self.secondary_output {
+                        s.kernel.set_arg_unchecked(0, core::ArgVal::from_raw(siz, &texture as *const _ as *const std::ffi::c_void, true))?;
+                    }
                 }
             },
             _ => { }
         }
 
-        self.buf_params.write(bytemuck::bytes_of(&itm.kernel_params)).enq()?;
-        self.buf_matrices.write(matrices).enq()?;
+        let mut kernel_params = itm.kernel_params;
+        kernel_params.drawing_offset = (self.drawing_index * self.drawing_len) as i32;
+        let mut work_offset = (0, 0);
+        if let Some((x, y, stride)) = self.output_offset {
+            kernel_params.output_x_offset = x as i32;
+            kernel_params.output_y_offset = y as i32;
+            if stride > 0 { kernel_params.output_stride = stride as i32; }
+            work_offset = (x, y);
+        }
+        // Output rect covering only a part of the buffer, eg. one cell of an atlas. Only that region is rendered and copied back
+        let sub_rect = match self.output_band {
+            Some((y, h)) => Some((0, y, self.output_size.0, h)),
+            None => buffers.output.sub_rect().filter(|_| self.output_offset.is_none())
+        };
+        let output_stride = buffers.output.size.2;
+
+        self.buf_params.write(bytemuck::bytes_of(&kernel_params)).enq()?;
+        let mut matrices_uploaded = false;
+        if let Some(ref b) = self.matrices_batch {
+            match b.selected.and_then(|i| b.frames.get(i)) {
+                // Only if these are the matrices of this frame, a wrong index would render with the transform of another frame
+                Some((buf, count, checksum)) if *count == itm.matrices.len() && *checksum == crc32fast::hash(bytemuck::cast_slice(matrices)) => {
+                    self.kernel.set_arg(3, buf)?;
+                    matrices_uploaded = true;
+                },
+                Some((_, count, _)) if *count == itm.matrices.len() => {
+                    ::log::warn!("Pre-uploaded matrices at index {:?} don't match the frame, uploading them", b.selected);
+                    self.kernel.set_arg(3, &self.buf_matrices)?;
+                },
+                _ => { self.kernel.set_arg(3, &self.buf_matrices)?; }
+            }
+        }
+        if let Some(d) = self.delta_matrices.as_ref().filter(|_| !matrices_uploaded) {
+            if let Some((base, deltas)) = encode_delta_matrices(&itm.matrices) {
+                d.buf_base.write(&base[..]).enq()?;
+                d.buf_deltas.write(&deltas).enq()?;
+                unsafe { d.kernel.cmd().global_work_size(deltas.len()).enq()?; }
+                matrices_uploaded = true;
+            }
+        }
+        if !matrices_uploaded {
+            self.buf_matrices.write(matrices).enq()?;
+        }
+
+        let external_textures = matches!(buffers.input.data, BufferSource::OpenCL { .. }) || matches!(buffers.output.data, BufferSource::OpenCL { .. });
+        let mut kernel_events = EventList::new();
+        if passthrough && !external_textures {
+            src.copy(dst, None, None).enq()?;
+        } else {
+            match &self.dirty_tiles {
+                Some((tile_size, dirty)) if self.output_band.is_none() && self.has_output.load(std::sync::atomic::Ordering::Relaxed) => {
+                    let (w, h) = self.output_size;
+                    let tiles_x = (w + tile_size - 1) / tile_size;
+                    for (i, _) in dirty.iter().enumerate().filter(|(_, d)| **d) {
+                        let (tx, ty) = ((i % tiles_x) * tile_size, (i / tiles_x) * tile_size);
+                        if tx >= w || ty >= h { continue; }
+                        // Edge tiles can go past the output with local tiling, these work items are outside of the output rect
+                        let size = if self.local_tiling { (*tile_size, *tile_size) } else { ((*tile_size).min(w - tx), (*tile_size).min(h - ty)) };
+                        unsafe { self.kernel.cmd().global_work_offset((work_offset.0 + tx, work_offset.1 + ty)).global_work_size(size).enew(&mut kernel_events).enq()?; }
+                    }
+                },
+                _ => {
+                    match sub_rect {
+                        Some((rx, ry, rw, rh)) => {
+                            let size = if self.local_tiling {
+                                let round_up = |x: usize| (x + TILE_WORK_GROUP - 1) / TILE_WORK_GROUP * TILE_WORK_GROUP;
+                                (round_up(rw), round_up(rh))
+                            } else {
+                                (rw, rh)
+                            };
+                            unsafe { self.kernel.cmd().global_work_offset((rx, ry)).global_work_size(size).enew(&mut kernel_events).enq()?; }
+                        },
+                        _ => {
+                            unsafe { self.kernel.cmd().global_work_offset(work_offset).enew(&mut kernel_events).enq()?; }
+                        }
+                    }
+                }
+            }
+        }
+        if self.profiling {
+            *self.kernel_timing.lock() = (Some(kernel_events), None);
+        }
+        self.has_output.store(true, std::sync::atomic::Ordering::Relaxed);
 
-        unsafe { self.kernel.enq()?; }
+        if let Some(ref h) = self.histogram {
+            h.buf.cmd().fill(0u32, None).enq()?;
+            unsafe { h.kernel.cmd().global_work_offset(work_offset).enq()?; }
+            h.buf.read(&mut *h.result.lock()).enq()?;
+        }
+        if let Some(ref s) = self.secondary_output {
+            unsafe { s.kernel.cmd().enq()?; }
+        }
 
         match &mut buffers.output.data {
             BufferSource::None => { },
             BufferSource::Cpu { buffer, .. } => {
-                self.dst.read(&mut **buffer).enq()?;
+                if let Some(ref host_dst) = host_dst {
+                    // Mapping makes the kernel output visible in the host memory, it's not a copy on unified memory
+                    let mut map = unsafe { host_dst.map().read().enq()? };
+                    map.unmap().enq()?;
+                } else if let Some((y, h)) = self.output_band {
+                    // The host buffer starts at the first row of the band
+                    let row_bytes = self.output_size.0 * kernel_params.bytes_per_pixel as usize;
+                    unsafe { self.dst.cmd().read(&mut **buffer).rect([0, y, 0], [0, 0, 0], [row_bytes, h, 1], output_stride, 0, output_stride, 0).block(blocking).enq()?; }
+                } else if let Some((rx, ry, rw, rh)) = sub_rect {
+                    // The rest of the host buffer is kept as is
+                    let (bpp, stride) = (kernel_params.bytes_per_pixel as usize, output_stride);
+                    let origin = [rx * bpp, ry, 0];
+                    unsafe { self.dst.cmd().read(&mut **buffer).rect(origin, origin, [rw * bpp, rh, 1], stride, 0, stride, 0).block(blocking).enq()?; }
+                } else {
+                    unsafe { self.dst.read(&mut **buffer).block(blocking).enq()?; }
+                }
             },
+            #[cfg(all(feature = "opengl", feature = "opencl-interop"))]
             BufferSource::OpenGL { texture, .. } => {
                 if let Some(ref tex) = self.image_dst {
                     let mut img = &tex.0;
@@ -404,6 +1574,7 @@ impl OclWrapper {
                     }
 
                     img.cmd().gl_acquire().enq()?;
+                    self.interop_acquired.store(true, std::sync::atomic::Ordering::SeqCst);
                     if let SpatialDims::Three(w, h, d) = img.dims() {
                         let _ = self.dst.cmd().copy_to_image(&img, [0, 0, 0], [*w, *h, *d]).enq();
                     }
@@ -411,10 +1582,11 @@ impl OclWrapper {
 
                 }
             },
-            #[cfg(target_os = "windows")]
+            #[cfg(all(target_os = "windows", feature = "directx"))]
             BufferSource::DirectX11 { .. } => {
                 if let Some(ref tex) = self.image_dst {
                     tex.0.cmd().d3d11_acquire().enq()?;
+                    self.interop_acquired.store(true, std::sync::atomic::Ordering::SeqCst);
                     if let SpatialDims::Three(w, h, d) = tex.0.dims() {
                         let _ = self.dst.cmd().copy_to_image(&tex.0, [0, 0, 0], [*w, *h, *d]).enq();
                     }
@@ -430,15 +1602,99 @@ impl OclWrapper {
     }
 }
 
+// Pending frame from `OclWrapper::submit_frame_async`, keeps the frame buffers borrowed until it's complete
+pub struct FrameHandle<'a> {
+    event: Option<Event>,
+    _buffers: std::marker::PhantomData<&'a mut [u8]>,
+}
+impl FrameHandle<'_> {
+    pub fn wait(&self) -> ocl::Result<()> {
+        if let Some(ref event) = self.event {
+            event.wait_for()?;
+        }
+        Ok(())
+    }
+    pub fn is_ready(&self) -> ocl::Result<bool> {
+        match self.event {
+            Some(ref event) => event.is_complete(),
+            None => Ok(true)
+        }
+    }
+}
+impl Drop for FrameHandle<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.wait() {
+            log::error!("OpenCL error waiting for the frame: {:?}", e);
+        }
+    }
+}
+
+// Encodes matrices as their mean plus f16 deltas. Returns None if the reconstruction doesn't match the full matrices within tolerance
+pub fn encode_delta_matrices(matrices: &[[f32; 12]]) -> Option<([f32; 12], Vec<u16>)> {
+    if matrices.is_empty() { return None; }
+
+    let mut base = [0.0f32; 12];
+    for m in matrices {
+        for i in 0..12 { base[i] += m[i]; }
+    }
+    for v in base.iter_mut() { *v /= matrices.len() as f32; }
+
+    let mut deltas = Vec::with_capacity(matrices.len() * 12);
+    for m in matrices {
+        for i in 0..12 {
+            let delta = half::f16::from_f32(m[i] - base[i]);
+            let reconstructed = base[i] + delta.to_f32();
+            if (reconstructed - m[i]).abs() > m[i].abs() * 1e-5 + 1e-7 {
+                return None;
+            }
+            deltas.push(delta.to_bits());
+        }
+    }
+    Some((base, deltas))
+}
+
+// Compiled program binaries in `<cache dir>/gyroflow/ocl_cache/<hash>.bin`. The hash covers the final kernel source and the device
+// and driver versions, so a driver update compiles again. Files are never removed, stale ones are just not looked up anymore
+#[cfg(feature = "ocl-cache")]
+mod disk_cache {
+    use sha2::{ Sha256, Digest };
+    use std::path::{ Path, PathBuf };
+
+    pub fn path(source: &str, device: &ocl::Device) -> Option<PathBuf> {
+        let driver = match device.info(ocl::core::DeviceInfo::DriverVersion) { Ok(ocl::core::DeviceInfoResult::DriverVersion(x)) => x, _ => String::new() };
+        let mut hasher = Sha256::new();
+        hasher.update(source.as_bytes());
+        hasher.update(format!("{} {} {} {}", device.vendor().ok()?, device.name().ok()?, device.version().ok()?, driver).as_bytes());
+        let hash = hasher.finalize().iter().map(|x| format!("{x:02x}")).collect::<String>();
+        Some(dirs::cache_dir()?.join("gyroflow").join("ocl_cache").join(format!("{hash}.bin")))
+    }
+
+    pub fn store(path: &Path, program: &ocl::Program) {
+        let binary = match program.info(ocl::enums::ProgramInfo::Binaries) {
+            Ok(ocl::enums::ProgramInfoResult::Binaries(mut x)) if !x.is_empty() && !x[0].is_empty() => x.swap_remove(0),
+            _ => { ::log::debug!("OpenCL driver didn't return the program binary, not caching"); return; }
+        };
+        // Write to a temporary file first, so another process never reads a partial binary
+        let tmp = path.with_extension("tmp");
+        let result = path.parent().map(std::fs::create_dir_all).unwrap_or(Ok(()))
+            .and_then(|_| std::fs::write(&tmp, &binary))
+            .and_then(|_| std::fs::rename(&tmp, path));
+        if let Err(e) = result {
+            ::log::warn!("Failed to store the OpenCL program in {}: {e:?}", path.display());
+        }
+    }
+}
+
 pub fn is_buffer_supported(buffers: &Buffers) -> bool {
     match buffers.input.data {
         BufferSource::None           => false,
         BufferSource::Cpu     { .. } => true,
-        BufferSource::OpenGL  { .. } => true,
+        #[cfg(feature = "opengl")]
+        BufferSource::OpenGL  { .. } => cfg!(feature = "opencl-interop"),
         BufferSource::OpenCL  { .. } => true,
-        #[cfg(target_os = "windows")]
+        #[cfg(all(target_os = "windows", feature = "directx"))]
         BufferSource::DirectX11 { .. } => true,
-        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        #[cfg(all(not(any(target_os = "macos", target_os = "ios")), feature = "vulkan"))]
         BufferSource::Vulkan  { .. } => false,
         #[cfg(any(target_os = "windows", target_os = "linux"))]
         BufferSource::CUDABuffer{ .. } => false,
@@ -446,3 +1702,125 @@ pub fn is_buffer_supported(buffers: &Buffers) -> bool {
         BufferSource::Metal { .. } | BufferSource::MetalBuffer { .. } => false,
     }
 }
+
+// Tests which create or reset the global context, a reset in parallel would invalidate the wrappers of the others
+#[cfg(test)]
+pub(crate) static GLOBAL_CONTEXT_LOCK: Mutex<()> = parking_lot::const_mutex(());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_params() -> KernelParams {
+        KernelParams { width: 16, height: 16, stride: 64, output_width: 16, output_height: 16, output_stride: 64, bytes_per_pixel: 4, pix_element_count: 4, interpolation: 2, ..Default::default() }
+    }
+
+    #[test]
+    fn failed_build_drops_reinitialized_context() {
+        let _lock = GLOBAL_CONTEXT_LOCK.lock();
+        // Global context with a different `surface_checksum` than the buffers below, so `create` initializes it again
+        if OclWrapper::initialize_context(None).is_err() { return; } // No OpenCL device
+
+        let (mut input, mut output) = (vec![0u8; 64 * 16], vec![0u8; 64 * 16]);
+        let buffers = Buffers {
+            input:  BufferDescription { size: (16, 16, 64), data: BufferSource::Cpu { buffer: &mut input }, ..Default::default() },
+            output: BufferDescription { size: (16, 16, 64), data: BufferSource::Cpu { buffer: &mut output }, ..Default::default() },
+        };
+        let ret = OclWrapper::new_from_source(&test_params(), "__kernel void undistort_image( {".into(), KernelCapabilities::default(), DistortionModel::default(), false, &buffers, 4, false, false);
+        assert!(ret.is_err());
+        assert!(CONTEXT.read().is_none());
+    }
+
+    #[test]
+    fn undersized_stride_is_rejected() {
+        let (mut input, mut output) = (vec![0u8; 64 * 16], vec![0u8; 64 * 16]);
+        let buffers = Buffers {
+            input:  BufferDescription { size: (16, 16, 60), data: BufferSource::Cpu { buffer: &mut input }, ..Default::default() },
+            output: BufferDescription { size: (16, 16, 64), data: BufferSource::Cpu { buffer: &mut output }, ..Default::default() },
+        };
+        let err = OclWrapper::check_params(&test_params(), &buffers).unwrap_err();
+        assert!(err.to_string().contains("Input stride is too small: 60 < 64"), "{err}");
+
+        let buffers = Buffers {
+            input:  BufferDescription { size: (16, 16, 64), data: BufferSource::Cpu { buffer: &mut input }, ..Default::default() },
+            output: BufferDescription { size: (16, 16, 32), data: BufferSource::Cpu { buffer: &mut output }, ..Default::default() },
+        };
+        let err = OclWrapper::check_params(&test_params(), &buffers).unwrap_err();
+        assert!(err.to_string().contains("Output stride is too small: 32 < 64"), "{err}");
+    }
+
+    // Renders a 16-bit gradient shifted by a fraction of a pixel and compares every channel with the bilinear interpolation of the input.
+    // Returns the largest difference in LSB
+    fn round_trip_16bit<T: crate::stabilization::PixelType>() -> f64 {
+        let bpp = T::COUNT * T::SCALAR_BYTES;
+        let (width, height, stride) = (16, 16, 16 * bpp);
+        let shift = (0.25, 0.5); // Multiples of 1/32, so the interpolation table has the exact weights
+        let params = KernelParams {
+            width: width as i32, height: height as i32, stride: stride as i32,
+            output_width: width as i32, output_height: height as i32, output_stride: stride as i32,
+            bytes_per_pixel: bpp as i32, pix_element_count: T::COUNT as i32, interpolation: 2, matrix_count: 1,
+            f: [1.0, 1.0], fov: 1.0, lens_correction_amount: 1.0,
+            pixel_value_limit: 65535.0, max_pixel_value: 65535.0,
+            source_rect: [0, 0, width as i32, height as i32], output_rect: [0, 0, width as i32, height as i32],
+            ..Default::default()
+        };
+        let itm = crate::stabilization::FrameTransform {
+            matrices: vec![[1.0, 0.0, shift.0,  0.0, 1.0, shift.1,  0.0, 0.0, 1.0,  0.0, 0.0, 0.0]],
+            kernel_params: params,
+            ..Default::default()
+        };
+
+        // Different slope in each axis and channel, so the fractional weights show up in the values
+        let gradient = |x: f64, y: f64, c: usize| x * 2500.0 + y * 1500.0 + c as f64 * 500.0;
+        let mut values = Vec::with_capacity(width * height * T::COUNT);
+        for y in 0..height {
+            for x in 0..width {
+                for c in 0..T::COUNT {
+                    values.push(gradient(x as f64, y as f64, c) as u16);
+                }
+            }
+        }
+        let mut input: Vec<u8> = values.iter().flat_map(|x| x.to_ne_bytes()).collect();
+        let mut output = vec![0u8; stride * height];
+        let mut buffers = Buffers {
+            input:  BufferDescription { size: (width, height, stride), data: BufferSource::Cpu { buffer: &mut input }, ..Default::default() },
+            output: BufferDescription { size: (width, height, stride), data: BufferSource::Cpu { buffer: &mut output }, ..Default::default() },
+        };
+        let _lock = GLOBAL_CONTEXT_LOCK.lock();
+        assert!(!OclWrapper::list_devices().is_empty(), "No OpenCL device");
+        let cl = OclWrapper::new(&params, T::ocl_names(), DistortionModel::from_name("opencv_standard"), None, None, &buffers, 4, false, false).unwrap();
+        cl.undistort_image(&mut buffers, &itm, &[]).unwrap();
+        drop(buffers);
+
+        let input_at = |x: usize, y: usize, c: usize| values[(y * width + x) * T::COUNT + c] as f64;
+        let (fx, fy) = shift;
+        let output: Vec<u16> = output.chunks_exact(2).map(|x| u16::from_ne_bytes([x[0], x[1]])).collect();
+        let mut max_diff: f64 = 0.0;
+        for y in 0..height - 1 { // The last row and column sample outside of the input
+            for x in 0..width - 1 {
+                for c in 0..T::COUNT {
+                    let expected = input_at(x,     y,     c) * (1.0 - fx) * (1.0 - fy) +
+                                   input_at(x + 1, y,     c) * fx         * (1.0 - fy) +
+                                   input_at(x,     y + 1, c) * (1.0 - fx) * fy +
+                                   input_at(x + 1, y + 1, c) * fx         * fy;
+                    assert_eq!(expected, gradient(x as f64 + fx, y as f64 + fy, c));
+                    let out = output[(y * width + x) * T::COUNT + c] as f64;
+                    max_diff = max_diff.max((out - expected).abs());
+                }
+            }
+        }
+        max_diff
+    }
+
+    #[test]
+    fn packed_rgb16_round_trip() {
+        let max_diff = round_trip_16bit::<crate::stabilization::RGB16>();
+        assert!(max_diff <= 1.0, "RGB16 differs by {max_diff}");
+    }
+
+    #[test]
+    fn rgba16_round_trip() {
+        let max_diff = round_trip_16bit::<crate::stabilization::RGBA16>();
+        assert!(max_diff <= 1.0, "RGBA16 differs by {max_diff}");
+    }
+}
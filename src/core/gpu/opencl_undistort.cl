@@ -45,8 +45,28 @@ typedef struct {
     float reserved1;                 // 8
     float reserved2;                 // 12
     float pixel_value_limit;         // 16
+    int4 input_crop;                 // 16
+    int output_x_offset;             // 4
+    int output_y_offset;             // 8
+    int projection_type;             // 12 - 0: perspective, 1: equirectangular
+    int output_orientation;          // 16 - bits 0-1: clockwise quarter turns, bit 2: flip horizontal, bit 3: flip vertical
+    float blend_k[12];               // 16, 16, 16 - distortion coefficients of the second lens model
+    float4 blend_region;             // 16 - start, end, direction x, y
+    float blend_with_original;       // 4
+    float color_offset;              // 8
+    int transfer_function;           // 12 - 0: none, 1: PQ, 2: HLG
+    int drawing_offset;              // 16 - in bytes, see `upload_drawing_buffer_batch`
+    float4 color_gain;               // 16
+    float perspective[8];            // 16, 16 - output homography, h33 = 1
+    float4 user_distortion;          // 16 - k1, k2, p1, p2 applied after the lens model
 } KernelParams;
 
+// Pixels in global memory. Packed 3-channel formats define these with vload3/vstore3 in EXTENSIONS, a 3-component vector takes the space of 4
+#ifndef LOAD_PIXEL
+#define LOAD_PIXEL(ptr) (*(__global const DATA_TYPE *)(ptr))
+#define STORE_PIXEL(v, ptr) (*(__global DATA_TYPE *)(ptr) = (v))
+#endif
+
 #if INTERPOLATION == 2 // Bilinear
 #define S_OFFSET 0.0f
 __constant float coeffs[64] = {
@@ -112,10 +132,55 @@ __constant float4 colors[9] = {
     (float4)(0.0f,   200.0f, 200.0f, 255.0f)  // Blue3
 };
 __constant float alphas[4] = { 1.0f, 0.75f, 0.50f, 0.25f };
+// Overlay color premultiplied by its alpha at canvas position cx, cy, and the alpha itself. Zero if there's nothing to draw at this stage
+float4 overlay_at(float cx, float cy, float canvas_width, bool isInput, __global const uchar *drawing, float *alphaf) {
+    uchar data = drawing[(int)round(cy * canvas_width + cx)];
+    if (data > 0) {
+        uchar color = (data & 0xF8) >> 3;
+        uchar alpha = (data & 0x06) >> 1;
+        uchar stage = data & 1;
+        if (((stage == 0 && isInput) || (stage == 1 && !isInput)) && color < 9 && alpha < 4) {
+            *alphaf = alphas[alpha];
+            return colors[color] * alphas[alpha];
+        }
+    }
+    *alphaf = 0.0f;
+    return (float4)(0.0f, 0.0f, 0.0f, 0.0f);
+}
+// Bilinear overlay fetch, for a canvas upscaled from a lower resolution
+void draw_pixel_bilinear(DATA_TYPE *out_pix, int x, int y, bool isInput, int width, __global KernelParams *params, __global const uchar *drawing) {
+    float canvas_width  = width / params->canvas_scale;
+    float canvas_height = max(params->height, params->output_height) / params->canvas_scale;
+    float fx = (float)x / params->canvas_scale - 0.5f;
+    float fy = (float)y / params->canvas_scale - 0.5f;
+    float x0 = clamp(floor(fx), 0.0f, floor(canvas_width)  - 1.0f);
+    float y0 = clamp(floor(fy), 0.0f, floor(canvas_height) - 1.0f);
+    float x1 = min(x0 + 1.0f, floor(canvas_width)  - 1.0f);
+    float y1 = min(y0 + 1.0f, floor(canvas_height) - 1.0f);
+    float tx = clamp(fx - x0, 0.0f, 1.0f);
+    float ty = clamp(fy - y0, 0.0f, 1.0f);
+
+    float a00, a10, a01, a11;
+    float4 c00 = overlay_at(x0, y0, canvas_width, isInput, drawing, &a00);
+    float4 c10 = overlay_at(x1, y0, canvas_width, isInput, drawing, &a10);
+    float4 c01 = overlay_at(x0, y1, canvas_width, isInput, drawing, &a01);
+    float4 c11 = overlay_at(x1, y1, canvas_width, isInput, drawing, &a11);
+    float4 colorf4 = mix(mix(c00, c10, tx), mix(c01, c11, tx), ty);
+    float alphaf   = mix(mix(a00, a10, tx), mix(a01, a11, tx), ty);
+    if (alphaf > 0.0f) {
+        DATA_TYPEF colorf = *(DATA_TYPEF *)&colorf4;
+        *out_pix = DATA_CONVERT(colorf + DATA_CONVERTF(*out_pix) * (1.0f - alphaf));
+    }
+}
 void draw_pixel(DATA_TYPE *out_pix, int x, int y, bool isInput, int width, __global KernelParams *params, __global const uchar *drawing) {
     if (!(params->flags & 8)) { // Drawing not enabled
         return;
     }
+    drawing += params->drawing_offset;
+    if (params->flags & 128) {
+        draw_pixel_bilinear(out_pix, x, y, isInput, width, params, drawing);
+        return;
+    }
     int pos = (int)round(floor((float)y / params->canvas_scale) * (width / params->canvas_scale) + floor((float)x / params->canvas_scale));
     uchar data = drawing[pos];
     if (data > 0) {
@@ -163,15 +228,96 @@ float2 rotate_point(float2 pos, float angle, float2 origin) {
                      sin(angle) * (pos.x - origin.x) + cos(angle) * (pos.y - origin.y) + origin.y);
 }
 
-DATA_TYPEF sample_input_at(float2 uv, __global const uchar *srcptr, __global KernelParams *params, __global const uchar *drawing, DATA_TYPEF bg) {
-    bool fix_range = params->flags & 1;
+// Maps a position in the oriented (rotated, then flipped) output back to the unrotated output of size w x h
+float2 unorient_output(float2 pos, int orientation, float w, float h) {
+    float ow = (orientation & 1)? h : w;
+    float oh = (orientation & 1)? w : h;
+    if (orientation & 4) pos.x = ow - 1.0f - pos.x;
+    if (orientation & 8) pos.y = oh - 1.0f - pos.y;
+    switch (orientation & 3) {
+        case 1: return (float2)(pos.y, h - 1.0f - pos.x);
+        case 2: return (float2)(w - 1.0f - pos.x, h - 1.0f - pos.y);
+        case 3: return (float2)(w - 1.0f - pos.y, pos.x);
+    }
+    return pos;
+}
+
+// Straight alpha is interpolated premultiplied, so transparent fill doesn't darken the edges. Empty for formats without alpha
+DATA_TYPEF premultiply_alpha(DATA_TYPEF px, __global KernelParams *params) { PREMULTIPLY_ALPHA; return px; }
+DATA_TYPEF unpremultiply_alpha(DATA_TYPEF px, __global KernelParams *params) { if (params->flags & 8192) return px; UNPREMULTIPLY_ALPHA; return px; }
+// Premultiplied input textures are already in the space we interpolate in, only the background needs converting
+DATA_TYPEF premultiply_source(DATA_TYPEF px, __global KernelParams *params) { if (params->flags & 8192) return px; return premultiply_alpha(px, params); }
+
+// Source tile in local memory, TILE_W and TILE_H are defined by the host when the device has enough local memory
+#ifdef TILE_W
+#define TILE_PARAMS , __local const DATA_TYPE *tile, int4 tile_rect
+#define TILE_ARGS , tile, tile_rect
+#else
+#define TILE_PARAMS
+#define TILE_ARGS
+#endif
+
+DATA_TYPE read_source_pixel(__global const uchar *srcptr, int px, int py, __global KernelParams *params TILE_PARAMS) {
+#ifdef TILE_W
+    if (px >= tile_rect.x && py >= tile_rect.y && px < tile_rect.x + tile_rect.z && py < tile_rect.y + tile_rect.w) {
+        return tile[(py - tile_rect.y) * TILE_W + (px - tile_rect.x)];
+    }
+#endif
+    return LOAD_PIXEL(&srcptr[py * params->stride + px * PIXEL_BYTES]);
+}
+
+// HDR transfer functions, normalized signal <-> linear light. Scene light for HLG, without the OOTF, so the round trip is exact
+float pq_to_linear(float e) {
+    float p = pow(clamp(e, 0.0f, 1.0f), 1.0f / 78.84375f);
+    return pow(max(p - 0.8359375f, 0.0f) / (18.8515625f - 18.6875f * p), 1.0f / 0.1593017578125f);
+}
+float linear_to_pq(float y) {
+    float p = pow(clamp(y, 0.0f, 1.0f), 0.1593017578125f);
+    return pow((0.8359375f + 18.8515625f * p) / (1.0f + 18.6875f * p), 78.84375f);
+}
+float hlg_to_linear(float e) {
+    e = clamp(e, 0.0f, 1.0f);
+    return e <= 0.5f? (e * e / 3.0f) : ((exp((e - 0.55991073f) / 0.17883277f) + 0.28466892f) / 12.0f);
+}
+float linear_to_hlg(float l) {
+    l = clamp(l, 0.0f, 1.0f);
+    return l <= 1.0f / 12.0f? sqrt(3.0f * l) : (0.17883277f * log(12.0f * l - 0.28466892f) + 0.55991073f);
+}
+// Applied to the color channels only, alpha is left as is
+DATA_TYPEF transfer_to_linear(DATA_TYPEF v, __global KernelParams *params) {
+    if (params->transfer_function == 0) return v;
+    float4 px = 0.0f;
+    *(DATA_TYPEF *)&px = v;
+    float scale = params->pixel_value_limit == 1.0f? 1.0f : params->max_pixel_value;
+    if (params->transfer_function == 1) { px.xyz = (float3)(pq_to_linear(px.x / scale), pq_to_linear(px.y / scale), pq_to_linear(px.z / scale)) * scale; }
+    else                                { px.xyz = (float3)(hlg_to_linear(px.x / scale), hlg_to_linear(px.y / scale), hlg_to_linear(px.z / scale)) * scale; }
+    return *(DATA_TYPEF *)&px;
+}
+DATA_TYPEF transfer_from_linear(DATA_TYPEF v, __global KernelParams *params) {
+    if (params->transfer_function == 0) return v;
+    float4 px = 0.0f;
+    *(DATA_TYPEF *)&px = v;
+    float scale = params->pixel_value_limit == 1.0f? 1.0f : params->max_pixel_value;
+    if (params->transfer_function == 1) { px.xyz = (float3)(linear_to_pq(px.x / scale), linear_to_pq(px.y / scale), linear_to_pq(px.z / scale)) * scale; }
+    else                                { px.xyz = (float3)(linear_to_hlg(px.x / scale), linear_to_hlg(px.y / scale), linear_to_hlg(px.z / scale)) * scale; }
+    return *(DATA_TYPEF *)&px;
+}
 
+// Position in the processing size to position in the source buffer
+float2 input_to_buffer(float2 uv, __global KernelParams *params) {
     if (params->input_rotation != 0.0) {
         uv = rotate_point(uv, params->input_rotation * (M_PI_F / 180.0), (float2)((float)params->width / 2.0, (float)params->height / 2.0));
     }
 
     uv.x = map_coord(uv.x, 0.0f, (float)params->width,  (float)params->source_rect.x, (float)(params->source_rect.x + params->source_rect.z));
     uv.y = map_coord(uv.y, 0.0f, (float)params->height, (float)params->source_rect.y, (float)(params->source_rect.y + params->source_rect.w));
+    return uv;
+}
+
+DATA_TYPEF sample_input_at(float2 uv, __global const uchar *srcptr, __global KernelParams *params, __global const uchar *drawing, DATA_TYPEF bg TILE_PARAMS) {
+    bool fix_range = params->flags & 1;
+
+    uv = input_to_buffer(uv, params);
 
     uv -= S_OFFSET;
 
@@ -187,22 +333,31 @@ DATA_TYPEF sample_input_at(float2 uv, __global const uchar *srcptr, __global Ker
     __constant float *coeffs_y = &coeffs[(sy0 & (INTER_TAB_SIZE - 1)) << shift];
 
     DATA_TYPEF sum = 0;
-    int src_index = sy * params->stride + sx * PIXEL_BYTES;
+    bool has_crop = params->input_crop.z > 0 && params->input_crop.w > 0;
+    bg = premultiply_alpha(transfer_to_linear(bg, params), params);
 
     #pragma unroll
     for (int yp = 0; yp < INTERPOLATION; ++yp) {
-        if (sy + yp >= params->source_rect.y && sy + yp < params->source_rect.y + params->source_rect.w) {
+        int py = sy + yp;
+        if (has_crop) { // Input crop, clamp to the edge pixel
+            py = clamp(py, params->input_crop.y, params->input_crop.y + params->input_crop.w - 1);
+        }
+        if (py >= params->source_rect.y && py < params->source_rect.y + params->source_rect.w) {
             DATA_TYPEF xsum = 0.0f;
             #pragma unroll
             for (int xp = 0; xp < INTERPOLATION; ++xp) {
-                if (sx + xp >= params->source_rect.x && sx + xp < params->source_rect.x + params->source_rect.z) {
-                    DATA_TYPE src_px = *(__global const DATA_TYPE *)&srcptr[src_index + PIXEL_BYTES * xp];
-                    draw_pixel(&src_px, sx + xp, sy + yp, true, max(params->width, params->output_width), params, drawing);
+                int px = sx + xp;
+                if (has_crop) {
+                    px = clamp(px, params->input_crop.x, params->input_crop.x + params->input_crop.z - 1);
+                }
+                if (px >= params->source_rect.x && px < params->source_rect.x + params->source_rect.z) {
+                    DATA_TYPE src_px = read_source_pixel(srcptr, px, py, params TILE_ARGS);
+                    draw_pixel(&src_px, px, py, true, max(params->width, params->output_width), params, drawing);
                     DATA_TYPEF srcpx = DATA_CONVERTF(src_px);
                     if (fix_range) {
                         srcpx = remap_colorrange(srcpx, PIXEL_BYTES == 1);
                     }
-                    xsum += srcpx * coeffs_x[xp];
+                    xsum += premultiply_source(transfer_to_linear(srcpx, params), params) * coeffs_x[xp];
                 } else {
                     xsum += bg * coeffs_x[xp];
                 }
@@ -211,23 +366,150 @@ DATA_TYPEF sample_input_at(float2 uv, __global const uchar *srcptr, __global Ker
         } else {
             sum += bg * coeffs_y[yp];
         }
-        src_index += params->stride;
     }
-    return min(sum, (DATA_TYPEF)(params->pixel_value_limit));
+    return min(transfer_from_linear(unpremultiply_alpha(sum, params), params), (DATA_TYPEF)(params->pixel_value_limit));
+}
+
+// One bilinear tap, same as in `sample_input_at`
+DATA_TYPEF source_tap(int px, int py, __global const uchar *srcptr, __global KernelParams *params, __global const uchar *drawing, DATA_TYPEF bg TILE_PARAMS) {
+    if (params->input_crop.z > 0 && params->input_crop.w > 0) { // Input crop, clamp to the edge pixel
+        px = clamp(px, params->input_crop.x, params->input_crop.x + params->input_crop.z - 1);
+        py = clamp(py, params->input_crop.y, params->input_crop.y + params->input_crop.w - 1);
+    }
+    if (px < params->source_rect.x || py < params->source_rect.y || px >= params->source_rect.x + params->source_rect.z || py >= params->source_rect.y + params->source_rect.w) {
+        return bg;
+    }
+    DATA_TYPE src_px = read_source_pixel(srcptr, px, py, params TILE_ARGS);
+    draw_pixel(&src_px, px, py, true, max(params->width, params->output_width), params, drawing);
+    DATA_TYPEF srcpx = DATA_CONVERTF(src_px);
+    if (params->flags & 1) {
+        srcpx = remap_colorrange(srcpx, PIXEL_BYTES == 1);
+    }
+    return premultiply_source(transfer_to_linear(srcpx, params), params);
+}
+// Cheaper sampling for the low quality regions of the quality map, regardless of the compiled interpolation
+DATA_TYPEF sample_input_bilinear(float2 uv, __global const uchar *srcptr, __global KernelParams *params, __global const uchar *drawing, DATA_TYPEF bg TILE_PARAMS) {
+    uv = input_to_buffer(uv, params);
+    float2 fl = floor(uv);
+    float2 w = uv - fl;
+    int sx = (int)fl.x;
+    int sy = (int)fl.y;
+    bg = premultiply_alpha(transfer_to_linear(bg, params), params);
+
+    DATA_TYPEF top    = mix(source_tap(sx, sy,     srcptr, params, drawing, bg TILE_ARGS), source_tap(sx + 1, sy,     srcptr, params, drawing, bg TILE_ARGS), w.x);
+    DATA_TYPEF bottom = mix(source_tap(sx, sy + 1, srcptr, params, drawing, bg TILE_ARGS), source_tap(sx + 1, sy + 1, srcptr, params, drawing, bg TILE_ARGS), w.x);
+    return min(transfer_from_linear(unpremultiply_alpha(mix(top, bottom, w.y), params), params), (DATA_TYPEF)(params->pixel_value_limit));
+}
+// Quality map: int tile size (0 = uniform quality), tiles_x, tiles_y, padding, then one level per tile, row-major over the output.
+// 255 is the compiled interpolation and 0 bilinear. The level is interpolated between tile centers, so there are no seams between regions
+float quality_at(float x, float y, __global const uchar *quality_map) {
+    __global const int *header = (__global const int *)quality_map;
+    int tile = header[0], tiles_x = header[1], tiles_y = header[2];
+    if (tile <= 0 || tiles_x <= 0 || tiles_y <= 0) return 1.0f;
+    __global const uchar *levels = quality_map + 16;
+
+    float2 t = (float2)(x, y) / (float)tile - 0.5f;
+    float2 fl = floor(t);
+    float2 w = t - fl;
+    int x0 = clamp((int)fl.x, 0, tiles_x - 1), x1 = clamp((int)fl.x + 1, 0, tiles_x - 1);
+    int y0 = clamp((int)fl.y, 0, tiles_y - 1), y1 = clamp((int)fl.y + 1, 0, tiles_y - 1);
+    float top    = mix((float)levels[y0 * tiles_x + x0], (float)levels[y0 * tiles_x + x1], w.x);
+    float bottom = mix((float)levels[y1 * tiles_x + x0], (float)levels[y1 * tiles_x + x1], w.x);
+    return mix(top, bottom, w.y) / 255.0f;
+}
+DATA_TYPEF sample_input_quality(float2 uv, float x, float y, __global const uchar *quality_map, __global const uchar *srcptr, __global KernelParams *params, __global const uchar *drawing, DATA_TYPEF bg TILE_PARAMS) {
+    float q = INTERPOLATION == 2? 1.0f : quality_at(x, y, quality_map);
+    if (q >= 1.0f) return sample_input_at(uv, srcptr, params, drawing, bg TILE_ARGS);
+    DATA_TYPEF low = sample_input_bilinear(uv, srcptr, params, drawing, bg TILE_ARGS);
+    if (q <= 0.0f) return low;
+    return mix(low, sample_input_at(uv, srcptr, params, drawing, bg TILE_ARGS), q);
+}
+
+// Weight of the second lens model, smooth across the transition band
+float lens_blend_weight(float2 pos, __global KernelParams *params) {
+    float2 dir = params->blend_region.zw;
+    float v = (dir.x != 0.0f || dir.y != 0.0f) ? dot(pos, normalize(dir)) : length(pos);
+    if (params->blend_region.y <= params->blend_region.x) { return v >= params->blend_region.x ? 1.0f : 0.0f; }
+    return smoothstep(params->blend_region.x, params->blend_region.y, v);
+}
+// Keystone correction, maps the corrected output position to the uncorrected one
+float2 apply_perspective(float2 p, __global KernelParams *params) {
+    __global const float *h = params->perspective;
+    float w = h[6] * p.x + h[7] * p.y + 1.0f;
+    return (float2)(h[0] * p.x + h[1] * p.y + h[2], h[3] * p.x + h[4] * p.y + h[5]) / w;
+}
+// Manual radial/tangential correction on top of the lens profile, in normalized camera coordinates
+float2 apply_user_distortion(float2 p, __global KernelParams *params) {
+    float4 d = params->user_distortion;
+    float r2 = dot(p, p);
+    float radial = 1.0f + d.x * r2 + d.y * r2 * r2;
+    return (float2)(p.x * radial + 2.0f * d.z * p.x * p.y + d.w * (r2 + 2.0f * p.x * p.x),
+                    p.y * radial + d.z * (r2 + 2.0f * p.y * p.y) + 2.0f * d.w * p.x * p.y);
+}
+float2 distort_point_blended(float x, float y, float z, __global KernelParams *params) {
+    if (params->flags & 512) { return (float2)(x, y) / z; } // Lens bypassed
+    float2 pt = distort_point(x, y, z, params);
+    if (params->flags & 32) { // Has blended lens
+        float t = lens_blend_weight((float2)(x, y) / z, params);
+        if (t > 0.0f) { pt = mix(pt, distort_point_b(x, y, z, params), t); }
+    }
+    return pt;
+}
+float2 undistort_point_blended(float2 pos, __global KernelParams *params) {
+    if (params->flags & 512) { return pos; } // Lens bypassed
+    float2 pt = undistort_point(pos, params);
+    if (params->flags & 32) { // Has blended lens
+        float t = lens_blend_weight(pt, params);
+        if (t > 0.0f) { pt = mix(pt, undistort_point_b(pos, params), t); }
+    }
+    return pt;
+}
+
+// Dense remap from a calibration: map width, map height, two unused floats, then the input position of each node, row-major.
+// Node (i, j) is at (i * width / (map_w - 1), j * height / (map_h - 1)) in the rectified image, see `FrameTransform::dense_map_from_model`
+float2 dense_map_lookup(float2 pos, __global KernelParams *params, __global const float *dense_map) {
+    int mw = (int)dense_map[0];
+    int mh = (int)dense_map[1];
+    if (mw < 2 || mh < 2) return (float2)(-99999.0f, -99999.0f);
+    float2 t = pos * (float2)((float)(mw - 1) / params->width, (float)(mh - 1) / params->height);
+    if (t.x < 0.0f || t.y < 0.0f || t.x > (float)(mw - 1) || t.y > (float)(mh - 1)) return (float2)(-99999.0f, -99999.0f);
+    int x0 = min((int)t.x, mw - 2);
+    int y0 = min((int)t.y, mh - 2);
+    float2 w = t - (float2)((float)x0, (float)y0);
+    __global const float2 *nodes = (__global const float2 *)(dense_map + 4);
+    float2 top    = mix(nodes[y0 * mw + x0],       nodes[y0 * mw + x0 + 1],       w.x);
+    float2 bottom = mix(nodes[(y0 + 1) * mw + x0], nodes[(y0 + 1) * mw + x0 + 1], w.x);
+    return mix(top, bottom, w.y);
 }
 
-float2 rotate_and_distort(float2 pos, uint idx, __global KernelParams *params, __global const float *matrices) {
+float2 rotate_and_distort(float2 pos, uint idx, __global KernelParams *params, __global const float *matrices, __global const float *dense_map) {
     __global const float *matrix = &matrices[idx];
-    float _x = (pos.x * matrix[0]) + (pos.y * matrix[1]) + matrix[2] + params->translation3d.x;
-    float _y = (pos.x * matrix[3]) + (pos.y * matrix[4]) + matrix[5] + params->translation3d.y;
-    float _w = (pos.x * matrix[6]) + (pos.y * matrix[7]) + matrix[8] + params->translation3d.z;
+    float3 dir = (float3)(pos.x, pos.y, 1.0f);
+    bool is_equirect = params->projection_type == 1;
+    if (is_equirect) { // Equirectangular, matrix is only the rotation of the direction on the sphere
+        float lon = (pos.x / params->output_width  - 0.5f) * 2.0f * M_PI_F;
+        float lat = (pos.y / params->output_height - 0.5f) * M_PI_F;
+        dir = (float3)(cos(lat) * sin(lon), sin(lat), cos(lat) * cos(lon));
+    }
+    float _x = (dir.x * matrix[0]) + (dir.y * matrix[1]) + dir.z * matrix[2] + params->translation3d.x;
+    float _y = (dir.x * matrix[3]) + (dir.y * matrix[4]) + dir.z * matrix[5] + params->translation3d.y;
+    float _w = (params->flags & 16384) && !is_equirect ? dir.z + params->translation3d.z // Affine, bottom row is [0, 0, 1]
+             : (dir.x * matrix[6]) + (dir.y * matrix[7]) + dir.z * matrix[8] + params->translation3d.z;
+    if (is_equirect) {
+        return params->f * distort_point(_x, _y, _w, params) + params->c;
+    }
     if (_w > 0) {
         if (params->r_limit > 0.0f && length((float2)(_x, _y) / _w) > params->r_limit) {
             return (float2)(-99999.0f, -99999.0f);
         }
-        float2 uv = params->f * distort_point(_x, _y, _w, params) + params->c;
+        if (params->flags & 32768) { // Dense remap, replaces the whole lens model
+            return dense_map_lookup(params->f * (float2)(_x, _y) / _w + params->c, params, dense_map);
+        }
+        float2 pt = distort_point_blended(_x, _y, _w, params);
+        if (params->flags & 4096) { pt = apply_user_distortion(pt, params); }
+        float2 uv = params->f * pt + params->c;
 
-        if (params->flags & 2) { // Has digital lens
+        if ((params->flags & 2) && !(params->flags & 1024)) { // Has digital lens, not bypassed
             uv = digital_distort_point(uv, params);
         }
 
@@ -239,47 +521,118 @@ float2 rotate_and_distort(float2 pos, uint idx, __global KernelParams *params, _
     return (float2)(-99999.0f, -99999.0f);
 }
 
+// Ghosting trail effect, mix in the unstabilized input at the same output position
+DATA_TYPE blend_with_original(DATA_TYPE pix, float x, float y, __global const uchar *srcptr, __global KernelParams *params, __global const uchar *drawing, DATA_TYPEF bg TILE_PARAMS) {
+    float2 org_uv = (float2)(x, y) * (float2)((float)params->width / params->output_width, (float)params->height / params->output_height);
+    DATA_TYPEF org = sample_input_at(org_uv, srcptr, params, drawing, bg TILE_ARGS);
+    return DATA_CONVERT(mix(DATA_CONVERTF(pix), org, params->blend_with_original));
+}
+
+// Per-frame exposure and white balance normalization, computed by the host
+DATA_TYPE color_correct(DATA_TYPE pix, __global KernelParams *params) {
+    float4 px = 0.0f;
+    *(DATA_TYPEF *)&px = DATA_CONVERTF(pix);
+    px.xyz = clamp(px.xyz * params->color_gain.xyz + params->color_offset, 0.0f, params->pixel_value_limit);
+    return DATA_CONVERT(*(DATA_TYPEF *)&px);
+}
+
 // Adapted from OpenCV: initUndistortRectifyMap + remap
 // https://github.com/opencv/opencv/blob/2b60166e5c65f1caccac11964ad760d847c536e4/modules/calib3d/src/fisheye.cpp#L465-L567
 // https://github.com/opencv/opencv/blob/2b60166e5c65f1caccac11964ad760d847c536e4/modules/imgproc/src/opencl/remap.cl#L390-L498
-__kernel void undistort_image(__global const uchar *srcptr, __global uchar *dstptr, __global const void *params_buf, __global const float *matrices, __global const uchar *drawing) {
+__kernel void undistort_image(__global const uchar *srcptr, __global uchar *dstptr, __global const void *params_buf, __global const float *matrices, __global const uchar *drawing, __global const uchar *quality_map, __global const float *dense_map) {
     int buf_x = get_global_id(0);
     int buf_y = get_global_id(1);
 
     __global KernelParams *params = (__global KernelParams *)params_buf;
 
-    float x = map_coord((float)buf_x, (float)params->output_rect.x, (float)(params->output_rect.x + params->output_rect.z), 0.0f, (float)params->output_width );
-    float y = map_coord((float)buf_y, (float)params->output_rect.y, (float)(params->output_rect.y + params->output_rect.w), 0.0f, (float)params->output_height);
-
+    // For 90 and 270 degrees the output buffer has width and height swapped
+    int orientation = params->output_orientation;
+    float out_w = (float)((orientation & 1)? params->output_height : params->output_width);
+    float out_h = (float)((orientation & 1)? params->output_width  : params->output_height);
+
+    // With a global work offset, buf_x/buf_y address the larger buffer, while the output rect is relative to the sub-region
+    float x = map_coord((float)(buf_x - params->output_x_offset), (float)params->output_rect.x, (float)(params->output_rect.x + params->output_rect.z), 0.0f, out_w);
+    float y = map_coord((float)(buf_y - params->output_y_offset), (float)params->output_rect.y, (float)(params->output_rect.y + params->output_rect.w), 0.0f, out_h);
+    if (orientation != 0) {
+        float2 p = unorient_output((float2)(x, y), orientation, (float)params->output_width, (float)params->output_height);
+        x = p.x;
+        y = p.y;
+    }
 
     DATA_TYPEF bg = (*(__global DATA_TYPEF *)&params->background) * params->max_pixel_value;
 
     if (matrices == 0 || params->width < 1) return;
 
+#ifdef TILE_W
+    // Every work item estimates its source position, the work group loads the bounding box of them into local memory.
+    // Samples outside of the tile are still read from global memory, so the estimate only affects performance.
+    // All work items have to reach the barriers, so there can't be any return before the tile is loaded
+    __local DATA_TYPE tile[TILE_W * TILE_H];
+    __local int tile_bounds[4];
+    int lid   = get_local_id(1) * get_local_size(0) + get_local_id(0);
+    int lsize = get_local_size(0) * get_local_size(1);
+    if (lid == 0) {
+        tile_bounds[0] = INT_MAX; tile_bounds[1] = INT_MAX;
+        tile_bounds[2] = INT_MIN; tile_bounds[3] = INT_MIN;
+    }
+    barrier(CLK_LOCAL_MEM_FENCE);
+    if (x >= 0.0f && y >= 0.0f && x < (float)params->output_width && y < (float)params->output_height && (params->flags & 4) == 0) {
+        float2 est_pos = (float2)(x, y);
+        if (params->flags & 2048) { est_pos = apply_perspective(est_pos, params); }
+        float2 est = rotate_and_distort(est_pos + params->translation2d, (params->matrix_count / 2) * 12, params, matrices, dense_map);
+        if (est.x > -99998.0f) {
+            int2 p = convert_int2_rtn(input_to_buffer(est, params));
+            atomic_min(&tile_bounds[0], p.x); atomic_min(&tile_bounds[1], p.y);
+            atomic_max(&tile_bounds[2], p.x); atomic_max(&tile_bounds[3], p.y);
+        }
+    }
+    barrier(CLK_LOCAL_MEM_FENCE);
+
+    // Interpolation taps start at -S_OFFSET, one more pixel on each side for rounding
+    int4 tile_rect = (int4)(tile_bounds[0] - (int)S_OFFSET - 1, tile_bounds[1] - (int)S_OFFSET - 1, 0, 0);
+    tile_rect.z = tile_bounds[2] - tile_bounds[0] + INTERPOLATION + 2;
+    tile_rect.w = tile_bounds[3] - tile_bounds[1] + INTERPOLATION + 2;
+    if (tile_bounds[0] > tile_bounds[2] || tile_rect.z > TILE_W || tile_rect.w > TILE_H) {
+        tile_rect = (int4)(0, 0, 0, 0); // Footprint doesn't fit, use global memory
+    }
+    for (int i = lid; i < tile_rect.z * tile_rect.w; i += lsize) {
+        int px = tile_rect.x + i % tile_rect.z;
+        int py = tile_rect.y + i / tile_rect.z;
+        if (px >= params->source_rect.x && py >= params->source_rect.y && px < params->source_rect.x + params->source_rect.z && py < params->source_rect.y + params->source_rect.w) {
+            tile[(py - tile_rect.y) * TILE_W + (px - tile_rect.x)] = LOAD_PIXEL(&srcptr[py * params->stride + px * PIXEL_BYTES]);
+        }
+    }
+    barrier(CLK_LOCAL_MEM_FENCE);
+#endif
+
     if (x >= 0.0f && y >= 0.0f && x < (float)params->output_width && y < (float)params->output_height) {
-        __global DATA_TYPE *out_pix = (__global DATA_TYPE *)&dstptr[buf_x * PIXEL_BYTES + buf_y * params->output_stride];
+        __global uchar *out_pix = &dstptr[buf_x * PIXEL_BYTES + buf_y * params->output_stride];
 
         if (params->flags & 4) { // Fill with background
-            *out_pix = DATA_CONVERT(bg);
+            STORE_PIXEL(DATA_CONVERT(bg), out_pix);
             return;
         }
 
-        float2 out_pos = (float2)(x, y) + params->translation2d;
+        float2 out_pos = (float2)(x, y);
+        if (params->flags & 2048) { // Perspective correction
+            out_pos = apply_perspective(out_pos, params);
+        }
+        out_pos += params->translation2d;
 
         ///////////////////////////////////////////////////////////////////
         // Add lens distortion back
-        if (params->lens_correction_amount < 1.0f) {
+        if (params->lens_correction_amount < 1.0f && params->projection_type == 0) {
             float2 factor = (float2)max(1.0f - params->lens_correction_amount, 0.001f); // FIXME: this is close but wrong
             float2 out_c = (float2)(params->output_width / 2.0f, params->output_height / 2.0f);
             float2 out_f = (params->f / params->fov) / factor;
 
             float2 new_out_pos = out_pos;
 
-            if (params->flags & 2) { // Has digital lens
+            if ((params->flags & 2) && !(params->flags & 1024)) { // Has digital lens, not bypassed
                 new_out_pos = digital_undistort_point(new_out_pos, params);
             }
             new_out_pos = (new_out_pos - out_c) / out_f;
-            new_out_pos = undistort_point(new_out_pos, params);
+            new_out_pos = undistort_point_blended(new_out_pos, params);
             new_out_pos = out_f * new_out_pos + out_c;
 
             out_pos = new_out_pos * (1.0f - params->lens_correction_amount) + (out_pos * params->lens_correction_amount);
@@ -296,7 +649,7 @@ __kernel void undistort_image(__global const uchar *srcptr, __global uchar *dstp
         }
         if (params->matrix_count > 1) {
             int idx = (params->matrix_count / 2) * 12; // Use middle matrix
-            float2 uv = rotate_and_distort(out_pos, idx, params, matrices);
+            float2 uv = rotate_and_distort(out_pos, idx, params, matrices, dense_map);
             if (uv.x > -99998.0f) {
                 if ((params->flags & 16) == 16) { // Horizontal RS
                     sy = min((int)params->width, max(0, (int)round(uv.x)));
@@ -310,7 +663,7 @@ __kernel void undistort_image(__global const uchar *srcptr, __global uchar *dstp
         DATA_TYPE final_pix;
 
         int idx = min(sy, params->matrix_count - 1) * 12;
-        float2 uv = rotate_and_distort(out_pos, idx, params, matrices);
+        float2 uv = rotate_and_distort(out_pos, idx, params, matrices, dense_map);
         if (uv.x > -99998.0f) {
             switch (params->background_mode) {
                 case 1: { // edge repeat
@@ -340,23 +693,106 @@ __kernel void undistort_image(__global const uchar *srcptr, __global uchar *dstp
                         pt2 *= (float2)(widthf, heightf);
                     }
 
-                    DATA_TYPEF c1 = sample_input_at(uv,  srcptr, params, drawing, bg);
-                    DATA_TYPEF c2 = sample_input_at(pt2, srcptr, params, drawing, bg);
+                    DATA_TYPEF c1 = sample_input_at(uv,  srcptr, params, drawing, bg TILE_ARGS);
+                    DATA_TYPEF c2 = sample_input_at(pt2, srcptr, params, drawing, bg TILE_ARGS);
                     final_pix = DATA_CONVERT(c1 * alpha + c2 * (1.0f - alpha));
+                    if (params->blend_with_original > 0.0f) {
+                        final_pix = blend_with_original(final_pix, x, y, srcptr, params, drawing, bg TILE_ARGS);
+                    }
+                    if (params->flags & 256) {
+                        final_pix = color_correct(final_pix, params);
+                    }
                     draw_pixel(&final_pix, x, y, false, max(params->width, params->output_width), params, drawing);
                     draw_safe_area(&final_pix, x, y, params);
-                    *out_pix = final_pix;
+                    STORE_PIXEL(final_pix, out_pix);
                     return;
                 } break;
             }
 
-            final_pix = DATA_CONVERT(sample_input_at(uv, srcptr, params, drawing, bg));
+            final_pix = DATA_CONVERT(sample_input_quality(uv, x, y, quality_map, srcptr, params, drawing, bg TILE_ARGS));
         } else {
             final_pix = DATA_CONVERT(bg);
         }
+        if (params->blend_with_original > 0.0f) {
+            final_pix = blend_with_original(final_pix, x, y, srcptr, params, drawing, bg TILE_ARGS);
+        }
+        if (params->flags & 256) {
+            final_pix = color_correct(final_pix, params);
+        }
         draw_pixel(&final_pix, x, y, false, max(params->width, params->output_width), params, drawing);
         draw_safe_area(&final_pix, x, y, params);
 
-        *out_pix = final_pix;
+        STORE_PIXEL(final_pix, out_pix);
     }
 }
+
+// Luma (and optionally per-channel) histogram of the rendered output, used for scopes.
+// Counted in `local_bins` of the work group and merged into `histogram` once per group, instead of a global atomic for every pixel
+__kernel void compute_histogram(__global const uchar *dstptr, __global const void *params_buf, __global uint *histogram, int bins, int rgb, int width, int height, __local uint *local_bins) {
+    int buf_x = get_global_id(0);
+    int buf_y = get_global_id(1);
+    int lid   = get_local_id(1) * get_local_size(0) + get_local_id(0);
+    int lsize = get_local_size(0) * get_local_size(1);
+    int total = bins * (rgb? 4 : 1);
+
+    __global KernelParams *params = (__global KernelParams *)params_buf;
+
+    for (int i = lid; i < total; i += lsize) { local_bins[i] = 0; }
+    barrier(CLK_LOCAL_MEM_FENCE);
+
+    // The work size is rounded up to whole work groups, work items outside of the output only take part in the barriers
+    if (buf_x - (int)get_global_offset(0) < width && buf_y - (int)get_global_offset(1) < height) {
+        DATA_TYPE pix = LOAD_PIXEL(&dstptr[buf_x * PIXEL_BYTES + buf_y * params->output_stride]);
+        float4 px = 0.0f;
+        *(DATA_TYPEF *)&px = DATA_CONVERTF(pix);
+        px = clamp(px / params->max_pixel_value, 0.0f, 1.0f);
+
+        float luma = px.x;
+        if (params->pix_element_count >= 3) {
+            luma = 0.2126f * px.x + 0.7152f * px.y + 0.0722f * px.z; // Rec. 709
+        }
+        atomic_inc(&local_bins[min((int)(luma * bins), bins - 1)]);
+
+        if (rgb && params->pix_element_count >= 3) {
+            atomic_inc(&local_bins[bins * 1 + min((int)(px.x * bins), bins - 1)]);
+            atomic_inc(&local_bins[bins * 2 + min((int)(px.y * bins), bins - 1)]);
+            atomic_inc(&local_bins[bins * 3 + min((int)(px.z * bins), bins - 1)]);
+        }
+    }
+    barrier(CLK_LOCAL_MEM_FENCE);
+
+    for (int i = lid; i < total; i += lsize) {
+        if (local_bins[i] > 0) { atomic_add(&histogram[i], local_bins[i]); }
+    }
+}
+
+// Area-averaged copy of the rendered output at another resolution. Pixels which don't fit in `out_stride` are skipped
+__kernel void downscale_output(__global const uchar *dstptr, __global const void *params_buf, __global uchar *outptr, int src_width, int src_height, int out_width, int out_height, int out_stride) {
+    int x = get_global_id(0);
+    int y = get_global_id(1);
+    if (x >= out_width || y >= out_height || (x + 1) * PIXEL_BYTES > out_stride) return;
+
+    __global KernelParams *params = (__global KernelParams *)params_buf;
+
+    float sx = (float)src_width / out_width;
+    float sy = (float)src_height / out_height;
+    int x0 = min((int)(x * sx), src_width - 1), x1 = max(x0 + 1, min((int)((x + 1) * sx), src_width));
+    int y0 = min((int)(y * sy), src_height - 1), y1 = max(y0 + 1, min((int)((y + 1) * sy), src_height));
+
+    float4 sum = 0.0f;
+    for (int py = y0; py < y1; ++py) {
+        for (int px = x0; px < x1; ++px) {
+            float4 v = 0.0f;
+            *(DATA_TYPEF *)&v = DATA_CONVERTF(LOAD_PIXEL(&dstptr[px * PIXEL_BYTES + py * params->output_stride]));
+            sum += v;
+        }
+    }
+    sum /= (float)((x1 - x0) * (y1 - y0));
+    STORE_PIXEL(DATA_CONVERT(*(DATA_TYPEF *)&sum), &outptr[x * PIXEL_BYTES + y * out_stride]);
+}
+
+// Reconstructs per-row matrices from the base matrix and half-precision deltas uploaded by the host
+__kernel void decode_matrices(__global const float *base, __global const half *deltas, __global float *matrices) {
+    int i = get_global_id(0);
+    matrices[i] = base[i % 12] + vload_half(i, deltas);
+}
@@ -4,6 +4,43 @@
 use glam::{ vec2, Vec2, Vec4 };
 use super::types::*;
 
+// HDR transfer functions, normalized signal <-> linear light. Scene light for HLG, without the OOTF, so the round trip is exact
+fn pq_to_linear(e: f32) -> f32 {
+    let p = e.max(0.0).min(1.0).powf(1.0 / 78.84375);
+    ((p - 0.8359375).max(0.0) / (18.8515625 - 18.6875 * p)).powf(1.0 / 0.1593017578125)
+}
+fn linear_to_pq(y: f32) -> f32 {
+    let p = y.max(0.0).min(1.0).powf(0.1593017578125);
+    ((0.8359375 + 18.8515625 * p) / (1.0 + 18.6875 * p)).powf(78.84375)
+}
+fn hlg_to_linear(e: f32) -> f32 {
+    let e = e.max(0.0).min(1.0);
+    if e <= 0.5 { e * e / 3.0 } else { (((e - 0.55991073) / 0.17883277).exp() + 0.28466892) / 12.0 }
+}
+fn linear_to_hlg(l: f32) -> f32 {
+    let l = l.max(0.0).min(1.0);
+    if l <= 1.0 / 12.0 { (3.0 * l).sqrt() } else { 0.17883277 * (12.0 * l - 0.28466892).ln() + 0.55991073 }
+}
+// Applied to the color channels only, alpha is left as is
+pub fn transfer_to_linear(px: Vec4, params: &KernelParams) -> Vec4 {
+    if params.transfer_function == 0 { return px; }
+    let scale = if params.pixel_value_limit == 1.0 { 1.0 } else { params.max_pixel_value };
+    if params.transfer_function == 1 {
+        glam::vec4(pq_to_linear(px.x / scale) * scale, pq_to_linear(px.y / scale) * scale, pq_to_linear(px.z / scale) * scale, px.w)
+    } else {
+        glam::vec4(hlg_to_linear(px.x / scale) * scale, hlg_to_linear(px.y / scale) * scale, hlg_to_linear(px.z / scale) * scale, px.w)
+    }
+}
+pub fn transfer_from_linear(px: Vec4, params: &KernelParams) -> Vec4 {
+    if params.transfer_function == 0 { return px; }
+    let scale = if params.pixel_value_limit == 1.0 { 1.0 } else { params.max_pixel_value };
+    if params.transfer_function == 1 {
+        glam::vec4(linear_to_pq(px.x / scale) * scale, linear_to_pq(px.y / scale) * scale, linear_to_pq(px.z / scale) * scale, px.w)
+    } else {
+        glam::vec4(linear_to_hlg(px.x / scale) * scale, linear_to_hlg(px.y / scale) * scale, linear_to_hlg(px.z / scale) * scale, px.w)
+    }
+}
+
 pub fn sample_input_at(uv: Vec2, _coeffs: &[f32], input: &ImageType, params: &KernelParams, _sampler: SamplerType) -> Vec4 {
     let bg = params.background * params.max_pixel_value;
     #[cfg(feature = "for_qtrhi")]
@@ -67,19 +104,21 @@ pub fn sample_input_at(uv: Vec2, _coeffs: &[f32], input: &ImageType, params: &Ke
                     } else {
                         bg
                     };
-                    xsum += pixel * _coeffs[coeffs_x + xp as usize];
+                    xsum += transfer_to_linear(pixel, params) * _coeffs[coeffs_x + xp as usize];
                     xp += 1;
                     if xp >= params.interpolation { break; } // Bug in Dx12 backend, doesn't work without it for some strange reason
                 }
 
                 sum += xsum * _coeffs[coeffs_y + yp as usize];
             } else {
-                sum += bg * Vec4::splat(_coeffs[coeffs_y + yp as usize]);
+                sum += transfer_to_linear(bg, params) * Vec4::splat(_coeffs[coeffs_y + yp as usize]);
             }
             _src_index += params.stride as isize;
             yp += 1;
             if yp >= params.interpolation { break; } // Bug in Dx12 backend, doesn't work without it for some strange reason
         }
+        // Hardware filtering above can't linearize the taps, only this path handles `transfer_function`
+        let sum = transfer_from_linear(sum, params);
         glam::vec4(
             sum.x.min(params.pixel_value_limit),
             sum.y.min(params.pixel_value_limit),
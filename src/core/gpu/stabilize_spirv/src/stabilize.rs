@@ -23,15 +23,31 @@ pub fn rotate_and_distort(pos: Vec2, idx: i32, params: &KernelParams, matrices:
     let point_3d = vec3(
         (pos.x * get_mtrx_param(size_for_rs, matrices, sampler, idx, 0)) + (pos.y * get_mtrx_param(size_for_rs, matrices, sampler, idx, 1)) + get_mtrx_param(size_for_rs, matrices, sampler, idx, 2) + params.translation3d.x,
         (pos.x * get_mtrx_param(size_for_rs, matrices, sampler, idx, 3)) + (pos.y * get_mtrx_param(size_for_rs, matrices, sampler, idx, 4)) + get_mtrx_param(size_for_rs, matrices, sampler, idx, 5) + params.translation3d.y,
-        (pos.x * get_mtrx_param(size_for_rs, matrices, sampler, idx, 6)) + (pos.y * get_mtrx_param(size_for_rs, matrices, sampler, idx, 7)) + get_mtrx_param(size_for_rs, matrices, sampler, idx, 8) + params.translation3d.z
+        if (params.flags & 16384) == 16384 { // Affine, bottom row is [0, 0, 1]
+            1.0 + params.translation3d.z
+        } else {
+            (pos.x * get_mtrx_param(size_for_rs, matrices, sampler, idx, 6)) + (pos.y * get_mtrx_param(size_for_rs, matrices, sampler, idx, 7)) + get_mtrx_param(size_for_rs, matrices, sampler, idx, 8) + params.translation3d.z
+        }
     );
     if point_3d.z > 0.0 {
         if params.r_limit > 0.0 && vec2(point_3d.x / point_3d.z, point_3d.y / point_3d.z).length_squared() > params.r_limit.powi(2) {
             return vec2(-99999.0, -99999.0);
         }
-        let mut uv = params.f * lens_distort(point_3d, params) + params.c;
+        let mut pt = if (params.flags & 512) == 512 { // Lens bypassed
+            vec2(point_3d.x, point_3d.y) / point_3d.z
+        } else {
+            lens_distort(point_3d, params)
+        };
+        if (params.flags & 4096) == 4096 { // Manual correction on top of the lens profile
+            let d = params.user_distortion;
+            let r2 = pt.dot(pt);
+            let radial = 1.0 + d.x * r2 + d.y * r2 * r2;
+            pt = vec2(pt.x * radial + 2.0 * d.z * pt.x * pt.y + d.w * (r2 + 2.0 * pt.x * pt.x),
+                      pt.y * radial + d.z * (r2 + 2.0 * pt.y * pt.y) + 2.0 * d.w * pt.x * pt.y);
+        }
+        let mut uv = params.f * pt + params.c;
 
-        if (params.flags & 2) == 2 { // Has digital lens
+        if (params.flags & (2 | 1024)) == 2 { // Has digital lens, not bypassed
             uv = digital_lens_distort(vec3(uv.x, uv.y, 1.0), params);
         }
 
@@ -58,6 +74,11 @@ pub fn undistort(uv: Vec2, params: &KernelParams, matrices: &MatricesType, coeff
     if out_pos.x < 0.0 || out_pos.y < 0.0 || out_pos.x > params.output_width as f32 || out_pos.y > params.output_height as f32 { return bg; }
 
     let org_out_pos = out_pos;
+    if (params.flags & 2048) == 2048 { // Perspective correction
+        let (h1, h2) = (params.perspective1, params.perspective2);
+        let w = h2.z * out_pos.x + h2.w * out_pos.y + 1.0;
+        out_pos = vec2(h1.x * out_pos.x + h1.y * out_pos.y + h1.z, h1.w * out_pos.x + h2.x * out_pos.y + h2.y) / w;
+    }
     out_pos = out_pos + params.translation2d;
 
     ///////////////////////////////////////////////////////////////////
@@ -68,7 +89,7 @@ pub fn undistort(uv: Vec2, params: &KernelParams, matrices: &MatricesType, coeff
         let out_f = params.f / params.fov / factor;
         let mut new_out_pos = out_pos;
 
-        if (params.flags & 2) == 2 { // Has digial lens
+        if (params.flags & (2 | 1024)) == 2 { // Has digital lens, not bypassed
             let pt = digital_lens_undistort(new_out_pos, params);
             if pt.x > -99998.0 {
                 new_out_pos = pt;
@@ -76,7 +97,9 @@ pub fn undistort(uv: Vec2, params: &KernelParams, matrices: &MatricesType, coeff
         }
 
         new_out_pos = (new_out_pos - out_c) / out_f;
-        new_out_pos = lens_undistort(new_out_pos, params);
+        if (params.flags & 512) == 0 {
+            new_out_pos = lens_undistort(new_out_pos, params);
+        }
         new_out_pos = new_out_pos * out_f + out_c;
 
         out_pos = new_out_pos * (1.0 - params.lens_correction_amount) + (out_pos * params.lens_correction_amount);
@@ -70,6 +70,23 @@ pub struct KernelParams {
     pub distortion_model:         crate::distortion_models::DistortionModel, // 8
     pub digital_lens:             crate::distortion_models::DistortionModel, // 12
     pub pixel_value_limit:        f32, // 16
+    pub input_crop:               IVec4, // 16 - x, y, w, h
+    pub output_x_offset:          i32, // 4
+    pub output_y_offset:          i32, // 8
+    pub projection_type:          i32, // 12 - 0: perspective, 1: equirectangular
+    pub output_orientation:       i32, // 16 - bits 0-1: clockwise quarter turns, bit 2: flip horizontal, bit 3: flip vertical
+    pub blend_k1:                 Vec4, // 16 - distortion coefficients of the second lens model
+    pub blend_k2:                 Vec4, // 16
+    pub blend_k3:                 Vec4, // 16
+    pub blend_region:             Vec4, // 16 - start, end, direction x, y
+    pub blend_with_original:      f32, // 4
+    pub color_offset:             f32, // 8
+    pub transfer_function:        i32, // 12 - 0: none, 1: PQ, 2: HLG
+    pub drawing_offset:           i32, // 16
+    pub color_gain:               Vec4, // 16
+    pub perspective1:             Vec4, // 16 - output homography, h33 = 1
+    pub perspective2:             Vec4, // 16
+    pub user_distortion:          Vec4, // 16 - k1, k2, p1, p2 applied after the lens model
 }
 
 // #[inline] pub fn fast_floor(x: f32) -> i32 { x as i32 }
@@ -45,6 +45,8 @@ pub struct WgpuWrapper  {
     out_size: u64,
     params_size: u64,
     drawing_size: u64,
+    adapter_info: String, // Name and backend of the adapter the device was created on
+    host_memory: super::host_memory::HostAllocation, // `staging_buffer`, counted in the host memory budget
 }
 impl Drop for WgpuWrapper {
     fn drop(&mut self) {
@@ -89,6 +91,12 @@ impl WgpuWrapper {
         ADAPTERS.read().iter().map(|x| { let x = x.get_info(); format!("{} ({:?})", x.name, x.backend) }).collect()
     }
 
+    // Forget the enumerated adapters, so they are listed and selected again on next use
+    pub fn reset_context() {
+        ADAPTERS.write().clear();
+        ADAPTER.store(0, SeqCst);
+    }
+
     pub fn set_device(index: usize) -> Option<()> {
         let mut i = 0;
         for a in ADAPTERS.read().iter() {
@@ -136,7 +144,7 @@ impl WgpuWrapper {
         Some((name, list_name))
     }
 
-    pub fn new(params: &KernelParams, wgpu_format: (wgpu::TextureFormat, &str, f64), distortion_model: DistortionModel, digital_lens: Option<DistortionModel>, buffers: &Buffers, mut drawing_len: usize) -> Result<Self, WgpuError> {
+    pub fn new(params: &KernelParams, wgpu_format: (wgpu::TextureFormat, &str, f64), distortion_model: DistortionModel, digital_lens: Option<DistortionModel>, blended_lens: Option<DistortionModel>, buffers: &Buffers, mut drawing_len: usize) -> Result<Self, WgpuError> {
         let max_matrix_count = 12 * if (params.flags & 16) == 16 { params.width } else { params.height } as usize;
 
         if params.height < 4 || params.output_height < 4 || buffers.input.size.0 < 16 || buffers.input.size.2 < 16 || buffers.output.size.0 < 16 || buffers.output.size.2 < 16 || params.width > 16384 || params.output_width > 16384 {
@@ -239,6 +247,9 @@ impl WgpuWrapper {
             let default_digital_lens = "fn digital_undistort_point(uv: vec2<f32>) -> vec2<f32> { return uv; }
                                             fn digital_distort_point(uv: vec2<f32>) -> vec2<f32> { return uv; }";
             lens_model_functions.push_str(digital_lens.as_ref().map(|x| x.wgsl_functions()).unwrap_or(default_digital_lens));
+            let default_blended_lens = "fn undistort_point_b(pos: vec2<f32>) -> vec2<f32> { return undistort_point(pos); }
+                                            fn distort_point_b(x: f32, y: f32, z: f32) -> vec2<f32> { return distort_point(x, y, z); }";
+            lens_model_functions.push_str(&blended_lens.as_ref().map(|x| x.wgsl_functions_blended()).unwrap_or(default_blended_lens.to_string()));
             kernel = kernel.replace("LENS_MODEL_FUNCTIONS;", &lens_model_functions);
             kernel = kernel.replace("SCALAR", wgpu_format.1);
             kernel = kernel.replace("bg_scaler", &format!("{:.6}", wgpu_format.2));
@@ -257,6 +268,7 @@ impl WgpuWrapper {
             kernel = kernel.replace("bool(params.flags & 1)", &format!("{}", (params.flags & 1) > 0)); // fix_range
             kernel = kernel.replace("bool(params.flags & 2)", &format!("{}", (params.flags & 2) > 0)); // has_digital_lens
             kernel = kernel.replace("bool(params.flags & 8)", &format!("{}", (params.flags & 8) > 0)); // has_drawing
+            kernel = kernel.replace("bool(params.flags & 32)", &format!("{}", (params.flags & 32) > 0)); // has_blended_lens
 
             let backend = adapter.get_info().backend;
             let in_texture = init_texture(&device, backend, &buffers.input, wgpu_format.0, true);
@@ -414,14 +426,23 @@ impl WgpuWrapper {
                 params_size,
                 drawing_size: drawing_len as u64,
                 pixel_format: wgpu_format.0,
-                padded_out_stride: padded_out_stride as u32
+                padded_out_stride: padded_out_stride as u32,
+                adapter_info: { let info = adapter.get_info(); format!("{} ({:?})", info.name, info.backend) },
+                host_memory: super::host_memory::HostAllocation::new(staging_size as usize),
             })
         } else {
             Err(WgpuError::NoAvailableAdapter)
         }
     }
 
+    // Adapter this wrapper renders on, which can differ from `get_info` after the device was changed
+    pub fn device_info(&self) -> &str { &self.adapter_info }
+
+    // The staging buffer went over `gpu::set_host_memory_budget`. Still renders, but should be dropped before the next frame
+    pub fn is_host_memory_evicted(&self) -> bool { self.host_memory.is_evicted() }
+
     pub fn undistort_image(&self, buffers: &mut Buffers, itm: &crate::stabilization::FrameTransform, drawing_buffer: &[u8]) -> bool {
+        self.host_memory.touch();
         let matrices = bytemuck::cast_slice(&itm.matrices);
 
         let in_size = (buffers.input.size.2 * buffers.input.size.1) as u64;
@@ -452,6 +473,8 @@ impl WgpuWrapper {
             },
             PipelineType::Render(p) => {
                 let view = self.out_texture.wgpu_texture.as_ref().unwrap().create_view(&wgpu::TextureViewDescriptor::default());
+                // Keep the rest of the texture when rendering only into a part of it, eg. one cell of an atlas
+                let sub_rect = buffers.output.sub_rect();
                 let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                     label: None,
                     timestamp_writes: None,
@@ -460,12 +483,15 @@ impl WgpuWrapper {
                         view: &view,
                         resolve_target: None,
                         ops: wgpu::Operations {
-                            load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                            load: if sub_rect.is_some() { wgpu::LoadOp::Load } else { wgpu::LoadOp::Clear(wgpu::Color::BLACK) },
                             store: wgpu::StoreOp::Store,
                         },
                     })],
                     depth_stencil_attachment: None,
                 });
+                if let Some((x, y, w, h)) = sub_rect {
+                    rpass.set_scissor_rect(x as u32, y as u32, w as u32, h as u32);
+                }
                 rpass.set_pipeline(p);
                 rpass.set_bind_group(0, self.bind_group.as_ref().unwrap(), &[]);
                 rpass.draw(0..6, 0..1);
@@ -476,6 +502,9 @@ impl WgpuWrapper {
 
         let sub_index = self.queue.submit(Some(encoder.finish()));
 
+        let sub_rect = buffers.output.sub_rect();
+        let bytes_per_pixel = itm.kernel_params.bytes_per_pixel as usize;
+
         match &mut buffers.output.data {
             BufferSource::Cpu { buffer, .. } => {
                 let buffer_slice = self.staging_buffer.slice(..);
@@ -486,7 +515,17 @@ impl WgpuWrapper {
 
                 if let Some(Ok(())) = pollster::block_on(receiver.receive()) {
                     let data = buffer_slice.get_mapped_range();
-                    if self.padded_out_stride == buffers.output.size.2 as u32 {
+                    if let Some((x, y, w, h)) = sub_rect {
+                        // Only copy the rendered region, the rest of the host buffer is kept as is
+                        let (from, to) = (x * bytes_per_pixel, (x + w) * bytes_per_pixel);
+                        data.as_ref()
+                            .chunks(self.padded_out_stride as usize)
+                            .zip(buffer.chunks_mut(buffers.output.size.2))
+                            .skip(y).take(h)
+                            .for_each(|(src, dest)| {
+                                dest[from..to].copy_from_slice(&src[from..to]);
+                            });
+                    } else if self.padded_out_stride == buffers.output.size.2 as u32 {
                         // Fast path
                         (&mut buffer[..buffers.output.size.1 * buffers.output.size.2]).copy_from_slice(data.as_ref());
                     } else {
@@ -526,12 +565,13 @@ pub fn is_buffer_supported(buffers: &Buffers) -> bool {
     match buffers.input.data {
         BufferSource::None           => false,
         BufferSource::Cpu     { .. } => true,
+        #[cfg(feature = "opengl")]
         BufferSource::OpenGL  { .. } => false,
-        #[cfg(target_os = "windows")]
+        #[cfg(all(target_os = "windows", feature = "directx"))]
         BufferSource::DirectX11 { .. } => true,
-        #[cfg(feature = "use-opencl")]
+        #[cfg(feature = "opencl-core")]
         BufferSource::OpenCL  { .. } => false,
-        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        #[cfg(all(not(any(target_os = "macos", target_os = "ios")), feature = "vulkan"))]
         BufferSource::Vulkan  { .. } => true,
         #[cfg(any(target_os = "windows", target_os = "linux"))]
         BufferSource::CUDABuffer{ .. } => true,
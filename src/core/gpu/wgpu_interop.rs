@@ -6,10 +6,11 @@
 
 use crate::gpu::{ BufferDescription, BufferSource };
 
-#[cfg(not(any(target_os = "macos", target_os = "ios")))] use { super::wgpu_interop_vulkan::*, ash::vk };
+#[cfg(all(not(any(target_os = "macos", target_os = "ios")), feature = "vulkan"))] use { super::wgpu_interop_vulkan::*, ash::vk };
 #[cfg(any(target_os = "windows", target_os = "linux"))]  use super::wgpu_interop_cuda::*;
 #[cfg(any(target_os = "macos", target_os = "ios"))]      use super::wgpu_interop_metal::*;
-#[cfg(target_os = "windows")]                            use { super::wgpu_interop_directx::*, windows::{ Win32::Graphics::Direct3D11::*, core::Interface } };
+#[cfg(target_os = "windows")]                            use super::wgpu_interop_directx::*;
+#[cfg(all(target_os = "windows", feature = "directx"))]  use windows::{ Win32::Graphics::Direct3D11::*, core::Interface };
 
 #[cfg(any(target_os = "macos", target_os = "ios"))]
 use metal::foreign_types::ForeignTypeRef;
@@ -19,7 +20,7 @@ use wgpu::{ Origin3d, Extent3d, TextureAspect, ImageCopyTexture, ImageCopyBuffer
 pub enum NativeTexture {
     #[cfg(any(target_os = "windows", target_os = "linux"))]
     Cuda(CudaSharedMemory),
-    #[cfg(target_os = "windows")]
+    #[cfg(all(target_os = "windows", feature = "directx"))]
     D3D11(DirectX11SharedTexture),
     #[cfg(any(target_os = "macos", target_os = "ios"))]
     Metal(metal::Texture)
@@ -164,8 +165,8 @@ pub fn init_texture(device: &wgpu::Device, backend: wgpu::Backend, buf: &BufferD
                 }
             }
         },
-        #[cfg(target_os = "windows")]
-        BufferSource::DirectX11 { texture, device: d3d11_device, device_context } => {
+        #[cfg(all(target_os = "windows", feature = "directx"))]
+        BufferSource::DirectX11 { texture, device: d3d11_device, device_context, .. } => {
             unsafe {
                 let d3d11_device = ID3D11Device::from_raw_borrowed(&d3d11_device).unwrap(); // TODO: unwrap
                 // let device_context = ID3D11DeviceContext::from_raw_borrowed(device_context);
@@ -205,7 +206,7 @@ pub fn init_texture(device: &wgpu::Device, backend: wgpu::Backend, buf: &BufferD
                 }
             }
         },
-        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        #[cfg(all(not(any(target_os = "macos", target_os = "ios")), feature = "vulkan"))]
         BufferSource::Vulkan { texture, device: vk_device, instance, .. } => {
             use ash::vk::Handle;
 
@@ -267,7 +268,7 @@ pub fn handle_input_texture(device: &wgpu::Device, buf: &BufferDescription, queu
                 size,
             );
         },
-        #[cfg(target_os = "windows")]
+        #[cfg(all(target_os = "windows", feature = "directx"))]
         BufferSource::DirectX11 { texture, device_context, .. } => {
             unsafe {
                 if let Some(NativeTexture::D3D11(i)) = &in_texture.native_texture {
@@ -304,7 +305,7 @@ pub fn handle_input_texture(device: &wgpu::Device, buf: &BufferDescription, queu
                 );
             }
         },
-        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        #[cfg(all(not(any(target_os = "macos", target_os = "ios")), feature = "vulkan"))]
         BufferSource::Vulkan { texture, .. } => {
             if buf.texture_copy {
                 use ash::vk::Handle;
@@ -376,7 +377,7 @@ pub fn handle_output_texture(device: &wgpu::Device, buf: &BufferDescription, _qu
                 );
             }
         },
-        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        #[cfg(all(not(any(target_os = "macos", target_os = "ios")), feature = "vulkan"))]
         BufferSource::Vulkan { texture, .. } => {
             if buf.texture_copy {
                 use ash::vk::Handle;
@@ -413,7 +414,7 @@ pub fn handle_output_texture(device: &wgpu::Device, buf: &BufferDescription, _qu
 
 pub fn handle_output_texture_post(device: &wgpu::Device, buf: &BufferDescription, out_texture: &TextureHolder, format: wgpu::TextureFormat, sub_index: wgpu::SubmissionIndex) {
     match &buf.data {
-        #[cfg(target_os = "windows")]
+        #[cfg(all(target_os = "windows", feature = "directx"))]
         BufferSource::DirectX11 { texture, device_context, .. } => {
             device.poll(wgpu::Maintain::WaitForSubmissionIndex(sub_index));
 
@@ -432,7 +433,7 @@ pub fn handle_output_texture_post(device: &wgpu::Device, buf: &BufferDescription
             }
             super::wgpu_interop_cuda::cuda_synchronize();
         },
-        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        #[cfg(all(not(any(target_os = "macos", target_os = "ios")), feature = "vulkan"))]
         BufferSource::Vulkan { .. } => {
             device.poll(wgpu::Maintain::WaitForSubmissionIndex(sub_index));
         },
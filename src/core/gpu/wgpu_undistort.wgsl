@@ -38,6 +38,21 @@ struct KernelParams {
     reserved1:                f32, // 8
     reserved2:                f32, // 12
     pixel_value_limit:        f32, // 16
+    input_crop:         vec4<i32>, // 16 - x, y, w, h
+    output_x_offset:          i32, // 4
+    output_y_offset:          i32, // 8
+    projection_type:          i32, // 12 - 0: perspective, 1: equirectangular
+    output_orientation:       i32, // 16 - bits 0-1: clockwise quarter turns, bit 2: flip horizontal, bit 3: flip vertical
+    blend_k1: vec4<f32>, blend_k2: vec4<f32>, blend_k3: vec4<f32>, // 16,16,16 - distortion coefficients of the second lens model
+    blend_region:       vec4<f32>, // 16 - start, end, direction x, y
+    blend_with_original:      f32, // 4
+    color_offset:             f32, // 8
+    transfer_function:        i32, // 12 - 0: none, 1: PQ, 2: HLG
+    drawing_offset:           i32, // 16
+    color_gain:         vec4<f32>, // 16
+    perspective1:       vec4<f32>, // 16 - output homography, h33 = 1
+    perspective2:       vec4<f32>, // 16
+    user_distortion:    vec4<f32>, // 16 - k1, k2, p1, p2 applied after the lens model
 }
 
 @group(0) @binding(0) @fragment var<uniform> params: KernelParams;
@@ -58,10 +73,51 @@ LENS_MODEL_FUNCTIONS;
 const INTER_BITS: u32 = 5u;
 const INTER_TAB_SIZE: i32 = 32; // (1u << INTER_BITS);
 
+// Overlay color premultiplied by its alpha, with the alpha in w. Zero if there's nothing to draw at this stage
+fn overlay_at(cx: f32, cy: f32, canvas_width: f32, isInput: bool) -> vec4<f32> {
+    let pos_byte = u32(round(cy * canvas_width + cx));
+    let pos_u32 = pos_byte / 4u;
+    let data = (drawing[pos_u32] >> ((pos_byte - (pos_u32 * 4u)) * 8u)) & 0xFFu;
+    if (data > 0u) {
+        let color = (data & 0xF8u) >> 3u;
+        let alpha = (data & 0x06u) >> 1u;
+        let stage = data & 1u;
+        if (((stage == 0u && isInput) || (stage == 1u && !isInput)) && color < 9u) {
+            let color_offs = 448u + (color * 4u);
+            let alphaf = coeffs[484u + alpha];
+            return vec4<f32>(vec3<f32>(coeffs[color_offs], coeffs[color_offs + 1u], coeffs[color_offs + 2u]) * params.max_pixel_value * alphaf, alphaf);
+        }
+    }
+    return vec4<f32>(0.0);
+}
+// Bilinear overlay fetch, for a canvas upscaled from a lower resolution
+fn draw_pixel_bilinear(in_pix: vec4<f32>, x: u32, y: u32, isInput: bool) -> vec4<f32> {
+    let canvas_width  = f32(max(params.width,  params.output_width))  / params.canvas_scale;
+    let canvas_height = f32(max(params.height, params.output_height)) / params.canvas_scale;
+    let fx = f32(x) / params.canvas_scale - 0.5;
+    let fy = f32(y) / params.canvas_scale - 0.5;
+    let x0 = clamp(floor(fx), 0.0, floor(canvas_width)  - 1.0);
+    let y0 = clamp(floor(fy), 0.0, floor(canvas_height) - 1.0);
+    let x1 = min(x0 + 1.0, floor(canvas_width)  - 1.0);
+    let y1 = min(y0 + 1.0, floor(canvas_height) - 1.0);
+    let tx = clamp(fx - x0, 0.0, 1.0);
+    let ty = clamp(fy - y0, 0.0, 1.0);
+
+    let overlay = mix(mix(overlay_at(x0, y0, canvas_width, isInput), overlay_at(x1, y0, canvas_width, isInput), tx),
+                      mix(overlay_at(x0, y1, canvas_width, isInput), overlay_at(x1, y1, canvas_width, isInput), tx), ty);
+    var pix = in_pix;
+    if (overlay.w > 0.0) {
+        pix = vec4<f32>(overlay.xyz + pix.xyz * (1.0 - overlay.w), params.max_pixel_value);
+    }
+    return pix;
+}
 fn draw_pixel(in_pix: vec4<f32>, x: u32, y: u32, isInput: bool) -> vec4<f32> {
     if (!bool(params.flags & 8)) { // Drawing not enabled
         return in_pix;
     }
+    if (bool(params.flags & 128)) {
+        return draw_pixel_bilinear(in_pix, x, y, isInput);
+    }
 
     let width = max(params.width, params.output_width);
 
@@ -133,10 +189,74 @@ fn rotate_point(pos: vec2<f32>, angle: f32, origin: vec2<f32>) -> vec2<f32> {
                       sin(angle) * (pos.x - origin.x) + cos(angle) * (pos.y - origin.y) + origin.y);
 }
 
+// Maps a position in the oriented (rotated, then flipped) output back to the unrotated output of size w x h
+fn unorient_output(pos_param: vec2<f32>, orientation: i32, w: f32, h: f32) -> vec2<f32> {
+    var pos = pos_param;
+    let swap = bool(orientation & 1);
+    let ow = select(w, h, swap);
+    let oh = select(h, w, swap);
+    if (bool(orientation & 4)) { pos.x = ow - 1.0 - pos.x; }
+    if (bool(orientation & 8)) { pos.y = oh - 1.0 - pos.y; }
+    switch (orientation & 3) {
+        case 1: { return vec2<f32>(pos.y, h - 1.0 - pos.x); }
+        case 2: { return vec2<f32>(w - 1.0 - pos.x, h - 1.0 - pos.y); }
+        case 3: { return vec2<f32>(w - 1.0 - pos.y, pos.x); }
+        default: { return pos; }
+    }
+}
+
+// Straight alpha is interpolated premultiplied, so transparent fill doesn't darken the edges
+fn premultiply_alpha(px: vec4<f32>) -> vec4<f32> {
+    if (!bool(params.flags & 64)) { return px; }
+    return vec4<f32>(px.xyz * (px.w / params.max_pixel_value), px.w);
+}
+fn unpremultiply_alpha(px: vec4<f32>) -> vec4<f32> {
+    if (!bool(params.flags & 64) || bool(params.flags & 8192) || px.w <= 0.0) { return px; }
+    return vec4<f32>(px.xyz / (px.w / params.max_pixel_value), px.w);
+}
+// Premultiplied input textures are already in the space we interpolate in, only the background needs converting
+fn premultiply_source(px: vec4<f32>) -> vec4<f32> {
+    if (bool(params.flags & 8192)) { return px; }
+    return premultiply_alpha(px);
+}
+
+// HDR transfer functions, normalized signal <-> linear light. Scene light for HLG, without the OOTF, so the round trip is exact
+fn pq_to_linear(e: vec3<f32>) -> vec3<f32> {
+    let p = pow(clamp(e, vec3<f32>(0.0), vec3<f32>(1.0)), vec3<f32>(1.0 / 78.84375));
+    return pow(max(p - 0.8359375, vec3<f32>(0.0)) / (18.8515625 - 18.6875 * p), vec3<f32>(1.0 / 0.1593017578125));
+}
+fn linear_to_pq(y: vec3<f32>) -> vec3<f32> {
+    let p = pow(clamp(y, vec3<f32>(0.0), vec3<f32>(1.0)), vec3<f32>(0.1593017578125));
+    return pow((0.8359375 + 18.8515625 * p) / (1.0 + 18.6875 * p), vec3<f32>(78.84375));
+}
+fn hlg_to_linear(e_param: vec3<f32>) -> vec3<f32> {
+    let e = clamp(e_param, vec3<f32>(0.0), vec3<f32>(1.0));
+    return select((exp((e - 0.55991073) / 0.17883277) + 0.28466892) / 12.0, e * e / 3.0, e <= vec3<f32>(0.5));
+}
+fn linear_to_hlg(l_param: vec3<f32>) -> vec3<f32> {
+    let l = clamp(l_param, vec3<f32>(0.0), vec3<f32>(1.0));
+    return select(0.17883277 * log(max(12.0 * l - 0.28466892, vec3<f32>(1e-6))) + 0.55991073, sqrt(3.0 * l), l <= vec3<f32>(1.0 / 12.0));
+}
+// Applied to the color channels only, alpha is left as is
+fn transfer_to_linear(px: vec4<f32>) -> vec4<f32> {
+    if (params.transfer_function == 0) { return px; }
+    var scale = params.max_pixel_value;
+    if (params.pixel_value_limit == 1.0) { scale = 1.0; }
+    if (params.transfer_function == 1) { return vec4<f32>(pq_to_linear(px.xyz / scale) * scale, px.w); }
+    return vec4<f32>(hlg_to_linear(px.xyz / scale) * scale, px.w);
+}
+fn transfer_from_linear(px: vec4<f32>) -> vec4<f32> {
+    if (params.transfer_function == 0) { return px; }
+    var scale = params.max_pixel_value;
+    if (params.pixel_value_limit == 1.0) { scale = 1.0; }
+    if (params.transfer_function == 1) { return vec4<f32>(linear_to_pq(px.xyz / scale) * scale, px.w); }
+    return vec4<f32>(linear_to_hlg(px.xyz / scale) * scale, px.w);
+}
+
 fn sample_input_at(uv_param: vec2<f32>) -> vec4<f32> {
     let fix_range = bool(params.flags & 1);
 
-    let bg = params.background * params.max_pixel_value;
+    let bg = premultiply_alpha(transfer_to_linear(params.background * params.max_pixel_value));
     var sum = vec4<f32>(0.0);
 
     let shift = (params.interpolation >> 2u) + 1u;
@@ -166,17 +286,28 @@ fn sample_input_at(uv_param: vec2<f32>) -> vec4<f32> {
     let coeffs_x = i32(ind + ((sx0 & (INTER_TAB_SIZE - 1)) << shift));
     let coeffs_y = i32(ind + ((sy0 & (INTER_TAB_SIZE - 1)) << shift));
 
+    let has_crop = params.input_crop.z > 0 && params.input_crop.w > 0;
+
     for (var yp: i32 = 0; yp < i32(params.interpolation); yp = yp + 1) {
-        if (sy + yp >= params.source_rect.y && sy + yp < params.source_rect.y + params.source_rect.w) {
+        var py = sy + yp;
+        if (has_crop) { // Input crop, clamp to the edge pixel
+            py = clamp(py, params.input_crop.y, params.input_crop.y + params.input_crop.w - 1);
+        }
+        if (py >= params.source_rect.y && py < params.source_rect.y + params.source_rect.w) {
             var xsum = vec4<f32>(0.0, 0.0, 0.0, 0.0);
             for (var xp: i32 = 0; xp < i32(params.interpolation); xp = xp + 1) {
                 var pixel: vec4<f32>;
-                if (sx + xp >= params.source_rect.x && sx + xp < params.source_rect.x + params.source_rect.z) {
-                    pixel = read_input_at(vec2<i32>(sx + xp, sy + yp));
-                    pixel = draw_pixel(pixel, u32(sx + xp), u32(sy + yp), true);
+                var px = sx + xp;
+                if (has_crop) {
+                    px = clamp(px, params.input_crop.x, params.input_crop.x + params.input_crop.z - 1);
+                }
+                if (px >= params.source_rect.x && px < params.source_rect.x + params.source_rect.z) {
+                    pixel = read_input_at(vec2<i32>(px, py));
+                    pixel = draw_pixel(pixel, u32(px), u32(py), true);
                     if (fix_range) {
                         pixel = remap_colorrange(pixel, params.bytes_per_pixel == 1);
                     }
+                    pixel = premultiply_source(transfer_to_linear(pixel));
                 } else {
                     pixel = bg;
                 }
@@ -187,6 +318,7 @@ fn sample_input_at(uv_param: vec2<f32>) -> vec4<f32> {
             sum = sum + bg * coeffs[coeffs_y + yp];
         }
     }
+    sum = transfer_from_linear(unpremultiply_alpha(sum));
     return vec4<f32>(
         min(sum.x, params.pixel_value_limit),
         min(sum.y, params.pixel_value_limit),
@@ -195,18 +327,75 @@ fn sample_input_at(uv_param: vec2<f32>) -> vec4<f32> {
     );
 }
 
+// Weight of the second lens model, smooth across the transition band
+fn lens_blend_weight(pos: vec2<f32>) -> f32 {
+    let dir = params.blend_region.zw;
+    var v = length(pos);
+    if (dir.x != 0.0 || dir.y != 0.0) { v = dot(pos, normalize(dir)); }
+    if (params.blend_region.y <= params.blend_region.x) { return select(0.0, 1.0, v >= params.blend_region.x); }
+    return smoothstep(params.blend_region.x, params.blend_region.y, v);
+}
+// Keystone correction, maps the corrected output position to the uncorrected one
+fn apply_perspective(p: vec2<f32>) -> vec2<f32> {
+    let h1 = params.perspective1;
+    let h2 = params.perspective2;
+    let w = h2.z * p.x + h2.w * p.y + 1.0;
+    return vec2<f32>(h1.x * p.x + h1.y * p.y + h1.z, h1.w * p.x + h2.x * p.y + h2.y) / w;
+}
+// Manual radial/tangential correction on top of the lens profile, in normalized camera coordinates
+fn apply_user_distortion(p: vec2<f32>) -> vec2<f32> {
+    let d = params.user_distortion;
+    let r2 = dot(p, p);
+    let radial = 1.0 + d.x * r2 + d.y * r2 * r2;
+    return vec2<f32>(p.x * radial + 2.0 * d.z * p.x * p.y + d.w * (r2 + 2.0 * p.x * p.x),
+                     p.y * radial + d.z * (r2 + 2.0 * p.y * p.y) + 2.0 * d.w * p.x * p.y);
+}
+fn distort_point_blended(x: f32, y: f32, z: f32) -> vec2<f32> {
+    if (bool(params.flags & 512)) { return vec2<f32>(x, y) / z; } // Lens bypassed
+    var pt = distort_point(x, y, z);
+    if (bool(params.flags & 32)) { // Has blended lens
+        let t = lens_blend_weight(vec2<f32>(x, y) / z);
+        if (t > 0.0) { pt = mix(pt, distort_point_b(x, y, z), t); }
+    }
+    return pt;
+}
+fn undistort_point_blended(pos: vec2<f32>) -> vec2<f32> {
+    if (bool(params.flags & 512)) { return pos; } // Lens bypassed
+    var pt = undistort_point(pos);
+    if (bool(params.flags & 32)) { // Has blended lens
+        let t = lens_blend_weight(pt);
+        if (t > 0.0) { pt = mix(pt, undistort_point_b(pos), t); }
+    }
+    return pt;
+}
+
 fn rotate_and_distort(pos: vec2<f32>, idx: u32, f: vec2<f32>, c: vec2<f32>, k1: vec4<f32>, k2: vec4<f32>, k3: vec4<f32>) -> vec2<f32> {
-    let _x = (pos.x * matrices[idx + 0u]) + (pos.y * matrices[idx + 1u]) + matrices[idx + 2u] + params.translation3d.x;
-    let _y = (pos.x * matrices[idx + 3u]) + (pos.y * matrices[idx + 4u]) + matrices[idx + 5u] + params.translation3d.y;
-    let _w = (pos.x * matrices[idx + 6u]) + (pos.y * matrices[idx + 7u]) + matrices[idx + 8u] + params.translation3d.z;
+    var dir = vec3<f32>(pos.x, pos.y, 1.0);
+    let is_equirect = params.projection_type == 1;
+    if (is_equirect) { // Equirectangular, matrix is only the rotation of the direction on the sphere
+        let lon = (pos.x / f32(params.output_width)  - 0.5) * 6.2831853;
+        let lat = (pos.y / f32(params.output_height) - 0.5) * 3.1415927;
+        dir = vec3<f32>(cos(lat) * sin(lon), sin(lat), cos(lat) * cos(lon));
+    }
+    let _x = (dir.x * matrices[idx + 0u]) + (dir.y * matrices[idx + 1u]) + dir.z * matrices[idx + 2u] + params.translation3d.x;
+    let _y = (dir.x * matrices[idx + 3u]) + (dir.y * matrices[idx + 4u]) + dir.z * matrices[idx + 5u] + params.translation3d.y;
+    var _w = dir.z + params.translation3d.z; // Affine, bottom row is [0, 0, 1]
+    if (!bool(params.flags & 16384) || is_equirect) {
+        _w = (dir.x * matrices[idx + 6u]) + (dir.y * matrices[idx + 7u]) + dir.z * matrices[idx + 8u] + params.translation3d.z;
+    }
 
+    if (is_equirect) {
+        return f * distort_point(_x, _y, _w) + c;
+    }
     if (_w > 0.0) {
         if (params.r_limit > 0.0 && length(vec2<f32>(_x, _y) / _w) > params.r_limit) {
             return vec2<f32>(-99999.0, -99999.0);
         }
-        var uv = f * distort_point(_x, _y, _w) + c;
+        var pt = distort_point_blended(_x, _y, _w);
+        if (bool(params.flags & 4096)) { pt = apply_user_distortion(pt); }
+        var uv = f * pt + c;
 
-        if (bool(params.flags & 2)) { // Has digital lens
+        if (bool(params.flags & 2) && !bool(params.flags & 1024)) { // Has digital lens, not bypassed
             uv = digital_distort_point(uv);
         }
 
@@ -218,6 +407,18 @@ fn rotate_and_distort(pos: vec2<f32>, idx: u32, f: vec2<f32>, c: vec2<f32>, k1:
     return vec2<f32>(-99999.0, -99999.0);
 }
 
+// Ghosting trail effect, mix in the unstabilized input at the same output position
+fn blend_with_original(pixel: vec4<f32>, p: vec2<f32>) -> vec4<f32> {
+    let org_uv = p * vec2<f32>(f32(params.width) / f32(params.output_width), f32(params.height) / f32(params.output_height));
+    return mix(pixel, sample_input_at(org_uv), params.blend_with_original);
+}
+
+// Per-frame exposure and white balance normalization, computed by the host
+fn color_correct(pixel: vec4<f32>) -> vec4<f32> {
+    let corrected = clamp(pixel.xyz * params.color_gain.xyz + params.color_offset, vec3<f32>(0.0), vec3<f32>(params.pixel_value_limit));
+    return vec4<f32>(corrected, pixel.w);
+}
+
 // Adapted from OpenCV: initUndistortRectifyMap + remap
 // https://github.com/opencv/opencv/blob/2b60166e5c65f1caccac11964ad760d847c536e4/modules/calib3d/src/fisheye.cpp#L465-L567
 // https://github.com/opencv/opencv/blob/2b60166e5c65f1caccac11964ad760d847c536e4/modules/imgproc/src/opencl/remap.cl#L390-L498
@@ -228,32 +429,40 @@ fn undistort(position: vec2<f32>) -> vec4<SCALAR> {
         return vec4<SCALAR>(bg);
     }
 
+    // For 90 and 270 degrees the output buffer has width and height swapped
+    let swap = bool(params.output_orientation & 1);
     var out_pos = vec2<f32>(
-        map_coord(position.x, f32(params.output_rect.x), f32(params.output_rect.x + params.output_rect.z), 0.0, f32(params.output_width) ),
-        map_coord(position.y, f32(params.output_rect.y), f32(params.output_rect.y + params.output_rect.w), 0.0, f32(params.output_height))
+        map_coord(position.x, f32(params.output_rect.x), f32(params.output_rect.x + params.output_rect.z), 0.0, f32(select(params.output_width, params.output_height, swap))),
+        map_coord(position.y, f32(params.output_rect.y), f32(params.output_rect.y + params.output_rect.w), 0.0, f32(select(params.output_height, params.output_width, swap)))
     );
+    if (params.output_orientation != 0) {
+        out_pos = unorient_output(out_pos, params.output_orientation, f32(params.output_width), f32(params.output_height));
+    }
 
     let p = out_pos;
 
     if (out_pos.x < 0.0 || out_pos.y < 0.0 || out_pos.x > f32(params.output_width) || out_pos.y > f32(params.output_height)) { return vec4<SCALAR>(bg); }
 
+    if (bool(params.flags & 2048)) { // Perspective correction
+        out_pos = apply_perspective(out_pos);
+    }
     out_pos = out_pos + params.translation2d;
 
     ///////////////////////////////////////////////////////////////////
     // Add lens distortion back
-    if (params.lens_correction_amount < 1.0) {
+    if (params.lens_correction_amount < 1.0 && params.projection_type == 0) {
         let factor = max(1.0 - params.lens_correction_amount, 0.001); // FIXME: this is close but wrong
         let out_c = vec2<f32>(f32(params.output_width) / 2.0, f32(params.output_height) / 2.0);
         let out_f = (params.f / params.fov) / factor;
 
         var new_out_pos = out_pos;
 
-        if (bool(params.flags & 2)) { // Has digital lens
+        if (bool(params.flags & 2) && !bool(params.flags & 1024)) { // Has digital lens, not bypassed
             new_out_pos = digital_undistort_point(new_out_pos);
         }
 
         new_out_pos = (new_out_pos - out_c) / out_f;
-        new_out_pos = undistort_point(new_out_pos);
+        new_out_pos = undistort_point_blended(new_out_pos);
         new_out_pos = out_f * new_out_pos + out_c;
 
         out_pos = new_out_pos * (1.0 - params.lens_correction_amount) + (out_pos * params.lens_correction_amount);
@@ -318,6 +527,12 @@ fn undistort(position: vec2<f32>) -> vec4<SCALAR> {
             let c1 = sample_input_at(uv);
             let c2 = sample_input_at(pt2);
             pixel = c1 * alpha + c2 * (1.0 - alpha);
+            if (params.blend_with_original > 0.0) {
+                pixel = blend_with_original(pixel, p);
+            }
+            if ((params.flags & 256) == 256) {
+                pixel = color_correct(pixel);
+            }
             pixel = draw_pixel(pixel, u32(p.x), u32(p.y), false);
             pixel = draw_safe_area(pixel, p.x, p.y);
             return vec4<SCALAR>(pixel);
@@ -325,6 +540,12 @@ fn undistort(position: vec2<f32>) -> vec4<SCALAR> {
 
         pixel = sample_input_at(uv);
     }
+    if (params.blend_with_original > 0.0) {
+        pixel = blend_with_original(pixel, p);
+    }
+    if ((params.flags & 256) == 256) {
+        pixel = color_correct(pixel);
+    }
     pixel = draw_pixel(pixel, u32(p.x), u32(p.y), false);
     pixel = draw_safe_area(pixel, p.x, p.y);
     return vec4<SCALAR>(pixel);
@@ -17,6 +17,11 @@ use super::imu_integration::*;
 use super::smoothing::SmoothingAlgorithm;
 use crate::StabilizationParams;
 
+pub mod android_sensor_csv;
+pub mod dji_flight_record;
+pub mod esp32;
+pub mod nokia_ozo;
+
 pub type Quat64 = UnitQuaternion<f64>;
 pub type TimeIMU = telemetry_parser::util::IMUData;
 pub type TimeQuat = BTreeMap<i64, Quat64>; // key is timestamp_us
@@ -99,6 +104,8 @@ pub struct GyroSource {
     pub gyro_bias: Option<[f64; 3]>,
 
     pub integration_method: usize,
+    pub initial_orientation: Option<Quat64>, // Used by gyro-only integration, estimated from the accelerometer if not set
+    pub ahrs_gains: Option<(f64, f64)>, // (kp, ki) of the Mahony integration, library defaults if not set
 
     pub quaternions: TimeQuat,
 
@@ -489,14 +496,141 @@ impl GyroSource {
             },
             1 => self.quaternions = ComplementaryIntegrator::integrate(&self.raw_imu, self.duration_ms),
             2 => self.quaternions = VQFIntegrator::integrate(&self.raw_imu, self.duration_ms),
-            3 => self.quaternions = SimpleGyroIntegrator::integrate(&self.raw_imu, self.duration_ms),
+            3 => self.quaternions = SimpleGyroIntegrator::integrate_with_initial(&self.raw_imu, self.duration_ms, self.get_initial_orientation()),
             4 => self.quaternions = SimpleGyroAccelIntegrator::integrate(&self.raw_imu, self.duration_ms),
-            5 => self.quaternions = MahonyIntegrator::integrate(&self.raw_imu, self.duration_ms),
+            5 => self.quaternions = match self.ahrs_gains {
+                Some((kp, ki)) => MahonyIntegrator::integrate_with_gains(&self.raw_imu, self.duration_ms, kp, ki, self.get_initial_orientation()),
+                None => MahonyIntegrator::integrate(&self.raw_imu, self.duration_ms)
+            },
             6 => self.quaternions = MadgwickIntegrator::integrate(&self.raw_imu, self.duration_ms),
             _ => log::error!("Unknown integrator")
         }
     }
 
+    fn get_initial_orientation(&self) -> Quat64 {
+        self.initial_orientation.unwrap_or_else(|| {
+            let accel_samples = self.raw_imu.iter().filter_map(|x| x.accl.map(|a| ((x.timestamp_ms * 1000.0) as i64, a[0], a[1], a[2]))).collect::<Vec<_>>();
+            compute_initial_attitude_from_accelerometer(&accel_samples, 1_000_000) // Assume the first second is static
+        })
+    }
+
+    // Switch to the Mahony integration with the given gains, which keeps roll and pitch from drifting on long clips
+    pub fn apply_ahrs_fusion(&mut self, ki: f64, kp: f64) -> &mut Self {
+        self.integration_method = 5;
+        self.ahrs_gains = Some((kp, ki));
+        self.integrate();
+        self
+    }
+
+    // Ranges `(from_us, to_us)` of gyro timestamps where the camera was still, ie. gyro magnitude stayed below `max_rate_deg_s` for at least `min_duration_ms`
+    pub fn detect_static_segments(&self, max_rate_deg_s: f64, min_duration_ms: f64) -> Vec<(i64, i64)> {
+        let mut segments = Vec::new();
+        let mut start: Option<f64> = None;
+        let mut last_ts = 0.0;
+        let mut close = |start: &mut Option<f64>, end: f64| {
+            if let Some(s) = start.take() {
+                if end - s >= min_duration_ms { segments.push(((s * 1000.0).round() as i64, (end * 1000.0).round() as i64)); }
+            }
+        };
+        for x in &self.raw_imu {
+            let Some(g) = x.gyro else { continue; };
+            if Vector3::new(g[0], g[1], g[2]).norm() < max_rate_deg_s {
+                start.get_or_insert(x.timestamp_ms);
+            } else {
+                close(&mut start, last_ts);
+            }
+            last_ts = x.timestamp_ms;
+        }
+        close(&mut start, last_ts);
+        segments
+    }
+
+    // Copy covering only the video from `video_start_us` to `video_end_us`, with timestamps starting at 0 at `video_start_us`.
+    // Video before the first gyro sample (eg. logger startup lag) is padded with the first sample without rotation (zero gyro, same orientation),
+    // and the data after the end is trimmed. Offsets keep their values, only their timestamps are shifted. Smoothing has to be computed again
+    pub fn slice_and_pad(&self, video_start_us: i64, video_end_us: i64) -> GyroSource {
+        let to_gyro_us = |ts: i64| ts - (self.offset_at_video_timestamp(ts as f64 / 1000.0) * 1000.0).round() as i64;
+        let (from_us, to_us) = (to_gyro_us(video_start_us), to_gyro_us(video_end_us));
+
+        fn slice_map<V: Clone>(map: &BTreeMap<i64, V>, from_us: i64, to_us: i64, shift_us: i64) -> BTreeMap<i64, V> {
+            // Keep one sample on each side for interpolation
+            let start = map.range(..from_us).next_back().map(|x| *x.0).unwrap_or(from_us);
+            let end = map.range(to_us + 1..).next().map(|x| *x.0).unwrap_or(to_us);
+            let mut out: BTreeMap<i64, V> = map.range(start..=end).map(|(k, v)| (*k, v.clone())).collect();
+            let step = match (map.keys().next(), map.keys().next_back()) {
+                (Some(first), Some(last)) if map.len() > 1 => ((last - first) / (map.len() as i64 - 1)).max(1),
+                _ => 1000
+            };
+            let first = out.iter().next().map(|(k, v)| (*k, v.clone()));
+            if let Some((first_ts, first)) = first {
+                let mut ts = first_ts - step;
+                while ts > from_us - step {
+                    out.insert(ts, first.clone());
+                    ts -= step;
+                }
+            }
+            out.into_iter().map(|(k, v)| (k - shift_us, v)).collect()
+        }
+        let slice_imu = |imu: &[TimeIMU]| -> Vec<TimeIMU> {
+            let map: BTreeMap<i64, TimeIMU> = imu.iter().map(|x| ((x.timestamp_ms * 1000.0).round() as i64, x.clone())).collect();
+            slice_map(&map, from_us, to_us, video_start_us).into_iter().map(|(ts, mut x)| {
+                if (x.timestamp_ms * 1000.0).round() as i64 != ts + video_start_us && x.gyro.is_some() {
+                    x.gyro = Some([0.0; 3]); // Padded sample
+                }
+                x.timestamp_ms = ts as f64 / 1000.0;
+                x
+            }).collect()
+        };
+
+        let mut ret = self.clone();
+        ret.duration_ms = (video_end_us - video_start_us).max(0) as f64 / 1000.0;
+        ret.raw_imu = slice_imu(&self.raw_imu);
+        ret.file_metadata.raw_imu = slice_imu(&self.file_metadata.raw_imu);
+        ret.quaternions = slice_map(&self.quaternions, from_us, to_us, video_start_us);
+        ret.file_metadata.quaternions = slice_map(&self.file_metadata.quaternions, from_us, to_us, video_start_us);
+        ret.file_metadata.gravity_vectors = self.file_metadata.gravity_vectors.as_ref().map(|x| slice_map(x, from_us, to_us, video_start_us));
+        ret.smoothed_quaternions.clear();
+        ret.org_smoothed_quaternions.clear();
+        ret.set_offsets(self.offsets.iter().map(|(k, v)| (k - video_start_us, *v)).collect());
+        ret
+    }
+
+    // Removes the integration drift using `static_segments` (gyro timestamps in us), where the orientation should be constant.
+    // The drift rate measured in each segment is interpolated linearly between the segments and subtracted from the rotation between every two samples
+    pub fn compute_drift_corrected_path(&mut self, static_segments: &[(i64, i64)]) -> &mut Self {
+        let quat_at = |ts: i64| self.quaternions.range(..=ts).next_back().or_else(|| self.quaternions.iter().next()).map(|x| *x.1);
+        // (segment center in us, drift rate in rad/us in camera axes)
+        let rates: Vec<(f64, Vector3<f64>)> = static_segments.iter().filter(|(from, to)| to > from).filter_map(|&(from, to)| {
+            let drift = quat_at(from)?.inverse() * quat_at(to)?;
+            Some(((from + to) as f64 / 2.0, drift.scaled_axis() / (to - from) as f64))
+        }).collect();
+        if rates.is_empty() || self.quaternions.len() < 2 { return self; }
+
+        let rate_at = |ts: f64| -> Vector3<f64> {
+            let next = rates.iter().position(|x| x.0 >= ts).unwrap_or(rates.len());
+            if next == 0 { return rates[0].1; }
+            if next == rates.len() { return rates[rates.len() - 1].1; }
+            let (a, b) = (&rates[next - 1], &rates[next]);
+            a.1.lerp(&b.1, (ts - a.0) / (b.0 - a.0))
+        };
+
+        let mut corrected = TimeQuat::new();
+        let mut prev: Option<(i64, Quat64, Quat64)> = None; // timestamp, original, corrected
+        for (&ts, &q) in &self.quaternions {
+            let c = match prev {
+                Some((prev_ts, prev_q, prev_c)) => {
+                    let drift = rate_at((prev_ts + ts) as f64 / 2.0) * (ts - prev_ts) as f64;
+                    prev_c * (prev_q.inverse() * q) * Quat64::from_scaled_axis(-drift)
+                },
+                None => q
+            };
+            corrected.insert(ts, c);
+            prev = Some((ts, q, c));
+        }
+        self.quaternions = corrected;
+        self
+    }
+
     pub fn recompute_smoothness(&self, alg: &dyn SmoothingAlgorithm, horizon_lock: super::smoothing::horizon::HorizonLock, stabilization_params: &StabilizationParams, keyframes: &KeyframeManager) -> (TimeQuat, TimeQuat, (f64, f64, f64)) {
         let mut smoothed_quaternions = self.quaternions.clone();
         if true {
@@ -552,6 +686,22 @@ impl GyroSource {
         self.adjust_offsets();
     }
 
+    // True frame rate from the sync points (video timestamp_us, offset_ms, cost) found with optical flow over the whole clip.
+    // With a nominal rate different from the sensor rate, the offset drifts linearly: offset = (1 - nominal / true) * t + c
+    pub fn estimate_true_fps_from_gyro_sync(optical_flow: &[(i64, f64, f64)], nominal_fps: f64) -> f64 {
+        let points: BTreeMap<i64, f64> = optical_flow.iter().filter(|(_, offset, cost)| offset.is_finite() && cost.is_finite()).map(|(ts, offset, _)| (*ts, *offset)).collect();
+        if points.len() < 2 || nominal_fps <= 0.0 { return nominal_fps; }
+        let Some([slope, _, _]) = Self::line_fit(&points) else { return nominal_fps; };
+
+        let drift = slope * 1000.0; // ms per ms of the video
+        if !drift.is_finite() || drift >= 1.0 { return nominal_fps; }
+        let true_fps = nominal_fps / (1.0 - drift);
+        if ((true_fps - nominal_fps) / nominal_fps).abs() > 0.0001 {
+            log::warn!("Frame rate from the sync points differs from the nominal one: {:.5} vs {:.5} fps, drift: {:.3} ms per minute", true_fps, nominal_fps, drift * 60000.0);
+        }
+        true_fps
+    }
+
     fn line_fit(offsets: &BTreeMap<i64, f64>) -> Option<[f64; 3]> {
         let a = OMatrix::<f64, nalgebra::Dyn, U2>::from_row_iterator(offsets.len(), offsets.iter().flat_map(|(k, _)| [*k as f64, 1.0]));
         let b = OVector::<f64, nalgebra::Dyn>::from_iterator(offsets.len(), offsets.iter().map(|(_, v)| *v));
@@ -756,6 +906,54 @@ impl GyroSource {
     pub fn      org_quat_at_timestamp(&self, timestamp_ms: f64) -> Quat64 { self.quat_at_timestamp(&self.quaternions,          timestamp_ms) }
     pub fn smoothed_quat_at_timestamp(&self, timestamp_ms: f64) -> Quat64 { self.quat_at_timestamp(&self.smoothed_quaternions, timestamp_ms) }
 
+    // Camera displacement in meters at `timestamp_ms` (video time) relative to the steady motion within `window_ms` around it, in camera axes.
+    // Accelerometer readings are rotated to world space, the mean over the window is removed (gravity and constant acceleration),
+    // then integrated twice and the linear trend is removed, so only the short-term shake is left and intentional movement is kept
+    pub fn translation_shake_at_timestamp(&self, timestamp_ms: f64, window_ms: f64) -> Vector3<f64> {
+        let gyro_ts = timestamp_ms - self.offset_at_video_timestamp(timestamp_ms);
+        let (from, to) = (gyro_ts - window_ms / 2.0, gyro_ts + window_ms / 2.0);
+
+        let quat_at = |ts_ms: f64| -> Quat64 {
+            self.quaternions.range(..=(ts_ms * 1000.0).round() as i64).next_back()
+                .or_else(|| self.quaternions.iter().next())
+                .map(|x| *x.1)
+                .unwrap_or_else(Quat64::identity)
+        };
+
+        let samples = self.raw_imu.iter()
+            .filter(|x| x.timestamp_ms >= from && x.timestamp_ms <= to)
+            .filter_map(|x| Some((x.timestamp_ms, quat_at(x.timestamp_ms) * Vector3::from(x.accl?))))
+            .collect::<Vec<_>>();
+        if samples.len() < 3 { return Vector3::zeros(); }
+
+        let mean = samples.iter().fold(Vector3::zeros(), |acc, x| acc + x.1) / samples.len() as f64;
+
+        let mut velocity = Vector3::zeros();
+        let mut position = Vector3::zeros();
+        let mut positions = Vec::with_capacity(samples.len());
+        positions.push((samples[0].0, position));
+        for w in samples.windows(2) {
+            let dt = (w[1].0 - w[0].0) / 1000.0;
+            let prev_velocity = velocity;
+            velocity += ((w[0].1 - mean) + (w[1].1 - mean)) * 0.5 * dt;
+            position += (prev_velocity + velocity) * 0.5 * dt;
+            positions.push((w[1].0, position));
+        }
+
+        // Least squares line through the positions, the residual at the center is the shake
+        let n = positions.len() as f64;
+        let mean_t = positions.iter().map(|x| x.0).sum::<f64>() / n;
+        let mean_p = positions.iter().fold(Vector3::zeros(), |acc, x| acc + x.1) / n;
+        let var_t = positions.iter().map(|x| (x.0 - mean_t).powi(2)).sum::<f64>();
+        if var_t <= 0.0 { return Vector3::zeros(); }
+        let slope = positions.iter().fold(Vector3::zeros(), |acc, x| acc + (x.1 - mean_p) * (x.0 - mean_t)) / var_t;
+
+        let center = positions.iter().min_by(|a, b| (a.0 - gyro_ts).abs().total_cmp(&(b.0 - gyro_ts).abs())).map(|x| x.1).unwrap_or_default();
+        let shake = center - (mean_p + slope * (gyro_ts - mean_t));
+
+        quat_at(gyro_ts).inverse() * shake
+    }
+
     pub fn offset_at_timestamp(offsets: &BTreeMap<i64, f64>, timestamp_ms: f64) -> f64 {
         match offsets.len() {
             0 => 0.0,
@@ -804,6 +1002,7 @@ impl GyroSource {
             },
             use_gravity_vectors:  self.use_gravity_vectors,
             integration_method:   self.integration_method,
+            ahrs_gains:           self.ahrs_gains,
             ..Default::default()
         }
     }
@@ -828,6 +1027,8 @@ impl GyroSource {
         hasher.write_usize(self.file_metadata.lens_params.len());
         hasher.write_u32(if self.use_gravity_vectors { 1 } else { 0 });
         hasher.write_usize(self.integration_method);
+        if let Some((kp, ki)) = self.ahrs_gains { hasher.write_u64(kp.to_bits()); hasher.write_u64(ki.to_bits()); }
+        if let Some(q) = &self.initial_orientation { for v in q.as_vector().iter() { hasher.write_u64(v.to_bits()); } }
         for (ts, v) in &self.offsets {
             hasher.write_i64(*ts);
             hasher.write_u64(v.to_bits());
@@ -893,3 +1094,133 @@ impl GyroSource {
         (bias_vals[0], bias_vals[1], bias_vals[2])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn ahrs_fusion_keeps_roll_and_pitch() {
+        // 5 minutes at 200 Hz of a level camera panning around the gravity axis, with a constant gyro bias
+        let rate = 200.0;
+        let bias = [0.5, -0.3, 0.2]; // deg/s
+        let mut gyro = GyroSource::new();
+        gyro.duration_ms = 5.0 * 60.0 * 1000.0;
+        gyro.initial_orientation = Some(Quat64::identity());
+        gyro.raw_imu = (0..(gyro.duration_ms / 1000.0 * rate) as usize).map(|i| {
+            let t = i as f64 / rate;
+            TimeIMU {
+                timestamp_ms: t * 1000.0,
+                gyro: Some([bias[0], bias[1], bias[2] + 20.0 * t.sin()]),
+                accl: Some([0.0, 0.0, 1.0]),
+                magn: None
+            }
+        }).collect();
+
+        gyro.apply_ahrs_fusion(0.05, 1.0);
+        assert_eq!(gyro.integration_method, 5);
+
+        let (_, last) = gyro.quaternions.last_key_value().unwrap();
+        let gravity = last.inverse_transform_vector(&Vector3::z());
+        let tilt_error = gravity.angle(&Vector3::z()).to_degrees();
+        assert!(tilt_error < 1.0, "Roll/pitch error after 5 minutes: {tilt_error:.3}°");
+    }
+
+    // 3 seconds at 1 kHz of a camera rotating in all axes, with an optional sideways oscillation of the body in world space
+    fn rotating_camera(lateral_accel: f64) -> GyroSource {
+        let mut gyro = GyroSource::new();
+        gyro.duration_ms = 3000.0;
+        for i in 0..3000 {
+            let t = i as f64 / 1000.0;
+            let q = Quat64::from_euler_angles(0.3 * (2.0 * PI * 1.3 * t).sin(), 0.2 * (2.0 * PI * 0.7 * t).sin(), 0.5 * t);
+            let world_accl = Vector3::new(lateral_accel * (2.0 * PI * 5.0 * t).sin(), 9.80665, 0.0);
+            let accl = q.inverse() * world_accl;
+            gyro.quaternions.insert(i * 1000, q);
+            gyro.raw_imu.push(TimeIMU { timestamp_ms: t * 1000.0, gyro: Some([0.0; 3]), accl: Some([accl.x, accl.y, accl.z]), magn: None });
+        }
+        gyro
+    }
+
+    #[test]
+    fn pure_rotation_has_no_translation_shake() {
+        let gyro = rotating_camera(0.0);
+        for ts in [500.0, 1050.0, 2000.0] {
+            let shake = gyro.translation_shake_at_timestamp(ts, 500.0);
+            assert!(shake.norm() < 1e-9, "Translation at {ts} ms: {shake:?}");
+        }
+
+        // Same rotation with the body moving sideways
+        let gyro = rotating_camera(1.0);
+        assert!(gyro.translation_shake_at_timestamp(1050.0, 500.0).norm() > 1e-4);
+    }
+
+    #[test]
+    fn drift_correction_reduces_error() {
+        // 10 minutes at 100 Hz, moving for 18 s and still for 2 s, integrated with a gyro bias that grows over time
+        let rate = 100;
+        let dt = 1.0 / rate as f64;
+        let period = 20 * rate;
+        let mut gyro = GyroSource::new();
+        gyro.duration_ms = 10.0 * 60.0 * 1000.0;
+        let mut truth = TimeQuat::new();
+        let mut static_segments = Vec::new();
+        let (mut q_true, mut q_meas) = (Quat64::identity(), Quat64::identity());
+        for i in 0..(10 * 60 * rate) {
+            let ts = i as i64 * 1_000_000 / rate as i64;
+            let t = i as f64 * dt;
+            let tau = (i % period) as f64 * dt;
+            let omega = if tau < 18.0 {
+                Vector3::new((1.1 * t).sin(), (0.7 * t).cos(), (0.3 * t + 1.0).sin()) * (20.0 * (PI * tau / 18.0).sin()).to_radians()
+            } else {
+                if i % period == 18 * rate { static_segments.push((ts, ts + 2_000_000 - 1_000_000 / rate as i64)); }
+                Vector3::zeros()
+            };
+            let bias = Vector3::new(0.05, -0.03, 0.02).to_radians() * (1.0 + t / 300.0);
+            truth.insert(ts, q_true);
+            gyro.quaternions.insert(ts, q_meas);
+            q_true = q_true * Quat64::from_scaled_axis(omega * dt);
+            q_meas = q_meas * Quat64::from_scaled_axis((omega + bias) * dt);
+        }
+
+        let rms = |quats: &TimeQuat| (quats.iter().map(|(ts, q)| q.angle_to(&truth[ts]).powi(2)).sum::<f64>() / quats.len() as f64).sqrt();
+        let before = rms(&gyro.quaternions);
+        gyro.compute_drift_corrected_path(&static_segments);
+        let after = rms(&gyro.quaternions);
+        assert!(after < before * 0.2, "RMS error {:.3}° -> {:.3}°", before.to_degrees(), after.to_degrees());
+    }
+
+    #[test]
+    fn padded_region_is_stable() {
+        // Gyro logging starts 500 ms after the video, 1 kHz until 3 s
+        let mut gyro = GyroSource::new();
+        gyro.duration_ms = 3000.0;
+        for ms in 500..3000 {
+            gyro.quaternions.insert(ms * 1000, Quat64::from_euler_angles(0.001 * ms as f64, 0.002 * ms as f64, 0.0));
+            gyro.raw_imu.push(TimeIMU { timestamp_ms: ms as f64, gyro: Some([1.0, 2.0, 3.0]), accl: Some([0.0, 9.8, 0.0]), magn: None });
+        }
+        let first = gyro.quaternions[&500_000];
+
+        let sliced = gyro.slice_and_pad(100_000, 2_100_000);
+        assert_eq!(sliced.duration_ms, 2000.0);
+
+        // Padded with the first sample at the original rate, shifted to the video start
+        let keys = sliced.quaternions.keys().copied().collect::<Vec<_>>();
+        assert!(keys[0] <= 0 && *keys.last().unwrap() <= 2_001_000);
+        assert!(keys.windows(2).all(|x| x[1] - x[0] == 1000));
+        for ts in keys.iter().filter(|x| **x <= 400_000) {
+            assert_eq!(sliced.quaternions[ts], first);
+        }
+        for ms in 0..400 {
+            let q = sliced.org_quat_at_timestamp(ms as f64);
+            assert!(q.coords.iter().all(|x| x.is_finite()) && q.angle_to(&first) < 1e-9, "Unstable orientation at {ms} ms: {q:?}");
+        }
+
+        // Padded samples don't rotate but keep the accelerometer
+        for x in sliced.raw_imu.iter().filter(|x| x.timestamp_ms < 400.0) {
+            assert_eq!(x.gyro, Some([0.0; 3]));
+            assert_eq!(x.accl, Some([0.0, 9.8, 0.0]));
+        }
+        assert!(sliced.raw_imu.iter().filter(|x| x.timestamp_ms >= 400.0).all(|x| x.gyro == Some([1.0, 2.0, 3.0])));
+    }
+}
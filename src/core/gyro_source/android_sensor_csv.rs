@@ -0,0 +1,232 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// CSV exports of Android sensor logging apps (eg. Sensor Logger, Physics Toolbox).
+// Supported layouts:
+// - a timestamp column and gyro (`gyrX`, `Gyroscope x`, `wx`...) and/or accelerometer (`accX`, `Accelerometer x`, `gFx`...) columns,
+//   rows can have only one of the sensors filled when they are interleaved
+// - a timestamp column, a sensor name column (`sensor`, `type`) and `x`, `y`, `z` columns
+// - a timestamp column and `x`, `y`, `z` columns in a file per sensor, named after the sensor (eg. `Gyroscope.csv`)
+// Timestamps can be in nanoseconds, milliseconds or seconds, gyro is in rad/s, accelerometer in m/s² (or g for `gF` columns)
+
+use std::path::Path;
+use super::{ GyroSource, FileMetadata, TimeIMU };
+use crate::GyroflowCoreError;
+
+// Android sensor axes are x right, y up, z out of the screen, which is the same as gyroflow's camera axes for the back camera
+const ANDROID_IMU_ORIENTATION: &str = "XYZ";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Sensor { Gyro, Accl, AcclG }
+
+#[derive(Clone, Copy)]
+enum TimeUnit { Nanoseconds, Milliseconds, Seconds, Auto }
+
+// Lowercase header without spaces, underscores and units, eg. "Gyroscope X (rad/s)" -> "gyroscopex"
+fn normalize_header(h: &str) -> String {
+    let h = h.split('(').next().unwrap_or_default();
+    h.trim().trim_matches('"').chars().filter(|c| c.is_alphanumeric()).collect::<String>().to_ascii_lowercase()
+}
+
+fn axis_column(name: &str) -> Option<(Sensor, usize)> {
+    let axis = match name.chars().last()? { 'x' => 0, 'y' => 1, 'z' => 2, _ => return None };
+    let prefix = &name[..name.len() - 1];
+    match prefix {
+        "gyr" | "gyro" | "gyroscope" | "w" | "rotationrate" => Some((Sensor::Gyro, axis)),
+        "acc" | "accel" | "accelerometer" | "a" | "acceleration" => Some((Sensor::Accl, axis)),
+        "gf" => Some((Sensor::AcclG, axis)),
+        _ => None
+    }
+}
+
+fn interpolate(samples: &[(f64, [f64; 3])], ts: f64) -> Option<[f64; 3]> {
+    let i = samples.partition_point(|x| x.0 < ts);
+    match (samples.get(i.wrapping_sub(1)), samples.get(i)) {
+        (Some(a), Some(b)) if b.0 > a.0 => {
+            let f = (ts - a.0) / (b.0 - a.0);
+            Some([0, 1, 2].map(|j| a.1[j] + (b.1[j] - a.1[j]) * f))
+        },
+        (Some(a), _) => Some(a.1),
+        (None, Some(b)) => Some(b.1),
+        _ => None
+    }
+}
+
+impl GyroSource {
+    pub fn from_android_sensor_csv(path: &Path) -> Result<GyroSource, GyroflowCoreError> {
+        let data = std::fs::read_to_string(path)?;
+        let mut lines = data.lines().filter(|l| !l.trim().is_empty() && !l.starts_with('#'));
+
+        let header_line = lines.next().ok_or(GyroflowCoreError::InvalidData)?;
+        let delimiter = if header_line.contains(';') { ';' } else if header_line.contains('\t') { '\t' } else { ',' };
+        let headers = header_line.split(delimiter).map(|h| (h.to_ascii_lowercase(), normalize_header(h))).collect::<Vec<_>>();
+
+        let time_col = headers.iter().position(|(_, h)| h == "timestamp" || h == "time" || h == "t" || h.starts_with("time"))
+            .ok_or(GyroflowCoreError::InvalidData)?;
+        let time_unit = {
+            let raw = &headers[time_col].0;
+            if raw.contains("ns") || raw.contains("nano") { TimeUnit::Nanoseconds }
+            else if raw.contains("ms") || raw.contains("milli") { TimeUnit::Milliseconds }
+            else if raw.contains("(s)") || raw.contains("sec") { TimeUnit::Seconds }
+            else { TimeUnit::Auto }
+        };
+        let sensor_col = headers.iter().position(|(_, h)| h == "sensor" || h == "type" || h == "sensortype");
+        let plain_axes = [ "x", "y", "z" ].map(|a| headers.iter().position(|(_, h)| h == a));
+        let mut axis_cols = headers.iter().enumerate().filter_map(|(i, (_, h))| axis_column(h).map(|(s, a)| (i, s, a))).collect::<Vec<_>>();
+        if axis_cols.is_empty() && sensor_col.is_none() {
+            let file_name = path.file_stem().map(|x| x.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
+            let sensor = if file_name.contains("gyro") { Some(Sensor::Gyro) } else if file_name.contains("acc") { Some(Sensor::Accl) } else { None };
+            if let Some(sensor) = sensor {
+                axis_cols.extend(plain_axes.iter().enumerate().filter_map(|(a, i)| Some(((*i)?, sensor, a))));
+            }
+        }
+
+        let mut gyro = Vec::new();
+        let mut accl = Vec::new();
+        for line in lines {
+            let cols = line.split(delimiter).map(|x| x.trim().trim_matches('"')).collect::<Vec<_>>();
+            let ts = match cols.get(time_col).and_then(|x| x.parse::<f64>().ok()) { Some(ts) => ts, None => continue };
+            let value = |i: Option<usize>| i.and_then(|i| cols.get(i)).and_then(|x| x.parse::<f64>().ok());
+
+            if let (Some(sensor_col), [Some(_), Some(_), Some(_)]) = (sensor_col, plain_axes) {
+                let sensor = cols.get(sensor_col).map(|x| x.to_ascii_lowercase()).unwrap_or_default();
+                if let (Some(x), Some(y), Some(z)) = (value(plain_axes[0]), value(plain_axes[1]), value(plain_axes[2])) {
+                    if sensor.contains("gyro") {
+                        gyro.push((ts, [x, y, z]));
+                    } else if sensor.contains("acc") && !sensor.contains("linear") {
+                        accl.push((ts, [x, y, z]));
+                    }
+                }
+                continue;
+            }
+
+            for sensor in [Sensor::Gyro, Sensor::Accl, Sensor::AcclG] {
+                let mut v = [None; 3];
+                for &(i, s, a) in &axis_cols {
+                    if s == sensor { v[a] = value(Some(i)); }
+                }
+                if let [Some(x), Some(y), Some(z)] = v {
+                    match sensor {
+                        Sensor::Gyro  => gyro.push((ts, [x, y, z])),
+                        Sensor::Accl  => accl.push((ts, [x, y, z])),
+                        Sensor::AcclG => accl.push((ts, [x * 9.80665, y * 9.80665, z * 9.80665])),
+                    }
+                }
+            }
+        }
+        if gyro.len() < 2 { return Err(GyroflowCoreError::InvalidData); }
+        gyro.sort_by(|a, b| a.0.total_cmp(&b.0));
+        accl.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        // Pick the unit from the sample interval if the header doesn't say, IMU rates are between ~10 Hz and a few kHz
+        let to_ms = match time_unit {
+            TimeUnit::Nanoseconds  => 1e-6,
+            TimeUnit::Milliseconds => 1.0,
+            TimeUnit::Seconds      => 1000.0,
+            TimeUnit::Auto => {
+                let avg_interval = (gyro[gyro.len() - 1].0 - gyro[0].0) / (gyro.len() - 1) as f64;
+                if avg_interval > 1e4 { 1e-6 } else if avg_interval > 0.1 { 1.0 } else { 1000.0 }
+            }
+        };
+
+        let first_ts = gyro[0].0;
+        let raw_imu = gyro.iter().map(|(ts, g)| TimeIMU {
+            timestamp_ms: (ts - first_ts) * to_ms,
+            gyro: Some(g.map(|x| x.to_degrees())),
+            accl: interpolate(&accl, *ts),
+            magn: None
+        }).collect::<Vec<_>>();
+
+        let duration_ms = raw_imu.last().map(|x| x.timestamp_ms).unwrap_or_default();
+        if duration_ms <= 0.0 { return Err(GyroflowCoreError::InvalidData); }
+
+        let md = FileMetadata {
+            imu_orientation: Some(ANDROID_IMU_ORIENTATION.into()),
+            detected_source: Some("Android sensor log".into()),
+            raw_imu,
+            ..Default::default()
+        };
+
+        let mut gyro_source = GyroSource::new();
+        gyro_source.duration_ms = duration_ms;
+        gyro_source.load_from_telemetry(md);
+        Ok(gyro_source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(name: &str, csv: &str) -> Result<GyroSource, GyroflowCoreError> {
+        let path = std::env::temp_dir().join(format!("gyroflow_test_{name}.csv"));
+        std::fs::write(&path, csv).unwrap();
+        let result = GyroSource::from_android_sensor_csv(&path);
+        let _ = std::fs::remove_file(&path);
+        result
+    }
+
+    fn assert_close(a: [f64; 3], b: [f64; 3]) {
+        assert!(a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-9), "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn axis_columns_nanoseconds() {
+        let gyro = parse("android_axis_columns", "\
+Timestamp (ns),gyrX,gyrY,gyrZ,accX,accY,accZ
+0,0.1,0.2,0.3,,,
+5000000,,,,1,2,3
+10000000,0.4,0.5,0.6,,,
+15000000,,,,3,4,5
+20000000,0.7,0.8,0.9,,,
+").unwrap();
+
+        let imu = &gyro.file_metadata.raw_imu;
+        assert_eq!(imu.iter().map(|x| x.timestamp_ms).collect::<Vec<_>>(), [0.0, 10.0, 20.0]);
+        assert_close(imu[1].gyro.unwrap(), [0.4f64.to_degrees(), 0.5f64.to_degrees(), 0.6f64.to_degrees()]);
+        // Accelerometer rows are interpolated at the gyro timestamps, and held at the ends
+        assert_close(imu[0].accl.unwrap(), [1.0, 2.0, 3.0]);
+        assert_close(imu[1].accl.unwrap(), [2.0, 3.0, 4.0]);
+        assert_close(imu[2].accl.unwrap(), [3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn sensor_column_milliseconds() {
+        let gyro = parse("android_sensor_column", "\
+time_ms;sensor;x;y;z
+100;Gyroscope;0.1;0.2;0.3
+102;Accelerometer;1;2;3
+105;Gyroscope;0.4;0.5;0.6
+107;LinearAcceleration;9;9;9
+110;Gyroscope;0.7;0.8;0.9
+112;Accelerometer;3;4;5
+").unwrap();
+
+        let imu = &gyro.file_metadata.raw_imu;
+        assert_eq!(imu.iter().map(|x| x.timestamp_ms).collect::<Vec<_>>(), [0.0, 5.0, 10.0]);
+        assert_close(imu[2].gyro.unwrap(), [0.7f64.to_degrees(), 0.8f64.to_degrees(), 0.9f64.to_degrees()]);
+        assert_close(imu[0].accl.unwrap(), [1.0, 2.0, 3.0]);
+        assert_close(imu[1].accl.unwrap(), [1.6, 2.6, 3.6]);
+        assert_close(imu[2].accl.unwrap(), [2.6, 3.6, 4.6]);
+    }
+
+    #[test]
+    fn axis_transform() {
+        let gyro = parse("android_axis_transform", "\
+time,gyrX,gyrY,gyrZ,accX,accY,accZ
+0,0.1,-0.2,0.3,1,-2,3
+10,0.4,-0.5,0.6,4,-5,6
+").unwrap();
+
+        // Android axes are already gyroflow's camera axes, the samples must come through unchanged
+        assert_eq!(gyro.imu_orientation.as_deref(), Some(ANDROID_IMU_ORIENTATION));
+        assert_eq!(gyro.raw_imu.len(), 2);
+        assert_close(gyro.raw_imu[0].gyro.unwrap(), [0.1f64.to_degrees(), -0.2f64.to_degrees(), 0.3f64.to_degrees()]);
+        assert_close(gyro.raw_imu[1].accl.unwrap(), [4.0, -5.0, 6.0]);
+    }
+
+    #[test]
+    fn no_gyro() {
+        assert!(matches!(parse("android_no_gyro", "time,accX,accY,accZ\n0,1,2,3\n10,1,2,3\n"), Err(GyroflowCoreError::InvalidData)));
+    }
+}
@@ -0,0 +1,129 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Decoded DJI flight records (`/dji_file/DJIFlightRecord_*.json` written by the RC-Pro or the O3 Air Unit).
+// Every entry in `osdFlightRecordData.updateItems` has an epoch `timestamp` in milliseconds and either raw IMU
+// (`gyroX/Y/Z` in deg/s, `accX/Y/Z` in g) or the attitude (`pitch`, `roll`, `yaw` in degrees), in the NED body frame
+
+use nalgebra::*;
+use super::{ GyroSource, FileMetadata, TimeIMU, TimeQuat, Quat64 };
+use crate::GyroflowCoreError;
+
+// NED body axes (x forward, y right, z down) to gyroflow's camera axes (x right, y up, z backward)
+const DJI_IMU_ORIENTATION: &str = "Yzx";
+
+impl GyroSource {
+    pub fn from_dji_flight_record_json(json: &str, video_start_epoch_ms: i64) -> Result<GyroSource, GyroflowCoreError> {
+        let v: serde_json::Value = serde_json::from_str(json)?;
+        let items = v.get("osdFlightRecordData").and_then(|x| x.get("updateItems")).and_then(|x| x.as_array()).ok_or(GyroflowCoreError::InvalidData)?;
+
+        let body_to_camera = Rotation3::from_matrix_unchecked(Matrix3::new(
+            0.0,  1.0,  0.0,
+            0.0,  0.0, -1.0,
+           -1.0,  0.0,  0.0
+        ));
+        let body_to_camera = UnitQuaternion::from_rotation_matrix(&body_to_camera);
+
+        let mut raw_imu = Vec::new();
+        let mut quaternions = TimeQuat::new();
+        for item in items {
+            let field = |name: &str| item.get(name).and_then(|x| x.as_f64());
+            let timestamp_ms = match field("timestamp") {
+                Some(ts) => ts - video_start_epoch_ms as f64,
+                None => continue
+            };
+            // Keep a bit before the video start for interpolation
+            if timestamp_ms < -1000.0 { continue; }
+
+            if let (Some(gx), Some(gy), Some(gz)) = (field("gyroX"), field("gyroY"), field("gyroZ")) {
+                let accl = match (field("accX"), field("accY"), field("accZ")) {
+                    (Some(ax), Some(ay), Some(az)) => Some([ax * 9.80665, ay * 9.80665, az * 9.80665]),
+                    _ => None
+                };
+                raw_imu.push(TimeIMU { timestamp_ms, gyro: Some([gx, gy, gz]), accl, magn: None });
+            } else if let (Some(pitch), Some(roll), Some(yaw)) = (field("pitch"), field("roll"), field("yaw")) {
+                let body = Quat64::from_euler_angles(roll.to_radians(), pitch.to_radians(), yaw.to_radians());
+                quaternions.insert((timestamp_ms * 1000.0).round() as i64, body_to_camera * body * body_to_camera.inverse());
+            }
+        }
+        raw_imu.sort_by(|a, b| a.timestamp_ms.total_cmp(&b.timestamp_ms));
+
+        let last_ts = raw_imu.last().map(|x| x.timestamp_ms).or_else(|| quaternions.keys().next_back().map(|x| *x as f64 / 1000.0));
+        let duration_ms = match last_ts {
+            Some(ts) if ts > 0.0 => ts,
+            _ => return Err(GyroflowCoreError::InvalidData)
+        };
+
+        let md = FileMetadata {
+            imu_orientation: if raw_imu.is_empty() { None } else { Some(DJI_IMU_ORIENTATION.into()) },
+            detected_source: Some("DJI flight record".into()),
+            raw_imu,
+            quaternions,
+            ..Default::default()
+        };
+
+        let mut gyro = GyroSource::new();
+        gyro.duration_ms = duration_ms;
+        gyro.load_from_telemetry(md);
+        Ok(gyro)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VIDEO_START: i64 = 1660000000000;
+
+    // Excerpt of a decoded RC-Pro flight record, one attitude change at a time
+    const ATTITUDE_RECORD: &str = r#"{ "osdFlightRecordData": { "updateItems": [
+        { "timestamp": 1659999998000, "pitch": 10.0, "roll": 10.0, "yaw": 10.0 },
+        { "timestamp": 1660000000000, "pitch":  0.0, "roll":  0.0, "yaw":  0.0 },
+        { "timestamp": 1660000000100, "pitch":  0.0, "roll":  0.0, "yaw": 90.0 },
+        { "timestamp": 1660000000200, "pitch": 30.0, "roll":  0.0, "yaw":  0.0 },
+        { "timestamp": 1660000000300, "pitch":  0.0, "roll": 45.0, "yaw":  0.0 },
+        { "pitch": 5.0, "roll": 5.0, "yaw": 5.0 }
+    ] } }"#;
+
+    const IMU_RECORD: &str = r#"{ "osdFlightRecordData": { "updateItems": [
+        { "timestamp": 1660000000000, "gyroX": 1.0, "gyroY": 2.0, "gyroZ": 3.0, "accX": 0.0, "accY": 0.0, "accZ": 1.0 },
+        { "timestamp": 1660000000005, "gyroX": 1.5, "gyroY": 2.5, "gyroZ": 3.5 }
+    ] } }"#;
+
+    #[test]
+    fn attitude_in_camera_axes() {
+        let gyro = GyroSource::from_dji_flight_record_json(ATTITUDE_RECORD, VIDEO_START).unwrap();
+        let quats = &gyro.file_metadata.quaternions;
+        assert_eq!(quats.keys().copied().collect::<Vec<_>>(), vec![0, 100_000, 200_000, 300_000]);
+
+        // Yaw turns around the down axis, pitch around the right axis and roll around the forward axis
+        let expected = [
+            (0,       Quat64::identity()),
+            (100_000, Quat64::from_axis_angle(&-Vector3::y_axis(), 90.0_f64.to_radians())),
+            (200_000, Quat64::from_axis_angle(&Vector3::x_axis(),  30.0_f64.to_radians())),
+            (300_000, Quat64::from_axis_angle(&-Vector3::z_axis(), 45.0_f64.to_radians())),
+        ];
+        for (ts, q) in expected {
+            let angle = quats[&ts].angle_to(&q).to_degrees();
+            assert!(angle < 1e-6, "Rotation at {ts} off by {angle}°");
+        }
+    }
+
+    #[test]
+    fn imu_samples_aligned_to_video_start() {
+        let gyro = GyroSource::from_dji_flight_record_json(IMU_RECORD, VIDEO_START).unwrap();
+        let imu = &gyro.file_metadata.raw_imu;
+        assert_eq!(gyro.file_metadata.imu_orientation.as_deref(), Some(DJI_IMU_ORIENTATION));
+        assert_eq!(imu.len(), 2);
+        assert_eq!(imu[0].timestamp_ms, 0.0);
+        assert_eq!(imu[1].timestamp_ms, 5.0);
+        assert_eq!(imu[0].gyro, Some([1.0, 2.0, 3.0]));
+        assert_eq!(imu[0].accl, Some([0.0, 0.0, 9.80665]));
+        assert_eq!(imu[1].accl, None);
+    }
+
+    #[test]
+    fn missing_update_items() {
+        assert!(matches!(GyroSource::from_dji_flight_record_json(r#"{ "osdFlightRecordData": {} }"#, VIDEO_START), Err(GyroflowCoreError::InvalidData)));
+    }
+}
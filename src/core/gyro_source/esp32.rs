@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+// Raw IMU logs of ESP32-based flight controllers (INAV, Betaflight).
+// The log is a sequence of 16-byte little-endian records: u32 timestamp in microseconds (wrapping),
+// then gyro x, y, z and accelerometer x, y, z as i16, in the body frame (x forward, y right, z down).
+// Samples are raw MPU-6000 readings at the default full scale ranges of the firmware: ±2000 °/s and ±16 g
+
+use super::{ GyroSource, FileMetadata, TimeIMU };
+use crate::GyroflowCoreError;
+
+const RECORD_SIZE: usize = 16;
+const GYRO_LSB_PER_DPS: f64 = 16.4;
+const ACCL_LSB_PER_G: f64 = 2048.0;
+
+// NED body axes (x forward, y right, z down) to gyroflow's camera axes (x right, y up, z backward)
+const ESP32_IMU_ORIENTATION: &str = "Yzx";
+
+impl GyroSource {
+    pub fn from_esplog_binary(bytes: &[u8]) -> Result<GyroSource, GyroflowCoreError> {
+        if bytes.len() < RECORD_SIZE || bytes.len() % RECORD_SIZE != 0 { return Err(GyroflowCoreError::InvalidData); }
+
+        let mut raw_imu = Vec::with_capacity(bytes.len() / RECORD_SIZE);
+        let mut first_ts = None;
+        let mut prev_ts = 0u32;
+        let mut wraps = 0i64;
+        for rec in bytes.chunks_exact(RECORD_SIZE) {
+            let ts = u32::from_le_bytes([rec[0], rec[1], rec[2], rec[3]]);
+            let v = |i: usize| i16::from_le_bytes([rec[4 + i * 2], rec[5 + i * 2]]) as f64;
+
+            if first_ts.is_some() && ts < prev_ts { wraps += 1; }
+            prev_ts = ts;
+            let ts_us = ts as i64 + (wraps << 32);
+            let first_ts = *first_ts.get_or_insert(ts_us);
+
+            raw_imu.push(TimeIMU {
+                timestamp_ms: (ts_us - first_ts) as f64 / 1000.0,
+                gyro: Some([v(0) / GYRO_LSB_PER_DPS, v(1) / GYRO_LSB_PER_DPS, v(2) / GYRO_LSB_PER_DPS]),
+                accl: Some([v(3) / ACCL_LSB_PER_G * 9.80665, v(4) / ACCL_LSB_PER_G * 9.80665, v(5) / ACCL_LSB_PER_G * 9.80665]),
+                magn: None
+            });
+        }
+
+        let duration_ms = match raw_imu.last() {
+            Some(x) if x.timestamp_ms > 0.0 => x.timestamp_ms,
+            _ => return Err(GyroflowCoreError::InvalidData)
+        };
+
+        let md = FileMetadata {
+            imu_orientation: Some(ESP32_IMU_ORIENTATION.into()),
+            detected_source: Some("ESP32 flight controller".into()),
+            raw_imu,
+            ..Default::default()
+        };
+
+        let mut gyro = GyroSource::new();
+        gyro.duration_ms = duration_ms;
+        gyro.load_from_telemetry(md);
+        Ok(gyro)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(ts: u32, v: [i16; 6]) -> Vec<u8> {
+        let mut rec = ts.to_le_bytes().to_vec();
+        for x in v { rec.extend_from_slice(&x.to_le_bytes()); }
+        rec
+    }
+
+    #[test]
+    fn scaling() {
+        let mut bytes = record(1000, [164, -328, 1640, 2048, -1024, 0]);
+        bytes.extend(record(2000, [0; 6]));
+        let gyro = GyroSource::from_esplog_binary(&bytes).unwrap();
+
+        let imu = &gyro.file_metadata.raw_imu;
+        assert_eq!(imu.len(), 2);
+        let g = imu[0].gyro.unwrap();
+        let a = imu[0].accl.unwrap();
+        for (v, e) in g.iter().zip([10.0, -20.0, 100.0]) { assert!((v - e).abs() < 1e-9, "gyro {v} != {e}"); }
+        for (v, e) in a.iter().zip([9.80665, -4.903325, 0.0]) { assert!((v - e).abs() < 1e-9, "accl {v} != {e}"); }
+        assert_eq!(imu[0].timestamp_ms, 0.0);
+        assert_eq!(imu[1].timestamp_ms, 1.0);
+
+        assert_eq!(gyro.imu_orientation.as_deref(), Some(ESP32_IMU_ORIENTATION));
+    }
+
+    #[test]
+    fn timestamp_wrap() {
+        let mut bytes = record(u32::MAX - 499, [0; 6]);
+        bytes.extend(record(500, [0; 6]));
+        bytes.extend(record(1500, [0; 6]));
+        let gyro = GyroSource::from_esplog_binary(&bytes).unwrap();
+
+        let ts: Vec<f64> = gyro.file_metadata.raw_imu.iter().map(|x| x.timestamp_ms).collect();
+        assert_eq!(ts, [0.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut bytes = record(1000, [0; 6]);
+        bytes.extend(record(2000, [0; 6]));
+        bytes.truncate(bytes.len() - 3);
+        assert!(matches!(GyroSource::from_esplog_binary(&bytes), Err(GyroflowCoreError::InvalidData)));
+        assert!(matches!(GyroSource::from_esplog_binary(&bytes[..10]), Err(GyroflowCoreError::InvalidData)));
+        assert!(matches!(GyroSource::from_esplog_binary(&[]), Err(GyroflowCoreError::InvalidData)));
+    }
+}
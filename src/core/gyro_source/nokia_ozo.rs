@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Nokia OZO spatial audio telemetry (`*_imu.json` exported by OZO Creator).
+// The file contains only the orientation of the microphone array, there are no raw gyro samples,
+// so the quaternions are used directly instead of being integrated.
+// Every entry in `orientation` has a `time` in seconds since the recording start and a `quaternion` as [w, x, y, z]
+
+use nalgebra::*;
+use super::{ GyroSource, FileMetadata, TimeQuat, Quat64 };
+use crate::GyroflowCoreError;
+
+impl GyroSource {
+    pub fn from_ozo_json(json: &str) -> Result<GyroSource, GyroflowCoreError> {
+        let v: serde_json::Value = serde_json::from_str(json)?;
+        let items = v.get("orientation").and_then(|x| x.as_array()).ok_or(GyroflowCoreError::InvalidData)?;
+
+        // The microphone array is mounted on top of the camera body, rotated 90° around the vertical axis,
+        // with x forward, y left, z up. Gyroflow's camera axes are x right, y up, z backward
+        let ozo_to_camera = Rotation3::from_matrix_unchecked(Matrix3::new(
+            0.0, -1.0,  0.0,
+            0.0,  0.0,  1.0,
+           -1.0,  0.0,  0.0
+        ));
+        let ozo_to_camera = UnitQuaternion::from_rotation_matrix(&ozo_to_camera);
+
+        let mut quaternions = TimeQuat::new();
+        for item in items {
+            let time = item.get("time").and_then(|x| x.as_f64());
+            let q = item.get("quaternion").and_then(|x| x.as_array()).map(|x| x.iter().filter_map(|v| v.as_f64()).collect::<Vec<_>>());
+            if let (Some(time), Some(q)) = (time, q) {
+                if q.len() != 4 { continue; }
+                let ozo = Quat64::from_quaternion(Quaternion::new(q[0], q[1], q[2], q[3]));
+                quaternions.insert((time * 1_000_000.0).round() as i64, ozo_to_camera * ozo * ozo_to_camera.inverse());
+            }
+        }
+
+        let duration_ms = match quaternions.keys().next_back() {
+            Some(ts) if *ts > 0 => *ts as f64 / 1000.0,
+            _ => return Err(GyroflowCoreError::InvalidData)
+        };
+
+        let md = FileMetadata {
+            detected_source: Some("Nokia OZO".into()),
+            quaternions,
+            ..Default::default()
+        };
+
+        let mut gyro = GyroSource::new();
+        gyro.duration_ms = duration_ms;
+        gyro.load_from_telemetry(md);
+        Ok(gyro)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Excerpt of an OZO Creator export, quaternions as [w, x, y, z] in the microphone array axes
+    const OZO_JSON: &str = r#"{ "orientation": [
+        { "time": 0.0, "quaternion": [1.0, 0.0, 0.0, 0.0] },
+        { "time": 0.5, "quaternion": [0.7071067811865476, 0.0, 0.0, 0.7071067811865476] },
+        { "time": 1.0, "quaternion": [0.9659258262890683, 0.25881904510252074, 0.0, 0.0] },
+        { "time": 1.5, "quaternion": [1.0, 0.0, 0.0] }
+    ] }"#;
+
+    #[test]
+    fn orientation_in_camera_axes() {
+        let gyro = GyroSource::from_ozo_json(OZO_JSON).unwrap();
+        // Orientation only, used directly instead of integrating
+        assert_eq!(gyro.integration_method, 0);
+        assert!(gyro.file_metadata.raw_imu.is_empty());
+        assert_eq!(gyro.quaternions.keys().copied().collect::<Vec<_>>(), vec![0, 500_000, 1_000_000]);
+
+        // 90° around the array's up axis and 30° around its forward axis
+        let expected = [
+            (0,         Quat64::identity()),
+            (500_000,   Quat64::from_axis_angle(&Vector3::y_axis(),  90.0_f64.to_radians())),
+            (1_000_000, Quat64::from_axis_angle(&-Vector3::z_axis(), 30.0_f64.to_radians())),
+        ];
+        for (ts, q) in expected {
+            let angle = gyro.quaternions[&ts].angle_to(&q).to_degrees();
+            assert!(angle < 1e-6, "Orientation at {ts} off by {angle}°");
+        }
+    }
+
+    #[test]
+    fn missing_orientation() {
+        assert!(matches!(GyroSource::from_ozo_json(r#"{ "orientation": [] }"#), Err(GyroflowCoreError::InvalidData)));
+        assert!(matches!(GyroSource::from_ozo_json(r#"{ "position": [] }"#),    Err(GyroflowCoreError::InvalidData)));
+    }
+}
@@ -149,11 +149,30 @@ impl GyroIntegrator for VQFIntegrator {
 ///////////////////////////////////////////////////////////////////////////////
 ///////////////////////////////////////////////////////////////////////////////
 
-impl GyroIntegrator for SimpleGyroIntegrator {
-    fn integrate(imu_data: &[TimeIMU], duration_ms: f64) -> TimeQuat {
+// Estimate the initial roll and pitch from the gravity direction, averaging the accelerometer over the first `duration_us` (assumed static).
+// `accel_samples` are (timestamp_us, x, y, z) in the raw IMU axes, same as `TimeIMU::accl`. Yaw can't be observed without a magnetometer, so it's left as in the default orientation
+pub fn compute_initial_attitude_from_accelerometer(accel_samples: &[(i64, f64, f64, f64)], duration_us: i64) -> UnitQuaternion<f64> {
+    let default_orientation = UnitQuaternion::from_euler_angles(std::f64::consts::FRAC_PI_2, 0.0, 0.0);
+    let first_ts = match accel_samples.first() { Some(x) => x.0, None => return default_orientation };
+
+    let mut sum = Vector3::zeros();
+    for (ts, x, y, z) in accel_samples {
+        if *ts - first_ts > duration_us { break; }
+        sum += Vector3::new(-y, *x, *z);
+    }
+    let acc = match sum.try_normalize(0.0) { Some(v) => v, None => return default_orientation };
+
+    // Minimal rotation bringing the measured gravity to world up. Its axis is horizontal, so it only affects roll and pitch
+    let acc_world = default_orientation * acc;
+    let correction = UnitQuaternion::rotation_between(&acc_world, &Vector3::z()).unwrap_or_else(|| UnitQuaternion::from_axis_angle(&Vector3::x_axis(), std::f64::consts::PI));
+    correction * default_orientation
+}
+
+impl SimpleGyroIntegrator {
+    pub fn integrate_with_initial(imu_data: &[TimeIMU], duration_ms: f64, initial: Quat64) -> TimeQuat {
         if imu_data.is_empty() { return BTreeMap::new(); }
         let mut quats = BTreeMap::new();
-        let mut orientation = UnitQuaternion::from_euler_angles(std::f64::consts::FRAC_PI_2, 0.0, 0.0);
+        let mut orientation = initial;
 
         let sample_time_ms = duration_ms / imu_data.len() as f64;
         let mut prev_time = imu_data[0].timestamp_ms - sample_time_ms;
@@ -178,6 +197,11 @@ impl GyroIntegrator for SimpleGyroIntegrator {
         quats
     }
 }
+impl GyroIntegrator for SimpleGyroIntegrator {
+    fn integrate(imu_data: &[TimeIMU], duration_ms: f64) -> TimeQuat {
+        Self::integrate_with_initial(imu_data, duration_ms, UnitQuaternion::from_euler_angles(std::f64::consts::FRAC_PI_2, 0.0, 0.0))
+    }
+}
 
 ///////////////////////////////////////////////////////////////////////////////
 ///////////////////////////////////////////////////////////////////////////////
@@ -233,13 +257,20 @@ impl GyroIntegrator for SimpleGyroAccelIntegrator {
 
 impl GyroIntegrator for MahonyIntegrator {
     fn integrate(imu_data: &[TimeIMU], duration_ms: f64) -> TimeQuat {
+        let init_pos = UnitQuaternion::from_euler_angles(std::f64::consts::FRAC_PI_2, 0.0, 0.0);
+        Self::integrate_with_gains(imu_data, duration_ms, 0.5, 0.0, init_pos)
+    }
+}
+
+impl MahonyIntegrator {
+    // `kp` - proportional gain, `ki` - integral gain, which also removes a constant gyro bias
+    pub fn integrate_with_gains(imu_data: &[TimeIMU], duration_ms: f64, kp: f64, ki: f64, initial: Quat64) -> TimeQuat {
         if imu_data.is_empty() { return BTreeMap::new(); }
 
         let mut quats = BTreeMap::new();
-        let init_pos = UnitQuaternion::from_euler_angles(std::f64::consts::FRAC_PI_2, 0.0, 0.0);
         let sample_time_s = duration_ms / 1000.0 / imu_data.len() as f64;
 
-        let mut ahrs = Mahony::new_with_quat(sample_time_s, 0.5, 0.0, init_pos);
+        let mut ahrs = Mahony::new_with_quat(sample_time_s, kp, ki, initial);
         let mut prev_time = imu_data[0].timestamp_ms - sample_time_s;
         for v in imu_data {
             if let Some(g) = v.gyro.as_ref() {
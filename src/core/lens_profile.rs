@@ -1,7 +1,7 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
 
-use std::collections::{ HashSet, BTreeMap };
+use std::collections::{ HashSet, HashMap, BTreeMap };
 use itertools::Itertools;
 
 use serde::{ Serialize, Deserialize };
@@ -11,6 +11,14 @@ use crate::stabilization::distortion_models::DistortionModel;
 #[cfg(feature = "opencv")]
 use super::LensCalibrator;
 
+#[derive(thiserror::Error, Debug)]
+pub enum LensProfileError {
+    #[error("JSON error {0:?}")]                  JSONError(#[from] serde_json::Error),
+    #[error("Unsupported profile version {0}")]   UnsupportedVersion(u64),
+    #[error("Missing camera matrix or calibration dimensions")] MissingCalibration,
+    #[error("Missing parameter {0}")]             MissingParameter(String),
+}
+
 #[derive(Deserialize, Serialize, Default, Clone, Debug)]
 pub struct Dimensions { pub w: usize, pub h: usize }
 
@@ -82,11 +90,72 @@ pub struct LensProfile {
 
 impl LensProfile {
     pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
-        serde_json::from_str(json)
+        let profile: Self = serde_json::from_str(json)?;
+        if let Some(model) = &profile.distortion_model {
+            // Custom models have to be registered before loading the profile
+            if crate::stabilization::distortion_models::DistortionModel::lookup_by_name(model).is_none() {
+                log::warn!("Unknown distortion model {model}, using the default one");
+            }
+        }
+        Ok(profile)
+    }
+
+    // Legacy format written by the original (Python) calibrator, with `use_opencv_fisheye`/`use_opencv_standard` flags
+    // and the coefficients in either `fisheye_params` or `calib_params`
+    pub fn from_calibrator_json_v1(s: &str) -> Result<Self, LensProfileError> {
+        let v: serde_json::Value = serde_json::from_str(s)?;
+        let mut profile = Self::from_json(s)?;
+
+        let use_standard = v.get("use_opencv_standard").and_then(|x| x.as_bool()).unwrap_or_default() &&
+                          !v.get("use_opencv_fisheye").and_then(|x| x.as_bool()).unwrap_or_default();
+        if use_standard {
+            if let Some(calib_params) = v.get("calib_params") {
+                profile.fisheye_params = <CameraParams as Deserialize>::deserialize(calib_params)?;
+            }
+        }
+        // An explicit model (eg. added by a later edit of the profile) takes precedence over the flags
+        if profile.distortion_model.is_none() {
+            profile.distortion_model = Some(if use_standard { "opencv_standard" } else { "opencv_fisheye" }.into());
+        }
+        if profile.calibrator_version.is_empty() {
+            profile.calibrator_version = "1".into();
+        }
+        profile.validate_calibration()?;
+        Ok(profile)
+    }
+
+    // Current format, with explicit `distortion_model`
+    pub fn from_calibrator_json_v2(s: &str) -> Result<Self, LensProfileError> {
+        let profile = Self::from_json(s)?;
+        if profile.calibrator_version.is_empty() {
+            return Err(LensProfileError::MissingParameter("calibrator_version".into()));
+        }
+        profile.validate_calibration()?;
+        Ok(profile)
+    }
+
+    // Detects the format from the `version` field. Profiles without it are v1 if they have the legacy `use_opencv_*` flags
+    pub fn from_calibrator_json_auto(s: &str) -> Result<Self, LensProfileError> {
+        let v: serde_json::Value = serde_json::from_str(s)?;
+        let version = v.get("version").and_then(|x| x.as_u64()).unwrap_or_else(|| {
+            if v.get("use_opencv_fisheye").is_some() || v.get("use_opencv_standard").is_some() { 1 } else { 2 }
+        });
+        match version {
+            1 => Self::from_calibrator_json_v1(s),
+            2 => Self::from_calibrator_json_v2(s),
+            _ => Err(LensProfileError::UnsupportedVersion(version))
+        }
+    }
+
+    fn validate_calibration(&self) -> Result<(), LensProfileError> {
+        if self.fisheye_params.camera_matrix.len() != 3 || self.calib_dimension.w == 0 || self.calib_dimension.h == 0 {
+            return Err(LensProfileError::MissingCalibration);
+        }
+        Ok(())
     }
 
     pub fn load_from_data(&mut self, data: &str) -> std::result::Result<(), crate::GyroflowCoreError> {
-        *self = Self::from_json(&data)?;
+        *self = Self::from_calibrator_json_auto(&data)?;
 
         // Trust lens profiles loaded from file
         self.official = true;
@@ -303,6 +372,55 @@ impl LensProfile {
         ret
     }
 
+    // OpenFX lens distortion parameters (Brown-Conrady: k1, k2, k3, p1, p2, fx, fy, cx, cy in calibration pixels),
+    // plus `width` and `height` of the calibration. Only `opencv_standard` profiles can be represented, returns an empty map otherwise
+    pub fn to_openfx_params(&self) -> HashMap<String, f64> {
+        let mut ret = HashMap::new();
+        if self.distortion_model.as_deref() != Some("opencv_standard") { return ret; }
+        let mat = match self.get_camera_matrix_internal() {
+            Some(mat) => mat,
+            None => return ret
+        };
+        let k = self.get_distortion_coeffs();
+        if k[5..].iter().any(|x| x.abs() > 0.0) {
+            log::warn!("Rational and thin prism coefficients are not supported by OpenFX lens distortion and will be ignored");
+        }
+        for (name, v) in [
+            ("k1", k[0]), ("k2", k[1]), ("k3", k[4]), ("p1", k[2]), ("p2", k[3]),
+            ("fx", mat[(0, 0)]), ("fy", mat[(1, 1)]), ("cx", mat[(0, 2)]), ("cy", mat[(1, 2)]),
+            ("width", self.calib_dimension.w as f64), ("height", self.calib_dimension.h as f64)
+        ] {
+            ret.insert(name.to_owned(), v);
+        }
+        ret
+    }
+
+    // Inverse of `to_openfx_params`. Missing distortion coefficients are 0, `width` and `height` default to twice the principal point
+    pub fn from_openfx_params(params: &HashMap<String, f64>) -> Result<LensProfile, LensProfileError> {
+        let get = |name: &str| params.get(name).copied().ok_or_else(|| LensProfileError::MissingParameter(name.to_owned()));
+        let (fx, fy, cx, cy) = (get("fx")?, get("fy")?, get("cx")?, get("cy")?);
+        let coeff = |name: &str| params.get(name).copied().unwrap_or_default();
+
+        let mut profile = LensProfile::default();
+        profile.distortion_model = Some("opencv_standard".into());
+        profile.calib_dimension = Dimensions {
+            w: params.get("width").copied().unwrap_or(cx * 2.0).round() as usize,
+            h: params.get("height").copied().unwrap_or(cy * 2.0).round() as usize
+        };
+        profile.orig_dimension = profile.calib_dimension.clone();
+        profile.asymmetrical = (cx - profile.calib_dimension.w as f64 / 2.0).abs() > 1e-6 || (cy - profile.calib_dimension.h as f64 / 2.0).abs() > 1e-6;
+        profile.input_horizontal_stretch = 1.0;
+        profile.input_vertical_stretch = 1.0;
+        profile.fisheye_params.camera_matrix = vec![
+            [fx,  0.0, cx],
+            [0.0, fy,  cy],
+            [0.0, 0.0, 1.0]
+        ];
+        profile.fisheye_params.distortion_coeffs = vec![coeff("k1"), coeff("k2"), coeff("p1"), coeff("p2"), coeff("k3")];
+        profile.validate_calibration()?;
+        Ok(profile)
+    }
+
     pub fn get_all_matching_profiles(&self) -> Vec<LensProfile> {
         let mut ret = Vec::with_capacity(self.compatible_settings.len() + 1);
         ret.push(self.clone());
@@ -564,3 +682,128 @@ impl LensProfile {
         }
     }
 }
+
+// Lens profiles of the cameras in a multi-camera rig (stereo pair, surround array) which share one gyro.
+// The isometry is the pose of the camera in the rig: its position and orientation relative to the gyro
+#[derive(Default, Clone, Debug)]
+pub struct CameraRigProfile {
+    pub cameras: Vec<(LensProfile, nalgebra::Isometry3<f64>)>
+}
+
+impl CameraRigProfile {
+    pub fn to_json(&self) -> String {
+        let cameras = self.cameras.iter().map(|(lens, pose)| {
+            let q = pose.rotation.quaternion();
+            serde_json::json!({
+                "lens": lens.get_json_value().unwrap_or_default(),
+                "translation": [pose.translation.x, pose.translation.y, pose.translation.z],
+                "rotation": [q.w, q.i, q.j, q.k]
+            })
+        }).collect::<Vec<_>>();
+        serde_json::to_string_pretty(&serde_json::json!({ "cameras": cameras })).unwrap_or_default()
+    }
+
+    pub fn from_json(s: &str) -> Result<Self, LensProfileError> {
+        let v: serde_json::Value = serde_json::from_str(s)?;
+        let cameras = v.get("cameras").and_then(|x| x.as_array()).ok_or_else(|| LensProfileError::MissingParameter("cameras".into()))?;
+        let floats = |cam: &serde_json::Value, name: &str, len: usize| -> Result<Vec<f64>, LensProfileError> {
+            let arr = cam.get(name).and_then(|x| x.as_array()).filter(|x| x.len() == len).ok_or_else(|| LensProfileError::MissingParameter(name.into()))?;
+            arr.iter().map(|x| x.as_f64().ok_or_else(|| LensProfileError::MissingParameter(name.into()))).collect()
+        };
+        Ok(Self {
+            cameras: cameras.iter().map(|cam| {
+                let lens: LensProfile = serde_json::from_value(cam.get("lens").cloned().ok_or_else(|| LensProfileError::MissingParameter("lens".into()))?)?;
+                let t = floats(cam, "translation", 3)?;
+                let r = floats(cam, "rotation", 4)?;
+                let rotation = nalgebra::UnitQuaternion::from_quaternion(nalgebra::Quaternion::new(r[0], r[1], r[2], r[3]));
+                Ok((lens, nalgebra::Isometry3::from_parts(nalgebra::Translation3::new(t[0], t[1], t[2]), rotation)))
+            }).collect::<Result<Vec<_>, LensProfileError>>()?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Written by the original Python calibrator, without `calibrator_version` and `distortion_model`
+    const V1_PROFILE: &str = r#"{
+        "name": "GoPro_HERO6_Black_4K_4by3_Wide",
+        "note": "",
+        "calibrated_by": "Elvin",
+        "camera_brand": "GoPro",
+        "camera_model": "HERO6 Black",
+        "lens_model": "",
+        "camera_setting": "4K 4:3 Wide",
+        "calib_dimension": { "w": 4000, "h": 3000 },
+        "orig_dimension": { "w": 4000, "h": 3000 },
+        "input_horizontal_stretch": 1,
+        "input_vertical_stretch": 1,
+        "num_images": 12,
+        "use_opencv_fisheye": true,
+        "fisheye_params": {
+            "RMS_error": 1.0875,
+            "camera_matrix": [[1856.42, 0.0, 2003.71], [0.0, 1855.16, 1501.42], [0.0, 0.0, 1.0]],
+            "distortion_coeffs": [0.0421, 0.0213, -0.0041, 0.0017]
+        },
+        "use_opencv_standard": false,
+        "calib_params": {}
+    }"#;
+
+    #[test]
+    fn load_v1_profile() {
+        let mut profile = LensProfile::default();
+        profile.load_from_data(V1_PROFILE).unwrap();
+
+        assert_eq!(profile.distortion_model.as_deref(), Some("opencv_fisheye"));
+        assert_eq!(profile.calibrator_version, "1");
+        assert_eq!((profile.calib_dimension.w, profile.calib_dimension.h), (4000, 3000));
+        assert_eq!(profile.fisheye_params.camera_matrix, vec![[1856.42, 0.0, 2003.71], [0.0, 1855.16, 1501.42], [0.0, 0.0, 1.0]]);
+        assert_eq!(profile.fisheye_params.distortion_coeffs, vec![0.0421, 0.0213, -0.0041, 0.0017]);
+    }
+
+    #[test]
+    fn v1_keeps_explicit_model() {
+        let json = V1_PROFILE.replace(r#""use_opencv_standard": false,"#, r#""use_opencv_standard": false, "distortion_model": "poly3","#);
+        let profile = LensProfile::from_calibrator_json_v1(&json).unwrap();
+        assert_eq!(profile.distortion_model.as_deref(), Some("poly3"));
+    }
+
+    #[test]
+    fn v2_requires_calibrator_version() {
+        let json = V1_PROFILE.replace(r#""use_opencv_fisheye": true,"#, r#""version": 2,"#);
+        assert!(matches!(LensProfile::from_calibrator_json_v2(&json), Err(LensProfileError::MissingParameter(_))));
+    }
+
+    fn openfx_params(values: &[(&str, f64)]) -> HashMap<String, f64> {
+        values.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+    }
+
+    #[test]
+    fn openfx_round_trip() {
+        // Centered and off-center principal point
+        for params in [
+            openfx_params(&[("k1", -0.2815), ("k2", 0.0921), ("k3", -0.0137), ("p1", 0.00041), ("p2", -0.00023), ("fx", 1623.5), ("fy", 1621.8), ("cx", 960.0), ("cy", 540.0), ("width", 1920.0), ("height", 1080.0)]),
+            openfx_params(&[("k1", 0.0113), ("k2", -0.0452), ("k3", 0.0078), ("p1", -0.0012), ("p2", 0.0009), ("fx", 2950.2), ("fy", 2948.7), ("cx", 1931.4), ("cy", 1088.6), ("width", 3840.0), ("height", 2160.0)]),
+        ] {
+            let profile = LensProfile::from_openfx_params(&params).unwrap();
+            assert_eq!(profile.distortion_model.as_deref(), Some("opencv_standard"));
+            assert_eq!(profile.to_openfx_params(), params);
+        }
+    }
+
+    #[test]
+    fn openfx_defaults_and_errors() {
+        // Missing coefficients are 0 and the size comes from the principal point
+        let profile = LensProfile::from_openfx_params(&openfx_params(&[("k1", 0.1), ("fx", 1000.0), ("fy", 1000.0), ("cx", 640.0), ("cy", 360.0)])).unwrap();
+        assert_eq!((profile.calib_dimension.w, profile.calib_dimension.h), (1280, 720));
+        assert_eq!(profile.fisheye_params.distortion_coeffs, vec![0.1, 0.0, 0.0, 0.0, 0.0]);
+
+        assert!(matches!(LensProfile::from_openfx_params(&openfx_params(&[("fy", 1000.0), ("cx", 640.0), ("cy", 360.0)])), Err(LensProfileError::MissingParameter(x)) if x == "fx"));
+
+        // Fisheye can't be represented
+        let mut profile = LensProfile::default();
+        profile.load_from_data(V1_PROFILE).unwrap();
+        assert!(profile.to_openfx_params().is_empty());
+    }
+}
@@ -21,6 +21,7 @@ pub mod gpu;
 
 pub mod util;
 pub mod stabilization_params;
+pub mod undo;
 
 use std::sync::{ Arc, atomic::{ AtomicU64, AtomicBool, Ordering::SeqCst } };
 use std::collections::BTreeMap;
@@ -59,6 +60,15 @@ pub struct InputFile {
     pub image_sequence_start: i32
 }
 
+// Worst-case framing of the export, see `StabilizationManager::compute_stabilization_limits`
+#[derive(Default, Clone, Copy, Debug)]
+pub struct StabilizationLimits {
+    pub min_crop_factor: f64, // Smallest zoom of the output relative to the full frame
+    pub max_required_zoom: f64, // Zoom needed to hide the borders in the worst frame
+    pub frames_exceeding_fov_limit: usize, // Frames showing the area outside of the source
+    pub recommended_fov_scale: f64, // Largest FOV scale without borders in any frame
+}
+
 pub struct StabilizationManager {
     pub gyro: Arc<RwLock<GyroSource>>,
     pub lens: Arc<RwLock<LensProfile>>,
@@ -77,11 +87,14 @@ pub struct StabilizationManager {
 
     pub camera_id: Arc<RwLock<Option<CameraIdentifier>>>,
     pub lens_profile_db: Arc<RwLock<LensProfileDatabase>>,
+    pub camera_rig: Arc<RwLock<Option<lens_profile::CameraRigProfile>>>,
 
     pub input_file: Arc<RwLock<InputFile>>,
 
     pub keyframes: Arc<RwLock<KeyframeManager>>,
 
+    pub undo_stack: Arc<RwLock<undo::UndoStack>>,
+
     pub params: Arc<RwLock<StabilizationParams>>
 }
 
@@ -105,6 +118,7 @@ impl Default for StabilizationManager {
             pose_estimator: Arc::new(synchronization::PoseEstimator::default()),
 
             lens_profile_db: Arc::new(RwLock::new(LensProfileDatabase::default())),
+            camera_rig: Arc::new(RwLock::new(None)),
 
             input_file: Arc::new(RwLock::new(InputFile::default())),
 
@@ -113,6 +127,8 @@ impl Default for StabilizationManager {
 
             keyframes: Arc::new(RwLock::new(KeyframeManager::new())),
 
+            undo_stack: Arc::new(RwLock::new(undo::UndoStack::default())),
+
             camera_id: Arc::new(RwLock::new(None)),
         }
     }
@@ -229,6 +245,39 @@ impl StabilizationManager {
         Ok(())
     }
 
+    // When there's no profile for the camera, estimate the distortion from straight edges in grayscale `frames` and load it as the current lens
+    #[cfg(feature = "opencv")]
+    pub fn estimate_unknown_lens_profile(&self, frames: &[Vec<u8>], width: u32, height: u32, num_frames: u32) -> Result<(), crate::GyroflowCoreError> {
+        let profile = calibration::straight_lines::estimate_lens_from_video_lines(frames, width, height, num_frames)?;
+        self.load_lens_profile(&profile.get_json()?)
+    }
+
+    // Roll of the horizon in grayscale `frames` (data, width, height, timestamp_us) as (timestamp_us, roll in degrees),
+    // a fallback gravity reference when the accelerometer data is unusable. Frames without a clear horizon are skipped
+    #[cfg(feature = "opencv")]
+    pub fn detect_horizon_from_video(&self, frames: &[(Vec<u8>, u32, u32, i64)]) -> Vec<(i64, f64)> {
+        use rayon::iter::{ ParallelIterator, IntoParallelRefIterator };
+        frames.par_iter().filter_map(|(data, width, height, timestamp_us)| {
+            Some((*timestamp_us, calibration::horizon_detection::detect_horizon(data, *width as usize, *height as usize)?))
+        }).collect()
+    }
+
+    // Use the roll angles from `detect_horizon_from_video` as the gravity vectors for horizon lock
+    pub fn apply_horizon_lock_gravity_vector(&self, horizon: &[(i64, f64)]) {
+        {
+            let mut gyro = self.gyro.write();
+            let gravity_vectors = horizon.iter().map(|&(timestamp_us, roll)| {
+                let timestamp_ms = timestamp_us as f64 / 1000.0;
+                let gyro_timestamp_us = ((timestamp_ms - gyro.offset_at_video_timestamp(timestamp_ms)) * 1000.0).round() as i64;
+                // Down is perpendicular to the horizon, camera Y axis when it's level
+                let roll = roll.to_radians();
+                (gyro_timestamp_us, nalgebra::Vector3::new(-roll.sin(), roll.cos(), 0.0))
+            }).collect::<TimeVec>();
+            gyro.file_metadata.gravity_vectors = if gravity_vectors.is_empty() { None } else { Some(gravity_vectors) };
+        }
+        self.set_use_gravity_vectors(true);
+    }
+
     pub fn load_lens_profile(&self, url: &str) -> Result<(), crate::GyroflowCoreError> {
         let url = if (url.starts_with('/') || url.starts_with('\\') || (url.len() > 3 && &url[1..2] == ":")) && !url.contains("://") && !url.starts_with('{') {
             crate::filesystem::path_to_url(url)
@@ -522,10 +571,32 @@ impl StabilizationManager {
         ret
     }
 
+    // Motion of the optical flow features between the frame at `timestamp_us` and the next one, after stabilization of each frame.
+    // Lines from the position in the first frame to the position in the second one, in output pixels. Zero for a perfectly stable output
+    pub fn get_residual_motion_lines(&self, timestamp_us: i64) -> Option<Vec<((f32, f32), (f32, f32))>> {
+        let ((ts1, pts1), (ts2, pts2)) = match self.pose_estimator.get_of_lines_for_timestamp(&timestamp_us, 0, 1.0, 1, false) {
+            (Some(lines), Some(frame_size)) if frame_size.0 > 0 => {
+                let ratio = self.params.read().size.0 as f32 / frame_size.0 as f32;
+                let scale = |pts: Vec<(f32, f32)>| pts.into_iter().map(|(x, y)| (x * ratio, y * ratio)).collect::<Vec<_>>();
+                ((lines.0.0, scale(lines.0.1)), (lines.1.0, scale(lines.1.1)))
+            },
+            _ => return None
+        };
+        if pts1.is_empty() || pts1.len() != pts2.len() { return None; }
+
+        let params = ComputeParams::from_manager(self);
+        let lens_correction_amount = params.lens_correction_amount;
+        let out1 = stabilization::undistort_points_with_rolling_shutter(&pts1, ts1 as f64 / 1000.0, &params, lens_correction_amount);
+        let out2 = stabilization::undistort_points_with_rolling_shutter(&pts2, ts2 as f64 / 1000.0, &params, lens_correction_amount);
+        Some(out1.into_iter().zip(out2.into_iter()).collect())
+    }
+
     pub fn draw_overlays(&self, drawing: &mut DrawCanvas, timestamp_us: i64) {
         drawing.clear();
 
+        let mut residual_motion = None;
         if let Some(p) = self.params.try_read() {
+            if p.show_residual_motion { residual_motion = Some(p.framebuffer_inverted); }
             let y_inverted = p.framebuffer_inverted;
             let size = p.size;
             let frame = frame_at_timestamp(timestamp_us as f64 / 1000.0, p.get_scaled_fps()) as usize; // used only to draw features and OF
@@ -573,6 +644,41 @@ impl StabilizationManager {
                 }
             }
         }
+        // After releasing `params`, computing the lines locks it again
+        if let Some(y_inverted) = residual_motion {
+            for (p1, p2) in self.get_residual_motion_lines(timestamp_us).unwrap_or_default() {
+                let len = ((p2.0 - p1.0).powi(2) + (p2.1 - p1.1).powi(2)).sqrt();
+                let color = if len < 1.0 { Color::Green } else if len < 3.0 { Color::Yellow } else { Color::Red };
+                // Exaggerated 4x, sub-pixel motion wouldn't be visible otherwise
+                let end = (p1.0 + (p2.0 - p1.0) * 4.0, p1.1 + (p2.1 - p1.1) * 4.0);
+                for point in line_drawing::Bresenham::new((p1.0 as isize, p1.1 as isize), (end.0 as isize, end.1 as isize)) {
+                    drawing.put_pixel(point.0 as i32, point.1 as i32, color, Alpha::Alpha100, Stage::OnOutput, y_inverted, 1);
+                }
+            }
+        }
+    }
+
+    // Estimates the true frame rate from the optical flow sync points (video timestamp_us, offset_ms, cost) and uses it for the video timestamps,
+    // see `override_video_fps`. Existing offsets are moved to the new timestamps, so they no longer drift. Returns the estimated frame rate
+    pub fn apply_true_fps_from_sync(&self, sync_points: &[(i64, f64, f64)]) -> f64 {
+        let (fps, old_scale) = {
+            let params = self.params.read();
+            (params.fps, params.fps_scale.unwrap_or(1.0))
+        };
+        let true_fps = GyroSource::estimate_true_fps_from_gyro_sync(sync_points, fps * old_scale);
+        let new_scale = if (true_fps - fps).abs() > 0.001 { true_fps / fps } else { 1.0 };
+        self.push_undo_state();
+        {
+            // Same gyro timestamp at the rescaled video timestamp
+            let mut gyro = self.gyro.write();
+            let offsets = gyro.get_offsets().iter().map(|(&ts, &offset)| {
+                let new_ts = (ts as f64 * old_scale / new_scale).round() as i64;
+                (new_ts, offset + (new_ts - ts) as f64 / 1000.0)
+            }).collect();
+            gyro.set_offsets(offsets);
+        }
+        self.override_video_fps(true_fps, true);
+        true_fps
     }
 
     pub fn process_pixels<T: PixelType>(&self, mut timestamp_us: i64, buffers: &mut Buffers) -> Result<stabilization::ProcessedInfo, GyroflowCoreError> {
@@ -593,6 +699,157 @@ impl StabilizationManager {
         undist.process_pixels::<T>(timestamp_us, buffers, None)
     }
 
+    // Temporal supersampling for slow motion. Synthesizes the frame at `phase` (0 = `prev`, 1 = `next`) between two neighboring frames (timestamp, pixels).
+    // Both are warped to the stabilized camera at the in-between timestamp, so they line up, and blended by `phase`. CPU buffers only
+    pub fn process_pixels_temporal<T: PixelType>(&self, prev: (i64, &mut [u8]), next: (i64, &mut [u8]), phase: f64, input_size: (usize, usize, usize), output: &mut [u8], output_size: (usize, usize, usize)) -> Result<stabilization::ProcessedInfo, GyroflowCoreError> {
+        if prev.1.is_empty() || next.1.is_empty() { return Err(GyroflowCoreError::InputBufferEmpty); }
+        if output.is_empty() { return Err(GyroflowCoreError::OutputBufferEmpty); }
+
+        let scale = self.params.read().fps_scale.unwrap_or(1.0);
+        let (prev_ts, next_ts) = ((prev.0 as f64 / scale).round() as i64, (next.0 as f64 / scale).round() as i64);
+        let phase = phase.clamp(0.0, 1.0);
+        let target_ts = prev_ts + ((next_ts - prev_ts) as f64 * phase).round() as i64;
+
+        let mut next_output = vec![0u8; output.len()];
+        let mut ret = None;
+        let mut fovs = Vec::with_capacity(2);
+        for (ts, input, out) in [(prev_ts, prev.1, &mut *output), (next_ts, next.1, &mut next_output[..])] {
+            let mut buffers = Buffers {
+                input:  gpu::BufferDescription { size: input_size,  data: gpu::BufferSource::Cpu { buffer: input }, ..Default::default() },
+                output: gpu::BufferDescription { size: output_size, data: gpu::BufferSource::Cpu { buffer: out },   ..Default::default() },
+            };
+            let transform = {
+                let mut undist = self.stabilization.write();
+                self.draw_overlays(&mut undist.drawing, target_ts);
+                undist.ensure_ready_for_processing::<T>(ts, &mut buffers);
+                undist.get_frame_transform_aligned_at::<T>(ts, Some(target_ts), &mut buffers)
+            };
+            fovs.push(transform.kernel_params.fov);
+            ret = Some(self.stabilization.read().process_pixels::<T>(ts, &mut buffers, Some(&transform))?);
+        }
+        // Both frames are aligned to the same camera, they only line up if they're zoomed the same as well
+        if (fovs[0] - fovs[1]).abs() > 1e-4 {
+            log::warn!("Temporal blend at {target_ts}: frames aren't aligned (fov {} != {})", fovs[0], fovs[1]);
+        }
+
+        let pixel_bytes = std::mem::size_of::<T>();
+        let phase = phase as f32;
+        for y in 0..output_size.1 {
+            let row = y * output_size.2;
+            let row_end = (row + output_size.0 * pixel_bytes).min(output.len());
+            if row >= row_end { break; }
+            for (a, b) in output[row..row_end].chunks_exact_mut(pixel_bytes).zip(next_output[row..row_end].chunks_exact(pixel_bytes)) {
+                let pa = T::to_float(*bytemuck::from_bytes::<T>(a));
+                let pb = T::to_float(*bytemuck::from_bytes::<T>(b));
+                a.copy_from_slice(bytemuck::bytes_of(&T::from_float(pa * (1.0 - phase) + pb * phase)));
+            }
+        }
+        ret.ok_or(GyroflowCoreError::Unknown)
+    }
+
+    // Size of `render_stabilized_thumbnail`, fits within `max_width` x `max_height` with the aspect ratio of the output
+    pub fn thumbnail_size(&self, max_width: u32, max_height: u32) -> (usize, usize) {
+        let (w, h) = self.params.read().video_output_size;
+        if w == 0 || h == 0 { return (0, 0); }
+        let scale = (max_width as f64 / w as f64).min(max_height as f64 / h as f64).min(1.0);
+        (((w as f64 * scale).round() as usize).max(1), ((h as f64 * scale).round() as usize).max(1))
+    }
+
+    // Stabilized thumbnail of a decoded RGBA8 `frame` (width, height, stride in bytes) of the video at `timestamp_us`, eg. for the project browser.
+    // Rendered with the CPU path directly at `thumbnail_size`, from the frame box-filtered to about twice that size, so it doesn't need a GPU.
+    // Returns RGBA8 pixels without row padding
+    pub fn render_stabilized_thumbnail(&self, frame: &[u8], frame_size: (usize, usize, usize), mut timestamp_us: i64, max_width: u32, max_height: u32) -> Result<Vec<u8>, GyroflowCoreError> {
+        use rayon::{ slice::ParallelSliceMut, iter::{ ParallelIterator, IndexedParallelIterator } };
+        let (width, height, stride) = frame_size;
+        if stride < width * 4 { return Err(GyroflowCoreError::InvalidStride(stride as i32, width as i32 * 4)); }
+        if frame.len() < stride * height { return Err(GyroflowCoreError::BufferTooSmall); }
+        let (tw, th) = self.thumbnail_size(max_width, max_height);
+        if tw < 4 || th < 4 || width < 4 || height < 4 { return Err(GyroflowCoreError::SizeTooSmall); }
+
+        // Bilinear sampling of the full frame would alias
+        let factor = (width / (tw * 2)).min(height / (th * 2)).max(1);
+        let (sw, sh) = (width / factor, height / factor);
+        let mut input = vec![0u8; sw * sh * 4];
+        input.par_chunks_mut(sw * 4).enumerate().for_each(|(y, row)| {
+            let n = (factor * factor) as u32;
+            for x in 0..sw {
+                let mut sum = [0u32; 4];
+                for sy in y * factor..(y + 1) * factor {
+                    let start = sy * stride + x * factor * 4;
+                    for px in frame[start..start + factor * 4].chunks_exact(4) {
+                        for c in 0..4 { sum[c] += px[c] as u32; }
+                    }
+                }
+                for c in 0..4 { row[x * 4 + c] = ((sum[c] + n / 2) / n) as u8; }
+            }
+        });
+
+        if let Some(scale) = self.params.read().fps_scale {
+            timestamp_us = (timestamp_us as f64 / scale).round() as i64;
+        }
+        let mut compute_params = ComputeParams::from_manager(self);
+        compute_params.width = sw;
+        compute_params.height = sh;
+        compute_params.output_width = tw;
+        compute_params.output_height = th;
+
+        // Separate instance without GPU backends, so the CPU path is used and the preview isn't affected
+        let mut stab = Stabilization::default();
+        stab.set_compute_params(compute_params);
+        stab.init_size((sw, sh), (tw, th));
+
+        let mut output = vec![0u8; tw * th * 4];
+        let mut buffers = Buffers {
+            input:  gpu::BufferDescription { size: (sw, sh, sw * 4), data: gpu::BufferSource::Cpu { buffer: &mut input }, ..Default::default() },
+            output: gpu::BufferDescription { size: (tw, th, tw * 4), data: gpu::BufferSource::Cpu { buffer: &mut output }, ..Default::default() },
+        };
+        let transform = stab.get_frame_transform_at::<stabilization::RGBA8>(timestamp_us, &mut buffers);
+        stab.process_pixels::<stabilization::RGBA8>(timestamp_us, &mut buffers, Some(&transform))?;
+        drop(buffers);
+        Ok(output)
+    }
+
+    // False-color RGBA8 image of the per-row matrices at `timestamp_us`, for debugging the rolling shutter correction.
+    // Every image row shows the matrix of the corresponding frame row: red is the horizontal displacement, green the vertical one
+    // and blue the rotation angle. 128 is zero, each channel is scaled to its largest absolute value in the frame
+    pub fn debug_render_matrix_visualization(&self, timestamp_us: i64, width: u32, height: u32) -> Vec<u8> {
+        let mut out = vec![0u8; width as usize * height as usize * 4];
+        if width == 0 || height == 0 { return out; }
+        let params = ComputeParams::from_manager(self);
+        let timestamp_ms = timestamp_us as f64 / 1000.0;
+        let ft = stabilization::FrameTransform::at_timestamp(&params, timestamp_ms, frame_at_timestamp(timestamp_ms, params.scaled_fps) as usize);
+        let (out_w, out_h) = (params.output_width as f64, params.output_height as f64);
+        let (sx, sy) = (params.width as f64 / out_w.max(1.0), params.height as f64 / out_h.max(1.0));
+        let (f, c) = (ft.kernel_params.f.map(|x| x as f64), ft.kernel_params.c.map(|x| x as f64));
+        let count = ft.matrices.len();
+
+        // Displacement of the output point in the middle of the row, and the angle of the row, without the lens model
+        let rows: Vec<(f64, f64, f64)> = ft.matrices.iter().enumerate().map(|(i, m)| {
+            let m = m.map(|x| x as f64);
+            let to_input = |x: f64, y: f64| -> Option<(f64, f64)> {
+                let z = x * m[6] + y * m[7] + m[8];
+                if z <= 0.0 { return None; }
+                Some(((x * m[0] + y * m[1] + m[2]) / z * f[0] + c[0], (x * m[3] + y * m[4] + m[5]) / z * f[1] + c[1]))
+            };
+            let (x, y) = (out_w / 2.0, (i as f64 + 0.5) * out_h / count as f64);
+            match (to_input(x, y), to_input(x + 10.0, y)) {
+                (Some(p), Some(p2)) => (p.0 - x * sx, p.1 - y * sy, (p2.1 - p.1).atan2(p2.0 - p.0)),
+                _ => (0.0, 0.0, 0.0)
+            }
+        }).collect();
+        if rows.is_empty() { return out; }
+
+        let max = |f: fn(&(f64, f64, f64)) -> f64| rows.iter().map(|r| f(r).abs()).fold(1e-9, f64::max);
+        let scale = (max(|r| r.0), max(|r| r.1), max(|r| r.2));
+        let encode = |v: f64, s: f64| (128.0 + v / s * 127.0).round().clamp(0.0, 255.0) as u8;
+        for (y, line) in out.chunks_exact_mut(width as usize * 4).enumerate() {
+            let r = &rows[(y * count / height as usize).min(count - 1)];
+            let px = [encode(r.0, scale.0), encode(r.1, scale.1), encode(r.2, scale.2), 255];
+            for p in line.chunks_exact_mut(4) { p.copy_from_slice(&px); }
+        }
+        out
+    }
+
     pub fn set_video_rotation(&self, v: f64) { self.params.write().video_rotation = v; self.invalidate_smoothing(); }
 
     pub fn set_trim_start(&self, v: f64) { self.params.write().trim_start = v; self.invalidate_smoothing(); }
@@ -601,22 +858,42 @@ impl StabilizationManager {
     pub fn set_of_method(&self, v: u32) { self.params.write().of_method = v; self.pose_estimator.clear(); }
     pub fn set_show_detected_features(&self, v: bool) { self.params.write().show_detected_features = v; }
     pub fn set_show_optical_flow     (&self, v: bool) { self.params.write().show_optical_flow      = v; }
+    pub fn set_show_residual_motion  (&self, v: bool) { self.params.write().show_residual_motion   = v; }
     pub fn set_stab_enabled          (&self, v: bool) { self.params.write().stab_enabled           = v; }
     pub fn set_frame_readout_time    (&self, v: f64)  { self.params.write().frame_readout_time     = v; }
     pub fn set_adaptive_zoom         (&self, v: f64)  { self.params.write().adaptive_zoom_window   = v; self.invalidate_zooming(); }
     pub fn set_zooming_center_x      (&self, v: f64)  { self.params.write().adaptive_zoom_center_offset.0 = v; self.invalidate_zooming(); }
     pub fn set_zooming_center_y      (&self, v: f64)  { self.params.write().adaptive_zoom_center_offset.1 = v; self.invalidate_zooming(); }
     pub fn set_zooming_method        (&self, v: i32)  { self.params.write().adaptive_zoom_method   = v;        self.invalidate_zooming(); }
+    // Zoom frame-by-frame only as much as needed to avoid black borders, smoothed over `smoothing_window` seconds.
+    // Replaces the static or dynamic zoom while enabled
+    pub fn apply_smart_zoom(&self, enabled: bool, smoothing_window: f64) {
+        {
+            let mut params = self.params.write();
+            params.smart_zoom_enabled = enabled;
+            params.smart_zoom_smoothing_window = smoothing_window.max(0.0);
+        }
+        self.invalidate_zooming();
+    }
     pub fn set_fov                   (&self, v: f64)  { self.params.write().fov                    = v; }
     pub fn set_fov_overview          (&self, v: bool) { self.params.write().fov_overview           = v; }
     pub fn set_show_safe_area        (&self, v: bool) { self.params.write().show_safe_area         = v; }
     pub fn set_lens_correction_amount(&self, v: f64)  { self.params.write().lens_correction_amount = v; self.invalidate_zooming(); }
     pub fn set_background_color      (&self, bg: Vector4<f32>) { self.params.write().background = bg; }
+    pub fn set_background_alpha      (&self, v: f32)  { self.params.write().background[3] = v; } // Ignored for formats without alpha
     pub fn set_background_mode       (&self, v: i32)  { self.params.write().background_mode = stabilization_params::BackgroundMode::from(v); }
     pub fn set_background_margin     (&self, v: f64)  { self.params.write().background_margin = v; }
+    pub fn set_output_color_space    (&self, cs: stabilization_params::ColorSpace) { self.params.write().output_color_space = Some(cs); }
     pub fn set_background_margin_feather(&self, v: f64) { self.params.write().background_margin_feather = v; }
+    pub fn set_stabilize_only_translation(&self, v: bool) { self.params.write().stabilize_only_translation = v; }
+    pub fn set_bypass_lens               (&self, v: bool) { self.params.write().bypass_lens = v; }
+    pub fn set_bypass_digital_lens       (&self, v: bool) { self.params.write().bypass_digital_lens = v; }
+    pub fn set_perspective_correction(&self, v: Option<[f64; 8]>) { self.params.write().perspective_correction = v; self.invalidate_zooming(); }
+    pub fn set_user_distortion(&self, v: [f64; 4]) { self.params.write().user_distortion = v; self.invalidate_zooming(); }
     pub fn set_input_horizontal_stretch (&self, v: f64) { self.lens.write().input_horizontal_stretch = v; self.invalidate_zooming(); }
     pub fn set_input_vertical_stretch   (&self, v: f64) { self.lens.write().input_vertical_stretch   = v; self.invalidate_zooming(); }
+    pub fn set_input_crop(&self, v: Option<(u32, u32, u32, u32)>) { self.params.write().input_crop = v; self.invalidate_zooming(); }
+    pub fn set_blended_lens(&self, v: Option<stabilization_params::BlendedLens>) { self.params.write().blended_lens = v; self.invalidate_zooming(); }
 
     pub fn set_video_speed(&self, v: f64, link_with_smoothness: bool, link_with_zooming: bool) {
         let mut params = self.params.write();
@@ -680,17 +957,40 @@ impl StabilizationManager {
         self.invalidate_zooming();
     }
 
+    // Treat the video as 360° equirectangular. Rotation is applied on the sphere instead of the perspective camera model
+    pub fn apply_to_360_equirectangular(&self, width: u32, height: u32) {
+        {
+            let mut lens = self.lens.write();
+            let (w, h) = (width as f64, height as f64);
+            lens.distortion_model = Some("equirectangular".into());
+            lens.digital_lens = None;
+            lens.calib_dimension = lens_profile::Dimensions { w: width as usize, h: height as usize };
+            lens.fisheye_params.camera_matrix = vec![
+                [w / (2.0 * std::f64::consts::PI), 0.0, w / 2.0],
+                [0.0, h / std::f64::consts::PI, h / 2.0],
+                [0.0, 0.0, 1.0]
+            ];
+            lens.fisheye_params.distortion_coeffs = vec![0.0; 4];
+            lens.fisheye_params.radial_distortion_limit = None;
+        }
+        self.params.write().adaptive_zoom_window = 0.0; // No borders to hide
+        self.invalidate_zooming();
+    }
+
     pub fn remove_offset(&self, timestamp_us: i64) {
+        self.push_undo_state();
         self.gyro.write().remove_offset(timestamp_us);
         self.keyframes.write().update_gyro(&self.gyro.read());
         self.invalidate_zooming();
     }
     pub fn set_offset(&self, timestamp_us: i64, offset_ms: f64) {
+        self.push_undo_state();
         self.gyro.write().set_offset(timestamp_us, offset_ms);
         self.keyframes.write().update_gyro(&self.gyro.read());
         self.invalidate_zooming();
     }
     pub fn clear_offsets(&self) {
+        self.push_undo_state();
         self.gyro.write().clear_offsets();
         self.keyframes.write().update_gyro(&self.gyro.read());
         self.invalidate_zooming();
@@ -700,6 +1000,7 @@ impl StabilizationManager {
     }
 
     pub fn set_imu_lpf(&self, lpf: f64) {
+        self.push_undo_state();
         self.gyro.write().imu_lpf = lpf;
     }
     pub fn set_imu_rotation(&self, pitch_deg: f64, roll_deg: f64, yaw_deg: f64) {
@@ -708,6 +1009,22 @@ impl StabilizationManager {
     pub fn set_acc_rotation(&self, pitch_deg: f64, roll_deg: f64, yaw_deg: f64) {
         self.gyro.write().acc_rotation_angles = Some([pitch_deg, roll_deg, yaw_deg]);
     }
+    // All cameras of the rig are stabilized with the gyro data of this manager, see `get_rig_camera`
+    pub fn set_camera_rig(&self, rig: Option<lens_profile::CameraRigProfile>) {
+        *self.camera_rig.write() = rig;
+    }
+    // Copy of this manager for camera `index` of the rig, with its lens profile and the gyro rotated into its orientation.
+    // The camera position doesn't matter for the rotation-only stabilization
+    pub fn get_rig_camera(&self, index: usize) -> Option<StabilizationManager> {
+        let (lens, pose) = self.camera_rig.read().as_ref()?.cameras.get(index)?.clone();
+        let mgr = self.get_cloned();
+        *mgr.lens.write() = lens;
+        let imu_rotation = mgr.gyro.read().imu_rotation.map(|x| nalgebra::UnitQuaternion::from_rotation_matrix(&x)).unwrap_or_else(nalgebra::UnitQuaternion::identity);
+        let (yaw, pitch, roll) = (pose.rotation.inverse() * imu_rotation).euler_angles(); // Same order as `GyroSource::apply_transforms`
+        mgr.set_imu_rotation(pitch.to_degrees(), roll.to_degrees(), yaw.to_degrees());
+        mgr.recompute_gyro();
+        Some(mgr)
+    }
     pub fn set_imu_orientation(&self, orientation: String) {
         self.gyro.write().imu_orientation = Some(orientation);
     }
@@ -718,10 +1035,87 @@ impl StabilizationManager {
         self.gyro.write().apply_transforms();
         self.invalidate_smoothing();
     }
+    pub fn detect_static_segments(&self, max_rate_deg_s: f64, min_duration_ms: f64) -> Vec<(i64, i64)> {
+        self.gyro.read().detect_static_segments(max_rate_deg_s, min_duration_ms)
+    }
+    // For long clips, where the gyro drift is noticeable. Has to be applied again after `recompute_gyro`
+    pub fn compute_drift_corrected_path(&self, static_segments: &[(i64, i64)]) {
+        self.gyro.write().compute_drift_corrected_path(static_segments);
+        self.invalidate_smoothing();
+    }
     pub fn set_sync_lpf(&self, lpf: f64) {
         let params = self.params.read();
         self.pose_estimator.lowpass_filter(lpf, params.fps);
     }
+    // Residual gyro delay against optical flow motion `(timestamp_us, x, y)`, for sub-frame sync refinement
+    pub fn compute_gyro_phase_response(&self, reference_motion: &[(i64, f64, f64)]) -> synchronization::phase_response::PhaseResponse {
+        synchronization::phase_response::compute_phase_response(&self.gyro.read(), reference_motion)
+    }
+
+    // Summary of the stabilized clip within the trim range, see `smoothing::quality::QualityScore`. Call after the smoothing and zooming are computed
+    pub fn compute_temporal_quality_score(&self) -> smoothing::quality::QualityScore {
+        smoothing::quality::compute_quality_score(&self.gyro.read(), &self.params.read())
+    }
+
+    // Fast pre-pass over the trim range before the export, computes the frame transforms without rendering.
+    // Call after the smoothing and zooming are computed. FOV keyframes are scaled together with the recommended scale
+    pub fn compute_stabilization_limits(&self) -> StabilizationLimits {
+        use rayon::iter::{ ParallelIterator, IntoParallelIterator };
+        let mut params = ComputeParams::from_manager(self);
+        // Only the FOV is needed, so compute a single matrix per frame instead of one per row
+        params.frame_readout_time = 0.0;
+
+        let frame_count = params.frame_count;
+        let fps = params.scaled_fps;
+        if frame_count == 0 || fps <= 0.0 {
+            return StabilizationLimits { min_crop_factor: 1.0, max_required_zoom: 1.0, frames_exceeding_fov_limit: 0, recommended_fov_scale: params.fov_scale };
+        }
+        let first = ((params.trim_start * frame_count as f64).floor() as usize).min(frame_count - 1);
+        let last = ((params.trim_end * frame_count as f64).ceil() as usize).clamp(first + 1, frame_count);
+
+        let fovs = (first..last).into_par_iter().map(|frame| {
+            let ft = stabilization::FrameTransform::at_timestamp(&params, timestamp_at_frame(frame as i32, fps), frame);
+            (ft.fov.max(0.001), ft.minimal_fov.max(0.001))
+        }).collect::<Vec<_>>();
+
+        let mut limits = StabilizationLimits { min_crop_factor: f64::MAX, max_required_zoom: 0.0, frames_exceeding_fov_limit: 0, recommended_fov_scale: f64::MAX };
+        for (fov, minimal_fov) in fovs {
+            limits.min_crop_factor = limits.min_crop_factor.min(1.0 / fov);
+            limits.max_required_zoom = limits.max_required_zoom.max(1.0 / minimal_fov);
+            if fov > minimal_fov + 1e-6 { limits.frames_exceeding_fov_limit += 1; }
+            limits.recommended_fov_scale = limits.recommended_fov_scale.min(params.fov_scale * minimal_fov / fov);
+        }
+        limits
+    }
+
+    // Shutter angle which would produce the existing motion blur for the motion visible after stabilization at `frame_timestamp_us`.
+    // Blur comes from the camera rotation during exposure, while the output only moves with the smoothed rotation.
+    // Infinite when the camera moved during exposure but the stabilized output is static
+    pub fn compute_effective_shutter_angle_after_stabilization(&self, frame_timestamp_us: i64, original_shutter_angle_deg: f64) -> f64 {
+        let fps = self.params.read().get_scaled_fps();
+        if fps <= 0.0 || original_shutter_angle_deg <= 0.0 { return original_shutter_angle_deg; }
+        let frame_duration_ms = 1000.0 / fps;
+        let exposure_ms = frame_duration_ms * original_shutter_angle_deg / 360.0;
+        let timestamp_ms = frame_timestamp_us as f64 / 1000.0;
+
+        let (blur_angle, output_angle) = {
+            let gyro = self.gyro.read();
+            (gyro.org_quat_at_timestamp(timestamp_ms - exposure_ms / 2.0).angle_to(&gyro.org_quat_at_timestamp(timestamp_ms + exposure_ms / 2.0)),
+             gyro.smoothed_quat_at_timestamp(timestamp_ms - frame_duration_ms / 2.0).angle_to(&gyro.smoothed_quat_at_timestamp(timestamp_ms + frame_duration_ms / 2.0)))
+        };
+        let effective = if output_angle > 1e-6 {
+            360.0 * blur_angle / output_angle
+        } else if blur_angle > 1e-6 {
+            f64::INFINITY
+        } else {
+            original_shutter_angle_deg // No motion at all
+        };
+
+        if (effective - original_shutter_angle_deg).abs() > original_shutter_angle_deg * 0.2 {
+            log::warn!("Motion blur may look unnatural at {frame_timestamp_us}us: effective shutter angle {effective:.1}° vs {original_shutter_angle_deg:.1}°");
+        }
+        effective
+    }
 
     pub fn set_lens_param(&self, param: &str, value: f64) {
         let mut lens = self.lens.write();
@@ -760,9 +1154,57 @@ impl StabilizationManager {
         smooth.current().get_parameters_json()
     }
     pub fn set_smoothing_param(&self, name: &str, val: f64) {
+        self.push_undo_state();
         self.smoothing.write().current_mut().as_mut().set_parameter(name, val);
         self.invalidate_smoothing();
     }
+    fn get_undo_state(&self) -> undo::UndoState {
+        let smoothing = self.smoothing.read();
+        let alg = smoothing.current();
+        let smoothing_params = alg.get_parameters_json().as_array().map(|params| {
+            params.iter().filter_map(|x| x.get("name").and_then(|x| x.as_str())).map(|name| (name.to_string(), alg.get_parameter(name))).collect()
+        }).unwrap_or_default();
+        let gyro = self.gyro.read();
+        undo::UndoState {
+            imu_lpf: gyro.imu_lpf,
+            smoothing_params,
+            sync_offsets: gyro.get_offsets().clone(),
+        }
+    }
+    fn apply_undo_diff(&self, diff: undo::UndoDiff) {
+        if let Some(v) = diff.imu_lpf {
+            self.gyro.write().imu_lpf = v;
+            self.recompute_gyro();
+        }
+        if let Some(v) = diff.smoothing_params {
+            let mut smoothing = self.smoothing.write();
+            for (name, val) in v {
+                smoothing.current_mut().as_mut().set_parameter(&name, val);
+            }
+            self.invalidate_smoothing();
+        }
+        if let Some(v) = diff.sync_offsets {
+            self.gyro.write().set_offsets(v);
+            self.keyframes.write().update_gyro(&self.gyro.read());
+            self.invalidate_zooming();
+        }
+    }
+    // Snapshot the current state before a change, for undo/redo
+    pub fn push_undo_state(&self) {
+        let state = self.get_undo_state();
+        self.undo_stack.write().push(state);
+    }
+    pub fn undo(&self) -> bool {
+        let state = self.get_undo_state();
+        let diff = self.undo_stack.write().undo(&state);
+        if let Some(diff) = diff { self.apply_undo_diff(diff); true } else { false }
+    }
+    pub fn redo(&self) -> bool {
+        let state = self.get_undo_state();
+        let diff = self.undo_stack.write().redo(&state);
+        if let Some(diff) = diff { self.apply_undo_diff(diff); true } else { false }
+    }
+
     pub fn set_horizon_lock(&self, lock_percent: f64, roll: f64) {
         self.smoothing.write().horizon_lock.set_horizon(lock_percent, roll);
         self.invalidate_smoothing();
@@ -794,6 +1236,7 @@ impl StabilizationManager {
             smoothing:  Arc::new(RwLock::new(self.smoothing.read().clone())),
             input_file: Arc::new(RwLock::new(self.input_file.read().clone())),
             lens_profile_db: self.lens_profile_db.clone(),
+            camera_rig: self.camera_rig.clone(),
 
             // NOT cloned:
             // stabilization
@@ -871,6 +1314,50 @@ impl StabilizationManager {
 
         Ok(())
     }
+    // Per-frame 2D transforms for the Assimilate SCRATCH stabilization module: translation in output pixels (y down), rotation in degrees and scale.
+    // Fitted to the middle row of each frame, lens distortion and rolling shutter can't be represented
+    pub fn export_scratch_xml(&self, output_path: &std::path::Path, frame_rate: f64, total_frames: usize) -> std::io::Result<()> {
+        if frame_rate <= 0.0 { return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid frame rate")); }
+        let params = ComputeParams::from_manager(self);
+        let (in_w, in_h) = (params.width as f64, params.height as f64);
+        let (out_w, out_h) = (params.output_width as f64, params.output_height as f64);
+        let name = output_path.file_stem().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str(&format!("<Project name=\"{}\" application=\"Gyroflow\" version=\"{}\" frameRate=\"{}\" width=\"{}\" height=\"{}\" frames=\"{}\">\n",
+            name.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;"), env!("CARGO_PKG_VERSION"), frame_rate, params.output_width, params.output_height, total_frames));
+        xml.push_str("  <Stabilization>\n");
+        for frame in 0..total_frames {
+            let timestamp_ms = frame as f64 * 1000.0 / frame_rate;
+            let ft = stabilization::FrameTransform::at_timestamp(&params, timestamp_ms, frame);
+            let (tx, ty, rz, sx, sy) = ft.matrices.get(ft.matrices.len() / 2).and_then(|m| {
+                let m = m.map(|x| x as f64);
+                let (f, c) = (ft.kernel_params.f.map(|x| x as f64), ft.kernel_params.c.map(|x| x as f64));
+                // Output pixel to input pixel, without the lens model
+                let to_input = |x: f64, y: f64| -> Option<(f64, f64)> {
+                    let z = x * m[6] + y * m[7] + m[8];
+                    if z <= 0.0 { return None; }
+                    Some(((x * m[0] + y * m[1] + m[2]) / z * f[0] + c[0], (x * m[3] + y * m[4] + m[5]) / z * f[1] + c[1]))
+                };
+                let d = 10.0;
+                let center = to_input(out_w / 2.0, out_h / 2.0)?;
+                let px = to_input(out_w / 2.0 + d, out_h / 2.0)?;
+                let py = to_input(out_w / 2.0, out_h / 2.0 + d)?;
+                let (ax, ay) = (((px.0 - center.0) / d, (px.1 - center.1) / d), ((py.0 - center.0) / d, (py.1 - center.1) / d));
+                let angle = ax.1.atan2(ax.0);
+                let (sx, sy) = (1.0 / (ax.0 * ax.0 + ax.1 * ax.1).sqrt(), 1.0 / (ay.0 * ay.0 + ay.1 * ay.1).sqrt());
+                // Where the center of the input ends up, relative to the center of the output
+                let (dx, dy) = (in_w / 2.0 - center.0, in_h / 2.0 - center.1);
+                let (cos, sin) = ((-angle).cos(), (-angle).sin());
+                Some(((dx * cos - dy * sin) * sx, (dx * sin + dy * cos) * sy, -angle.to_degrees(), sx, sy))
+            }).unwrap_or((0.0, 0.0, 0.0, 1.0, 1.0));
+            xml.push_str(&format!("    <Transform frame=\"{frame}\" tx=\"{tx:.4}\" ty=\"{ty:.4}\" rz=\"{rz:.5}\" sx=\"{sx:.6}\" sy=\"{sy:.6}\"/>\n"));
+        }
+        xml.push_str("  </Stabilization>\n</Project>\n");
+
+        std::fs::write(output_path, xml)
+    }
+
     pub fn export_gyroflow_data(&self, typ: GyroflowProjectType, additional_data: &str, _project_url: Option<&str>) -> Result<String, GyroflowCoreError> {
         let gyro = self.gyro.read();
         let params = self.params.read();
@@ -914,6 +1401,7 @@ impl StabilizationManager {
             "background_mode":  params.background_mode as i32,
             "background_margin":          params.background_margin,
             "background_margin_feather":  params.background_margin_feather,
+            "output_color_space":         params.output_color_space.map(|x| x as i32),
 
             "video_info": {
                 "width":       params.video_size.0,
@@ -934,6 +1422,8 @@ impl StabilizationManager {
                 "adaptive_zoom_window":   params.adaptive_zoom_window,
                 "adaptive_zoom_center_offset": params.adaptive_zoom_center_offset,
                 "adaptive_zoom_method":   params.adaptive_zoom_method,
+                "smart_zoom_enabled":     params.smart_zoom_enabled,
+                "smart_zoom_smoothing_window": params.smart_zoom_smoothing_window,
                 "lens_correction_amount": params.lens_correction_amount,
                 "horizon_lock_amount":    horizon_amount,
                 "horizon_lock_roll":      horizon_roll,
@@ -943,6 +1433,11 @@ impl StabilizationManager {
                 "video_speed_affects_smoothing": params.video_speed_affects_smoothing,
                 "video_speed_affects_zooming":   params.video_speed_affects_zooming,
                 "horizontal_rs":          params.horizontal_rs,
+                "stabilize_only_translation": params.stabilize_only_translation,
+                "bypass_lens":            params.bypass_lens,
+                "bypass_digital_lens":    params.bypass_digital_lens,
+                "perspective_correction": params.perspective_correction,
+                "user_distortion":        params.user_distortion,
             },
             "gyro_source": {
                 "filepath":           gyro.file_url,
@@ -1222,8 +1717,15 @@ impl StabilizationManager {
                 if let Some(v) = obj.get("fov")                   .and_then(|x| x.as_f64()) { params.fov                     = v; }
                 if let Some(v) = obj.get("frame_readout_time")    .and_then(|x| x.as_f64()) { params.frame_readout_time      = v; }
                 if let Some(v) = obj.get("adaptive_zoom_window")  .and_then(|x| x.as_f64()) { params.adaptive_zoom_window    = v; }
+                if let Some(v) = obj.get("smart_zoom_enabled")    .and_then(|x| x.as_bool()) { params.smart_zoom_enabled     = v; }
+                if let Some(v) = obj.get("smart_zoom_smoothing_window").and_then(|x| x.as_f64()) { params.smart_zoom_smoothing_window = v; }
                 if let Some(v) = obj.get("lens_correction_amount").and_then(|x| x.as_f64()) { params.lens_correction_amount  = v; }
                 if let Some(v) = obj.get("horizontal_rs")        .and_then(|x| x.as_bool()) { params.horizontal_rs          = v; }
+                if let Some(v) = obj.get("stabilize_only_translation").and_then(|x| x.as_bool()) { params.stabilize_only_translation = v; }
+                if let Some(v) = obj.get("bypass_lens")          .and_then(|x| x.as_bool()) { params.bypass_lens            = v; }
+                if let Some(v) = obj.get("bypass_digital_lens")  .and_then(|x| x.as_bool()) { params.bypass_digital_lens    = v; }
+                if let Some(v) = obj.get("perspective_correction") { params.perspective_correction = serde_json::from_value(v.clone()).ok(); }
+                if let Some(v) = obj.get("user_distortion") { params.user_distortion = serde_json::from_value(v.clone()).unwrap_or_default(); }
 
                 if let Some(v) = obj.get("video_speed").and_then(|x| x.as_f64()) { params.video_speed = v; }
                 if let Some(v) = obj.get("video_speed_affects_smoothing").and_then(|x| x.as_bool()) { params.video_speed_affects_smoothing = v; }
@@ -1325,6 +1827,7 @@ impl StabilizationManager {
                 if let Some(v) = obj.get("background_mode").and_then(|x| x.as_i64()) { params.background_mode = stabilization_params::BackgroundMode::from(v as i32); }
                 if let Some(v) = obj.get("background_margin").and_then(|x| x.as_f64()) { params.background_margin = v; }
                 if let Some(v) = obj.get("background_margin_feather").and_then(|x| x.as_f64()) { params.background_margin_feather = v; }
+                params.output_color_space = obj.get("output_color_space").and_then(|x| x.as_i64()).map(|v| stabilization_params::ColorSpace::from(v as i32));
             }
 
             {
@@ -1519,6 +2022,13 @@ pub enum GyroflowCoreError {
     #[error("Filesystem error {0:?}")]
     FilesystemError(#[from] crate::filesystem::FilesystemError),
 
+    #[error("Lens profile error {0:?}")]
+    LensProfileError(#[from] crate::lens_profile::LensProfileError),
+
+    #[cfg(feature = "opencv")]
+    #[error("Lens estimation error {0:?}")]
+    LensEstimationError(#[from] crate::calibration::straight_lines::EstimationError),
+
     #[error("IO error {0:?}")]
     IOError(#[from] std::io::Error),
 
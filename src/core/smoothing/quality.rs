@@ -0,0 +1,81 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use crate::gyro_source::GyroSource;
+use crate::stabilization_params::StabilizationParams;
+
+// Single scalar summary of a stabilized clip, eg. for automated quality gating in batch rendering
+#[derive(Debug, Default, Clone, Copy)]
+pub struct QualityScore {
+    pub overall: f64, // 0-100, weighted combination of the other fields
+    pub jitter_reduction_db: f64, // High frequency rotation of the original vs the smoothed camera path
+    pub mean_crop_factor: f64, // 1 = no zoom, from the adaptive zoom
+    pub gyro_coverage_fraction: f64, // 0-1, frames with gyro data around their timestamp
+    pub sync_quality: f64, // 0-1, consistency of the sync offsets
+}
+
+const MAX_JITTER_DB: f64 = 60.0;
+const MAX_GYRO_GAP_US: i64 = 50_000;
+
+// Frame-to-frame change of the rotation delta, ie. angular acceleration per frame, RMS over the clip
+fn jitter_rms<F: Fn(f64) -> crate::Quat64>(timestamps: &[f64], quat_at: F) -> f64 {
+    let quats: Vec<_> = timestamps.iter().map(|&ts| quat_at(ts)).collect();
+    let deltas: Vec<_> = quats.windows(2).map(|w| w[0].inverse() * w[1]).collect();
+    if deltas.len() < 2 { return 0.0; }
+    let sum: f64 = deltas.windows(2).map(|w| w[0].angle_to(&w[1]).powi(2)).sum();
+    (sum / (deltas.len() - 1) as f64).sqrt()
+}
+
+pub fn compute_quality_score(gyro: &GyroSource, params: &StabilizationParams) -> QualityScore {
+    let fps = params.get_scaled_fps();
+    let frame_count = params.frame_count;
+    if fps <= 0.0 || frame_count == 0 { return QualityScore::default(); }
+
+    let first = ((params.trim_start * frame_count as f64).floor() as usize).min(frame_count - 1);
+    let last = ((params.trim_end * frame_count as f64).ceil() as usize).max(first + 1).min(frame_count);
+    let timestamps: Vec<f64> = (first..last).map(|i| crate::timestamp_at_frame(i as i32, fps)).collect();
+
+    let org = jitter_rms(&timestamps, |ts| gyro.org_quat_at_timestamp(ts));
+    let smoothed = jitter_rms(&timestamps, |ts| gyro.smoothed_quat_at_timestamp(ts));
+    let jitter_reduction_db = if org <= 1e-9 {
+        0.0 // Nothing to stabilize
+    } else if smoothed <= 1e-9 {
+        MAX_JITTER_DB
+    } else {
+        (20.0 * (org / smoothed).log10()).min(MAX_JITTER_DB)
+    };
+
+    let crop: Vec<f64> = (first..last).filter_map(|i| params.fovs.get(i)).map(|fov| 1.0 / fov.max(0.001)).collect();
+    let mean_crop_factor = if crop.is_empty() { 1.0 } else { crop.iter().sum::<f64>() / crop.len() as f64 };
+
+    let covered = timestamps.iter().filter(|&&ts| {
+        let lookup = ((ts - gyro.offset_at_video_timestamp(ts)) * 1000.0).round() as i64;
+        match (gyro.quaternions.range(..=lookup).next_back(), gyro.quaternions.range(lookup..).next()) {
+            (Some(a), Some(b)) => b.0 - a.0 <= MAX_GYRO_GAP_US,
+            _ => false
+        }
+    }).count();
+    let gyro_coverage_fraction = covered as f64 / timestamps.len() as f64;
+
+    // Offsets drift linearly with the clock difference between the camera and the IMU, residuals of a linear fit mean bad sync points.
+    // Without offsets the sync can't be assessed
+    let offsets: Vec<(f64, f64)> = gyro.get_offsets().iter().map(|(k, v)| (*k as f64 / 1000.0, *v)).collect();
+    let sync_quality = match offsets.len() {
+        0 => 0.5,
+        1 | 2 => 1.0,
+        n => {
+            let n = n as f64;
+            let (mx, my) = (offsets.iter().map(|x| x.0).sum::<f64>() / n, offsets.iter().map(|x| x.1).sum::<f64>() / n);
+            let sxx: f64 = offsets.iter().map(|x| (x.0 - mx).powi(2)).sum();
+            let slope = if sxx > 0.0 { offsets.iter().map(|x| (x.0 - mx) * (x.1 - my)).sum::<f64>() / sxx } else { 0.0 };
+            let rms = (offsets.iter().map(|x| (x.1 - (my + slope * (x.0 - mx))).powi(2)).sum::<f64>() / n).sqrt();
+            (-rms / (1000.0 / fps)).exp() // 1/e at a residual of one frame
+        }
+    };
+
+    let jitter_score = (jitter_reduction_db / 20.0).clamp(0.0, 1.0);
+    let crop_score = (2.0 - mean_crop_factor).clamp(0.0, 1.0);
+    let overall = 100.0 * (0.35 * jitter_score + 0.2 * crop_score + 0.25 * gyro_coverage_fraction + 0.2 * sync_quality);
+
+    QualityScore { overall, jitter_reduction_db, mean_crop_factor, gyro_coverage_fraction, sync_quality }
+}
@@ -46,14 +46,25 @@ pub struct ComputeParams {
     pub adaptive_zoom_window: f64,
     pub adaptive_zoom_center_offset: (f64, f64),
     pub adaptive_zoom_method: i32,
+    pub smart_zoom_enabled: bool, // Per-frame zoom, only as much as needed to fill the frame. Takes precedence over `adaptive_zoom_window`
+    pub smart_zoom_smoothing_window: f64, // seconds
     pub framebuffer_inverted: bool,
     pub horizontal_rs: bool,
+    pub stabilize_only_translation: bool,
+    pub bypass_lens: bool,
+    pub bypass_digital_lens: bool,
+    pub perspective_correction_enabled: bool,
+    pub perspective_correction_params: [f64; 8], // Homography from the corrected to the original position in normalized (0-1) output coordinates, h33 = 1
+    pub user_distortion: [f64; 4], // k1, k2, p1, p2 - Brown-Conrady distortion applied after the lens profile, in normalized camera coordinates
+    pub input_crop: Option<(u32, u32, u32, u32)>, // x, y, width, height - in video pixels
 
     pub zooming_debug_points: bool,
 
     pub distortion_model: DistortionModel,
     pub digital_lens: Option<DistortionModel>,
-    pub digital_lens_params: Option<Vec<f64>>
+    pub digital_lens_params: Option<Vec<f64>>,
+    pub blended_lens: Option<DistortionModel>,
+    pub blended_lens_params: Option<crate::stabilization_params::BlendedLens>
 }
 impl ComputeParams {
     pub fn from_manager(mgr: &StabilizationManager) -> Self {
@@ -63,6 +74,7 @@ impl ComputeParams {
 
         let distortion_model = DistortionModel::from_name(lens.distortion_model.as_deref().unwrap_or("opencv_fisheye"));
         let digital_lens = lens.digital_lens.as_ref().map(|x| DistortionModel::from_name(&x));
+        let blended_lens = params.blended_lens.as_ref().map(|x| DistortionModel::from_name(&x.distortion_model));
 
         Self {
             gyro: mgr.gyro.clone(),
@@ -91,6 +103,13 @@ impl ComputeParams {
             lens_correction_amount: params.lens_correction_amount,
             framebuffer_inverted: params.framebuffer_inverted,
             horizontal_rs: params.horizontal_rs,
+            stabilize_only_translation: params.stabilize_only_translation,
+            bypass_lens: params.bypass_lens,
+            bypass_digital_lens: params.bypass_digital_lens,
+            perspective_correction_enabled: params.perspective_correction.is_some(),
+            perspective_correction_params: params.perspective_correction.unwrap_or_default(),
+            user_distortion: params.user_distortion,
+            input_crop: params.input_crop,
             frame_readout_time: params.frame_readout_time,
             trim_start: params.trim_start,
             trim_end: params.trim_end,
@@ -98,6 +117,8 @@ impl ComputeParams {
             adaptive_zoom_window: params.adaptive_zoom_window,
             adaptive_zoom_center_offset: params.adaptive_zoom_center_offset,
             adaptive_zoom_method: params.adaptive_zoom_method,
+            smart_zoom_enabled: params.smart_zoom_enabled,
+            smart_zoom_smoothing_window: params.smart_zoom_smoothing_window,
             video_speed: params.video_speed,
             video_speed_affects_smoothing: params.video_speed_affects_smoothing,
             video_speed_affects_zooming: params.video_speed_affects_zooming,
@@ -105,12 +126,31 @@ impl ComputeParams {
             distortion_model,
             digital_lens,
             digital_lens_params: lens.digital_lens_params.clone(),
+            blended_lens,
+            blended_lens_params: params.blended_lens.clone(),
 
             keyframes: mgr.keyframes.read().clone(),
 
             zooming_debug_points: false
         }
     }
+
+    // Homography for `perspective_correction_params`, from four coplanar points `selected` in the frame and the positions they should be moved to,
+    // eg. the corners of a rectangle. Normalized (0-1) output coordinates. None if the points are degenerate (three of them on a line)
+    pub fn perspective_correction_from_points(selected: &[(f64, f64); 4], target: &[(f64, f64); 4]) -> Option<[f64; 8]> {
+        // The kernel maps the corrected (target) position to the original (selected) one
+        let mut a = nalgebra::SMatrix::<f64, 8, 8>::zeros();
+        let mut b = nalgebra::SVector::<f64, 8>::zeros();
+        for (i, (&(x, y), &(u, v))) in target.iter().zip(selected.iter()).enumerate() {
+            a.set_row(i * 2,     &nalgebra::SMatrix::<f64, 1, 8>::from_row_slice(&[x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y]));
+            a.set_row(i * 2 + 1, &nalgebra::SMatrix::<f64, 1, 8>::from_row_slice(&[0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y]));
+            b[i * 2] = u;
+            b[i * 2 + 1] = v;
+        }
+        let h = a.lu().solve(&b)?;
+        if h.iter().any(|x| !x.is_finite()) { return None; }
+        Some([h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7]])
+    }
 }
 
 impl std::fmt::Debug for ComputeParams {
@@ -150,10 +190,14 @@ impl std::fmt::Debug for ComputeParams {
          .field("adaptive_zoom_window",      &self.adaptive_zoom_window)
          .field("adaptive_zoom_center_offset", &self.adaptive_zoom_center_offset)
          .field("adaptive_zoom_method",      &self.adaptive_zoom_method)
+         .field("smart_zoom_enabled",        &self.smart_zoom_enabled)
+         .field("smart_zoom_smoothing_window", &self.smart_zoom_smoothing_window)
          .field("framebuffer_inverted",      &self.framebuffer_inverted)
+         .field("input_crop",                &self.input_crop)
          .field("zooming_debug_points",      &self.zooming_debug_points)
          .field("distortion_model",          &self.distortion_model.id())
          .field("digital_lens",              &self.digital_lens.as_ref().map(|x| x.id()).unwrap_or("None"))
+         .field("blended_lens",              &self.blended_lens.as_ref().map(|x| x.id()).unwrap_or("None"))
          .finish()
     }
 }
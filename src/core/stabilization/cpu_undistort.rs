@@ -119,7 +119,7 @@ impl Stabilization {
     // Adapted from OpenCV: initUndistortRectifyMap + remap
     // https://github.com/opencv/opencv/blob/2b60166e5c65f1caccac11964ad760d847c536e4/modules/calib3d/src/fisheye.cpp#L465-L567
     // https://github.com/opencv/opencv/blob/2b60166e5c65f1caccac11964ad760d847c536e4/modules/imgproc/src/opencl/remap.cl#L390-L498
-    pub fn undistort_image_cpu<const I: i32, T: PixelType>(buffers: &mut Buffers, params: &KernelParams, distortion_model: &DistortionModel, digital_lens: Option<&DistortionModel>, matrices: &[[f32; 12]], drawing: &[u8]) -> bool {
+    pub fn undistort_image_cpu<const I: i32, T: PixelType>(buffers: &mut Buffers, params: &KernelParams, distortion_model: &DistortionModel, digital_lens: Option<&DistortionModel>, blended_lens: Option<&DistortionModel>, matrices: &[[f32; 12]], drawing: &[u8]) -> bool {
         // #[cold]
         // fn draw_pixel(pix: &mut Vector4<f32>, x: i32, y: i32, is_input: bool, width: i32, params: &KernelParams, drawing: &[u8]) {
         //     if drawing.is_empty() || (params.flags & 8) == 0 { return; }
@@ -151,30 +151,17 @@ impl Stabilization {
             return (x - in_min) * (out_max - out_min) / (in_max - in_min) + out_min;
         }
 
-        fn rotate_and_distort(pos: (f32, f32), idx: usize, params: &KernelParams, matrices: &[[f32; 12]], distortion_model: &DistortionModel, digital_lens: Option<&DistortionModel>, r_limit: f32) -> Option<(f32, f32)> {
-            let matrices = matrices[idx];
-            let _x = (pos.0 * matrices[0]) + (pos.1 * matrices[1]) + matrices[2] + params.translation3d[0];
-            let _y = (pos.0 * matrices[3]) + (pos.1 * matrices[4]) + matrices[5] + params.translation3d[1];
-            let _w = (pos.0 * matrices[6]) + (pos.1 * matrices[7]) + matrices[8] + params.translation3d[2];
-            if _w > 0.0 {
-                if params.r_limit > 0.0 && ((_x / _w).powi(2) + (_y / _w).powi(2)).sqrt() > r_limit {
-                    return None;
+        fn undistort_point_blended(pos: (f32, f32), params: &KernelParams, distortion_model: &DistortionModel, blended: Option<(&DistortionModel, &KernelParams)>) -> (f32, f32) {
+            if (params.flags & 512) == 512 { return pos; } // Lens bypassed
+            let mut pt = distortion_model.undistort_point(pos, params).unwrap_or_default();
+            if let Some((blended_lens, params_b)) = blended {
+                let t = DistortionModel::blend_weight(pt, params);
+                if t > 0.0 {
+                    let pt_b = blended_lens.undistort_point(pos, params_b).unwrap_or_default();
+                    pt = (pt.0 + (pt_b.0 - pt.0) * t, pt.1 + (pt_b.1 - pt.1) * t);
                 }
-                let mut uv = distortion_model.distort_point(_x, _y, _w, &params);
-                uv = ((uv.0 * params.f[0]) + params.c[0], (uv.1 * params.f[1]) + params.c[1]);
-
-                if (params.flags & 2) == 2 { // Has digital lens
-                    if let Some(digital) = digital_lens {
-                        uv = digital.distort_point(uv.0, uv.1, 1.0, params);
-                    }
-                }
-
-                if params.input_horizontal_stretch > 0.001 { uv.0 /= params.input_horizontal_stretch; }
-                if params.input_vertical_stretch   > 0.001 { uv.1 /= params.input_vertical_stretch; }
-
-                return Some(uv);
             }
-            return None;
+            pt
         }
 
         fn rotate_point(pos: (f32, f32), angle: f32, origin: (f32, f32)) -> (f32, f32) {
@@ -182,6 +169,19 @@ impl Stabilization {
                      angle.sin() * (pos.0 - origin.0) + angle.cos() * (pos.1 - origin.1) + origin.1);
         }
 
+        // Ghosting trail effect, mix in the unstabilized input at the same output position
+        fn blend_with_original<const I: i32, T: PixelType>(pixel: Vector4<f32>, p: (f32, f32), input: &[u8], params: &KernelParams, bg: &Vector4<f32>, drawing: &[u8]) -> Vector4<f32> {
+            let org_uv = (p.0 * params.width as f32 / params.output_width as f32, p.1 * params.height as f32 / params.output_height as f32);
+            let org = sample_input_at::<I, T>(org_uv, input, params, bg, drawing);
+            pixel * (1.0 - params.blend_with_original) + org * params.blend_with_original
+        }
+
+        // Per-frame exposure and white balance normalization, computed by the host
+        fn color_correct(pixel: Vector4<f32>, params: &KernelParams) -> Vector4<f32> {
+            let correct = |v: f32, gain: f32| (v * gain + params.color_offset).max(0.0).min(params.pixel_value_limit);
+            Vector4::new(correct(pixel[0], params.color_gain[0]), correct(pixel[1], params.color_gain[1]), correct(pixel[2], params.color_gain[2]), pixel[3])
+        }
+
         fn sample_input_at<const I: i32, T: PixelType>(mut uv: (f32, f32), input: &[u8], params: &KernelParams, bg: &Vector4<f32>, _drawing: &[u8]) -> Vector4<f32> {
             const INTER_BITS: usize = 5;
             const INTER_TAB_SIZE: usize = 1 << INTER_BITS;
@@ -210,18 +210,35 @@ impl Stabilization {
             let coeffs_x = &COEFFS[ind + ((sx0 as usize & (INTER_TAB_SIZE - 1)) << shift)..];
             let coeffs_y = &COEFFS[ind + ((sy0 as usize & (INTER_TAB_SIZE - 1)) << shift)..];
 
+            // Straight alpha is interpolated premultiplied, so transparent fill doesn't darken the edges
+            let has_alpha = (params.flags & 64) == 64;
+            let premultiply = |v: Vector4<f32>| if has_alpha { let a = v.w / params.max_pixel_value; Vector4::new(v.x * a, v.y * a, v.z * a, v.w) } else { v };
+            let bg = &premultiply(transfer_to_linear(*bg, params));
+            // Premultiplied input is resampled as-is and stays premultiplied, see KernelParamsFlags::PREMULTIPLIED_INPUT
+            let premultiplied_input = (params.flags & 8192) == 8192;
+
             let mut sum = Vector4::from_element(0.0);
-            let mut src_index = sy as isize * params.stride as isize + sx as isize * params.bytes_per_pixel as isize;
+            let has_crop = params.input_crop[2] > 0 && params.input_crop[3] > 0;
 
             for yp in 0..I {
-                if sy + yp >= params.source_rect[1] && sy + yp < params.source_rect[1] + params.source_rect[3] {
+                let mut py = sy + yp;
+                if has_crop { // Input crop, clamp to the edge pixel
+                    py = py.max(params.input_crop[1]).min(params.input_crop[1] + params.input_crop[3] - 1);
+                }
+                if py >= params.source_rect[1] && py < params.source_rect[1] + params.source_rect[3] {
                     let mut xsum = Vector4::<f32>::from_element(0.0);
                     for xp in 0..I {
-                        let pixel = if sx + xp >= params.source_rect[0] && sx + xp < params.source_rect[0] + params.source_rect[2] {
-                            let px1: &T = bytemuck::from_bytes(&input[src_index as usize + (params.bytes_per_pixel * xp) as usize..src_index as usize + (params.bytes_per_pixel * (xp + 1)) as usize]);
+                        let mut px = sx + xp;
+                        if has_crop {
+                            px = px.max(params.input_crop[0]).min(params.input_crop[0] + params.input_crop[2] - 1);
+                        }
+                        let pixel = if px >= params.source_rect[0] && px < params.source_rect[0] + params.source_rect[2] {
+                            let src_index = py as usize * params.stride as usize + px as usize * params.bytes_per_pixel as usize;
+                            let px1: &T = bytemuck::from_bytes(&input[src_index..src_index + params.bytes_per_pixel as usize]);
                             let src_px = PixelType::to_float(*px1);
                             // draw_pixel(&mut src_px, sx + xp, sy + yp, true, params.width, params, drawing);
-                            src_px
+                            let src_px = transfer_to_linear(src_px, params);
+                            if premultiplied_input { src_px } else { premultiply(src_px) }
                         } else {
                             *bg
                         };
@@ -232,8 +249,12 @@ impl Stabilization {
                 } else {
                     sum += bg * coeffs_y[yp as usize];
                 }
-                src_index += params.stride as isize;
             }
+            if has_alpha && !premultiplied_input && sum.w > 0.0 {
+                let a = sum.w / params.max_pixel_value;
+                sum = Vector4::new(sum.x / a, sum.y / a, sum.z / a, sum.w);
+            }
+            let sum = transfer_from_linear(sum, params);
             Vector4::new(
                 sum.x.min(params.max_pixel_value),
                 sum.y.min(params.max_pixel_value),
@@ -246,6 +267,10 @@ impl Stabilization {
             if let BufferSource::Cpu { buffer: output } = &mut buffers.output.data {
                 let r_limit = params.r_limit * params.r_limit; // Square it so we don't have to do sqrt on the point length
 
+                // Second lens model reads its own coefficients
+                let params_b = KernelParams { k: params.blend_k, ..*params };
+                let blended = blended_lens.filter(|_| (params.flags & 32) == 32).map(|x| (x, &params_b));
+
                 let bg = Vector4::<f32>::new(params.background[0], params.background[1], params.background[2], params.background[3]) * params.max_pixel_value;
                 let bg_t: T = PixelType::from_float(bg);
 
@@ -262,20 +287,31 @@ impl Stabilization {
                     return false;
                 }
 
+                // Every pixel is computed independently, so the thread scheduling doesn't change the output
                 output.par_chunks_mut(buffers.output.size.2).enumerate().for_each(|(y, row_bytes)| { // Parallel iterator over buffer rows
                     row_bytes.chunks_mut(params.bytes_per_pixel as usize).enumerate().for_each(|(x, pix_chunk)| { // iterator over row pixels
 
+                        // For 90 and 270 degrees the output buffer has width and height swapped
+                        let (out_w, out_h) = if params.output_orientation & 1 != 0 { (params.output_height, params.output_width) } else { (params.output_width, params.output_height) };
                         let mut out_pos = (
-                            map_coord(x as f32, params.output_rect[0] as f32, (params.output_rect[0] + params.output_rect[2]) as f32, 0.0, params.output_width as f32 ),
-                            map_coord(y as f32, params.output_rect[1] as f32, (params.output_rect[1] + params.output_rect[3]) as f32, 0.0, params.output_height as f32)
+                            map_coord(x as f32, params.output_rect[0] as f32, (params.output_rect[0] + params.output_rect[2]) as f32, 0.0, out_w as f32),
+                            map_coord(y as f32, params.output_rect[1] as f32, (params.output_rect[1] + params.output_rect[3]) as f32, 0.0, out_h as f32)
                         );
+                        if params.output_orientation != 0 {
+                            out_pos = unorient_output(out_pos, params.output_orientation, params.output_width as f32, params.output_height as f32);
+                        }
 
                         if out_pos.0 >= 0.0 && out_pos.1 >= 0.0 && (out_pos.0 as i32) < params.output_width && (out_pos.1 as i32) < params.output_height {
                             assert!(pix_chunk.len() == std::mem::size_of::<T>());
 
-                            // let p = out_pos;
+                            let p = out_pos;
                             let mut pixel = bg;
 
+                            if (params.flags & 2048) == 2048 { // Perspective correction
+                                let h = &params.perspective;
+                                let w = h[6] * out_pos.0 + h[7] * out_pos.1 + 1.0;
+                                out_pos = ((h[0] * out_pos.0 + h[1] * out_pos.1 + h[2]) / w, (h[3] * out_pos.0 + h[4] * out_pos.1 + h[5]) / w);
+                            }
                             out_pos.0 += params.translation2d[0];
                             out_pos.1 += params.translation2d[1];
 
@@ -288,10 +324,10 @@ impl Stabilization {
 
                             ///////////////////////////////////////////////////////////////////
                             // Add lens distortion back
-                            if params.lens_correction_amount < 1.0 {
+                            if params.lens_correction_amount < 1.0 && params.projection_type == 0 {
                                 let mut new_out_pos = out_pos;
 
-                                if (params.flags & 2) == 2 { // Has digial lens
+                                if (params.flags & (2 | 1024)) == 2 { // Has digital lens, not bypassed
                                     if let Some(digital) = digital_lens {
                                         if let Some(pt) = digital.undistort_point(new_out_pos, params) {
                                             new_out_pos = pt;
@@ -300,7 +336,7 @@ impl Stabilization {
                                 }
 
                                 new_out_pos = ((new_out_pos.0 - out_c.0) / out_f.0, (new_out_pos.1 - out_c.1) / out_f.1);
-                                new_out_pos = distortion_model.undistort_point(new_out_pos, &params).unwrap_or_default();
+                                new_out_pos = undistort_point_blended(new_out_pos, params, distortion_model, blended);
                                 new_out_pos = ((new_out_pos.0 * out_f.0) + out_c.0, (new_out_pos.1 * out_f.1) + out_c.1);
 
                                 out_pos = (
@@ -319,7 +355,7 @@ impl Stabilization {
                             };
                             if params.matrix_count > 1 {
                                 let idx = params.matrix_count as usize / 2;
-                                if let Some(pt) = rotate_and_distort(out_pos, idx, params, matrices, distortion_model, digital_lens, r_limit) {
+                                if let Some(pt) = rotate_and_distort(out_pos, idx, params, matrices, distortion_model, digital_lens, blended, r_limit) {
                                     if (params.flags & 16) == 16 { // Horizontal RS
                                         sy = (pt.0.round() as i32).min(params.width).max(0) as usize;
                                     } else {
@@ -330,7 +366,7 @@ impl Stabilization {
                             ///////////////////////////////////////////////////////////////////
 
                             let idx = sy.min(params.matrix_count as usize - 1);
-                            if let Some(mut uv) = rotate_and_distort(out_pos, idx, params, matrices, distortion_model, digital_lens, r_limit) {
+                            if let Some(mut uv) = rotate_and_distort(out_pos, idx, params, matrices, distortion_model, digital_lens, blended, r_limit) {
                                 let width_f = params.width as f32;
                                 let height_f = params.height as f32;
                                 match params.background_mode {
@@ -370,6 +406,12 @@ impl Stabilization {
                                         let c1 = sample_input_at::<I, T>(uv, input, params, &bg, drawing);
                                         let c2 = sample_input_at::<I, T>(pt2, input, params, &bg, drawing);
                                         pixel = c1 * alpha + c2 * (1.0 - alpha);
+                                        if params.blend_with_original > 0.0 {
+                                            pixel = blend_with_original::<I, T>(pixel, p, input, params, &bg, drawing);
+                                        }
+                                        if (params.flags & 256) == 256 {
+                                            pixel = color_correct(pixel, params);
+                                        }
                                         // draw_pixel(&mut pixel, p.0 as i32, p.1 as i32, false, params.output_width, params, drawing);
                                         if fix_range {
                                             remap_colorrange(&mut pixel, is_y)
@@ -382,6 +424,12 @@ impl Stabilization {
 
                                 pixel = sample_input_at::<I, T>(uv, input, params, &bg, drawing);
                             }
+                            if params.blend_with_original > 0.0 {
+                                pixel = blend_with_original::<I, T>(pixel, p, input, params, &bg, drawing);
+                            }
+                            if (params.flags & 256) == 256 {
+                                pixel = color_correct(pixel, params);
+                            }
                             // draw_pixel(&mut pixel, p.0 as i32, p.1 as i32, false, params.output_width, params, drawing);
 
                             if fix_range {
@@ -401,6 +449,80 @@ impl Stabilization {
     }
 }
 
+fn distort_point_blended(x: f32, y: f32, z: f32, params: &KernelParams, distortion_model: &DistortionModel, blended: Option<(&DistortionModel, &KernelParams)>) -> (f32, f32) {
+    if (params.flags & 512) == 512 { return (x / z, y / z); } // Lens bypassed
+    let mut pt = distortion_model.distort_point(x, y, z, params);
+    if let Some((blended_lens, params_b)) = blended {
+        let t = DistortionModel::blend_weight((x / z, y / z), params);
+        if t > 0.0 {
+            let pt_b = blended_lens.distort_point(x, y, z, params_b);
+            pt = (pt.0 + (pt_b.0 - pt.0) * t, pt.1 + (pt_b.1 - pt.1) * t);
+        }
+    }
+    pt
+}
+
+fn rotate_and_distort(pos: (f32, f32), idx: usize, params: &KernelParams, matrices: &[[f32; 12]], distortion_model: &DistortionModel, digital_lens: Option<&DistortionModel>, blended: Option<(&DistortionModel, &KernelParams)>, r_limit: f32) -> Option<(f32, f32)> {
+    let matrices = matrices[idx];
+    let is_equirect = params.projection_type == 1;
+    let dir = if is_equirect { // Equirectangular, matrix is only the rotation of the direction on the sphere
+        super::distortion_models::Equirectangular::pixel_to_direction(pos, params.output_width as f32, params.output_height as f32)
+    } else {
+        (pos.0, pos.1, 1.0)
+    };
+    let _x = (dir.0 * matrices[0]) + (dir.1 * matrices[1]) + dir.2 * matrices[2] + params.translation3d[0];
+    let _y = (dir.0 * matrices[3]) + (dir.1 * matrices[4]) + dir.2 * matrices[5] + params.translation3d[1];
+    let _w = if (params.flags & 16384) == 16384 && !is_equirect { // Affine, bottom row is [0, 0, 1]
+        dir.2 + params.translation3d[2]
+    } else {
+        (dir.0 * matrices[6]) + (dir.1 * matrices[7]) + dir.2 * matrices[8] + params.translation3d[2]
+    };
+    if is_equirect {
+        let uv = distortion_model.distort_point(_x, _y, _w, params);
+        return Some(((uv.0 * params.f[0]) + params.c[0], (uv.1 * params.f[1]) + params.c[1]));
+    }
+    if _w > 0.0 {
+        if params.r_limit > 0.0 && ((_x / _w).powi(2) + (_y / _w).powi(2)).sqrt() > r_limit {
+            return None;
+        }
+        let mut uv = distort_point_blended(_x, _y, _w, params, distortion_model, blended);
+        if (params.flags & 4096) == 4096 { // Manual correction on top of the lens profile
+            let [k1, k2, p1, p2] = params.user_distortion;
+            let (x, y) = uv;
+            let r2 = x * x + y * y;
+            let radial = 1.0 + k1 * r2 + k2 * r2 * r2;
+            uv = (x * radial + 2.0 * p1 * x * y + p2 * (r2 + 2.0 * x * x),
+                  y * radial + p1 * (r2 + 2.0 * y * y) + 2.0 * p2 * x * y);
+        }
+        uv = ((uv.0 * params.f[0]) + params.c[0], (uv.1 * params.f[1]) + params.c[1]);
+
+        if (params.flags & (2 | 1024)) == 2 { // Has digital lens, not bypassed
+            if let Some(digital) = digital_lens {
+                uv = digital.distort_point(uv.0, uv.1, 1.0, params);
+            }
+        }
+
+        if params.input_horizontal_stretch > 0.001 { uv.0 /= params.input_horizontal_stretch; }
+        if params.input_vertical_stretch   > 0.001 { uv.1 /= params.input_vertical_stretch; }
+
+        return Some(uv);
+    }
+    return None;
+}
+
+// Maps a position in the oriented (rotated, then flipped) output back to the unrotated output of size w x h
+pub(super) fn unorient_output(mut pos: (f32, f32), orientation: i32, w: f32, h: f32) -> (f32, f32) {
+    let (ow, oh) = if orientation & 1 != 0 { (h, w) } else { (w, h) };
+    if orientation & 4 != 0 { pos.0 = ow - 1.0 - pos.0; }
+    if orientation & 8 != 0 { pos.1 = oh - 1.0 - pos.1; }
+    match orientation & 3 {
+        1 => (pos.1, h - 1.0 - pos.0),
+        2 => (w - 1.0 - pos.0, h - 1.0 - pos.1),
+        3 => (w - 1.0 - pos.1, pos.0),
+        _ => pos
+    }
+}
+
 pub fn undistort_points_with_rolling_shutter(distorted: &[(f32, f32)], timestamp_ms: f64, params: &ComputeParams, lens_correction_amount: f64) -> Vec<(f32, f32)> {
     if distorted.is_empty() { return Vec::new(); }
     let (camera_matrix, distortion_coeffs, _p, rotations) = FrameTransform::at_timestamp_for_points(params, distorted, timestamp_ms);
@@ -498,3 +620,75 @@ pub fn undistort_points(distorted: &[(f32, f32)], camera_matrix: Matrix3<f64>, d
         }
     }).collect()
 }
+
+// HDR transfer functions, normalized signal <-> linear light. Scene light for HLG, without the OOTF, so the round trip is exact
+pub fn pq_to_linear(e: f32) -> f32 {
+    let p = e.max(0.0).min(1.0).powf(1.0 / 78.84375);
+    ((p - 0.8359375).max(0.0) / (18.8515625 - 18.6875 * p)).powf(1.0 / 0.1593017578125)
+}
+pub fn linear_to_pq(y: f32) -> f32 {
+    let p = y.max(0.0).min(1.0).powf(0.1593017578125);
+    ((0.8359375 + 18.8515625 * p) / (1.0 + 18.6875 * p)).powf(78.84375)
+}
+pub fn hlg_to_linear(e: f32) -> f32 {
+    let e = e.max(0.0).min(1.0);
+    if e <= 0.5 { e * e / 3.0 } else { (((e - 0.55991073) / 0.17883277).exp() + 0.28466892) / 12.0 }
+}
+pub fn linear_to_hlg(l: f32) -> f32 {
+    let l = l.max(0.0).min(1.0);
+    if l <= 1.0 / 12.0 { (3.0 * l).sqrt() } else { 0.17883277 * (12.0 * l - 0.28466892).ln() + 0.55991073 }
+}
+// Applied to the color channels only, alpha is left as is
+fn transfer_to_linear(px: Vector4<f32>, params: &KernelParams) -> Vector4<f32> {
+    let f = match params.transfer_function { 1 => pq_to_linear, 2 => hlg_to_linear, _ => return px };
+    let scale = if params.pixel_value_limit == 1.0 { 1.0 } else { params.max_pixel_value };
+    Vector4::new(f(px.x / scale) * scale, f(px.y / scale) * scale, f(px.z / scale) * scale, px.w)
+}
+fn transfer_from_linear(px: Vector4<f32>, params: &KernelParams) -> Vector4<f32> {
+    let f = match params.transfer_function { 1 => linear_to_pq, 2 => linear_to_hlg, _ => return px };
+    let scale = if params.pixel_value_limit == 1.0 { 1.0 } else { params.max_pixel_value };
+    Vector4::new(f(px.x / scale) * scale, f(px.y / scale) * scale, f(px.z / scale) * scale, px.w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn equirect_params(w: i32, h: i32) -> KernelParams {
+        KernelParams {
+            width: w, height: h,
+            output_width: w, output_height: h,
+            f: [w as f32 / (2.0 * std::f32::consts::PI), h as f32 / std::f32::consts::PI],
+            c: [w as f32 / 2.0, h as f32 / 2.0],
+            projection_type: 1,
+            ..Default::default()
+        }
+    }
+
+    // Rotation of the sphere around the vertical axis, laid out like FrameTransform's matrices
+    fn yaw_matrix(deg: f32) -> [f32; 12] {
+        let (s, c) = deg.to_radians().sin_cos();
+        [c, 0.0, s,   0.0, 1.0, 0.0,   -s, 0.0, c,   0.0, 0.0, 0.0]
+    }
+
+    #[test]
+    fn equirectangular_yaw_brings_hemisphere_to_center() {
+        let (w, h) = (400, 200);
+        let params = equirect_params(w, h);
+        let model = DistortionModel::from_name("equirectangular");
+        let center = (w as f32 / 2.0, h as f32 / 2.0);
+
+        let sample = |deg: f32, pos: (f32, f32)| rotate_and_distort(pos, 0, &params, &[yaw_matrix(deg)], &model, None, None, 0.0).unwrap();
+        let assert_near = |a: (f32, f32), b: (f32, f32)| assert!((a.0 - b.0).abs() < 0.01 && (a.1 - b.1).abs() < 0.01, "{a:?} != {b:?}");
+
+        // No rotation samples the same pixel
+        assert_near(sample(0.0, center), center);
+        // 90° yaw brings the middle of the right hemisphere (longitude +90°) to the center, and the left one for -90°
+        assert_near(sample(90.0,  center), (w as f32 * 0.75, center.1));
+        assert_near(sample(-90.0, center), (w as f32 * 0.25, center.1));
+        // The former center moves to the other side
+        assert_near(sample(90.0, (w as f32 * 0.25, center.1)), center);
+        // Poles and the horizon don't move vertically
+        assert_near(sample(90.0, (center.0, h as f32 * 0.25)), (w as f32 * 0.75, h as f32 * 0.25));
+    }
+}
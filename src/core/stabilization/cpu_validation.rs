@@ -0,0 +1,222 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use super::Stabilization;
+#[cfg(any(debug_assertions, feature = "opencl-core"))]
+use super::FrameTransform;
+#[cfg(any(debug_assertions, feature = "opencl-core"))]
+use super::PixelType;
+#[cfg(any(debug_assertions, feature = "opencl-core"))]
+use crate::gpu::{ Buffers, BufferDescription, BufferSource };
+
+impl Stabilization {
+    // Debug builds only: after every GPU render to CPU buffers, render the frame again with the CPU reference and compare
+    // every `sample_step`-th pixel in both directions. Differences above `tolerance` (fraction of the max pixel value) are logged.
+    // Very slow, meant for catching kernel regressions during development. 0 disables it, no-op in release builds
+    pub fn set_cpu_validation(&mut self, sample_step: usize, tolerance: f32) {
+        self.cpu_validation = (sample_step, tolerance.max(0.0));
+    }
+
+    #[cfg(debug_assertions)]
+    pub(super) fn validate_against_cpu<T: PixelType>(&self, buffers: &Buffers, itm: &FrameTransform, backend: &str) {
+        let (step, tolerance) = self.cpu_validation;
+        let (BufferSource::Cpu { buffer: input }, BufferSource::Cpu { buffer: output }) = (&buffers.input.data, &buffers.output.data) else { return; };
+
+        let mut input_copy = input.to_vec();
+        let mut reference = vec![0u8; output.len()];
+        let mut ref_buffers = Buffers {
+            input: BufferDescription { size: buffers.input.size, rect: buffers.input.rect, rotation: buffers.input.rotation, data: BufferSource::Cpu { buffer: &mut input_copy }, ..Default::default() },
+            output: BufferDescription { size: buffers.output.size, rect: buffers.output.rect, rotation: buffers.output.rotation, data: BufferSource::Cpu { buffer: &mut reference }, ..Default::default() },
+        };
+        if !self.undistort_cpu::<T>(&mut ref_buffers, itm, self.drawing.get_buffer()) {
+            log::warn!("CPU validation: reference render failed");
+            return;
+        }
+
+        let (width, height, stride) = buffers.output.size;
+        let bpp = T::COUNT * T::SCALAR_BYTES;
+        let max = T::default_max_value().unwrap_or(1.0);
+        let mut diverged = 0;
+        for y in (0..height).step_by(step) {
+            for x in (0..width).step_by(step) {
+                let offs = y * stride + x * bpp;
+                if offs + bpp > output.len() { continue; }
+                let a = T::to_float(bytemuck::pod_read_unaligned::<T>(&output[offs..offs + bpp]));
+                let b = T::to_float(bytemuck::pod_read_unaligned::<T>(&reference[offs..offs + bpp]));
+                let diff = (a - b).abs().max() / max;
+                if diff > tolerance {
+                    if diverged < 10 {
+                        log::warn!("CPU validation: {backend} output differs at ({x}, {y}) by {diff:.4}: {:?} vs CPU {:?}", a.as_slice(), b.as_slice());
+                    }
+                    diverged += 1;
+                }
+            }
+        }
+        if diverged > 0 {
+            log::warn!("CPU validation: {diverged} sampled pixels of {backend} output differ by more than {tolerance}");
+        }
+    }
+
+    // Render the frame at `timestamp_us` to CPU buffers with fresh OpenCL and wgpu wrappers and compare every pixel.
+    // Returns the number of differing pixels and the largest difference (fraction of the max pixel value), None if either backend failed.
+    // The frame has to be computed already, eg. by `ensure_ready_for_processing`. For checking that the WGSL kernel matches the OpenCL one
+    #[cfg(feature = "opencl-core")]
+    pub fn compare_wgpu_with_opencl<T: PixelType>(&self, timestamp_us: i64, buffers: &Buffers) -> Option<(usize, f32)> {
+        let itm = self.stab_data.get(&timestamp_us)?;
+        let (BufferSource::Cpu { buffer: input }, BufferSource::Cpu { .. }) = (&buffers.input.data, &buffers.output.data) else { return None; };
+        let output_len = buffers.output.size.2 * buffers.output.size.1;
+        let canvas_len = self.drawing.get_buffer_len();
+        let mut params = itm.kernel_params;
+        params.interpolation = self.effective_interpolation() as i32;
+
+        let render = |use_wgpu: bool| -> Option<Vec<u8>> {
+            let mut input_copy = input.to_vec();
+            let mut output = vec![0u8; output_len];
+            let mut buffers = Buffers {
+                input: BufferDescription { size: buffers.input.size, rect: buffers.input.rect, rotation: buffers.input.rotation, data: BufferSource::Cpu { buffer: &mut input_copy }, ..Default::default() },
+                output: BufferDescription { size: buffers.output.size, rect: buffers.output.rect, rotation: buffers.output.rotation, data: BufferSource::Cpu { buffer: &mut output }, ..Default::default() },
+            };
+            let (distortion_model, digital_lens, blended_lens) = (self.compute_params.distortion_model.clone(), self.compute_params.digital_lens.clone(), self.compute_params.blended_lens.clone());
+            if use_wgpu {
+                let wgpu = crate::gpu::wgpu::WgpuWrapper::new(&params, T::wgpu_format()?, distortion_model, digital_lens, blended_lens, &buffers, canvas_len)
+                    .map_err(|e| log::error!("Backend comparison: wgpu init error {e:?}")).ok()?;
+                if !wgpu.undistort_image(&mut buffers, itm, self.drawing.get_buffer()) { return None; }
+            } else {
+                let cl = crate::gpu::opencl::OclWrapper::new(&params, T::ocl_names(), distortion_model, digital_lens, blended_lens, &buffers, canvas_len, false, false)
+                    .map_err(|e| log::error!("Backend comparison: OpenCL init error {e:?}")).ok()?;
+                cl.undistort_image(&mut buffers, itm, self.drawing.get_buffer()).map_err(|e| log::error!("Backend comparison: OpenCL error {e:?}")).ok()?;
+            }
+            Some(output)
+        };
+        let opencl = render(false)?;
+        let wgpu = render(true)?;
+
+        let (width, height, stride) = buffers.output.size;
+        let bpp = T::COUNT * T::SCALAR_BYTES;
+        let max = T::default_max_value().unwrap_or(1.0);
+        let mut differing = 0;
+        let mut max_diff = 0.0f32;
+        for y in 0..height {
+            for x in 0..width {
+                let offs = y * stride + x * bpp;
+                if offs + bpp > output_len { continue; }
+                let a = T::to_float(bytemuck::pod_read_unaligned::<T>(&opencl[offs..offs + bpp]));
+                let b = T::to_float(bytemuck::pod_read_unaligned::<T>(&wgpu[offs..offs + bpp]));
+                let diff = (a - b).abs().max() / max;
+                if diff > 0.0 {
+                    differing += 1;
+                    max_diff = max_diff.max(diff);
+                }
+            }
+        }
+        Some((differing, max_diff))
+    }
+
+    // Render the frame at `timestamp_us` with a fresh OpenCL wrapper writing the secondary output of `size` (width, height, stride),
+    // and independently with the output at that size, then compare every pixel. The secondary output is a box downscale of the full render,
+    // so returns the number of pixels differing by more than `tolerance` (fraction of the max pixel value) and the largest difference.
+    // `size` has to keep the aspect ratio of the output. Drawing is disabled in both renders. None if either render failed
+    #[cfg(feature = "opencl-core")]
+    pub fn compare_secondary_output<T: PixelType>(&mut self, timestamp_us: i64, buffers: &Buffers, size: (usize, usize, usize), tolerance: f32) -> Option<(usize, f32)> {
+        let (BufferSource::Cpu { buffer: input }, BufferSource::Cpu { .. }) = (&buffers.input.data, &buffers.output.data) else { return None; };
+        let (width, height, stride) = size;
+        let full_itm = self.stab_data.get(&timestamp_us)?.clone();
+
+        // Same frame computed for the output at `size`
+        let saved = (self.output_size, self.compute_params.output_width, self.compute_params.output_height);
+        self.output_size = (width, height);
+        self.compute_params.output_width = width;
+        self.compute_params.output_height = height;
+        let mut input_copy = input.to_vec();
+        let mut output = vec![0u8; stride * height];
+        let mut small_buffers = Buffers {
+            input: BufferDescription { size: buffers.input.size, rect: buffers.input.rect, rotation: buffers.input.rotation, data: BufferSource::Cpu { buffer: &mut input_copy }, ..Default::default() },
+            output: BufferDescription { size, data: BufferSource::Cpu { buffer: &mut output }, ..Default::default() },
+        };
+        let small_itm = self.get_frame_transform_at::<T>(timestamp_us, &mut small_buffers);
+        (self.output_size, self.compute_params.output_width, self.compute_params.output_height) = saved;
+
+        let render = |itm: &FrameTransform, output_size: (usize, usize, usize), secondary: Option<(usize, usize, usize)>| -> Option<Vec<u8>> {
+            let mut itm = itm.clone();
+            itm.kernel_params.flags &= !super::KernelParamsFlags::DRAWING_ENABLED.bits();
+            let mut input_copy = input.to_vec();
+            let mut output = vec![0u8; output_size.2 * output_size.1];
+            let mut buffers = Buffers {
+                input: BufferDescription { size: buffers.input.size, rect: buffers.input.rect, rotation: buffers.input.rotation, data: BufferSource::Cpu { buffer: &mut input_copy }, ..Default::default() },
+                output: BufferDescription { size: output_size, data: BufferSource::Cpu { buffer: &mut output }, ..Default::default() },
+            };
+            let (distortion_model, digital_lens, blended_lens) = (self.compute_params.distortion_model.clone(), self.compute_params.digital_lens.clone(), self.compute_params.blended_lens.clone());
+            let mut cl = crate::gpu::opencl::OclWrapper::new(&itm.kernel_params, T::ocl_names(), distortion_model, digital_lens, blended_lens, &buffers, 0, false, false)
+                .map_err(|e| log::error!("Secondary output comparison: OpenCL init error {e:?}")).ok()?;
+            cl.set_secondary_output(secondary).map_err(|e| log::error!("Secondary output comparison: OpenCL error {e:?}")).ok()?;
+            cl.undistort_image(&mut buffers, &itm, &[]).map_err(|e| log::error!("Secondary output comparison: OpenCL error {e:?}")).ok()?;
+            if secondary.is_some() {
+                let mut secondary_output = vec![0u8; stride * height];
+                cl.read_secondary_output(&mut secondary_output).map_err(|e| log::error!("Secondary output comparison: OpenCL error {e:?}")).ok()?;
+                return Some(secondary_output);
+            }
+            Some(output)
+        };
+        let secondary = render(&full_itm, buffers.output.size, Some(size))?;
+        let independent = render(&small_itm, size, None)?;
+
+        let bpp = T::COUNT * T::SCALAR_BYTES;
+        let max = T::default_max_value().unwrap_or(1.0);
+        let mut differing = 0;
+        let mut max_diff = 0.0f32;
+        for y in 0..height {
+            for x in 0..width {
+                let offs = y * stride + x * bpp;
+                if offs + bpp > secondary.len() { continue; }
+                let a = T::to_float(bytemuck::pod_read_unaligned::<T>(&secondary[offs..offs + bpp]));
+                let b = T::to_float(bytemuck::pod_read_unaligned::<T>(&independent[offs..offs + bpp]));
+                let diff = (a - b).abs().max() / max;
+                if diff > tolerance { differing += 1; }
+                max_diff = max_diff.max(diff);
+            }
+        }
+        Some((differing, max_diff))
+    }
+}
+
+#[cfg(all(test, feature = "opencl-core"))]
+mod tests {
+    use super::*;
+    use crate::stabilization::{ KernelParams, RGBA8, distortion_models::DistortionModel };
+
+    #[test]
+    fn wgpu_matches_opencl() {
+        if crate::gpu::wgpu::WgpuWrapper::list_devices().is_empty() || crate::gpu::opencl::OclWrapper::list_devices().is_empty() {
+            return; // Needs both backends
+        }
+        let _lock = crate::gpu::opencl::GLOBAL_CONTEXT_LOCK.lock();
+
+        let (width, height, stride) = (32, 32, 32 * 4);
+        let params = KernelParams {
+            width: width as i32, height: height as i32, stride: stride as i32,
+            output_width: width as i32, output_height: height as i32, output_stride: stride as i32,
+            bytes_per_pixel: 4, pix_element_count: 4, matrix_count: 1,
+            f: [1.0, 1.0], fov: 1.0, lens_correction_amount: 1.0,
+            pixel_value_limit: 255.0, max_pixel_value: 255.0,
+            source_rect: [0, 0, width as i32, height as i32], output_rect: [0, 0, width as i32, height as i32],
+            ..Default::default()
+        };
+        let mut stab = Stabilization::default();
+        stab.init_size((width, height), (width, height));
+        stab.compute_params.distortion_model = DistortionModel::from_name("opencv_standard");
+        stab.stab_data.insert(0, FrameTransform {
+            matrices: vec![[1.0, 0.0, 1.0,  0.0, 1.0, 0.0,  0.0, 0.0, 1.0,  0.0, 0.0, 0.0]], // x + 1
+            kernel_params: params,
+            ..Default::default()
+        });
+
+        let mut input: Vec<u8> = (0..stride * height).map(|i| ((i % stride) * 2 + (i / stride) * 3) as u8).collect();
+        let mut output = vec![0u8; stride * height];
+        let buffers = Buffers {
+            input:  BufferDescription { size: (width, height, stride), data: BufferSource::Cpu { buffer: &mut input }, ..Default::default() },
+            output: BufferDescription { size: (width, height, stride), data: BufferSource::Cpu { buffer: &mut output }, ..Default::default() },
+        };
+        let (differing, max_diff) = stab.compare_wgpu_with_opencl::<RGBA8>(0, &buffers).expect("Render failed");
+        assert_eq!(differing, 0, "{differing} pixels differ, up to {max_diff}");
+    }
+}
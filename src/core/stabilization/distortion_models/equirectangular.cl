@@ -0,0 +1,13 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+float2 undistort_point(float2 pos, __global KernelParams *params) {
+    if (fabs(pos.x) >= M_PI_2_F || fabs(pos.y) >= M_PI_2_F) {
+        return (float2)(0.0f, 0.0f);
+    }
+    return (float2)(tan(pos.x), tan(pos.y) / cos(pos.x));
+}
+
+float2 distort_point(float x, float y, float z, __global KernelParams *params) {
+    return (float2)(atan2(x, z), atan2(y, sqrt(x * x + z * z)));
+}
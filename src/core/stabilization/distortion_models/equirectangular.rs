@@ -0,0 +1,38 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// 360° equirectangular projection. Points are directions on the sphere, distorted coordinates are (longitude, latitude) in radians.
+// Camera matrix is expected to be f = (width / 2π, height / π), c = (width / 2, height / 2)
+
+use crate::stabilization::KernelParams;
+
+#[derive(Default, Clone)]
+pub struct Equirectangular { }
+
+impl Equirectangular {
+    pub fn undistort_point(&self, point: (f32, f32), _params: &KernelParams) -> Option<(f32, f32)> {
+        let (lon, lat) = point;
+        if lon.abs() >= std::f32::consts::FRAC_PI_2 || lat.abs() >= std::f32::consts::FRAC_PI_2 {
+            return None; // Not visible on the plane in front of the camera
+        }
+        Some((lon.tan(), lat.tan() / lon.cos()))
+    }
+
+    pub fn distort_point(&self, x: f32, y: f32, z: f32, _params: &KernelParams) -> (f32, f32) {
+        (x.atan2(z), y.atan2((x * x + z * z).sqrt()))
+    }
+    pub fn adjust_lens_profile(&self, _profile: &mut crate::LensProfile) { }
+
+    // Direction on the sphere for a pixel of the equirectangular frame
+    pub fn pixel_to_direction(pos: (f32, f32), width: f32, height: f32) -> (f32, f32, f32) {
+        let lon = (pos.0 / width  - 0.5) * 2.0 * std::f32::consts::PI;
+        let lat = (pos.1 / height - 0.5) * std::f32::consts::PI;
+        (lat.cos() * lon.sin(), lat.sin(), lat.cos() * lon.cos())
+    }
+
+    pub fn id() -> &'static str { "equirectangular" }
+    pub fn name() -> &'static str { "Equirectangular (360°)" }
+
+    pub fn opencl_functions(&self) -> &'static str { include_str!("equirectangular.cl") }
+    pub fn wgsl_functions(&self)   -> &'static str { include_str!("equirectangular.wgsl") }
+}
@@ -0,0 +1,13 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+fn undistort_point(pos: vec2<f32>) -> vec2<f32> {
+    if (abs(pos.x) >= 1.5707963 || abs(pos.y) >= 1.5707963) {
+        return vec2<f32>(0.0, 0.0);
+    }
+    return vec2<f32>(tan(pos.x), tan(pos.y) / cos(pos.x));
+}
+
+fn distort_point(x: f32, y: f32, z: f32) -> vec2<f32> {
+    return vec2<f32>(atan2(x, z), atan2(y, sqrt(x * x + z * z)));
+}
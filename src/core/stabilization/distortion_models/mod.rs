@@ -7,18 +7,40 @@ mod poly3;
 mod poly5;
 mod ptlens;
 mod insta360;
+mod equirectangular;
 
 mod gopro_superview;
 mod gopro_hyperview;
 mod digital_stretch;
 
 use super::KernelParams;
+use std::collections::HashMap;
+use std::sync::Arc;
+use parking_lot::RwLock;
+
+// Distortion model implemented outside of this crate, added with `DistortionModel::register`.
+// The kernel functions have the same signatures as the built-in models
+pub trait DistortionModelImpl: Send + Sync {
+    fn id(&self) -> &'static str;
+    fn name(&self) -> &'static str;
+    fn undistort_point(&self, point: (f32, f32), params: &KernelParams) -> Option<(f32, f32)>;
+    fn distort_point(&self, x: f32, y: f32, z: f32, params: &KernelParams) -> (f32, f32);
+    fn adjust_lens_profile(&self, _profile: &mut crate::LensProfile) { }
+    fn opencl_functions(&self) -> &'static str;
+    fn wgsl_functions(&self) -> &'static str;
+}
+pub type DistortionModelFactory = fn() -> Box<dyn DistortionModelImpl>;
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: RwLock<HashMap<&'static str, DistortionModelFactory>> = RwLock::new(HashMap::new());
+}
 
 macro_rules! impl_models {
     ($($name:ident => $class:ty,)*) => {
         #[derive(Clone)]
         pub enum DistortionModels {
             $($name($class),)*
+            Custom(Arc<dyn DistortionModelImpl>),
         }
         impl Default for DistortionModels {
             fn default() -> Self { Self::OpenCVFisheye(Default::default()) }
@@ -31,34 +53,102 @@ macro_rules! impl_models {
             pub fn undistort_point(&self, point: (f32, f32), params: &KernelParams) -> Option<(f32, f32)> {
                 match &self.inner {
                     $(DistortionModels::$name(m) => m.undistort_point(point, params),)*
+                    DistortionModels::Custom(m) => m.undistort_point(point, params),
                 }
             }
             pub fn distort_point(&self, x: f32, y: f32, z: f32, params: &KernelParams) -> (f32, f32) {
                 match &self.inner {
                     $(DistortionModels::$name(m) => m.distort_point(x, y, z, params),)*
+                    DistortionModels::Custom(m) => m.distort_point(x, y, z, params),
                 }
             }
             pub fn adjust_lens_profile(&self, profile: &mut crate::LensProfile) {
                 match &self.inner {
                     $(DistortionModels::$name(m) => m.adjust_lens_profile(profile),)*
+                    DistortionModels::Custom(m) => m.adjust_lens_profile(profile),
                 }
             }
 
-            pub fn id(&self)               -> &'static str { match &self.inner { $(DistortionModels::$name(_) => <$class>::id(),)* } }
-            pub fn name(&self)             -> &'static str { match &self.inner { $(DistortionModels::$name(_) => <$class>::name(),)* } }
-            pub fn opencl_functions(&self) -> &'static str { match &self.inner { $(DistortionModels::$name(x) => x.opencl_functions(),)* } }
-            pub fn wgsl_functions(&self)   -> &'static str { match &self.inner { $(DistortionModels::$name(x) => x.wgsl_functions(),)* } }
+            pub fn id(&self)               -> &'static str { match &self.inner { $(DistortionModels::$name(_) => <$class>::id(),)*          DistortionModels::Custom(x) => x.id() } }
+            pub fn name(&self)             -> &'static str { match &self.inner { $(DistortionModels::$name(_) => <$class>::name(),)*        DistortionModels::Custom(x) => x.name() } }
+            pub fn opencl_functions(&self) -> &'static str { match &self.inner { $(DistortionModels::$name(x) => x.opencl_functions(),)* DistortionModels::Custom(x) => x.opencl_functions() } }
+            pub fn wgsl_functions(&self)   -> &'static str { match &self.inner { $(DistortionModels::$name(x) => x.wgsl_functions(),)*   DistortionModels::Custom(x) => x.wgsl_functions() } }
 
-            pub fn from_name(id: &str) -> Self {
+            fn builtin(id: &str) -> Option<Self> {
                 $(
-                    if <$class>::id() == id { return Self { inner: DistortionModels::$name(Default::default()) }; }
+                    if <$class>::id() == id { return Some(Self { inner: DistortionModels::$name(Default::default()) }); }
                 )*
-                DistortionModel::default()
+                None
             }
         }
     };
 }
 
+pub use equirectangular::Equirectangular;
+
+impl DistortionModel {
+    // Makes the model available by `name` in lens profiles and project files. Built-in models can't be replaced
+    pub fn register(name: &'static str, factory: DistortionModelFactory) {
+        if Self::builtin(name).is_some() {
+            log::warn!("Distortion model {name} is built-in, not registering");
+            return;
+        }
+        REGISTRY.write().insert(name, factory);
+    }
+    pub fn lookup_by_name(id: &str) -> Option<Self> {
+        Self::builtin(id).or_else(|| {
+            REGISTRY.read().get(id).map(|factory| Self { inner: DistortionModels::Custom(Arc::from(factory())) })
+        })
+    }
+    pub fn from_name(id: &str) -> Self {
+        Self::lookup_by_name(id).unwrap_or_default()
+    }
+
+    pub fn is_equirectangular(&self) -> bool { matches!(self.inner, DistortionModels::Equirectangular(_)) }
+
+    // True if the model leaves points unchanged with these coefficients. By default when all coefficients are near zero
+    pub fn is_identity(&self, params: &[f64]) -> bool {
+        match self.inner {
+            // Fisheye is equidistant and equirectangular is spherical even without coefficients, GoPro lenses have no coefficients at all
+            DistortionModels::OpenCVFisheye(_) | DistortionModels::Equirectangular(_) |
+            DistortionModels::GoProSuperview(_) | DistortionModels::GoProHyperview(_) => false,
+            DistortionModels::DigitalStretch(_) => params.iter().take(2).all(|x| (x - 1.0).abs() < 1e-6),
+            _ => params.iter().all(|x| x.abs() < 1e-6)
+        }
+    }
+
+    // Functions of the second model when blending two lens models. Renamed to `*_b` and reading the `blend_k` coefficients
+    pub fn opencl_functions_blended(&self) -> String {
+        self.opencl_functions().replace("undistort_point(", "undistort_point_b(").replace("distort_point(", "distort_point_b(").replace("params->k[", "params->blend_k[")
+    }
+    pub fn wgsl_functions_blended(&self) -> String {
+        self.wgsl_functions().replace("undistort_point(", "undistort_point_b(").replace("distort_point(", "distort_point_b(").replace("params.k", "params.blend_k")
+    }
+
+    // Runs the model's OpenCL `undistort_point` and `distort_point` (with z = 1) on the device at `device_index` (in the `set_device` order), with coefficients `params`.
+    // Returns (undistorted, distorted), compare with the CPU functions to check the kernel code of a new model before shipping it
+    #[cfg(feature = "opencl-core")]
+    pub fn test_gpu_implementation(&self, device_index: usize, params: &[f64], test_points: &[(f64, f64)]) -> Result<(Vec<(f64, f64)>, Vec<(f64, f64)>), crate::gpu::GpuError> {
+        let mut kernel_params = KernelParams::default();
+        for (k, v) in kernel_params.k.iter_mut().zip(params) { *k = *v as f32; }
+        let points: Vec<(f32, f32)> = test_points.iter().map(|&(x, y)| (x as f32, y as f32)).collect();
+
+        let (undistorted, distorted) = crate::gpu::opencl::OclWrapper::test_distortion_model(device_index, self, &kernel_params, &points)?;
+        let to_f64 = |v: Vec<(f32, f32)>| -> Vec<(f64, f64)> { v.into_iter().map(|(x, y)| (x as f64, y as f64)).collect() };
+        Ok((to_f64(undistorted), to_f64(distorted)))
+    }
+
+    // Weight of the second model at normalized point `pos`, smooth across the transition band
+    pub fn blend_weight(pos: (f32, f32), params: &KernelParams) -> f32 {
+        let [start, end, dx, dy] = params.blend_region;
+        let dir_len = (dx * dx + dy * dy).sqrt();
+        let v = if dir_len > 0.0 { (pos.0 * dx + pos.1 * dy) / dir_len } else { (pos.0 * pos.0 + pos.1 * pos.1).sqrt() };
+        if end <= start { return if v >= start { 1.0 } else { 0.0 }; }
+        let t = ((v - start) / (end - start)).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
 impl_models! {
     // Physical lenses
     OpenCVFisheye  => opencv_fisheye::OpenCVFisheye,
@@ -67,6 +157,7 @@ impl_models! {
     Poly5          => poly5::Poly5,
     PtLens         => ptlens::PtLens,
     Insta360       => insta360::Insta360,
+    Equirectangular => equirectangular::Equirectangular,
 
     // Digital lenses (ie. post-processing)
     GoProSuperview => gopro_superview::GoProSuperview,
@@ -0,0 +1,83 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
+
+// Largest output rectangle (with the output aspect ratio) which stays within the source pixels in every analyzed frame,
+// ie. the crop which never shows the background anywhere in the clip
+
+use super::{ FrameTransform, distortion_models::DistortionModel };
+
+#[derive(Debug, Clone, Copy)]
+pub struct FillCrop {
+    pub rect: (f64, f64, f64, f64), // x, y, width, height in output pixels
+    pub zoom: f64, // Scale to apply so the rect fills the output
+}
+
+const EDGE_SAMPLES: usize = 32;
+
+fn covers(itm: &FrameTransform, distortion_model: &DistortionModel, rect: (f64, f64, f64, f64)) -> bool {
+    let (in_w, in_h) = (itm.kernel_params.width as f32, itm.kernel_params.height as f32);
+    let inside = |x: f64, y: f64| match itm.map_to_input((x as f32, y as f32), distortion_model) {
+        Some(uv) => uv.0 >= 0.0 && uv.1 >= 0.0 && uv.0 <= in_w - 1.0 && uv.1 <= in_h - 1.0,
+        None => false
+    };
+    // The valid area doesn't have holes, so it's enough to check the edges
+    (0..=EDGE_SAMPLES).all(|i| {
+        let t = i as f64 / EDGE_SAMPLES as f64;
+        inside(rect.0 + rect.2 * t, rect.1)          && inside(rect.0 + rect.2 * t, rect.1 + rect.3) &&
+        inside(rect.0,          rect.1 + rect.3 * t) && inside(rect.0 + rect.2,     rect.1 + rect.3 * t)
+    })
+}
+
+// Binary search for the largest scale of the output rect centered at `center` (relative to the output center, in pixels)
+fn max_scale_at(transforms: &[&FrameTransform], distortion_model: &DistortionModel, size: (f64, f64), center: (f64, f64)) -> f64 {
+    let rect_at = |s: f64| {
+        let (w, h) = (size.0 * s, size.1 * s);
+        (size.0 / 2.0 + center.0 - w / 2.0, size.1 / 2.0 + center.1 - h / 2.0, w, h)
+    };
+    let fits = |s: f64| transforms.iter().all(|itm| covers(itm, distortion_model, rect_at(s)));
+
+    let (mut lo, mut hi) = (0.0, 1.0);
+    if fits(hi) { return hi; }
+    for _ in 0..20 {
+        let mid = (lo + hi) / 2.0;
+        if fits(mid) { lo = mid; } else { hi = mid; }
+    }
+    lo
+}
+
+// `transforms` should come from `Stabilization::get_frame_transform_at` (with the output size set).
+// With `centered` the rect stays in the middle of the output, otherwise its position is searched as well
+pub fn compute_fill_crop<'a, I: IntoIterator<Item = &'a FrameTransform>>(transforms: I, distortion_model: &DistortionModel, centered: bool) -> Option<FillCrop> {
+    let transforms: Vec<&FrameTransform> = transforms.into_iter().filter(|x| !x.matrices.is_empty()).collect();
+    let first = transforms.first()?;
+    let size = (first.kernel_params.output_width as f64, first.kernel_params.output_height as f64);
+    if size.0 < 1.0 || size.1 < 1.0 { return None; }
+
+    let mut center = (0.0, 0.0);
+    if !centered {
+        // Coarse to fine search of the center on a subset of frames, the scale is a unimodal-enough function of it in practice
+        let subset: Vec<&FrameTransform> = transforms.iter().step_by((transforms.len() / 200).max(1)).copied().collect();
+        let mut best = max_scale_at(&subset, distortion_model, size, center);
+        let mut step = (size.0 * 0.125, size.1 * 0.125);
+        while step.0 > 1.0 || step.1 > 1.0 {
+            let c = center;
+            for (dx, dy) in [(-1.0, 0.0), (1.0, 0.0), (0.0, -1.0), (0.0, 1.0), (-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+                let candidate = (c.0 + dx * step.0, c.1 + dy * step.1);
+                let scale = max_scale_at(&subset, distortion_model, size, candidate);
+                if scale > best { best = scale; center = candidate; }
+            }
+            step = (step.0 / 2.0, step.1 / 2.0);
+        }
+    }
+
+    // Final scale has to hold for every frame
+    let scale = max_scale_at(&transforms, distortion_model, size, center);
+    if scale <= 0.0 { return None; }
+
+    // Shrink by a pixel on each side, the edges are only sampled
+    let (w, h) = ((size.0 * scale - 2.0).max(1.0), (size.1 * scale - 2.0).max(1.0));
+    Some(FillCrop {
+        rect: (size.0 / 2.0 + center.0 - w / 2.0, size.1 / 2.0 + center.1 - h / 2.0, w, h),
+        zoom: (size.0 / w).min(size.1 / h),
+    })
+}
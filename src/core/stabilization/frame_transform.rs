@@ -2,10 +2,15 @@
 // Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
 
 use nalgebra::Matrix3;
-use super::{ ComputeParams, KernelParams };
+use super::{ ComputeParams, KernelParams, distortion_models::DistortionModel };
 use rayon::iter::{ ParallelIterator, IntoParallelIterator };
 use crate::keyframes::KeyframeType;
 
+// Window for separating the translational shake from the intentional camera movement
+const TRANSLATION_WINDOW_MS: f64 = 500.0;
+// Assumed distance to the scene in meters, the translation is corrected for a plane at this depth
+const TRANSLATION_PLANE_DISTANCE: f64 = 3.0;
+
 #[derive(Default, Clone)]
 pub struct FrameTransform {
     pub matrices: Vec<[f32; 12]>,
@@ -107,10 +112,23 @@ impl FrameTransform {
             camera_matrix[(0, 2)] *= lens_ratiox;
             camera_matrix[(1, 2)] *= lens_ratioy;
         }
+        if let Some((x, y, _, _)) = params.input_crop {
+            // Lens profile describes the cropped area, so move the principal point by the crop offset
+            camera_matrix[(0, 2)] += x as f64;
+            camera_matrix[(1, 2)] += y as f64;
+        }
         (camera_matrix, distortion_coeffs, radial_distortion_limit, input_horizontal_stretch, input_vertical_stretch, focal_length)
     }
 
     pub fn at_timestamp(params: &ComputeParams, timestamp_ms: f64, frame: usize) -> Self {
+        Self::at_timestamp_aligned(params, timestamp_ms, frame, None)
+    }
+
+    // Warps the frame at `timestamp_ms` to the stabilized camera and zoom of `target` (timestamp_ms, frame) instead of its own,
+    // so frames at different timestamps line up in the output. Lens and rolling shutter are still from the source frame
+    pub fn at_timestamp_aligned(params: &ComputeParams, timestamp_ms: f64, frame: usize, target: Option<(f64, usize)>) -> Self {
+        let (target_timestamp_ms, target_frame) = target.unwrap_or((timestamp_ms, frame));
+
         // ----------- Keyframes -----------
         let video_rotation = params.keyframes.value_at_video_timestamp(&KeyframeType::VideoRotation, timestamp_ms).unwrap_or(params.video_rotation);
         let background_margin = params.keyframes.value_at_video_timestamp(&KeyframeType::BackgroundMargin, timestamp_ms).unwrap_or(params.background_margin);
@@ -130,8 +148,8 @@ impl FrameTransform {
         // ----------- Lens -----------
 
         let img_dim_ratio = Self::get_ratio(params);
-        let mut fov = Self::get_fov(params, frame, true, timestamp_ms, false);
-        let mut ui_fov = Self::get_fov(params, frame, true, timestamp_ms, true);
+        let mut fov = Self::get_fov(params, target_frame, true, target_timestamp_ms, false);
+        let mut ui_fov = Self::get_fov(params, target_frame, true, target_timestamp_ms, true);
         if let Some(adj) = params.lens.optimal_fov {
             if params.fovs.is_empty() {
                 fov *= adj;
@@ -151,6 +169,7 @@ impl FrameTransform {
 
         let row_readout_time = frame_readout_time / if params.horizontal_rs { params.width } else { params.height } as f64;
         let timestamp_ms = timestamp_ms + gyro.file_metadata.per_frame_time_offsets.get(frame).unwrap_or(&0.0);
+        let target_timestamp_ms = target_timestamp_ms + gyro.file_metadata.per_frame_time_offsets.get(target_frame).unwrap_or(&0.0);
         let start_ts = timestamp_ms - (frame_readout_time / 2.0);
         // ----------- Rolling shutter correction -----------
 
@@ -158,7 +177,20 @@ impl FrameTransform {
 
 
         let quat1 = gyro.org_quat_at_timestamp(timestamp_ms).inverse();
-        let smoothed_quat1 = gyro.smoothed_quat_at_timestamp(timestamp_ms);
+        // With translation-only stabilization the rotation is passed through, same as with no smoothing
+        let smoothed_quat1 = if params.stabilize_only_translation { gyro.org_quat_at_timestamp(target_timestamp_ms) } else { gyro.smoothed_quat_at_timestamp(target_timestamp_ms) };
+
+        let translation3d = if params.stabilize_only_translation && !params.distortion_model.is_equirectangular() {
+            let shake = gyro.translation_shake_at_timestamp(timestamp_ms, TRANSLATION_WINDOW_MS) / TRANSLATION_PLANE_DISTANCE;
+            // Kernel rays have y flipped unless the framebuffer is inverted, same as the rotation matrix below
+            let y_sign = if params.framebuffer_inverted { 1.0 } else { -1.0 };
+            [shake.x as f32, (shake.y * y_sign) as f32, 0.0, 0.0]
+        } else {
+            [0.0; 4]
+        };
+
+        // For 360° videos only the rotation is needed, the kernel maps pixels to directions on the sphere
+        let is_equirect = params.distortion_model.is_equirectangular();
 
         // Only compute 1 matrix if not using rolling shutter correction
         let rows = if frame_readout_time.abs() > 0.0 { if params.horizontal_rs { params.width } else { params.height } } else { 1 };
@@ -183,7 +215,7 @@ impl FrameTransform {
                 r[(1, 0)] *= -1.0; r[(2, 0)] *= -1.0;
             }
 
-            let i_r = (new_k * r).pseudo_inverse(0.000001);
+            let i_r = if is_equirect { Ok(r.transpose()) } else { (new_k * r).pseudo_inverse(0.000001) };
             if let Err(err) = i_r {
                 log::error!("Failed to multiply matrices: {:?} * {:?}: {}", new_k, r, err);
             }
@@ -206,6 +238,30 @@ impl FrameTransform {
         if params.framebuffer_inverted {
             adaptive_zoom_center_y *= -1.0;
         }
+        let input_crop = params.input_crop.map(|(x, y, w, h)| [
+            (x as f64 * img_dim_ratio).round() as i32,
+            (y as f64 * img_dim_ratio).round() as i32,
+            (w as f64 * img_dim_ratio).round() as i32,
+            (h as f64 * img_dim_ratio).round() as i32
+        ]).unwrap_or_default();
+
+        let mut blend_k = [0f32; 12];
+        let mut blend_region = [0f32; 4];
+        if let Some(b) = &params.blended_lens_params {
+            for (i, v) in b.distortion_coeffs.iter().take(12).enumerate() {
+                blend_k[i] = *v as f32;
+            }
+            blend_region = b.region.map(|x| x as f32);
+        }
+
+        // Normalized homography to output pixels: S * H * S^-1, S = diag(w, h, 1)
+        let perspective = if params.perspective_correction_enabled {
+            let [a, b, c, d, e, f, g, h] = params.perspective_correction_params;
+            let (w, hh) = (params.output_width as f64, params.output_height as f64);
+            [a, b * w / hh, c * w, d * hh / w, e, f * hh, g / w, h / hh].map(|x| x as f32)
+        } else {
+            [0.0; 8]
+        };
 
         let kernel_params = KernelParams {
             matrix_count:  matrices.len() as i32,
@@ -221,8 +277,14 @@ impl FrameTransform {
             background_margin:        background_margin as f32,
             background_margin_feather:background_feather as f32,
             translation2d: [(adaptive_zoom_center_x * params.width as f64 / fov) as f32, (adaptive_zoom_center_y * params.height as f64 / fov) as f32],
-            translation3d: [0.0, 0.0, 0.0, 0.0], // currently unused
+            translation3d,
             digital_lens_params,
+            input_crop,
+            blend_k,
+            blend_region,
+            perspective,
+            user_distortion: params.user_distortion.map(|x| x as f32),
+            projection_type: if is_equirect { 1 } else { 0 },
             ..Default::default()
         };
 
@@ -230,7 +292,7 @@ impl FrameTransform {
             matrices,
             kernel_params,
             fov: ui_fov,
-            minimal_fov: *params.minimal_fovs.get(frame).unwrap_or(&1.0),
+            minimal_fov: *params.minimal_fovs.get(target_frame).unwrap_or(&1.0),
             focal_length
         }
     }
@@ -263,7 +325,7 @@ impl FrameTransform {
         let image_rotation = Matrix3::new_rotation(video_rotation * (std::f64::consts::PI / 180.0));
 
         let quat1 = gyro.org_quat_at_timestamp(timestamp_ms).inverse();
-        let smoothed_quat1 = gyro.smoothed_quat_at_timestamp(timestamp_ms);
+        let smoothed_quat1 = if params.stabilize_only_translation { gyro.org_quat_at_timestamp(timestamp_ms) } else { gyro.smoothed_quat_at_timestamp(timestamp_ms) };
 
         // Only compute 1 matrix if not using rolling shutter correction
         let points_iter = if frame_readout_time.abs() > 0.0 { points } else { &[(0.0, 0.0)] };
@@ -287,4 +349,279 @@ impl FrameTransform {
 
         (scaled_k, distortion_coeffs, new_k, rotations)
     }
+
+    // Output pixel -> input pixel, same as `rotate_and_distort` in the kernels, without the digital and blended lens and the radial limit
+    pub(super) fn map_to_input(&self, pos: (f32, f32), distortion_model: &DistortionModel) -> Option<(f32, f32)> {
+        let params = &self.kernel_params;
+        let project = |idx: usize| -> Option<(f32, f32)> {
+            let m = self.matrices.get(idx)?;
+            let dir = if params.projection_type == 1 {
+                super::distortion_models::Equirectangular::pixel_to_direction(pos, params.output_width as f32, params.output_height as f32)
+            } else {
+                (pos.0, pos.1, 1.0)
+            };
+            let x = dir.0 * m[0] + dir.1 * m[1] + dir.2 * m[2] + params.translation3d[0];
+            let y = dir.0 * m[3] + dir.1 * m[4] + dir.2 * m[5] + params.translation3d[1];
+            let w = dir.0 * m[6] + dir.1 * m[7] + dir.2 * m[8] + params.translation3d[2];
+            if params.projection_type == 0 && w <= 0.0 { return None; }
+            Some(self.distort_to_input(x, y, w, distortion_model))
+        };
+        if self.matrices.len() < 2 { return project(0); }
+
+        // Pick the rolling shutter row the same way as the kernel
+        let horizontal_rs = (params.flags & 16) == 16;
+        let mid = project(self.matrices.len() / 2)?;
+        let row = if horizontal_rs { mid.0.round() as i32 } else { mid.1.round() as i32 };
+        project((row.max(0) as usize).min(self.matrices.len() - 1))
+    }
+
+    // Camera ray -> input pixel, the lens part of `map_to_input`
+    fn distort_to_input(&self, x: f32, y: f32, w: f32, distortion_model: &DistortionModel) -> (f32, f32) {
+        let params = &self.kernel_params;
+        let mut uv = if params.projection_type == 0 && (params.flags & 512) == 512 { (x / w, y / w) } else { distortion_model.distort_point(x, y, w, params) };
+        if params.projection_type == 0 && (params.flags & 4096) == 4096 { // User distortion
+            let [k1, k2, p1, p2] = params.user_distortion;
+            let r2 = uv.0 * uv.0 + uv.1 * uv.1;
+            let radial = 1.0 + k1 * r2 + k2 * r2 * r2;
+            uv = (uv.0 * radial + 2.0 * p1 * uv.0 * uv.1 + p2 * (r2 + 2.0 * uv.0 * uv.0),
+                  uv.1 * radial + p1 * (r2 + 2.0 * uv.1 * uv.1) + 2.0 * p2 * uv.0 * uv.1);
+        }
+        let mut uv = (uv.0 * params.f[0] + params.c[0], uv.1 * params.f[1] + params.c[1]);
+        if params.input_horizontal_stretch > 0.001 { uv.0 /= params.input_horizontal_stretch; }
+        if params.input_vertical_stretch   > 0.001 { uv.1 /= params.input_vertical_stretch; }
+        uv
+    }
+
+    // Dense remap reproducing the lens model of this transform, for `Stabilization::set_dense_map`. Node (i, j) of the `map_size` grid is at
+    // (i * width / (map_w - 1), j * height / (map_h - 1)) in the rectified image (processing size pixels) and holds the input pixel it comes from.
+    // Interleaved x, y. Without the digital and blended lens
+    pub fn dense_map_from_model(&self, distortion_model: &DistortionModel, map_size: (usize, usize)) -> Vec<f32> {
+        let params = &self.kernel_params;
+        let (mw, mh) = (map_size.0.max(2), map_size.1.max(2));
+        let step = (params.width as f32 / (mw - 1) as f32, params.height as f32 / (mh - 1) as f32);
+        let mut map = Vec::with_capacity(mw * mh * 2);
+        for j in 0..mh {
+            for i in 0..mw {
+                let pos = (i as f32 * step.0, j as f32 * step.1);
+                let uv = self.distort_to_input((pos.0 - params.c[0]) / params.f[0], (pos.1 - params.c[1]) / params.f[1], 1.0, distortion_model);
+                map.extend_from_slice(&[uv.0, uv.1]);
+            }
+        }
+        map
+    }
+
+    // Bilinear lookup in a dense map at the rectified position `pos`, same as `dense_map_lookup` in the OpenCL kernel
+    pub fn dense_map_lookup(map: &[f32], map_size: (usize, usize), pos: (f32, f32), size: (f32, f32)) -> Option<(f32, f32)> {
+        let (mw, mh) = map_size;
+        if mw < 2 || mh < 2 || map.len() < mw * mh * 2 { return None; }
+        let t = (pos.0 * (mw - 1) as f32 / size.0, pos.1 * (mh - 1) as f32 / size.1);
+        if t.0 < 0.0 || t.1 < 0.0 || t.0 > (mw - 1) as f32 || t.1 > (mh - 1) as f32 { return None; }
+        let (x0, y0) = ((t.0 as usize).min(mw - 2), (t.1 as usize).min(mh - 2));
+        let (wx, wy) = (t.0 - x0 as f32, t.1 - y0 as f32);
+        let node = |x: usize, y: usize| (map[(y * mw + x) * 2], map[(y * mw + x) * 2 + 1]);
+        let lerp = |a: (f32, f32), b: (f32, f32), w: f32| (a.0 + (b.0 - a.0) * w, a.1 + (b.1 - a.1) * w);
+        Some(lerp(lerp(node(x0, y0), node(x0 + 1, y0), wx), lerp(node(x0, y0 + 1), node(x0 + 1, y0 + 1), wx), wy))
+    }
+
+    // Applies the whole warp to mesh UVs in place, eg. to bake the stabilization into the geometry in a 3D app.
+    // UVs are normalized to the (unoriented) output and become normalized input coordinates, or NaN if they don't map to the input.
+    // Same mapping as the kernel for the pixel at (u * output_width, v * output_height), without the digital and blended lens
+    pub fn map_uvs(&self, uvs: &mut [(f32, f32)], distortion_model: &DistortionModel) {
+        let params = &self.kernel_params;
+        let (out_w, out_h) = (params.output_width as f32, params.output_height as f32);
+        let (in_w, in_h) = (params.width as f32, params.height as f32);
+        let factor = (1.0 - params.lens_correction_amount).max(0.001);
+        let out_c = (out_w / 2.0, out_h / 2.0);
+        let out_f = (params.f[0] / params.fov / factor, params.f[1] / params.fov / factor);
+
+        for uv in uvs.iter_mut() {
+            let mut pos = (uv.0 * out_w, uv.1 * out_h);
+            if (params.flags & 2048) == 2048 { // Perspective correction
+                let h = &params.perspective;
+                let w = h[6] * pos.0 + h[7] * pos.1 + 1.0;
+                pos = ((h[0] * pos.0 + h[1] * pos.1 + h[2]) / w, (h[3] * pos.0 + h[4] * pos.1 + h[5]) / w);
+            }
+            pos = (pos.0 + params.translation2d[0], pos.1 + params.translation2d[1]);
+
+            // Add lens distortion back
+            if params.lens_correction_amount < 1.0 && params.projection_type == 0 {
+                let mut pt = ((pos.0 - out_c.0) / out_f.0, (pos.1 - out_c.1) / out_f.1);
+                if (params.flags & 512) == 0 {
+                    pt = distortion_model.undistort_point(pt, params).unwrap_or_default();
+                }
+                pt = (pt.0 * out_f.0 + out_c.0, pt.1 * out_f.1 + out_c.1);
+                pos = (pt.0 * (1.0 - params.lens_correction_amount) + pos.0 * params.lens_correction_amount,
+                       pt.1 * (1.0 - params.lens_correction_amount) + pos.1 * params.lens_correction_amount);
+            }
+
+            *uv = match self.map_to_input(pos, distortion_model) {
+                Some(p) if in_w > 0.0 && in_h > 0.0 => (p.0 / in_w, p.1 / in_h),
+                _ => (f32::NAN, f32::NAN)
+            };
+        }
+    }
+
+    // Smallest and largest linear scale (square root of the Jacobian determinant) of the output -> input mapping, sampled on a grid over the output.
+    // Scale above 1 means the input is minified and needs prefiltering or supersampling, below 1 means it's magnified.
+    // Requires the output size in `kernel_params`, so use the transform returned by `Stabilization::get_frame_transform_at`
+    pub fn sampling_extremes(&self, distortion_model: &DistortionModel) -> (f32, f32) {
+        const GRID: usize = 16;
+        let (w, h) = (self.kernel_params.output_width as f32, self.kernel_params.output_height as f32);
+        if self.matrices.is_empty() || w < 2.0 || h < 2.0 { return (1.0, 1.0); }
+
+        let mut min_scale = f32::MAX;
+        let mut max_scale = 0.0f32;
+        for gy in 0..=GRID {
+            for gx in 0..=GRID {
+                let x = (w - 2.0) * gx as f32 / GRID as f32;
+                let y = (h - 2.0) * gy as f32 / GRID as f32;
+                if let Some(scale) = self.local_scale((x, y), distortion_model) {
+                    min_scale = min_scale.min(scale);
+                    max_scale = max_scale.max(scale);
+                }
+            }
+        }
+        if min_scale > max_scale { return (1.0, 1.0); }
+        (min_scale, max_scale)
+    }
+
+    // Same scale as `sampling_extremes`, on a (grid + 1) x (grid + 1) grid of nodes spanning the (unoriented) output, row by row.
+    // Nodes which don't map to the input are 1.0
+    pub fn local_scale_grid(&self, distortion_model: &DistortionModel, grid: usize) -> Vec<f32> {
+        let (w, h) = (self.kernel_params.output_width as f32, self.kernel_params.output_height as f32);
+        if self.matrices.is_empty() || w < 2.0 || h < 2.0 || grid == 0 { return vec![1.0; (grid + 1) * (grid + 1)]; }
+
+        (0..=grid).flat_map(|gy| (0..=grid).map(move |gx| (gx, gy))).map(|(gx, gy)| {
+            let x = (w - 2.0) * gx as f32 / grid as f32;
+            let y = (h - 2.0) * gy as f32 / grid as f32;
+            self.local_scale((x, y), distortion_model).unwrap_or(1.0)
+        }).collect()
+    }
+
+    fn local_scale(&self, pos: (f32, f32), distortion_model: &DistortionModel) -> Option<f32> {
+        let p  = self.map_to_input(pos, distortion_model)?;
+        let px = self.map_to_input((pos.0 + 1.0, pos.1), distortion_model)?;
+        let py = self.map_to_input((pos.0, pos.1 + 1.0), distortion_model)?;
+        let det = (px.0 - p.0) * (py.1 - p.1) - (py.0 - p.0) * (px.1 - p.1);
+        if det.is_finite() { Some(det.abs().sqrt()) } else { None }
+    }
+}
+
+#[cfg(feature = "debug-matrices")]
+impl FrameTransform {
+    // Per-row rotation (in degrees) and shift of the output center (in pixels) relative to the middle row.
+    // Rolling shutter correction should produce smooth curves here, noise means noisy gyro data or timing
+    pub fn debug_matrices_per_row(&self) -> Vec<(f32, f32)> {
+        let to_mat = |m: &[f32; 12]| nalgebra::Matrix3::new(m[0], m[1], m[2], m[3], m[4], m[5], m[6], m[7], m[8]);
+        if self.matrices.is_empty() { return Vec::new(); }
+        let mid = to_mat(&self.matrices[self.matrices.len() / 2]);
+        let mid_inv = match mid.try_inverse() {
+            Some(x) => x,
+            None => return Vec::new()
+        };
+
+        let center = nalgebra::Vector3::new(self.kernel_params.output_width as f32 / 2.0, self.kernel_params.output_height as f32 / 2.0, 1.0);
+        let project = |m: &Matrix3<f32>| { let p = m * center; (p.x / p.z * self.kernel_params.f[0], p.y / p.z * self.kernel_params.f[1]) };
+        let mid_center = project(&mid);
+
+        self.matrices.iter().map(|m| {
+            let m = to_mat(m);
+            // (K * R_i)^-1 * (K * R_mid) = R_i^T * R_mid, so the camera matrix cancels out
+            let rel = m * mid_inv;
+            let angle = ((rel.trace() - 1.0) / 2.0).clamp(-1.0, 1.0).acos().to_degrees();
+            let c = project(&m);
+            (angle, ((c.0 - mid_center.0).powi(2) + (c.1 - mid_center.1).powi(2)).sqrt())
+        }).collect()
+    }
+
+    // Plot of `debug_matrices_per_row` as a grayscale image, one image row per matrix.
+    // Left half is the rotation, right half is the shift, both normalized to their maximum. Returns (width, height, pixels)
+    pub fn debug_matrices_image(&self, width: usize) -> (usize, usize, Vec<u8>) {
+        let rows = self.debug_matrices_per_row();
+        let half = (width / 2).max(1);
+        let width = half * 2;
+        let max = rows.iter().fold((f32::EPSILON, f32::EPSILON), |a, r| (a.0.max(r.0), a.1.max(r.1)));
+
+        let mut pixels = vec![0u8; width * rows.len()];
+        for (y, (angle, shift)) in rows.iter().enumerate() {
+            let line = &mut pixels[y * width..(y + 1) * width];
+            line[half - 1] = 64; // Separator
+            let ax = ((angle / max.0) * (half - 1) as f32).round() as usize;
+            let sx = ((shift / max.1) * (half - 1) as f32).round() as usize;
+            for v in &mut line[..=ax.min(half - 1)] { *v = (*v).max(128); }
+            line[half + sx.min(half - 1)] = 255;
+        }
+        (width, rows.len(), pixels)
+    }
+
+    // Largest distance in input pixels between a dense map from `dense_map_from_model` and the lens model it encodes, checked halfway between the nodes
+    // where the bilinear interpolation of the map is the least accurate. Points mapping outside of the input are skipped
+    pub fn debug_validate_dense_map(&self, distortion_model: &DistortionModel, map_size: (usize, usize)) -> f32 {
+        let params = &self.kernel_params;
+        let (mw, mh) = (map_size.0.max(2), map_size.1.max(2));
+        let size = (params.width as f32, params.height as f32);
+        let map = self.dense_map_from_model(distortion_model, (mw, mh));
+
+        let mut max_diff = 0.0f32;
+        for j in 0..mh - 1 {
+            for i in 0..mw - 1 {
+                let pos = ((i as f32 + 0.5) * size.0 / (mw - 1) as f32, (j as f32 + 0.5) * size.1 / (mh - 1) as f32);
+                let analytic = self.distort_to_input((pos.0 - params.c[0]) / params.f[0], (pos.1 - params.c[1]) / params.f[1], 1.0, distortion_model);
+                if analytic.0 < 0.0 || analytic.1 < 0.0 || analytic.0 > size.0 || analytic.1 > size.1 { continue; }
+                if let Some(dense) = Self::dense_map_lookup(&map, (mw, mh), pos, size) {
+                    max_diff = max_diff.max(((dense.0 - analytic.0).powi(2) + (dense.1 - analytic.1).powi(2)).sqrt());
+                }
+            }
+        }
+        max_diff
+    }
+
+    // Largest distance in input pixels between `map_uvs` and the CPU kernel, checked at every 8th output pixel.
+    // The kernel renders an input which stores its own coordinates, bilinear interpolation returns the sampled position (within 1/32 px).
+    // Pixels sampling outside of the input are skipped
+    pub fn debug_validate_map_uvs(&self, distortion_model: &DistortionModel) -> f32 {
+        let (w, h) = (self.kernel_params.width.max(1) as usize, self.kernel_params.height.max(1) as usize);
+        let (ow, oh) = (self.kernel_params.output_width.max(1) as usize, self.kernel_params.output_height.max(1) as usize);
+
+        let mut params = self.kernel_params;
+        params.bytes_per_pixel = 16;
+        params.stride = (w * 16) as i32;
+        params.output_stride = (ow * 16) as i32;
+        params.max_pixel_value = f32::MAX; // No clamping of the coordinates
+        params.flags &= !(1 | 2 | 4 | 8 | 32 | 64 | 256); // Only the geometry, without the digital and blended lens
+        params.background = [0.0; 4];
+        params.background_mode = 0;
+        params.blend_with_original = 0.0;
+        params.transfer_function = 0;
+        params.input_rotation = 0.0;
+        params.input_crop = [0; 4];
+        params.source_rect = [0, 0, w as i32, h as i32];
+        params.output_rect = [0, 0, ow as i32, oh as i32];
+        params.output_orientation = 0;
+
+        let mut input: Vec<f32> = (0..h).flat_map(|y| (0..w).flat_map(move |x| [x as f32, y as f32, 0.0, 1.0])).collect();
+        let mut output = vec![0.0f32; ow * oh * 4];
+        {
+            let mut buffers = crate::gpu::Buffers {
+                input:  crate::gpu::BufferDescription { size: (w, h, w * 16), data: crate::gpu::BufferSource::Cpu { buffer: bytemuck::cast_slice_mut(&mut input) }, ..Default::default() },
+                output: crate::gpu::BufferDescription { size: (ow, oh, ow * 16), data: crate::gpu::BufferSource::Cpu { buffer: bytemuck::cast_slice_mut(&mut output) }, ..Default::default() },
+            };
+            if !super::Stabilization::undistort_image_cpu::<2, super::RGBAf>(&mut buffers, &params, distortion_model, None, None, &self.matrices, &[]) {
+                return f32::MAX;
+            }
+        }
+
+        let mut max_diff = 0.0f32;
+        for y in (0..oh).step_by(8) {
+            for x in (0..ow).step_by(8) {
+                let px = &output[(y * ow + x) * 4..(y * ow + x) * 4 + 4];
+                if px[3] < 0.999 { continue; } // Partially outside of the input
+                let mut uv = [(x as f32 / ow as f32, y as f32 / oh as f32)];
+                self.map_uvs(&mut uv, distortion_model);
+                if !uv[0].0.is_finite() || !uv[0].1.is_finite() { continue; }
+                max_diff = max_diff.max(((uv[0].0 * w as f32 - px[0]).powi(2) + (uv[0].1 * h as f32 - px[1]).powi(2)).sqrt());
+            }
+        }
+        max_diff
+    }
 }
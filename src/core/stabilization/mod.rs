@@ -6,7 +6,7 @@ use std::cell::RefCell;
 
 use crate::GyroflowCoreError;
 
-#[cfg(feature = "use-opencl")]
+#[cfg(feature = "opencl-core")]
 use super::gpu::opencl;
 use super::gpu::*;
 use super::StabilizationManager;
@@ -16,13 +16,23 @@ mod compute_params;
 mod frame_transform;
 mod cpu_undistort;
 mod pixel_formats;
+mod streaming;
+mod wrapper_config;
+mod output_encode;
+mod cpu_validation;
+mod sharpen;
+mod fill_crop;
 pub mod distortion_models;
 pub use pixel_formats::*;
 pub use compute_params::ComputeParams;
 pub use frame_transform::FrameTransform;
 pub use cpu_undistort::*;
+pub use streaming::{ StreamFrame, StreamOutput, LiveStream };
+pub use wrapper_config::WrapperConfig;
+pub use output_encode::EncodedFormat;
+pub use fill_crop::{ FillCrop, compute_fill_crop };
 
-#[derive(Default, Clone, Copy)]
+#[derive(Default, Clone, Copy, Debug)]
 pub enum Interpolation {
     #[default]
     Bilinear = 2,
@@ -45,6 +55,17 @@ bitflags::bitflags! {
         const FILL_WITH_BACKGROUND = 4;
         const DRAWING_ENABLED      = 8;
         const HORIZONTAL_RS        = 16; // right-to-left or left-to-right rolling shutter
+        const HAS_BLENDED_LENS     = 32; // two lens models blended across the frame
+        const HAS_ALPHA            = 64; // straight alpha in the 4th channel, interpolated premultiplied
+        const OVERLAY_BILINEAR     = 128; // filter the drawing overlay instead of nearest fetch, for low resolution canvases
+        const COLOR_CORRECTION     = 256; // apply color_gain and color_offset to the resampled pixel
+        const BYPASS_LENS          = 512; // skip the lens model (including the blended one), input treated as rectilinear
+        const BYPASS_DIGITAL_LENS  = 1024; // skip the digital lens even if compiled in
+        const PERSPECTIVE_CORRECTION = 2048; // apply the `perspective` homography to output coordinates
+        const USER_DISTORTION        = 4096; // apply `user_distortion` after the lens model
+        const PREMULTIPLIED_INPUT    = 8192; // input color already multiplied by alpha, resampled as-is and output stays premultiplied
+        const AFFINE_MATRICES        = 16384; // bottom row of every matrix is [0, 0, 1], the kernel skips it
+        const DENSE_MAP              = 32768; // lens model replaced by the dense remap from `Stabilization::set_dense_map`
     }
 }
 
@@ -89,10 +110,48 @@ pub struct KernelParams {
     pub distortion_model:         stabilize_spirv::DistortionModel, // 8
     pub digital_lens:             stabilize_spirv::DistortionModel, // 12
     pub pixel_value_limit:        f32, // 16
+    pub input_crop:               [i32; 4], // 16 - x, y, w, h
+    pub output_x_offset:          i32, // 4 - for rendering into a sub-region of a larger buffer
+    pub output_y_offset:          i32, // 8
+    pub projection_type:          i32, // 12 - 0: perspective, 1: equirectangular
+    pub output_orientation:       i32, // 16 - bits 0-1: clockwise quarter turns, bit 2: flip horizontal, bit 3: flip vertical
+    pub blend_k:                  [f32; 12], // 16,16,16 - distortion coefficients of the second lens model
+    pub blend_region:             [f32; 4], // 16 - start, end, direction x, y. Radial if direction is 0
+    pub blend_with_original:      f32, // 4 - mix of the unstabilized input over the result, 0: stabilized only, 1: original only
+    pub color_offset:             f32, // 8 - added after color_gain, in pixel values
+    pub transfer_function:        i32, // 12 - 0: none, 1: PQ, 2: HLG, see `TransferFunction`
+    pub drawing_offset:           i32, // 16 - in bytes, region of the drawing buffer with several overlay versions (OpenCL only)
+    pub color_gain:               [f32; 4], // 16 - per-channel multiplier, exposure gain times white balance
+    pub perspective:              [f32; 8], // 16,16 - homography applied to output pixel coordinates, row-major without h33 = 1
+    pub user_distortion:          [f32; 4], // 16 - k1, k2, p1, p2 applied after the lens model
 }
 unsafe impl bytemuck::Zeroable for KernelParams {}
 unsafe impl bytemuck::Pod for KernelParams {}
 
+// Transfer function of HDR input. The kernel linearizes the source pixels before interpolation and encodes the result again,
+// so the edges between bright and dark areas are resampled in linear light. RGB formats only
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferFunction {
+    #[default]
+    None = 0,
+    Pq   = 1, // SMPTE ST 2084
+    Hlg  = 2, // ARIB STD-B67
+}
+
+// Exposure and white balance normalization of a single frame, eg. against auto-exposure flicker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorCorrection {
+    pub gain: f32, // exposure multiplier
+    pub offset: f32, // added after the gain, in fraction of the maximum pixel value
+    pub white_balance: [f32; 3], // per-channel multiplier
+}
+impl Default for ColorCorrection {
+    fn default() -> Self { Self { gain: 1.0, offset: 0.0, white_balance: [1.0; 3] } }
+}
+impl ColorCorrection {
+    pub fn is_identity(&self) -> bool { *self == Self::default() }
+}
+
 #[derive(Default)]
 pub struct Stabilization {
     pub stab_data: BTreeMap<i64, FrameTransform>,
@@ -104,7 +163,7 @@ pub struct Stabilization {
     pub interpolation: Interpolation,
     pub kernel_flags: KernelParamsFlags,
 
-    #[cfg(feature = "use-opencl")]
+    #[cfg(feature = "opencl-core")]
     cl: Option<opencl::OclWrapper>,
 
     wgpu: Option<wgpu::WgpuWrapper>,
@@ -117,7 +176,49 @@ pub struct Stabilization {
     pub pending_device_change: Option<isize>,
 
     pub share_wgpu_instances: bool,
-    next_backend: Option<&'static str>
+    next_backend: Option<&'static str>,
+
+    histogram: (usize, bool), // bins (0 = disabled), rgb
+    secondary_output: Option<(usize, usize, usize)>, // width, height, stride
+    delta_matrices: bool,
+    priority_hint: bool,
+    profiling: bool,
+    reuse_source: bool,
+    dirty_tiles: Option<(usize, Vec<bool>)>, // tile size, row-major flags over the output
+    quality_map: Option<(usize, usize, Vec<u8>)>, // tile size, tiles per row, row-major levels over the output
+    dense_map: Option<(usize, usize, std::sync::Arc<Vec<f32>>)>, // width, height, interleaved input positions of the nodes
+    output_orientation: i32, // bits 0-1: clockwise quarter turns, bit 2: flip horizontal, bit 3: flip vertical
+    sharpening: (f32, f32), // strength (0 = disabled), magnification threshold
+    blend_with_original: f32,
+    transfer_function: TransferFunction,
+    color_correction: BTreeMap<i64, ColorCorrection>, // by timestamp_us
+    cpu_validation: (usize, f32), // sample step in pixels (0 = disabled), tolerance. Debug builds only
+    warning_channel: Option<std::sync::mpsc::SyncSender<FrameWarning>>,
+    affine_matrices: bool,
+    last_device: parking_lot::Mutex<Option<RenderDevice>>,
+    device_change: parking_lot::Mutex<Option<(RenderDevice, RenderDevice)>>, // from, to. Kept until `take_device_change`
+}
+
+// Backend and device which rendered a frame
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RenderDevice {
+    pub backend: &'static str,
+    pub name: String,
+    pub id: usize, // native handle, tells apart identical GPUs. 0 if not available
+}
+impl std::fmt::Display for RenderDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.name, self.backend)
+    }
+}
+
+// Non-fatal issue with a rendered frame, sent to the channel from `set_warning_channel`. The frame is still rendered
+#[derive(Debug, Clone)]
+pub enum FrameWarning {
+    NonFiniteMatrices { timestamp_us: i64 }, // NaN or infinite values in the frame matrices, eg. from broken gyro data
+    ContentOffFrame { timestamp_us: i64, fov: f64, minimal_fov: f64 }, // zoom too low for this frame, the stabilization pushed the content off-frame
+    BackendFallback { timestamp_us: i64, from: &'static str, to: &'static str }, // the initialized GPU backend failed or didn't support the buffers
+    DeviceChanged { timestamp_us: i64, from: RenderDevice, to: RenderDevice }, // rendered on a different device than the previous frame, see `take_device_change`
 }
 
 #[derive(Debug)]
@@ -151,21 +252,61 @@ impl Stabilization {
         ret
     }
 
+    // Copy the separate alpha plane into the 4th channel of the input, so it's warped together with the color
+    fn interleave_alpha<T: PixelType>(desc: &mut BufferDescription) -> Result<(), GyroflowCoreError> {
+        let (alpha, alpha_stride) = match desc.alpha { Some(a) => a, None => return Ok(()) };
+        if !T::HAS_ALPHA { return Err(GyroflowCoreError::UnsupportedFormat("separate alpha requires a pixel format with an alpha channel".into())); }
+        let (width, height, stride) = desc.size;
+        let buffer = match &mut desc.data {
+            BufferSource::Cpu { buffer } => buffer,
+            _ => return Err(GyroflowCoreError::UnsupportedFormat("separate alpha is only supported for CPU input buffers".into()))
+        };
+        let scalar = T::SCALAR_BYTES;
+        let pixel = T::COUNT * scalar;
+        if height == 0 || alpha_stride < width * scalar || alpha.len() < alpha_stride * (height - 1) + width * scalar || buffer.len() < stride * (height - 1) + width * pixel {
+            return Err(GyroflowCoreError::BufferTooSmall);
+        }
+        for y in 0..height {
+            let src = &alpha[y * alpha_stride..y * alpha_stride + width * scalar];
+            let dst = &mut buffer[y * stride..y * stride + width * pixel];
+            for (a, px) in src.chunks_exact(scalar).zip(dst.chunks_exact_mut(pixel)) {
+                px[3 * scalar..].copy_from_slice(a);
+            }
+        }
+        Ok(())
+    }
+
     pub fn get_frame_transform_at<T: PixelType>(&mut self, timestamp_us: i64, buffers: &mut Buffers) -> FrameTransform {
+        self.get_frame_transform_aligned_at::<T>(timestamp_us, None, buffers)
+    }
+    // Frame at `timestamp_us` warped to the stabilized camera at `target_timestamp_us`, see `FrameTransform::at_timestamp_aligned`
+    pub fn get_frame_transform_aligned_at<T: PixelType>(&mut self, timestamp_us: i64, target_timestamp_us: Option<i64>, buffers: &mut Buffers) -> FrameTransform {
         let timestamp_ms = (timestamp_us as f64) / 1000.0;
         let frame = crate::frame_at_timestamp(timestamp_ms, self.compute_params.scaled_fps) as usize; // Only for FOVs
+        let target = target_timestamp_us.map(|ts| {
+            let ts = ts as f64 / 1000.0;
+            (ts, crate::frame_at_timestamp(ts, self.compute_params.scaled_fps) as usize)
+        });
 
         self.kernel_flags.set(KernelParamsFlags::HAS_DIGITAL_LENS, self.compute_params.digital_lens.is_some());
         self.kernel_flags.set(KernelParamsFlags::HORIZONTAL_RS, self.compute_params.horizontal_rs);
+        self.kernel_flags.set(KernelParamsFlags::HAS_BLENDED_LENS, self.compute_params.blended_lens.is_some());
+        self.kernel_flags.set(KernelParamsFlags::HAS_ALPHA, T::HAS_ALPHA);
+        self.kernel_flags.set(KernelParamsFlags::BYPASS_LENS, self.compute_params.bypass_lens);
+        self.kernel_flags.set(KernelParamsFlags::BYPASS_DIGITAL_LENS, self.compute_params.bypass_digital_lens);
+        self.kernel_flags.set(KernelParamsFlags::PERSPECTIVE_CORRECTION, self.compute_params.perspective_correction_enabled);
+        self.kernel_flags.set(KernelParamsFlags::USER_DISTORTION, self.compute_params.user_distortion.iter().any(|x| *x != 0.0));
+        self.kernel_flags.set(KernelParamsFlags::PREMULTIPLIED_INPUT, T::HAS_ALPHA && buffers.input.data.is_premultiplied());
+        self.kernel_flags.set(KernelParamsFlags::DENSE_MAP, self.dense_map.is_some() && cfg!(feature = "opencl-core"));
 
-        let mut transform = FrameTransform::at_timestamp(&self.compute_params, timestamp_ms, frame);
+        let mut transform = FrameTransform::at_timestamp_aligned(&self.compute_params, timestamp_ms, frame, target);
         transform.kernel_params.pixel_value_limit = T::default_max_value().unwrap_or(f32::MAX);
         // If the pixel format gets converted to normalized 0-1 float in shader
         if self.wgpu.is_some() && T::default_max_value().is_some() && T::wgpu_format().map(|x| format!("{:?}", x.0).contains("Unorm")).unwrap_or_default() {
             transform.kernel_params.pixel_value_limit = 1.0;
         }
         transform.kernel_params.max_pixel_value = T::default_max_value().unwrap_or(1.0);
-        transform.kernel_params.interpolation = self.interpolation as i32;
+        transform.kernel_params.interpolation = self.effective_interpolation() as i32;
         transform.kernel_params.width  = self.size.0 as i32;
         transform.kernel_params.height = self.size.1 as i32;
         transform.kernel_params.output_width  = self.output_size.0 as i32;
@@ -175,6 +316,16 @@ impl Stabilization {
         transform.kernel_params.pix_element_count = T::COUNT as i32;
         transform.kernel_params.canvas_scale = self.drawing.scale as f32;
         transform.kernel_params.flags = self.kernel_flags.bits();
+        transform.kernel_params.output_orientation = self.output_orientation;
+        transform.kernel_params.blend_with_original = self.blend_with_original;
+        transform.kernel_params.transfer_function = self.transfer_function as i32;
+        if let Some(cc) = self.color_correction.get(&timestamp_us) {
+            // Offset in the same units as pixel values in the kernel
+            let scale = if transform.kernel_params.pixel_value_limit == 1.0 { 1.0 } else { transform.kernel_params.max_pixel_value };
+            transform.kernel_params.color_gain = [cc.white_balance[0] * cc.gain, cc.white_balance[1] * cc.gain, cc.white_balance[2] * cc.gain, 1.0];
+            transform.kernel_params.color_offset = cc.offset * scale;
+            transform.kernel_params.flags |= KernelParamsFlags::COLOR_CORRECTION.bits();
+        }
 
         transform.kernel_params.stride        = buffers.input.size.2 as i32;
         transform.kernel_params.output_stride = buffers.output.size.2 as i32;
@@ -207,6 +358,29 @@ impl Stabilization {
         transform.kernel_params.source_rect = Self::get_rect(&buffers.input);
         transform.kernel_params.output_rect = Self::get_rect(&buffers.output);
 
+        if transform.kernel_params.input_crop[2] > 0 && transform.kernel_params.input_crop[3] > 0 {
+            // Input crop is in processing size, map it to the source rect in the buffer
+            let src = transform.kernel_params.source_rect;
+            let crop = transform.kernel_params.input_crop;
+            let ratio = (src[2] as f64 / self.size.0.max(1) as f64, src[3] as f64 / self.size.1.max(1) as f64);
+            transform.kernel_params.input_crop = [
+                src[0] + (crop[0] as f64 * ratio.0).round() as i32,
+                src[1] + (crop[1] as f64 * ratio.1).round() as i32,
+                (crop[2] as f64 * ratio.0).round() as i32,
+                (crop[3] as f64 * ratio.1).round() as i32
+            ];
+        }
+
+        if self.affine_matrices {
+            // Only if every row really is affine, so the fast path gives the same result as the full homography
+            let is_affine = transform.kernel_params.projection_type != 1 && transform.matrices.iter().all(|m| m[6].abs() < 1e-6 && m[7].abs() < 1e-6 && (m[8] - 1.0).abs() < 1e-6);
+            if is_affine {
+                transform.kernel_params.flags |= KernelParamsFlags::AFFINE_MATRICES.bits();
+            } else {
+                log::debug!("Matrices at {timestamp_us} aren't affine, using the full homography");
+            }
+        }
+
         transform
     }
 
@@ -235,23 +409,206 @@ impl Stabilization {
 
     pub fn get_current_key(&self, buffers: &Buffers) -> String {
         format!(
-            "{}{}{}{}{}{:?}{:?}",
+            "{}{}{}{}{}{}{}{:?}{:?}",
             buffers.get_checksum(),
             self.compute_params.distortion_model.id(),
             self.compute_params.digital_lens.as_ref().map(|x| x.id()).unwrap_or_default(),
-            self.interpolation as u32,
+            self.compute_params.blended_lens.as_ref().map(|x| x.id()).unwrap_or_default(),
+            self.effective_interpolation() as u32,
+            self.use_delta_matrices(),
             self.kernel_flags.bits(),
             self.size,
             self.output_size
         )
     }
+    // Opt-in histogram of the rendered output for QC scopes. Only computed by the OpenCL backend
+    pub fn set_output_histogram(&mut self, bins: usize, rgb: bool) {
+        if self.histogram != (bins, rgb) {
+            self.histogram = (bins, rgb);
+            self.backend_initialized = None;
+        }
+    }
+    // Returns (bins, data), where data is luma bins followed by R, G, B bins if rgb was requested
+    pub fn get_output_histogram(&self) -> Option<(usize, Vec<u32>)> {
+        #[cfg(feature = "opencl-core")]
+        if let Some(ref cl) = self.cl {
+            return cl.get_histogram();
+        }
+        None
+    }
+
+    // Second output of the same frame at another resolution (width, height, stride), eg. a preview next to the full resolution render.
+    // Computed from the rendered output in the same pass. Only supported by the OpenCL backend
+    pub fn set_secondary_output(&mut self, size: Option<(usize, usize, usize)>) {
+        if self.secondary_output != size {
+            self.secondary_output = size;
+            self.backend_initialized = None;
+        }
+    }
+    // Copies the secondary output of the last rendered frame to `buffer`. False if it's not available with the current backend
+    pub fn get_secondary_output(&self, buffer: &mut [u8]) -> bool {
+        #[cfg(feature = "opencl-core")]
+        if let Some(ref cl) = self.cl {
+            return match cl.read_secondary_output(buffer) {
+                Ok(_) => true,
+                Err(e) => { log::error!("OpenCL error read_secondary_output: {:?}", e); false }
+            };
+        }
+        let _ = buffer;
+        false
+    }
+    // Features of the OpenCL kernel in use, None for the other backends
+    #[cfg(feature = "opencl-core")]
+    pub fn kernel_capabilities(&self) -> Option<opencl::KernelCapabilities> {
+        self.cl.as_ref().map(|cl| cl.kernel_capabilities())
+    }
+    // GPU time of the last OpenCL kernel, see `set_profiling`. None for the other backends
+    #[cfg(feature = "opencl-core")]
+    pub fn last_kernel_duration_ns(&self) -> Option<u64> {
+        self.cl.as_ref().and_then(|cl| cl.last_kernel_duration_ns())
+    }
+
+    // `interpolation` limited by the current `gpu::PowerProfile`
+    pub fn effective_interpolation(&self) -> Interpolation {
+        let max = crate::gpu::power_profile_settings().max_interpolation;
+        if (self.interpolation as i32) > (max as i32) { max } else { self.interpolation }
+    }
+    fn use_delta_matrices(&self) -> bool {
+        self.delta_matrices || crate::gpu::power_profile_settings().delta_matrices
+    }
+
+    // Upload per-row matrices delta-encoded against their mean. Only used by the OpenCL backend, full upload is the default
+    pub fn set_delta_matrices(&mut self, enabled: bool) {
+        if self.delta_matrices != enabled {
+            self.delta_matrices = enabled;
+            self.backend_initialized = None;
+        }
+    }
+
+    // High priority GPU queue for the live preview, OpenCL with `cl_khr_priority_hints` only. Advisory, the effect depends on the driver
+    pub fn set_priority_hint(&mut self, enabled: bool) {
+        if self.priority_hint != enabled {
+            self.priority_hint = enabled;
+            self.backend_initialized = None;
+        }
+    }
+    // Record the GPU time of the OpenCL kernel, see `last_kernel_duration_ns`. Adds a small overhead per command on some drivers
+    pub fn set_profiling(&mut self, enabled: bool) {
+        if self.profiling != enabled {
+            self.profiling = enabled;
+            self.backend_initialized = None;
+        }
+    }
+
+    // Reuse the input already uploaded to the GPU instead of copying it again. Only for OpenCL, when the input is known to be unchanged
+    pub fn set_reuse_source(&mut self, v: bool) {
+        self.reuse_source = v;
+        #[cfg(feature = "opencl-core")]
+        if let Some(ref mut cl) = self.cl {
+            cl.set_reuse_source(v);
+        }
+    }
+
+    // Only re-render the flagged output tiles and keep the others from the previous frame, eg. for fine scrubbing in the preview.
+    // Only for OpenCL, other backends always render the full frame
+    pub fn set_dirty_tiles(&mut self, tiles: Option<(usize, Vec<bool>)>) {
+        self.dirty_tiles = tiles;
+        #[cfg(feature = "opencl-core")]
+        if let Some(ref mut cl) = self.cl {
+            cl.set_dirty_tiles(self.dirty_tiles.clone());
+        }
+    }
+
+    // Higher interpolation quality only where it matters, eg. the center of a large output, and bilinear elsewhere.
+    // One level per `tile_size` output tile, 255 is the selected interpolation and 0 bilinear. Only for OpenCL, other backends use uniform quality
+    pub fn set_quality_map(&mut self, map: Option<(usize, usize, Vec<u8>)>) {
+        self.quality_map = map;
+        #[cfg(feature = "opencl-core")]
+        if let Some(ref mut cl) = self.cl {
+            if let Err(e) = cl.set_quality_map(self.quality_map.clone()) { log::error!("OpenCL error set_quality_map: {:?}", e); }
+        }
+    }
+
+    // Per-pixel remap from a calibration (eg. a mesh), used instead of the analytic lens model: `width` x `height` nodes over the rectified image,
+    // each the input position it comes from, see `FrameTransform::dense_map_from_model`. The per-row stabilization matrices are still applied.
+    // Uploaded once per backend initialization. Only for OpenCL, other backends keep using the lens model
+    pub fn set_dense_map(&mut self, map: Option<(usize, usize, std::sync::Arc<Vec<f32>>)>) {
+        self.dense_map = map;
+        self.stab_data.clear();
+        #[cfg(feature = "opencl-core")]
+        if let Some(ref mut cl) = self.cl {
+            if let Err(e) = cl.set_dense_map(self.dense_map.as_ref().map(|(w, h, m)| (*w, *h, &m[..]))) { log::error!("OpenCL error set_dense_map: {:?}", e); }
+        }
+    }
+
+    // Whether the OpenCL backend uses the CPU buffers directly (unified memory on integrated GPUs) instead of copying them
+    pub fn is_zero_copy(&self) -> bool {
+        #[cfg(feature = "opencl-core")]
+        if let Some(ref cl) = self.cl {
+            return cl.is_zero_copy();
+        }
+        false
+    }
+
+    // Host to device and device to host copy speed of the current OpenCL device in GB/s
+    pub fn measure_transfer_bandwidth(&self) -> Option<(f64, f64)> {
+        #[cfg(feature = "opencl-core")]
+        if let Some(ref cl) = self.cl {
+            return cl.measure_transfer_bandwidth().map_err(|e| log::error!("OpenCL error measure_transfer_bandwidth: {:?}", e)).ok();
+        }
+        None
+    }
+
+    // Rotate the rendered output by 0, 90, 180 or 270 degrees clockwise and optionally flip it, applied after stabilization and cropping.
+    // For 90 and 270 the output buffer is expected to have width and height swapped
+    pub fn set_output_orientation(&mut self, rotation: i32, flip_horizontal: bool, flip_vertical: bool) {
+        let orientation = (rotation.rem_euclid(360) / 90) | if flip_horizontal { 4 } else { 0 } | if flip_vertical { 8 } else { 0 };
+        if self.output_orientation != orientation {
+            self.output_orientation = orientation;
+            self.stab_data.clear();
+        }
+    }
+    // Mix the unstabilized input over the result for a ghosting trail effect, 0 (default) is stabilized only, 1 is the original only
+    pub fn set_blend_with_original(&mut self, amount: f32) {
+        let amount = amount.max(0.0).min(1.0);
+        if self.blend_with_original != amount {
+            self.blend_with_original = amount;
+            self.stab_data.clear();
+        }
+    }
+    // Matrices are 2x3 affine transforms, eg. for translation-only stabilization. The kernel skips the bottom row.
+    // Checked per frame, frames with a projective bottom row still use the full homography. Disabled by default
+    pub fn set_affine_matrices(&mut self, affine: bool) {
+        if self.affine_matrices != affine {
+            self.affine_matrices = affine;
+            self.stab_data.clear();
+        }
+    }
+    pub fn set_transfer_function(&mut self, tf: TransferFunction) {
+        if self.transfer_function != tf {
+            self.transfer_function = tf;
+            self.stab_data.clear();
+        }
+    }
+    // Correction for the frame at `timestamp_us`, applied after resampling. The host computes it, eg. from the frame's mean luminance
+    pub fn set_frame_color_correction(&mut self, timestamp_us: i64, correction: Option<ColorCorrection>) {
+        match correction {
+            Some(cc) if !cc.is_identity() => { self.color_correction.insert(timestamp_us, cc); },
+            _ => { self.color_correction.remove(&timestamp_us); }
+        }
+        self.stab_data.remove(&timestamp_us);
+    }
+    pub fn oriented_output_size(&self) -> (usize, usize) {
+        if self.output_orientation & 1 != 0 { (self.output_size.1, self.output_size.0) } else { self.output_size }
+    }
+
     pub fn get_current_checksum(&self, buffers: &Buffers) -> u32 {
         crc32fast::hash(self.get_current_key(buffers).as_bytes())
     }
 
     pub fn init_size(&mut self, size: (usize, usize), output_size: (usize, usize)) {
         self.backend_initialized = None;
-        #[cfg(feature = "use-opencl")]
+        #[cfg(feature = "opencl-core")]
         { self.cl = None; }
         self.wgpu = None;
 
@@ -276,7 +633,7 @@ impl Stabilization {
     pub fn list_devices(&self) -> Vec<String> {
         let mut ret = Vec::new();
 
-        #[cfg(feature = "use-opencl")]
+        #[cfg(feature = "opencl-core")]
         if std::env::var("NO_OPENCL").unwrap_or_default().is_empty() {
             ret.extend(opencl::OclWrapper::list_devices().into_iter().map(|x| format!("[OpenCL] {x}")));
         }
@@ -294,13 +651,13 @@ impl Stabilization {
         self.stab_data.clear();
         self.next_backend = None;
         self.backend_initialized = None;
-        #[cfg(feature = "use-opencl")]
+        #[cfg(feature = "opencl-core")]
         { self.cl = None; }
         self.wgpu = None;
 
         let hash = self.get_current_checksum(buffers);
         if i < 0 { // CPU
-            #[cfg(feature = "use-opencl")]
+            #[cfg(feature = "opencl-core")]
             { self.cl = None; }
             self.wgpu = None;
             CACHED_WGPU.with(|x| x.borrow_mut().clear());
@@ -311,7 +668,7 @@ impl Stabilization {
         if let Some(name) = gpu_list.get(i as usize) {
             if name.starts_with("[OpenCL]") {
                 self.backend_initialized = None;
-                #[cfg(feature = "use-opencl")]
+                #[cfg(feature = "opencl-core")]
                 match opencl::OclWrapper::set_device(i as usize, buffers) {
                     Ok(_) => { self.next_backend = Some("opencl"); return true; },
                     Err(e) => {
@@ -338,25 +695,66 @@ impl Stabilization {
     pub fn init_backends<T: PixelType>(&mut self, timestamp_us: i64, buffers: &Buffers) {
         let hash = self.get_current_checksum(buffers);
 
+        #[cfg(feature = "opencl-core")]
+        if self.cl.as_ref().map(|cl| !cl.is_context_valid()).unwrap_or_default() {
+            // The global context was reset, create the wrapper again on the newly selected device
+            self.cl = None;
+            self.backend_initialized = None;
+        }
+        #[cfg(feature = "opencl-core")]
+        if self.cl.as_ref().map(|cl| cl.is_host_memory_evicted()).unwrap_or_default() {
+            log::debug!("OpenCL buffers evicted by the host memory budget");
+            self.cl = None;
+            self.backend_initialized = None;
+        }
+        if self.wgpu.as_ref().map(|wgpu| wgpu.is_host_memory_evicted()).unwrap_or_default() {
+            log::debug!("wgpu buffers evicted by the host memory budget");
+            self.wgpu = None;
+            self.backend_initialized = None;
+        }
+        if self.share_wgpu_instances {
+            CACHED_WGPU.with(|x| {
+                let mut cached = x.borrow_mut();
+                let evicted: Vec<u32> = cached.iter().filter(|(_, wgpu)| wgpu.is_host_memory_evicted()).map(|(k, _)| *k).collect();
+                for k in evicted {
+                    cached.pop(&k);
+                    if k == hash { self.backend_initialized = None; }
+                }
+            });
+        }
+
         if self.backend_initialized.is_none() || self.backend_initialized.unwrap() != hash {
             #[allow(unused_mut)]
             let mut gpu_initialized = false;
             if let Some(itm) = self.stab_data.get(&timestamp_us) {
-                let params = itm.kernel_params;
+                let mut params = itm.kernel_params;
+                params.interpolation = self.effective_interpolation() as i32; // Power profile could have changed since the frame was computed
                 let canvas_len = self.drawing.get_buffer_len();
                 #[allow(unused_mut)]
                 let mut next_backend = self.next_backend.take().unwrap_or_default();
 
-                #[cfg(feature = "use-opencl")]
+                #[cfg(feature = "opencl-core")]
                 if std::env::var("NO_OPENCL").unwrap_or_default().is_empty() && next_backend != "wgpu" && opencl::is_buffer_supported(buffers) {
                     self.cl = None;
                     let distortion_model = self.compute_params.distortion_model.clone();
                     let digital_lens = self.compute_params.digital_lens.clone();
+                    let blended_lens = self.compute_params.blended_lens.clone();
+                    let priority_hint = self.priority_hint;
+                    let profiling = self.profiling;
                     let cl = std::panic::catch_unwind(|| {
-                        opencl::OclWrapper::new(&params, T::ocl_names(), distortion_model, digital_lens, buffers, canvas_len)
+                        opencl::OclWrapper::new(&params, T::ocl_names(), distortion_model, digital_lens, blended_lens, buffers, canvas_len, priority_hint, profiling)
                     });
                     match cl {
-                        Ok(Ok(cl)) => { self.cl = Some(cl); gpu_initialized = true; log::info!("Initialized OpenCL for {:?} -> {:?}", buffers.input.size, buffers.output.size); },
+                        Ok(Ok(mut cl)) => {
+                            if let Err(e) = cl.set_histogram(self.histogram.0, self.histogram.1) { log::error!("OpenCL error set_histogram: {:?}", e); }
+                            if let Err(e) = cl.set_secondary_output(self.secondary_output) { log::error!("OpenCL error set_secondary_output: {:?}", e); }
+                            if let Err(e) = cl.set_delta_matrices(self.use_delta_matrices()) { log::error!("OpenCL error set_delta_matrices: {:?}", e); }
+                            cl.set_reuse_source(self.reuse_source);
+                            cl.set_dirty_tiles(self.dirty_tiles.clone());
+                            if let Err(e) = cl.set_quality_map(self.quality_map.clone()) { log::error!("OpenCL error set_quality_map: {:?}", e); }
+                            if let Err(e) = cl.set_dense_map(self.dense_map.as_ref().map(|(w, h, m)| (*w, *h, &m[..]))) { log::error!("OpenCL error set_dense_map: {:?}", e); }
+                            self.cl = Some(cl); gpu_initialized = true; log::info!("Initialized OpenCL for {:?} -> {:?}", buffers.input.size, buffers.output.size);
+                        },
                         Ok(Err(e)) => { next_backend = ""; log::error!("OpenCL error init_backends: {:?}", e); },
                         Err(e) => {
                             next_backend = "";
@@ -375,8 +773,9 @@ impl Stabilization {
                         self.wgpu = None;
                         let distortion_model = self.compute_params.distortion_model.clone();
                         let digital_lens = self.compute_params.digital_lens.clone();
+                        let blended_lens = self.compute_params.blended_lens.clone();
                         let wgpu = std::panic::catch_unwind(|| {
-                            wgpu::WgpuWrapper::new(&params, T::wgpu_format().unwrap(), distortion_model, digital_lens, buffers, canvas_len)
+                            wgpu::WgpuWrapper::new(&params, T::wgpu_format().unwrap(), distortion_model, digital_lens, blended_lens, buffers, canvas_len)
                         });
                         match wgpu {
                             Ok(Ok(wgpu)) => {
@@ -407,6 +806,24 @@ impl Stabilization {
         }
     }
 
+    // Loads a .gyroflow project and computes the frame transforms at given timestamps, so external tools can stabilize raw frames
+    // without setting up the full manager. Sizes are taken from `buffers`. Pass the result to `process_pixels` or the backend's `undistort_image`
+    pub fn frame_transforms_from_project<T: PixelType>(project_data: &[u8], timestamps_us: &[i64], buffers: &mut Buffers) -> Result<Vec<FrameTransform>, GyroflowCoreError> {
+        let mgr = StabilizationManager::default();
+        let mut is_preset = false;
+        mgr.import_gyroflow_data(project_data, true, None, |_| (), std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false)), &mut is_preset)?;
+        mgr.set_size(buffers.input.size.0, buffers.input.size.1);
+        mgr.set_output_size(buffers.output.size.0, buffers.output.size.1);
+        mgr.recompute_blocking();
+
+        let fps_scale = mgr.params.read().fps_scale;
+        let mut stab = mgr.stabilization.write();
+        Ok(timestamps_us.iter().map(|&ts| {
+            let ts = fps_scale.map(|scale| (ts as f64 / scale).round() as i64).unwrap_or(ts);
+            stab.get_frame_transform_at::<T>(ts, buffers)
+        }).collect())
+    }
+
     pub fn ensure_ready_for_processing<T: PixelType>(&mut self, timestamp_us: i64, buffers: &mut Buffers) {
         if let Some(dev) = self.pending_device_change.take() {
             log::debug!("Setting device {dev}");
@@ -426,7 +843,80 @@ impl Stabilization {
             }
         }
     }
+    // Bilinear or nearest (default, pixel exact) fetch of the drawing overlay, independent of `interpolation`
+    pub fn set_overlay_bilinear(&mut self, v: bool) {
+        if self.kernel_flags.contains(KernelParamsFlags::OVERLAY_BILINEAR) != v {
+            self.kernel_flags.set(KernelParamsFlags::OVERLAY_BILINEAR, v);
+            self.stab_data.clear();
+        }
+    }
+
+    // Largest crop which doesn't show the background in any of the cached frames (`stab_data`), see `compute_fill_crop`
+    pub fn compute_fill_crop(&self, centered: bool) -> Option<FillCrop> {
+        compute_fill_crop(self.stab_data.values(), &self.compute_params.distortion_model, centered)
+    }
+
+    // Sharpen the output where the warp magnifies the input by more than `threshold` (eg. 1.2), with `strength` reached at threshold + 1.
+    // Applied after any backend, only to CPU output buffers. 0 strength disables it
+    pub fn set_adaptive_sharpening(&mut self, strength: f32, threshold: f32) {
+        self.sharpening = (strength.max(0.0), threshold);
+    }
+
+    // Opt-in per-frame warnings. Sent with `try_send`, so they are dropped when the channel is full and the render loop never waits on the receiver
+    pub fn set_warning_channel(&mut self, sender: Option<std::sync::mpsc::SyncSender<FrameWarning>>) {
+        self.warning_channel = sender;
+    }
+    fn emit_warning(&self, warning: FrameWarning) {
+        if let Some(tx) = &self.warning_channel {
+            let _ = tx.try_send(warning);
+        }
+    }
+
+    // Device which rendered the last frame
+    pub fn last_render_device(&self) -> Option<RenderDevice> {
+        self.last_device.lock().clone()
+    }
+    // Last change of the rendering device (from, to) since the previous call, eg. after a context rebuild picked another GPU
+    // or `BufferSource::OpenCL` buffers came from a different device. Also sent to the warning channel
+    pub fn take_device_change(&self) -> Option<(RenderDevice, RenderDevice)> {
+        self.device_change.lock().take()
+    }
+    fn track_device(&self, timestamp_us: i64, device: RenderDevice) {
+        let mut last = self.last_device.lock();
+        if let Some(prev) = last.as_ref().filter(|x| **x != device) {
+            log::warn!("Rendering device changed at {timestamp_us}: {prev} -> {device}");
+            self.emit_warning(FrameWarning::DeviceChanged { timestamp_us, from: prev.clone(), to: device.clone() });
+            *self.device_change.lock() = Some((prev.clone(), device.clone()));
+        }
+        *last = Some(device);
+    }
+
     pub fn process_pixels<T: PixelType>(&self, timestamp_us: i64, buffers: &mut Buffers, frame_transform: Option<&FrameTransform>) -> Result<ProcessedInfo, GyroflowCoreError> {
+        Self::interleave_alpha::<T>(&mut buffers.input)?;
+
+        let ret = self.undistort_pixels::<T>(timestamp_us, buffers, frame_transform)?;
+
+        #[cfg(debug_assertions)]
+        if self.cpu_validation.0 > 0 && ret.backend != "CPU" {
+            let last_frame_data = self.last_frame_data.borrow();
+            self.validate_against_cpu::<T>(buffers, frame_transform.unwrap_or(&last_frame_data), ret.backend);
+        }
+
+        let (strength, threshold) = self.sharpening;
+        if strength > 0.0 && crate::gpu::power_profile_settings().sharpening {
+            let output_size = buffers.output.size;
+            if let BufferSource::Cpu { buffer } = &mut buffers.output.data {
+                // Without an explicit transform, `last_frame_data` holds the one which was just rendered
+                let last_frame_data = self.last_frame_data.borrow();
+                let itm = frame_transform.unwrap_or(&last_frame_data);
+                let scale_grid = itm.local_scale_grid(&self.compute_params.distortion_model, sharpen::SCALE_GRID);
+                sharpen::adaptive_unsharp_mask::<T>(buffer, output_size, &itm.kernel_params, &scale_grid, strength, threshold);
+            }
+        }
+        Ok(ret)
+    }
+
+    fn undistort_pixels<T: PixelType>(&self, timestamp_us: i64, buffers: &mut Buffers, frame_transform: Option<&FrameTransform>) -> Result<ProcessedInfo, GyroflowCoreError> {
         if /*self.size != buffers.input.size || */buffers.input.size.1 < 4 || buffers.output.size.1 < 4 { return Err(GyroflowCoreError::SizeTooSmall); }
 
         let mut _last_frame_data = None;
@@ -456,16 +946,30 @@ impl Stabilization {
             if self.output_size != (itm.kernel_params.output_width as usize, itm.kernel_params.output_height as usize) { return Err(GyroflowCoreError::SizeMismatch(self.size, (itm.kernel_params.output_width as usize, itm.kernel_params.output_height as usize))); }
 
             if self.size.0 as i32        > itm.kernel_params.stride        { return Err(GyroflowCoreError::InvalidStride(itm.kernel_params.stride, self.size.0 as i32)); }
-            if self.output_size.0 as i32 > itm.kernel_params.output_stride { return Err(GyroflowCoreError::InvalidStride(itm.kernel_params.output_stride, self.output_size.0 as i32)); }
+            let oriented_width = self.oriented_output_size().0 as i32;
+            if oriented_width            > itm.kernel_params.output_stride { return Err(GyroflowCoreError::InvalidStride(itm.kernel_params.output_stride, oriented_width)); }
+
+            if self.warning_channel.is_some() {
+                if itm.matrices.iter().flatten().any(|x| !x.is_finite()) {
+                    self.emit_warning(FrameWarning::NonFiniteMatrices { timestamp_us });
+                }
+                if itm.fov > itm.minimal_fov + 1e-6 {
+                    self.emit_warning(FrameWarning::ContentOffFrame { timestamp_us, fov: itm.fov, minimal_fov: itm.minimal_fov });
+                }
+            }
+            let mut failed_backend: Option<&'static str> = None;
 
             // OpenCL path
-            #[cfg(feature = "use-opencl")]
+            #[cfg(feature = "opencl-core")]
             if let Some(ref cl) = self.cl {
+                failed_backend = Some("OpenCL");
                 if opencl::is_buffer_supported(buffers) {
                     if let Err(err) = cl.undistort_image(buffers, &itm, drawing_buffer) {
                         log::error!("OpenCL error undistort: {:?}", err);
                     } else {
                         ret.backend = "OpenCL";
+                        let (id, name) = cl.device_id();
+                        self.track_device(timestamp_us, RenderDevice { backend: ret.backend, name, id });
                         return Ok(ret);
                     }
                 }
@@ -482,6 +986,7 @@ impl Stabilization {
                             if let Some(wgpu) = cached.get(&hash) {
                                 wgpu.undistort_image(buffers, &itm, drawing_buffer);
                                 ret.backend = "wgpu";
+                                self.track_device(timestamp_us, RenderDevice { backend: ret.backend, name: wgpu.device_info().to_string(), id: 0 });
                                 Ok(ret)
                             } else {
                                 Err(GyroflowCoreError::NoCachedWgpuInstance(self.get_current_key(buffers)))
@@ -492,19 +997,23 @@ impl Stabilization {
                     if let Some(ref wgpu) = self.wgpu {
                         wgpu.undistort_image(buffers, &itm, drawing_buffer);
                         ret.backend = "wgpu";
+                        self.track_device(timestamp_us, RenderDevice { backend: ret.backend, name: wgpu.device_info().to_string(), id: 0 });
+                        if let Some(from) = failed_backend {
+                            self.emit_warning(FrameWarning::BackendFallback { timestamp_us, from, to: ret.backend });
+                        }
                         return Ok(ret);
                     }
                 }
             }
+            if self.wgpu.is_some() { failed_backend = failed_backend.or(Some("wgpu")); }
 
             // CPU path
-            let ok = match self.interpolation {
-                Interpolation::Bilinear => { Self::undistort_image_cpu::<2, T>(buffers, &itm.kernel_params, &self.compute_params.distortion_model, self.compute_params.digital_lens.as_ref(), &itm.matrices, drawing_buffer) },
-                Interpolation::Bicubic  => { Self::undistort_image_cpu::<4, T>(buffers, &itm.kernel_params, &self.compute_params.distortion_model, self.compute_params.digital_lens.as_ref(), &itm.matrices, drawing_buffer) },
-                Interpolation::Lanczos4 => { Self::undistort_image_cpu::<8, T>(buffers, &itm.kernel_params, &self.compute_params.distortion_model, self.compute_params.digital_lens.as_ref(), &itm.matrices, drawing_buffer) },
-            };
-            if ok {
+            if self.undistort_cpu::<T>(buffers, itm, drawing_buffer) {
                 ret.backend = "CPU";
+                self.track_device(timestamp_us, RenderDevice { backend: ret.backend, name: "CPU".into(), id: 0 });
+                if let Some(from) = failed_backend {
+                    self.emit_warning(FrameWarning::BackendFallback { timestamp_us, from, to: ret.backend });
+                }
                 return Ok(ret);
             }
         } else {
@@ -513,6 +1022,14 @@ impl Stabilization {
         }
         Err(GyroflowCoreError::Unknown)
     }
+
+    fn undistort_cpu<T: PixelType>(&self, buffers: &mut Buffers, itm: &FrameTransform, drawing_buffer: &[u8]) -> bool {
+        match self.effective_interpolation() {
+            Interpolation::Bilinear => { Self::undistort_image_cpu::<2, T>(buffers, &itm.kernel_params, &self.compute_params.distortion_model, self.compute_params.digital_lens.as_ref(), self.compute_params.blended_lens.as_ref(), &itm.matrices, drawing_buffer) },
+            Interpolation::Bicubic  => { Self::undistort_image_cpu::<4, T>(buffers, &itm.kernel_params, &self.compute_params.distortion_model, self.compute_params.digital_lens.as_ref(), self.compute_params.blended_lens.as_ref(), &itm.matrices, drawing_buffer) },
+            Interpolation::Lanczos4 => { Self::undistort_image_cpu::<8, T>(buffers, &itm.kernel_params, &self.compute_params.distortion_model, self.compute_params.digital_lens.as_ref(), self.compute_params.blended_lens.as_ref(), &itm.matrices, drawing_buffer) },
+        }
+    }
 }
 
 unsafe impl Send for Stabilization { }
@@ -0,0 +1,52 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use image::{ ImageOutputFormat, RgbaImage, DynamicImage };
+use super::{ Stabilization, PixelType };
+use crate::GyroflowCoreError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncodedFormat {
+    Jpeg(u8), // quality 1-100
+    Png,
+}
+
+impl Stabilization {
+    // Compresses a rendered output buffer, eg. for streaming the preview over network.
+    // None of the GPU backends can encode on the device, so this works on the output read back to the CPU with any backend
+    pub fn encode_output<T: PixelType>(buffer: &[u8], size: (usize, usize, usize), format: EncodedFormat) -> Result<Vec<u8>, GyroflowCoreError> {
+        if T::IS_YUV { return Err(GyroflowCoreError::UnsupportedFormat("YUV output can't be encoded".into())); }
+        let (width, height, stride) = size;
+        let bpp = T::COUNT * T::SCALAR_BYTES;
+        if width == 0 || height == 0 { return Err(GyroflowCoreError::SizeTooSmall); }
+        if stride < width * bpp { return Err(GyroflowCoreError::InvalidStride(stride as i32, (width * bpp) as i32)); }
+        if buffer.len() < stride * (height - 1) + width * bpp { return Err(GyroflowCoreError::BufferTooSmall); }
+
+        let max = T::default_max_value().unwrap_or(1.0);
+        let is_bgr = matches!(T::wgpu_format(), Some((wgpu::TextureFormat::Bgra8Unorm, _, _)));
+
+        let mut img = RgbaImage::new(width as u32, height as u32);
+        for y in 0..height {
+            let row = &buffer[y * stride..y * stride + width * bpp];
+            for (x, px) in row.chunks_exact(bpp).enumerate() {
+                let v = T::to_float(bytemuck::pod_read_unaligned::<T>(px)) / max;
+                let c = |i: usize| (v[i].clamp(0.0, 1.0) * 255.0).round() as u8;
+                let (r, g, b) = match T::COUNT {
+                    1 | 2 => (c(0), c(0), c(0)),
+                    _ if is_bgr => (c(2), c(1), c(0)),
+                    _ => (c(0), c(1), c(2))
+                };
+                let a = if T::HAS_ALPHA { c(3) } else { 255 };
+                img.put_pixel(x as u32, y as u32, image::Rgba([r, g, b, a]));
+            }
+        }
+
+        let mut out = std::io::Cursor::new(Vec::new());
+        let result = match format {
+            EncodedFormat::Jpeg(quality) => DynamicImage::ImageRgba8(img).to_rgb8().write_to(&mut out, ImageOutputFormat::Jpeg(quality.clamp(1, 100))),
+            EncodedFormat::Png => img.write_to(&mut out, ImageOutputFormat::Png),
+        };
+        result.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(out.into_inner())
+    }
+}
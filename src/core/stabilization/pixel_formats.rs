@@ -6,6 +6,8 @@ use nalgebra::Vector4;
 pub trait PixelType: Default + Copy + Send + Sync + bytemuck::Pod {
     const COUNT: usize = 1;
     const SCALAR_BYTES: usize = 1;
+    const HAS_ALPHA: bool = false; // Straight alpha in the 4th channel
+    const IS_YUV: bool = false;
     type Scalar: Default + bytemuck::Pod;
 
     fn to_float_glam(bytes: &[u8]) -> stabilize_spirv::glam::Vec4;
@@ -111,7 +113,7 @@ impl PixelType for RGB8 {
     #[inline] fn to_float(v: Self) -> Vector4<f32> { Vector4::new(v.0 as f32, v.1 as f32, v.2 as f32, 0.0) }
     #[inline] fn from_float(v: Vector4<f32>) -> Self { Self(v[0] as Self::Scalar, v[1] as Self::Scalar, v[2] as Self::Scalar) }
     #[inline] fn from_rgb_color(v: Vector4<f32>, _ind: &[usize], _is_limited: bool) -> Vector4<f32> { v }
-    #[inline] fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str) { ("uchar3", "convert_uchar3_sat", "float4", "convert_float4") } // FIXME: uchar3 can't be converted to float4
+    #[inline] fn ocl_names() -> (&'static str, &'static str, &'static str, &'static str) { ("uchar3", "convert_uchar3_sat", "float4", "convert_float4") }
     #[inline] fn wgpu_format() -> Option<(wgpu::TextureFormat, &'static str, f64)> { None }
     #[inline] fn default_max_value() -> Option<f32> { Some(255.0) }
 }
@@ -119,6 +121,7 @@ unsafe impl bytemuck::Zeroable for RGBA8 { }
 unsafe impl bytemuck::Pod for RGBA8 { }
 impl PixelType for RGBA8 {
     const COUNT: usize = 4;
+    const HAS_ALPHA: bool = true;
     const SCALAR_BYTES: usize = 1;
     type Scalar = u8;
     #[inline] fn to_float_glam(bytes: &[u8]) -> stabilize_spirv::glam::Vec4 {
@@ -137,6 +140,7 @@ unsafe impl bytemuck::Zeroable for BGRA8 { }
 unsafe impl bytemuck::Pod for BGRA8 { }
 impl PixelType for BGRA8 {
     const COUNT: usize = 4;
+    const HAS_ALPHA: bool = true;
     const SCALAR_BYTES: usize = 1;
     type Scalar = u8;
     #[inline] fn to_float_glam(bytes: &[u8]) -> stabilize_spirv::glam::Vec4 {
@@ -173,6 +177,7 @@ unsafe impl bytemuck::Zeroable for RGBA16 { }
 unsafe impl bytemuck::Pod for RGBA16 { }
 impl PixelType for RGBA16 {
     const COUNT: usize = 4;
+    const HAS_ALPHA: bool = true;
     const SCALAR_BYTES: usize = 2;
     type Scalar = u16;
     #[inline] fn to_float_glam(bytes: &[u8]) -> stabilize_spirv::glam::Vec4 {
@@ -192,6 +197,7 @@ unsafe impl bytemuck::Pod for AYUV16 { }
 impl PixelType for AYUV16 {
     const COUNT: usize = 4;
     const SCALAR_BYTES: usize = 2;
+    const IS_YUV: bool = true;
     type Scalar = u16;
     #[inline] fn to_float_glam(bytes: &[u8]) -> stabilize_spirv::glam::Vec4 {
         let v: &Self = bytemuck::from_bytes(&bytes);
@@ -209,6 +215,7 @@ unsafe impl bytemuck::Zeroable for RGBAf { }
 unsafe impl bytemuck::Pod for RGBAf { }
 impl PixelType for RGBAf {
     const COUNT: usize = 4;
+    const HAS_ALPHA: bool = true;
     const SCALAR_BYTES: usize = 4;
     type Scalar = f32;
     #[inline] fn to_float_glam(bytes: &[u8]) -> stabilize_spirv::glam::Vec4 {
@@ -232,6 +239,7 @@ unsafe impl bytemuck::Zeroable for RGBAf16 { }
 unsafe impl bytemuck::Pod for RGBAf16 { }
 impl PixelType for RGBAf16 {
     const COUNT: usize = 4;
+    const HAS_ALPHA: bool = true;
     const SCALAR_BYTES: usize = 2;
     type Scalar = Ff16;
     #[inline] fn to_float_glam(bytes: &[u8]) -> stabilize_spirv::glam::Vec4 {
@@ -0,0 +1,70 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
+
+// Adaptive unsharp mask for regions where the warp magnified the input, which look soft after interpolation.
+// Strength follows the local scale of the output -> input mapping (`FrameTransform::local_scale_grid`)
+
+use super::{ PixelType, KernelParams, cpu_undistort::unorient_output };
+use nalgebra::Vector4;
+use rayon::{ prelude::ParallelSliceMut, iter::{ ParallelIterator, IndexedParallelIterator } };
+
+pub const SCALE_GRID: usize = 16;
+
+// `size` is (width, height, stride) of the oriented output buffer. Sharpening starts at `threshold` magnification
+// and reaches `strength` one unit of magnification above it
+pub fn adaptive_unsharp_mask<T: PixelType>(buffer: &mut [u8], size: (usize, usize, usize), params: &KernelParams, scale_grid: &[f32], strength: f32, threshold: f32) {
+    let (width, height, stride) = size;
+    let pixel_bytes = std::mem::size_of::<T>();
+    if strength <= 0.0 || width < 3 || height < 3 || scale_grid.len() != (SCALE_GRID + 1) * (SCALE_GRID + 1) || buffer.len() < stride * height { return; }
+
+    // Nothing is magnified enough, skip the copy
+    if scale_grid.iter().all(|s| *s <= 0.0 || 1.0 / *s <= threshold) { return; }
+
+    // Float formats can be above the nominal max value (HDR)
+    let max_value = if T::default_max_value().is_some() { params.max_pixel_value } else { f32::MAX };
+    let (out_w, out_h) = (params.output_width as f32, params.output_height as f32);
+    let amount_at = |x: usize, y: usize| -> f32 {
+        let (px, py) = if params.output_orientation != 0 {
+            unorient_output((x as f32, y as f32), params.output_orientation, out_w, out_h)
+        } else {
+            (x as f32, y as f32)
+        };
+        // Bilinear interpolation between the grid nodes
+        let gx = (px / (out_w - 2.0).max(1.0) * SCALE_GRID as f32).clamp(0.0, SCALE_GRID as f32);
+        let gy = (py / (out_h - 2.0).max(1.0) * SCALE_GRID as f32).clamp(0.0, SCALE_GRID as f32);
+        let (x0, y0) = ((gx as usize).min(SCALE_GRID - 1), (gy as usize).min(SCALE_GRID - 1));
+        let (fx, fy) = (gx - x0 as f32, gy - y0 as f32);
+        let node = |x: usize, y: usize| scale_grid[y * (SCALE_GRID + 1) + x];
+        let top    = node(x0, y0    ) * (1.0 - fx) + node(x0 + 1, y0    ) * fx;
+        let bottom = node(x0, y0 + 1) * (1.0 - fx) + node(x0 + 1, y0 + 1) * fx;
+        let scale = top * (1.0 - fy) + bottom * fy;
+        if scale <= 0.0 { return 0.0; }
+        strength * (1.0 / scale - threshold).clamp(0.0, 1.0)
+    };
+
+    let src = buffer[..stride * height].to_vec();
+    let read = |x: usize, y: usize| -> Vector4<f32> {
+        let offs = y * stride + x * pixel_bytes;
+        T::to_float(bytemuck::pod_read_unaligned::<T>(&src[offs..offs + pixel_bytes]))
+    };
+
+    buffer[..stride * height].par_chunks_mut(stride).enumerate().for_each(|(y, row)| {
+        let (ym, yp) = (y.saturating_sub(1), (y + 1).min(height - 1));
+        for x in 0..width {
+            let amount = amount_at(x, y);
+            if amount <= 0.0 { continue; }
+            let (xm, xp) = (x.saturating_sub(1), (x + 1).min(width - 1));
+
+            // 3x3 gaussian
+            let blur = (read(xm, ym) + read(xp, ym) + read(xm, yp) + read(xp, yp)
+                     + (read(x, ym) + read(xm, y) + read(xp, y) + read(x, yp)) * 2.0
+                     + read(x, y) * 4.0) / 16.0;
+            let px = read(x, y);
+            let mut out = (px + (px - blur) * amount).map(|v| v.clamp(0.0, max_value));
+            if T::HAS_ALPHA { out.w = px.w; }
+
+            let out: T = PixelType::from_float(out);
+            row[x * pixel_bytes..(x + 1) * pixel_bytes].copy_from_slice(bytemuck::bytes_of(&out));
+        }
+    });
+}
@@ -0,0 +1,293 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
+
+use std::sync::{ Arc, mpsc, atomic::{ AtomicBool, Ordering::Relaxed } };
+use std::collections::VecDeque;
+use parking_lot::RwLock;
+use nalgebra::{ Quaternion, UnitQuaternion, Vector3 };
+use super::{ Stabilization, FrameTransform, ProcessedInfo, PixelType };
+use crate::gpu::{ Buffers, BufferDescription, BufferSource };
+use crate::gyro_source::{ GyroSource, Quat64, TimeIMU };
+use crate::GyroflowCoreError;
+
+pub struct StreamFrame {
+    pub timestamp_us: i64,
+    pub input: Vec<u8>,
+    pub input_size: (usize, usize, usize), // width, height, stride
+    pub transform: Option<FrameTransform>, // Computed from the loaded motion data if None
+}
+
+pub struct StreamOutput {
+    pub timestamp_us: i64,
+    pub output: Vec<u8>,
+    pub result: Result<ProcessedInfo, GyroflowCoreError>,
+}
+
+impl Stabilization {
+    // Process an arbitrarily long sequence of frames with bounded memory. `producer` runs on its own thread and blocks
+    // when `max_in_flight` frames are waiting, so decoding can't outrun the GPU. Outputs are passed to `consumer` in order,
+    // return false from it to stop early. Transforms are not cached in `stab_data`. Returns the number of processed frames
+    pub fn process_stream<T: PixelType, P, C>(&mut self, producer: P, output_size: (usize, usize, usize), max_in_flight: usize, mut consumer: C) -> usize
+        where P: Iterator<Item = StreamFrame> + Send,
+              C: FnMut(StreamOutput) -> bool
+    {
+        let (tx, rx) = mpsc::sync_channel::<StreamFrame>(max_in_flight.max(1));
+        let stop = AtomicBool::new(false);
+
+        std::thread::scope(|s| {
+            let stop = &stop;
+            s.spawn(move || {
+                for frame in producer {
+                    if stop.load(Relaxed) || tx.send(frame).is_err() { break; }
+                }
+            });
+
+            let mut processed = 0;
+            for mut frame in rx.iter() {
+                let mut output = vec![0u8; output_size.2 * output_size.1];
+                let result = {
+                    let mut buffers = Buffers {
+                        input:  BufferDescription { size: frame.input_size, data: BufferSource::Cpu { buffer: &mut frame.input }, ..Default::default() },
+                        output: BufferDescription { size: output_size,      data: BufferSource::Cpu { buffer: &mut output },      ..Default::default() },
+                    };
+                    if self.backend_initialized.is_none() {
+                        self.ensure_ready_for_processing::<T>(frame.timestamp_us, &mut buffers);
+                        self.stab_data.clear();
+                    }
+                    let transform = match frame.transform.take() {
+                        Some(t) => t,
+                        None => self.get_frame_transform_at::<T>(frame.timestamp_us, &mut buffers)
+                    };
+                    self.process_pixels::<T>(frame.timestamp_us, &mut buffers, Some(&transform))
+                };
+                processed += 1;
+                if !consumer(StreamOutput { timestamp_us: frame.timestamp_us, output, result }) {
+                    stop.store(true, Relaxed);
+                    break;
+                }
+            }
+            drop(rx); // Unblocks the producer if it's waiting on a full channel
+            processed
+        })
+    }
+}
+
+// Live capture: the host pushes frames and gyro samples as they arrive and calls `process` regularly.
+// A frame is rendered once the gyro data reaches `latency_ms` past its last row, and its orientation is smoothed over that window on both sides
+pub struct LiveStream {
+    gyro: Arc<RwLock<GyroSource>>, // Integrated orientations of the buffered samples, used instead of the loaded motion data
+    frames: VecDeque<StreamFrame>,
+    orientation: Quat64,
+    last_sample_ms: Option<f64>,
+    latency_ms: f64,
+    sync_offset_ms: f64,
+    max_frames: usize,
+    dropped_frames: usize,
+}
+
+impl LiveStream {
+    pub fn new(latency_ms: f64, max_frames: usize) -> Self {
+        Self {
+            gyro: Arc::new(RwLock::new(GyroSource::new())),
+            frames: VecDeque::new(),
+            orientation: UnitQuaternion::from_euler_angles(std::f64::consts::FRAC_PI_2, 0.0, 0.0), // Same as `SimpleGyroIntegrator`
+            last_sample_ms: None,
+            latency_ms: latency_ms.max(0.0),
+            sync_offset_ms: 0.0,
+            max_frames: max_frames.max(1),
+            dropped_frames: 0,
+        }
+    }
+
+    pub fn set_latency(&mut self, latency_ms: f64) { self.latency_ms = latency_ms.max(0.0); }
+    pub fn get_latency(&self) -> f64 { self.latency_ms }
+
+    // Same meaning as the sync offsets of recorded files: gyro timestamp = frame timestamp - offset
+    pub fn set_sync_offset(&mut self, offset_ms: f64) {
+        self.sync_offset_ms = offset_ms;
+        self.gyro.write().set_offsets([(0, offset_ms)].into_iter().collect());
+    }
+    pub fn get_sync_offset(&self) -> f64 { self.sync_offset_ms }
+
+    // Frames waiting for the gyro data to catch up
+    pub fn buffered_frames(&self) -> usize { self.frames.len() }
+    pub fn dropped_frames(&self) -> usize { self.dropped_frames }
+
+    // Gyro in deg/s, timestamps in ms on the gyro clock. Samples older than the last pushed one are ignored
+    pub fn push_imu(&mut self, samples: &[TimeIMU]) {
+        let mut gyro = self.gyro.write();
+        for v in samples {
+            if let Some(g) = v.gyro.as_ref() {
+                if let Some(prev) = self.last_sample_ms {
+                    if v.timestamp_ms <= prev { continue; }
+                    let omega = Vector3::new(-g[1], g[0], g[2]) * (std::f64::consts::PI / 180.0);
+                    let delta_q = UnitQuaternion::from_scaled_axis(omega * (v.timestamp_ms - prev) / 1000.0);
+                    self.orientation = Quat64::from_quaternion(self.orientation.quaternion() * delta_q.quaternion());
+                }
+                self.last_sample_ms = Some(v.timestamp_ms);
+                gyro.quaternions.insert((v.timestamp_ms * 1000.0) as i64, self.orientation);
+            }
+        }
+        gyro.duration_ms = self.last_sample_ms.unwrap_or_default();
+    }
+
+    // Frames have to be pushed in presentation order. When more than `max_frames` are waiting for the gyro data, the oldest ones are dropped
+    pub fn push_frame(&mut self, frame: StreamFrame) {
+        self.frames.push_back(frame);
+        while self.frames.len() > self.max_frames {
+            self.frames.pop_front();
+            self.dropped_frames += 1;
+        }
+    }
+
+    // Render all frames which have enough gyro data after them, in order. Returns the number of processed frames
+    pub fn process<T: PixelType, C>(&mut self, stab: &mut Stabilization, output_size: (usize, usize, usize), mut consumer: C) -> usize
+        where C: FnMut(StreamOutput)
+    {
+        let readout_ms = stab.compute_params.frame_readout_time;
+        let mut processed = 0;
+        while let Some(mut frame) = self.pop_ready_frame(readout_ms) {
+            let mut output = vec![0u8; output_size.2 * output_size.1];
+            let result = {
+                let mut buffers = Buffers {
+                    input:  BufferDescription { size: frame.input_size, data: BufferSource::Cpu { buffer: &mut frame.input }, ..Default::default() },
+                    output: BufferDescription { size: output_size,      data: BufferSource::Cpu { buffer: &mut output },      ..Default::default() },
+                };
+                if stab.backend_initialized.is_none() {
+                    stab.ensure_ready_for_processing::<T>(frame.timestamp_us, &mut buffers);
+                    stab.stab_data.clear();
+                }
+                let transform = match frame.transform.take() {
+                    Some(t) => t,
+                    None => {
+                        let loaded_gyro = std::mem::replace(&mut stab.compute_params.gyro, self.gyro.clone());
+                        let t = stab.get_frame_transform_at::<T>(frame.timestamp_us, &mut buffers);
+                        stab.compute_params.gyro = loaded_gyro;
+                        t
+                    }
+                };
+                stab.process_pixels::<T>(frame.timestamp_us, &mut buffers, Some(&transform))
+            };
+            processed += 1;
+            consumer(StreamOutput { timestamp_us: frame.timestamp_us, output, result });
+        }
+        self.prune(readout_ms);
+        processed
+    }
+
+    // Pops the oldest frame if the gyro data reaches `latency_ms` past its last row, and smooths the orientations it needs
+    fn pop_ready_frame(&mut self, readout_ms: f64) -> Option<StreamFrame> {
+        let timestamp_ms = self.frames.front()?.timestamp_us as f64 / 1000.0 - self.sync_offset_ms;
+        if !self.last_sample_ms.map(|last| last >= timestamp_ms + readout_ms.abs() / 2.0 + self.latency_ms).unwrap_or_default() { return None; }
+
+        self.smooth_at(timestamp_ms, readout_ms);
+        self.frames.pop_front()
+    }
+
+    // First and last row of a frame in microseconds on the gyro clock
+    fn rows_range_us(timestamp_ms: f64, readout_ms: f64) -> (i64, i64) {
+        let half_readout_ms = readout_ms.abs() / 2.0;
+        (((timestamp_ms - half_readout_ms) * 1000.0).floor() as i64, ((timestamp_ms + half_readout_ms) * 1000.0).ceil() as i64)
+    }
+
+    // Gaussian weighted mean of the orientations within `latency_ms` around every sample the frame's rows can reach,
+    // including the ones right outside for interpolation, so it's interpolated the same way as recorded data
+    fn smooth_at(&self, timestamp_ms: f64, readout_ms: f64) {
+        let mut gyro = self.gyro.write();
+        let (first_us, last_us) = Self::rows_range_us(timestamp_ms, readout_ms);
+        let window_us = (self.latency_ms * 1000.0).round() as i64;
+        let sigma_ms = (self.latency_ms / 2.0).max(1.0);
+
+        let first_us = gyro.quaternions.range(..=first_us).next_back().map(|(ts, _)| *ts).unwrap_or(first_us);
+        let last_us  = gyro.quaternions.range(last_us..).next().map(|(ts, _)| *ts).unwrap_or(last_us);
+        let keys = gyro.quaternions.range(first_us..=last_us).map(|(ts, _)| *ts).collect::<Vec<_>>();
+        for key in keys {
+            let center = gyro.quaternions[&key];
+            let mut sum = Quaternion::new(0.0, 0.0, 0.0, 0.0);
+            for (ts, q) in gyro.quaternions.range(key - window_us..=key + window_us) {
+                let weight = (-((ts - key) as f64 / 1000.0 / sigma_ms).powi(2) / 2.0).exp();
+                // Same hemisphere as the center, q and -q are the same rotation
+                let q = if q.coords.dot(&center.coords) < 0.0 { -*q.quaternion() } else { *q.quaternion() };
+                sum += q * weight;
+            }
+            gyro.smoothed_quaternions.insert(key, Quat64::from_quaternion(sum));
+        }
+    }
+
+    // Drop the orientations which are no longer needed by the buffered frames
+    fn prune(&mut self, readout_ms: f64) {
+        if let Some(last) = self.last_sample_ms {
+            let oldest_ms = self.frames.front().map(|x| x.timestamp_us as f64 / 1000.0 - self.sync_offset_ms).unwrap_or(last).min(last);
+            let (first_us, _) = Self::rows_range_us(oldest_ms, readout_ms);
+            let mut gyro = self.gyro.write();
+            // The sample before the first row is used for interpolation, and it's smoothed with the window before it
+            let first_us = gyro.quaternions.range(..=first_us).next_back().map(|(ts, _)| *ts).unwrap_or(first_us);
+            let cutoff_us = first_us - (self.latency_ms * 1000.0).round() as i64;
+            gyro.quaternions = gyro.quaternions.split_off(&cutoff_us);
+            gyro.smoothed_quaternions = gyro.smoothed_quaternions.split_off(&cutoff_us);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_samples(stream: &mut LiveStream, from_ms: i64, to_ms: i64) {
+        let samples = (from_ms..=to_ms).map(|ms| TimeIMU { timestamp_ms: ms as f64, gyro: Some([10.0, -5.0, 2.0]), accl: None, magn: None }).collect::<Vec<_>>();
+        stream.push_imu(&samples);
+    }
+    fn frame(timestamp_ms: i64) -> StreamFrame {
+        StreamFrame { timestamp_us: timestamp_ms * 1000, input: Vec::new(), input_size: (0, 0, 0), transform: None }
+    }
+
+    #[test]
+    fn frames_wait_for_latency() {
+        let mut stream = LiveStream::new(50.0, 10);
+        stream.push_frame(frame(40));
+        stream.push_frame(frame(60));
+        assert!(stream.pop_ready_frame(0.0).is_none()); // No gyro data yet
+
+        push_samples(&mut stream, 0, 89);
+        assert!(stream.pop_ready_frame(0.0).is_none());
+        push_samples(&mut stream, 90, 100);
+        assert_eq!(stream.pop_ready_frame(0.0).map(|x| x.timestamp_us), Some(40_000));
+        assert!(stream.pop_ready_frame(0.0).is_none());
+
+        push_samples(&mut stream, 101, 109);
+        assert!(stream.pop_ready_frame(0.0).is_none());
+        push_samples(&mut stream, 110, 110);
+        assert_eq!(stream.pop_ready_frame(0.0).map(|x| x.timestamp_us), Some(60_000));
+        assert_eq!(stream.buffered_frames(), 0);
+        assert_eq!(stream.dropped_frames(), 0);
+    }
+
+    #[test]
+    fn rolling_shutter_window() {
+        let mut stream = LiveStream::new(20.0, 10);
+        stream.push_frame(frame(100));
+        push_samples(&mut stream, 0, 129);
+        // The last row is at 110 ms
+        assert!(stream.pop_ready_frame(20.0).is_none());
+        push_samples(&mut stream, 130, 130);
+        assert!(stream.pop_ready_frame(20.0).is_some());
+
+        // Every sample the rows can reach is smoothed, other ones are left alone
+        let gyro = stream.gyro.read();
+        let smoothed = gyro.smoothed_quaternions.keys().copied().collect::<Vec<_>>();
+        assert_eq!(smoothed, (90..=110).map(|ms| ms * 1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn dropped_frames() {
+        let mut stream = LiveStream::new(10.0, 3);
+        for ms in 0..5 { stream.push_frame(frame(ms * 10)); }
+        assert_eq!(stream.buffered_frames(), 3);
+        assert_eq!(stream.dropped_frames(), 2);
+
+        // The oldest ones are dropped
+        push_samples(&mut stream, 0, 100);
+        let ready = std::iter::from_fn(|| stream.pop_ready_frame(0.0)).map(|x| x.timestamp_us).collect::<Vec<_>>();
+        assert_eq!(ready, [20_000, 30_000, 40_000]);
+    }
+}
@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2023 Adrian <adrian.eddy at gmail>
+
+use super::{ Stabilization, KernelParams, FrameTransform, PixelType };
+use super::distortion_models::DistortionModel;
+use crate::gpu::{ Buffers, BufferDescription, BufferSource };
+
+// Everything the GPU wrappers are created from, dumped for a single frame.
+// Allows reconstructing an equivalent wrapper from a bug report without the original video or GPU textures
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct WrapperConfig {
+    pub kernel_params: Vec<u32>, // Raw KernelParams words, so the struct round-trips bit-exact
+    pub matrices: Vec<[f32; 12]>,
+    pub pixel_format: String,
+    pub ocl_names: [String; 4],
+    pub input_size: (usize, usize, usize), // width, height, stride
+    pub output_size: (usize, usize, usize),
+    pub input_rect: Option<(usize, usize, usize, usize)>,
+    pub output_rect: Option<(usize, usize, usize, usize)>,
+    pub distortion_model: String,
+    pub digital_lens: Option<String>,
+    pub blended_lens: Option<String>,
+    pub drawing_len: usize,
+    pub compute_params: String, // Debug dump, for reference only
+}
+
+impl WrapperConfig {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    pub fn kernel_params(&self) -> KernelParams {
+        let mut params = KernelParams::default();
+        let dst = bytemuck::bytes_of_mut(&mut params);
+        let src: &[u8] = bytemuck::cast_slice(&self.kernel_params);
+        let len = dst.len().min(src.len());
+        dst[..len].copy_from_slice(&src[..len]);
+        params
+    }
+    pub fn frame_transform(&self) -> FrameTransform {
+        FrameTransform {
+            matrices: self.matrices.clone(),
+            kernel_params: self.kernel_params(),
+            ..Default::default()
+        }
+    }
+    pub fn distortion_models(&self) -> (DistortionModel, Option<DistortionModel>, Option<DistortionModel>) {
+        (
+            DistortionModel::from_name(&self.distortion_model),
+            self.digital_lens.as_deref().map(DistortionModel::from_name),
+            self.blended_lens.as_deref().map(DistortionModel::from_name)
+        )
+    }
+
+    // Zeroed input and output pixel data with the dumped strides, to be used with `buffers`
+    pub fn synthetic_buffers(&self) -> (Vec<u8>, Vec<u8>) {
+        (
+            vec![0u8; self.input_size.2 * self.input_size.1],
+            vec![0u8; self.output_size.2 * self.output_size.1]
+        )
+    }
+    pub fn buffers<'a>(&self, input: &'a mut [u8], output: &'a mut [u8]) -> Buffers<'a> {
+        Buffers {
+            input: BufferDescription {
+                size: self.input_size,
+                rect: self.input_rect,
+                data: BufferSource::Cpu { buffer: input },
+                ..Default::default()
+            },
+            output: BufferDescription {
+                size: self.output_size,
+                rect: self.output_rect,
+                data: BufferSource::Cpu { buffer: output },
+                ..Default::default()
+            }
+        }
+    }
+}
+
+impl Stabilization {
+    // Dumps the wrapper configuration of the frame at `timestamp_us`. Frame has to be computed already (see `get_frame_transform_at`)
+    pub fn serialize_config<T: PixelType>(&self, timestamp_us: i64, buffers: &Buffers) -> Option<String> {
+        let itm = self.stab_data.get(&timestamp_us)?;
+        let names = T::ocl_names();
+        let config = WrapperConfig {
+            kernel_params: bytemuck::cast_slice(bytemuck::bytes_of(&itm.kernel_params)).to_vec(),
+            matrices: itm.matrices.clone(),
+            pixel_format: std::any::type_name::<T>().to_string(),
+            ocl_names: [names.0.to_string(), names.1.to_string(), names.2.to_string(), names.3.to_string()],
+            input_size: buffers.input.size,
+            output_size: buffers.output.size,
+            input_rect: buffers.input.rect,
+            output_rect: buffers.output.rect,
+            distortion_model: self.compute_params.distortion_model.id().to_string(),
+            digital_lens: self.compute_params.digital_lens.as_ref().map(|x| x.id().to_string()),
+            blended_lens: self.compute_params.blended_lens.as_ref().map(|x| x.id().to_string()),
+            drawing_len: self.drawing.get_buffer_len(),
+            compute_params: format!("{:?}", self.compute_params),
+        };
+        Some(config.to_json())
+    }
+}
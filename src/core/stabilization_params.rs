@@ -26,6 +26,58 @@ impl From<i32> for BackgroundMode {
     }
 }
 
+// Color description written to the output file. The processing itself doesn't depend on it
+#[allow(non_camel_case_types)]
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub enum ColorSpace {
+    #[default]
+    Rec709 = 0,
+    Rec2020 = 1,
+    Rec2020_HLG = 2,
+    Rec2020_PQ = 3,
+    SLog3_SGamut3Cine = 4,
+    LogC_AWG = 5,
+    CanonLog2 = 6,
+}
+impl From<i32> for ColorSpace {
+    fn from(v: i32) -> Self {
+        match v {
+            1 => Self::Rec2020,
+            2 => Self::Rec2020_HLG,
+            3 => Self::Rec2020_PQ,
+            4 => Self::SLog3_SGamut3Cine,
+            5 => Self::LogC_AWG,
+            6 => Self::CanonLog2,
+            _ => Self::Rec709
+        }
+    }
+}
+impl ColorSpace {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Rec709            => "Rec. 709",
+            Self::Rec2020           => "Rec. 2020",
+            Self::Rec2020_HLG       => "Rec. 2020 HLG",
+            Self::Rec2020_PQ        => "Rec. 2020 PQ",
+            Self::SLog3_SGamut3Cine => "S-Log3 S-Gamut3.Cine",
+            Self::LogC_AWG          => "LogC ARRI Wide Gamut",
+            Self::CanonLog2         => "Canon Log 2",
+        }
+    }
+    // Camera log encodings don't have standard color primaries and transfer characteristics in the codecs' color description
+    pub fn is_camera_log(&self) -> bool {
+        matches!(self, Self::SLog3_SGamut3Cine | Self::LogC_AWG | Self::CanonLog2)
+    }
+}
+
+// Second lens model blended across the frame, for hybrid optics (eg. catadioptric or dual-lens rigs)
+#[derive(Default, Clone, Debug)]
+pub struct BlendedLens {
+    pub distortion_model: String,
+    pub distortion_coeffs: Vec<f64>,
+    pub region: [f64; 4], // start, end, direction x, y - in normalized coordinates. Blends radially if the direction is 0
+}
+
 #[derive(Clone, Debug)]
 pub struct StabilizationParams {
     pub size: (usize, usize), // Processing input size
@@ -39,6 +91,8 @@ pub struct StabilizationParams {
     pub adaptive_zoom_window: f64,
     pub adaptive_zoom_center_offset: (f64, f64),
     pub adaptive_zoom_method: i32,
+    pub smart_zoom_enabled: bool,
+    pub smart_zoom_smoothing_window: f64, // seconds
     pub fov: f64,
     pub fov_overview: bool,
     pub show_safe_area: bool,
@@ -65,13 +119,24 @@ pub struct StabilizationParams {
     pub background_margin: f64,
     pub background_margin_feather: f64,
 
+    pub input_crop: Option<(u32, u32, u32, u32)>, // x, y, width, height - in video pixels
+    pub blended_lens: Option<BlendedLens>,
+
+    pub output_color_space: Option<ColorSpace>, // None keeps the color description of the input
+
     pub horizontal_rs: bool,
+    pub stabilize_only_translation: bool, // Keep the camera rotation and only correct the translational shake
+    pub bypass_lens: bool, // Don't undistort the lens, treat the input as rectilinear. Keeps the original look, only the rotation is stabilized
+    pub bypass_digital_lens: bool,
+    pub perspective_correction: Option<[f64; 8]>, // Keystone homography in normalized output coordinates, see `ComputeParams::perspective_correction_from_points`
+    pub user_distortion: [f64; 4], // k1, k2, p1, p2 - manual correction of the residual distortion on top of the lens profile
     pub framebuffer_inverted: bool,
     pub is_calibrator: bool,
 
     pub stab_enabled: bool,
     pub show_detected_features: bool,
     pub show_optical_flow: bool,
+    pub show_residual_motion: bool, // Diagnostic overlay of the motion left after stabilization, see `StabilizationManager::get_residual_motion_lines`
 
     pub of_method: u32,
     pub current_device: i32,
@@ -90,10 +155,13 @@ impl Default for StabilizationParams {
             stab_enabled: true,
             show_detected_features: true,
             show_optical_flow: true,
+            show_residual_motion: false,
             frame_readout_time: 0.0,
             adaptive_zoom_window: 4.0,
             adaptive_zoom_center_offset: (0.0, 0.0),
             adaptive_zoom_method: 0,
+            smart_zoom_enabled: false,
+            smart_zoom_smoothing_window: 1.0,
 
             size: (0, 0),
             output_size: (0, 0),
@@ -107,7 +175,17 @@ impl Default for StabilizationParams {
             background_margin: 0.0,
             background_margin_feather: 0.0,
 
+            input_crop: None,
+            blended_lens: None,
+
+            output_color_space: None,
+
             horizontal_rs: false,
+            stabilize_only_translation: false,
+            bypass_lens: false,
+            bypass_digital_lens: false,
+            perspective_correction: None,
+            user_distortion: [0.0; 4],
             framebuffer_inverted: false,
             is_calibrator: false,
 
@@ -221,6 +299,7 @@ impl StabilizationParams {
             stab_enabled:              self.stab_enabled,
             show_detected_features:    self.show_detected_features,
             show_optical_flow:         self.show_optical_flow,
+            show_residual_motion:      self.show_residual_motion,
             background:                self.background,
             adaptive_zoom_window:      self.adaptive_zoom_window,
             framebuffer_inverted:      self.framebuffer_inverted,
@@ -231,9 +310,12 @@ impl StabilizationParams {
             background_mode:           self.background_mode,
             background_margin:         self.background_margin,
             background_margin_feather: self.background_margin_feather,
+            output_color_space:        self.output_color_space,
             of_method:                 self.of_method,
             current_device:            self.current_device,
             adaptive_zoom_method:      self.adaptive_zoom_method,
+            smart_zoom_enabled:        self.smart_zoom_enabled,
+            smart_zoom_smoothing_window: self.smart_zoom_smoothing_window,
             fov_overview:              self.fov_overview,
             show_safe_area:            self.show_safe_area,
             ..Default::default()
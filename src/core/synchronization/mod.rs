@@ -21,6 +21,7 @@ mod find_offset { pub mod rs_sync; pub mod essential_matrix; pub mod visual_feat
 use super::gyro_source::TimeIMU;
 
 pub mod optimsync;
+pub mod phase_response;
 mod autosync;
 pub use autosync::AutosyncProcess;
 use crate::util::MapClosest;
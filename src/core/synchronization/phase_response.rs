@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
+
+use rustfft::{ num_complex::Complex, FftPlanner };
+use crate::gyro_source::GyroSource;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PhaseResponse {
+    pub group_delay_ms: f64, // Positive when the gyro lags the image, relative to the current sync offsets
+    pub confidence: f64, // 0-1, how well the phase follows a pure delay
+}
+
+const BAND_HZ: (f64, f64) = (1.0, 30.0);
+
+// Fits a pure delay to the phase of the cross power spectrum between the reference motion and gyro rate.
+// `reference_motion` is (timestamp_us, x, y) image motion, timestamp in the middle of the frame pair
+pub fn compute_phase_response(gyro: &GyroSource, reference_motion: &[(i64, f64, f64)]) -> PhaseResponse {
+    if reference_motion.len() < 16 || gyro.raw_imu.len() < 2 { return PhaseResponse::default(); }
+
+    let mut dts = reference_motion.windows(2).map(|w| (w[1].0 - w[0].0) as f64 / 1000.0).filter(|x| *x > 0.0).collect::<Vec<f64>>();
+    if dts.is_empty() { return PhaseResponse::default(); }
+    dts.sort_by(|a, b| a.total_cmp(b));
+    let sample_rate = 1000.0 / dts[dts.len() / 2];
+
+    // Gyro rate at the reference timestamps, with the current sync offsets applied
+    let gyro_at = |ts_ms: f64| -> [f64; 3] {
+        let ts_ms = ts_ms - gyro.offset_at_video_timestamp(ts_ms);
+        let imu = &gyro.raw_imu;
+        let r = imu.partition_point(|x| x.timestamp_ms < ts_ms).min(imu.len() - 1);
+        let l = r.max(1) - 1;
+        let (gl, gr) = (imu[l].gyro.unwrap_or_default(), imu[r].gyro.unwrap_or_default());
+        let span = imu[r].timestamp_ms - imu[l].timestamp_ms;
+        let t = if span > 0.0 { ((ts_ms - imu[l].timestamp_ms) / span).clamp(0.0, 1.0) } else { 0.0 };
+        [gl[0] + (gr[0] - gl[0]) * t, gl[1] + (gr[1] - gl[1]) * t, gl[2] + (gr[2] - gl[2]) * t]
+    };
+    let gyro_samples = reference_motion.iter().map(|x| gyro_at(x.0 as f64 / 1000.0)).collect::<Vec<_>>();
+
+    let n = reference_motion.len();
+    let mut planner = FftPlanner::<f64>::new();
+    let fft = planner.plan_fft_forward(n);
+    let spectrum = |signal: &mut dyn Iterator<Item = f64>| -> Vec<Complex<f64>> {
+        let v = signal.collect::<Vec<f64>>();
+        let mean = v.iter().sum::<f64>() / n as f64;
+        let mut buf = v.iter().enumerate().map(|(i, x)| {
+            let hann = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / (n - 1) as f64).cos();
+            Complex::new((x - mean) * hann, 0.0)
+        }).collect::<Vec<_>>();
+        fft.process(&mut buf);
+        buf
+    };
+    let gyro_spectra = (0..3).map(|a| spectrum(&mut gyro_samples.iter().map(|g| g[a]))).collect::<Vec<_>>();
+
+    let bins = (((BAND_HZ.0 * n as f64 / sample_rate).ceil() as usize).max(1))..=((BAND_HZ.1.min(sample_rate / 2.0) * n as f64 / sample_rate).floor() as usize).min(n / 2);
+    if bins.is_empty() { return PhaseResponse::default(); }
+
+    // (weight, frequency, phase) of every bin, for both image axes
+    let mut points = Vec::new();
+    for ref_spectrum in [spectrum(&mut reference_motion.iter().map(|x| x.1)), spectrum(&mut reference_motion.iter().map(|x| x.2))] {
+        // Pick the gyro axis (and its sign) that matches this image axis best
+        let cross = gyro_spectra.iter().map(|g| bins.clone().map(|i| ref_spectrum[i] * g[i].conj()).collect::<Vec<_>>()).collect::<Vec<_>>();
+        let best = cross.iter().max_by(|a, b| {
+            let sa = a.iter().map(|x| x.re).sum::<f64>().abs();
+            let sb = b.iter().map(|x| x.re).sum::<f64>().abs();
+            sa.total_cmp(&sb)
+        });
+        if let Some(cross) = best {
+            let sign = if cross.iter().map(|x| x.re).sum::<f64>() < 0.0 { -1.0 } else { 1.0 };
+            let mut prev_phase = 0.0;
+            for (i, c) in bins.clone().zip(cross.iter()) {
+                let c = *c * sign;
+                // Unwrap against the previous bin, the phase of a pure delay grows linearly with frequency
+                let mut phase = c.arg();
+                while phase - prev_phase >  std::f64::consts::PI { phase -= 2.0 * std::f64::consts::PI; }
+                while phase - prev_phase < -std::f64::consts::PI { phase += 2.0 * std::f64::consts::PI; }
+                prev_phase = phase;
+                points.push((c.norm(), i as f64 * sample_rate / n as f64, phase));
+            }
+        }
+    }
+
+    // Weighted least squares of phase = 2π * f * delay, through the origin
+    let (num, den) = points.iter().fold((0.0, 0.0), |a, (w, f, p)| (a.0 + w * f * p, a.1 + w * f * f));
+    if den <= 0.0 { return PhaseResponse::default(); }
+    let delay_s = num / den / (2.0 * std::f64::consts::PI);
+
+    // Length of the mean residual phasor, 1 when every bin agrees with the delay
+    let total_weight = points.iter().map(|x| x.0).sum::<f64>();
+    let residual = points.iter().fold(Complex::new(0.0, 0.0), |a, (w, f, p)| a + Complex::from_polar(*w, p - 2.0 * std::f64::consts::PI * f * delay_s));
+
+    PhaseResponse {
+        group_delay_ms: delay_s * 1000.0,
+        confidence: if total_weight > 0.0 { residual.norm() / total_weight } else { 0.0 }
+    }
+}
@@ -0,0 +1,98 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// Copyright © 2021-2022 Adrian <adrian.eddy at gmail>
+
+use std::collections::{ BTreeMap, VecDeque };
+
+// Values restored by undo/redo
+#[derive(Default, Clone, PartialEq, Debug)]
+pub struct UndoState {
+    pub imu_lpf: f64,
+    pub smoothing_params: Vec<(String, f64)>,
+    pub sync_offsets: BTreeMap<i64, f64>,
+}
+
+// Only the fields which changed, to limit memory usage
+#[derive(Default, Clone, Debug)]
+pub struct UndoDiff {
+    pub imu_lpf: Option<f64>,
+    pub smoothing_params: Option<Vec<(String, f64)>>,
+    pub sync_offsets: Option<BTreeMap<i64, f64>>,
+}
+
+impl UndoState {
+    // Fields of `self` which are different in `other`
+    pub fn diff(&self, other: &UndoState) -> UndoDiff {
+        UndoDiff {
+            imu_lpf:          if self.imu_lpf          != other.imu_lpf          { Some(self.imu_lpf) } else { None },
+            smoothing_params: if self.smoothing_params != other.smoothing_params { Some(self.smoothing_params.clone()) } else { None },
+            sync_offsets:     if self.sync_offsets     != other.sync_offsets     { Some(self.sync_offsets.clone()) } else { None },
+        }
+    }
+    // Fields of `self` which are set in `diff`, used to go back after applying it
+    pub fn inverse_of(&self, diff: &UndoDiff) -> UndoDiff {
+        UndoDiff {
+            imu_lpf:          diff.imu_lpf         .as_ref().map(|_| self.imu_lpf),
+            smoothing_params: diff.smoothing_params.as_ref().map(|_| self.smoothing_params.clone()),
+            sync_offsets:     diff.sync_offsets    .as_ref().map(|_| self.sync_offsets.clone()),
+        }
+    }
+}
+impl UndoDiff {
+    pub fn is_empty(&self) -> bool {
+        self.imu_lpf.is_none() && self.smoothing_params.is_none() && self.sync_offsets.is_none()
+    }
+}
+
+pub struct UndoStack {
+    undo: VecDeque<UndoDiff>,
+    redo: Vec<UndoDiff>,
+    // Full snapshot from the last push. It's turned into a diff once we know the state after the change
+    pending: Option<UndoState>,
+    max_entries: usize,
+}
+impl Default for UndoStack {
+    fn default() -> Self {
+        Self { undo: VecDeque::new(), redo: Vec::new(), pending: None, max_entries: 50 }
+    }
+}
+
+impl UndoStack {
+    pub fn push(&mut self, current: UndoState) {
+        self.finalize_pending(&current);
+        self.pending = Some(current);
+        self.redo.clear();
+    }
+
+    // Returns the diff to apply to `current` to go back to the previous state
+    pub fn undo(&mut self, current: &UndoState) -> Option<UndoDiff> {
+        self.finalize_pending(current);
+        let diff = self.undo.pop_back()?;
+        self.redo.push(current.inverse_of(&diff));
+        Some(diff)
+    }
+
+    // Returns the diff to apply to `current` to go forward to the next state
+    pub fn redo(&mut self, current: &UndoState) -> Option<UndoDiff> {
+        let diff = self.redo.pop()?;
+        self.undo.push_back(current.inverse_of(&diff));
+        Some(diff)
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.pending = None;
+    }
+
+    fn finalize_pending(&mut self, current: &UndoState) {
+        if let Some(pending) = self.pending.take() {
+            let diff = pending.diff(current);
+            if !diff.is_empty() {
+                self.undo.push_back(diff);
+                while self.undo.len() > self.max_entries {
+                    self.undo.pop_front();
+                }
+            }
+        }
+    }
+}
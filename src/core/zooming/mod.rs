@@ -52,7 +52,10 @@ pub fn calculate_fovs(compute_params: &ComputeParams, timestamps: &[f64], keyfra
 
     let fov_estimator = fov_iterative::FovIterative::new(&compute_params);
     let mut fov_values = fov_estimator.compute(timestamps, (compute_params.trim_start, compute_params.trim_end));
-    let (final_fovs, final_fovs_minimal) = if compute_params.adaptive_zoom_window < -0.9 {
+    let (final_fovs, final_fovs_minimal) = if compute_params.smart_zoom_enabled {
+        // Smart zoom
+        zoom_dynamic::compute_smart(&compute_params, fov_values)
+    } else if compute_params.adaptive_zoom_window < -0.9 {
         // Static zoom
         let fov_minimal = fov_values.clone();
         if let Some(max_f) = fov_values.iter().copied().reduce(f64::min) {
@@ -84,6 +87,8 @@ pub fn get_checksum(compute_params: &ComputeParams) -> u64 {
     hasher.write_u64(compute_params.trim_end.to_bits());
     hasher.write_u64(compute_params.video_rotation.to_bits());
     hasher.write_u64(compute_params.adaptive_zoom_window.to_bits());
+    hasher.write_u8(compute_params.smart_zoom_enabled as u8);
+    hasher.write_u64(compute_params.smart_zoom_smoothing_window.to_bits());
 
     hasher.finish()
 }
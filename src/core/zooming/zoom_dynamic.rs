@@ -77,6 +77,24 @@ pub fn compute(compute_params: &ComputeParams, mut fov_values: Vec<f64>, timesta
     (fov_values, fov_minimal)
 }
 
+// Zooms each frame only as much as it needs, `fov_values` are already the per-frame minimum from the corner projections.
+// Rolling minimum and Gaussian over `smart_zoom_smoothing_window` to avoid jumps, then clamped so no frame ends up zoomed less than it needs
+pub fn compute_smart(compute_params: &ComputeParams, fov_values: Vec<f64>) -> (Vec<f64>, Vec<f64>) {
+    let mut frames = (compute_params.smart_zoom_smoothing_window * compute_params.scaled_fps).floor().max(1.0) as usize;
+    if frames % 2 == 0 {
+        frames += 1;
+    }
+
+    let fov_values_pad = pad_edge(&fov_values, (frames / 2, frames / 2));
+    let fov_min = min_rolling(&fov_values_pad, frames);
+    let fov_min_pad = pad_edge(&fov_min, (frames / 2, frames / 2));
+
+    let gaussian = gaussian_window_normalized(frames, frames as f64 / 6.0);
+    let smoothed = convolve(&fov_min_pad, &gaussian).into_iter().zip(&fov_values).map(|(s, &v)| s.min(v)).collect();
+
+    (smoothed, fov_values)
+}
+
 fn get_frames_per_window(compute_params: &ComputeParams) -> usize {
     let mut frames = (compute_params.adaptive_zoom_window * compute_params.scaled_fps).floor() as usize;
     if frames % 2 == 0 {
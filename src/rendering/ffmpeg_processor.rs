@@ -69,6 +69,7 @@ pub enum FFmpegError {
     InternalError(ffmpeg_next::Error),
     CannotOpenInputFile((String, FilesystemError)),
     CannotOpenOutputFile((String, FilesystemError)),
+    StabilizationError(String),
 }
 
 impl std::fmt::Display for FFmpegError {
@@ -94,6 +95,7 @@ impl std::fmt::Display for FFmpegError {
             FFmpegError::InternalError(e)     => write!(f, "ffmpeg error: {:?}", e),
             FFmpegError::CannotOpenInputFile((url, e))   => write!(f, "Cannot open input file {url}: {e:?}"),
             FFmpegError::CannotOpenOutputFile((url, e))   => write!(f, "Cannot open output file {url}: {e:?}"),
+            FFmpegError::StabilizationError(e)           => write!(f, "Stabilization error: {e}"),
         }
     }
 }
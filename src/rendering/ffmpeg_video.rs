@@ -42,6 +42,7 @@ pub struct EncoderParams<'a> {
     pub frame_rate: Option<Rational>,
     pub time_base: Option<Rational>,
     pub keyframe_distance_s: f64,
+    pub color_description: Option<(util::color::Primaries, util::color::TransferCharacteristic, util::color::Space)>, // Overrides the one from the input
 }
 #[derive(Default)]
 pub struct VideoTranscoder<'a> {
@@ -133,6 +134,14 @@ impl<'a> VideoTranscoder<'a> {
             }
             (*encoder.as_mut_ptr()).color_primaries = (*frame.as_ptr()).color_primaries;
         }
+        if let Some((primaries, trc, space)) = params.color_description {
+            log::debug!("Setting output color description: {:?}, {:?}, {:?}", primaries, trc, space);
+            encoder.set_colorspace(space);
+            unsafe {
+                (*encoder.as_mut_ptr()).color_primaries = primaries.into();
+                (*encoder.as_mut_ptr()).color_trc = trc.into();
+            }
+        }
 
         if global_header {
             encoder.set_flags(codec::Flags::GLOBAL_HEADER);
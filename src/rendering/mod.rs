@@ -252,6 +252,7 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
     proc.video.encoder_params.hw_device_type = encoder.2;
     proc.video.encoder_params.options.set("threads", "auto");
     proc.video.encoder_params.metadata = render_options.get_metadata_dict();
+    proc.video.encoder_params.color_description = params.output_color_space.map(color_description);
     proc.video.processing_order = order;
     log::debug!("video_codec: {:?}, processing_order: {:?}", &proc.video_codec, proc.video.processing_order);
 
@@ -660,6 +661,85 @@ pub fn render<F, F2>(stab: Arc<StabilizationManager>, progress: F, input_file: &
     Ok(())
 }
 
+// Stabilized RGBA8 thumbnail of the video at `timestamp_us`, fitting within `max_width` x `max_height` (see `StabilizationManager::thumbnail_size`).
+// Decodes on the CPU and the converter already scales the frame down, the stabilization uses the CPU path
+pub fn render_stabilized_thumbnail(stab: &StabilizationManager, input_file: &gyroflow_core::InputFile, timestamp_us: i64, max_width: u32, max_height: u32) -> Result<Vec<u8>, FFmpegError> {
+    let (tw, th) = stab.thumbnail_size(max_width, max_height);
+    let (vw, vh) = stab.params.read().video_size;
+    if tw == 0 || th == 0 || vw == 0 || vh == 0 { return Err(FFmpegError::FrameEmpty); }
+    // About twice the thumbnail size, the rest of the downscaling is in `render_stabilized_thumbnail` of the core
+    let scale = ((tw * 2) as f64 / vw as f64).max((th * 2) as f64 / vh as f64).min(1.0);
+    let (dw, dh) = (((vw as f64 * scale / 2.0).round() as u32 * 2).max(2), ((vh as f64 * scale / 2.0).round() as u32 * 2).max(2));
+
+    let decoded = Arc::new(parking_lot::Mutex::new(None));
+    let decoded2 = decoded.clone();
+    let cancel_flag = Arc::new(AtomicBool::new(false));
+    let cancel_flag2 = cancel_flag.clone();
+
+    let fs_base = gyroflow_core::filesystem::get_engine_base();
+    let mut proc = VideoProcessor::from_file(&fs_base, &input_file.url, false, 0, None)?;
+    proc.on_frame(move |timestamp_us, input_frame, _output_frame, converter, _rate_control| {
+        let mut decoded = decoded2.lock();
+        if decoded.is_none() {
+            let sf = converter.scale(input_frame, Pixel::RGBA, dw, dh)?;
+            *decoded = Some((timestamp_us, sf.plane_width(0) as usize, sf.plane_height(0) as usize, sf.stride(0), sf.data(0).to_vec()));
+            cancel_flag2.store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+        Ok(())
+    });
+    let timestamp_ms = timestamp_us as f64 / 1000.0;
+    proc.start_decoder_only(vec![(timestamp_ms, timestamp_ms + 100.0)], cancel_flag)?;
+
+    let (timestamp_us, width, height, stride, data) = decoded.lock().take().ok_or(FFmpegError::FrameEmpty)?;
+    stab.render_stabilized_thumbnail(&data, (width, height, stride), timestamp_us, max_width, max_height).map_err(|e| FFmpegError::StabilizationError(e.to_string()))
+}
+
+pub struct RenderJob {
+    pub stab: Arc<StabilizationManager>, // Per-clip parameters, must not be shared between jobs
+    pub input_file: gyroflow_core::InputFile,
+    pub render_options: RenderOptions, // Output path is `output_folder` + `output_filename`
+}
+pub type RenderResult = Result<(), FFmpegError>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RenderProgress {
+    pub job_index: usize,
+    pub frames_done: usize,
+    pub total_frames: usize,
+}
+
+// Render multiple clips concurrently, up to `max_concurrent` at a time. Results are in the same order as `jobs`.
+// Every job has its own StabilizationManager and thus its own GPU wrapper, the OpenCL context and device are shared by all of them
+pub fn batch_render(jobs: Vec<RenderJob>, max_concurrent: usize, progress: std::sync::mpsc::Sender<RenderProgress>, cancel_flag: Arc<AtomicBool>) -> Vec<RenderResult> {
+    use rayon::iter::{ IntoParallelIterator, IndexedParallelIterator, ParallelIterator };
+
+    let progress = Arc::new(parking_lot::Mutex::new(progress));
+    let render_job = |(job_index, job): (usize, RenderJob)| -> RenderResult {
+        let progress = progress.clone();
+        let cb = move |(_, frames_done, total_frames, _, _): (f64, usize, usize, bool, bool)| {
+            let _ = progress.lock().send(RenderProgress { job_index, frames_done, total_frames });
+        };
+        let pause_flag = Arc::new(AtomicBool::new(false));
+        let result = render(job.stab.clone(), cb.clone(), &job.input_file, &job.render_options, 0, cancel_flag.clone(), pause_flag.clone(), |_| { });
+        match result {
+            Err(FFmpegError::PixelFormatNotSupported(_)) => result,
+            Err(e) if !cancel_flag.load(std::sync::atomic::Ordering::SeqCst) => {
+                ::log::warn!("Job {job_index} failed with GPU decoding ({e}), retrying without it");
+                render(job.stab, cb, &job.input_file, &job.render_options, -1, cancel_flag.clone(), pause_flag, |_| { })
+            },
+            _ => result
+        }
+    };
+
+    match rayon::ThreadPoolBuilder::new().num_threads(max_concurrent.max(1)).build() {
+        Ok(pool) => pool.install(|| jobs.into_par_iter().enumerate().map(render_job).collect()),
+        Err(e) => {
+            ::log::error!("Failed to create the render thread pool: {e:?}");
+            jobs.into_iter().enumerate().map(render_job).collect()
+        }
+    }
+}
+
 pub fn init_log() {
 	unsafe {
         ffi::av_log_set_level(ffi::AV_LOG_INFO);
@@ -667,6 +747,20 @@ pub fn init_log() {
     }
 }
 
+// Container/bitstream color description (primaries, transfer, matrix) for the output color space.
+// Camera log curves and gamuts have no codes there, so only the matrix is tagged and the rest is unspecified
+pub fn color_description(cs: gyroflow_core::stabilization_params::ColorSpace) -> (ffmpeg_next::color::Primaries, ffmpeg_next::color::TransferCharacteristic, ffmpeg_next::color::Space) {
+    use gyroflow_core::stabilization_params::ColorSpace;
+    use ffmpeg_next::color::{ Primaries, TransferCharacteristic, Space };
+    match cs {
+        ColorSpace::Rec709      => (Primaries::BT709,  TransferCharacteristic::BT709,       Space::BT709),
+        ColorSpace::Rec2020     => (Primaries::BT2020, TransferCharacteristic::BT2020_10,   Space::BT2020NCL),
+        ColorSpace::Rec2020_HLG => (Primaries::BT2020, TransferCharacteristic::ARIB_STD_B67, Space::BT2020NCL),
+        ColorSpace::Rec2020_PQ  => (Primaries::BT2020, TransferCharacteristic::SMPTE2084,   Space::BT2020NCL),
+        ColorSpace::SLog3_SGamut3Cine | ColorSpace::LogC_AWG | ColorSpace::CanonLog2 => (Primaries::Unspecified, TransferCharacteristic::Unspecified, Space::BT709),
+    }
+}
+
 pub fn fps_to_rational(fps: f64) -> ffmpeg_next::Rational {
     if fps.fract() > 0.1 {
         ffmpeg_next::Rational::new((fps * 1001.0).round() as i32, 1001)
@@ -1020,6 +1020,8 @@ impl RenderQueue {
                             fov:                    params.fov,
                             background:             params.background,
                             adaptive_zoom_window:   params.adaptive_zoom_window,
+                            smart_zoom_enabled:     params.smart_zoom_enabled,
+                            smart_zoom_smoothing_window: params.smart_zoom_smoothing_window,
                             lens_correction_amount: params.lens_correction_amount,
                             background_mode:           params.background_mode,
                             background_margin:         params.background_margin,
@@ -190,6 +190,7 @@ MenuItem {
         Qt.callLater(notifySizeChanged);
     }
     function loadGyroflow(obj) {
+        if (obj.hasOwnProperty("output_color_space")) outputColorSpace.currentIndex = obj.output_color_space === null ? 0 : +obj.output_color_space + 1;
         const output = obj.output || { };
         if (output && Object.keys(output).length > 0) {
             if (output.output_path) {
@@ -550,6 +551,20 @@ MenuItem {
                 currentIndex: 0;
             }
         }
+        Label {
+            position: Label.LeftPosition;
+            text: qsTr("Color space");
+            ComboBox {
+                id: outputColorSpace;
+                // Index - 1 is `ColorSpace` in the core, the first one keeps the input color description
+                model: [qsTr("Same as input"), "Rec. 709", "Rec. 2020", "Rec. 2020 HLG", "Rec. 2020 PQ", "S-Log3 S-Gamut3.Cine", "LogC ARRI Wide Gamut", "Canon Log 2"];
+                font.pixelSize: 12 * dpiScale;
+                width: parent.width;
+                currentIndex: 0;
+                tooltip: qsTr("Only changes the color metadata of the output file, the pixels are not converted");
+                onCurrentIndexChanged: controller.output_color_space = currentIndex - 1;
+            }
+        }
         Label {
             position: Label.TopPosition;
             text: qsTr("Device for rendering");